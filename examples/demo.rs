@@ -0,0 +1,22 @@
+//! Sign a message with several freshly generated keys and verify every
+//! signature: `cargo run --example demo -- 3`
+
+use multisig_ecdsa::crypto::{self, Network};
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let n = match std::env::args().nth(1) {
+        Some(n) => n.parse()?,
+        None => 3,
+    };
+    let secp = secp256k1::Secp256k1::verification_only();
+    println!("content: {}", String::from_utf8_lossy(crypto::DEMO_CONTENT));
+    for (pubkey, signature) in crypto::demo_multisig(n)? {
+        crypto::verify(&secp, crypto::DEMO_CONTENT, &signature, &pubkey)?;
+        println!(
+            "{}: {}",
+            crypto::bt_addr_from_pk(&pubkey, Network::Bitcoin),
+            signature.serialize_der()
+        );
+    }
+    Ok(())
+}
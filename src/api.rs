@@ -1,20 +1,44 @@
-use std::collections::HashMap;
-
 use anyhow::{anyhow, Context};
 use axum::body::Body;
-use axum::extract::{Path, Query, State};
+use axum::extract::{Path, Query, Request, State};
+use axum::middleware::Next;
 use axum::response::{IntoResponse, Response};
 use axum::Router;
 use axum::{routing, Json};
-use http::StatusCode;
-use secp256k1::hashes::{hash160, Hash};
-use secp256k1::Keypair;
+use futures::StreamExt;
+use http::{HeaderMap, StatusCode};
+use metrics_exporter_prometheus::PrometheusHandle;
+use secp256k1::hashes::{hash160, sha256, Hash, HashEngine};
+use secp256k1::{All, PublicKey, Secp256k1};
+use serde::Serialize;
+use time::OffsetDateTime;
 
-use crate::crypto;
-use crate::domain::message::Message;
-use crate::startup::api_doc::{self, PostMsgRequest, SignMsgRequest};
+use crate::capture::RequestCapture;
+use crate::config::Settings;
+use crate::crypto::{self, SecretKeypair};
+use crate::domain::message::{self, ContentDisplay, Integrity, Message};
+use crate::domain::multisig;
+use crate::encoding::{Encoded, ResponseEncoding};
+use crate::extract::{FieldError, Validate, ValidatedJson, ValidatedQuery};
+use crate::i18n::{self, Locale};
+use crate::idempotency::{Claim, IDEMPOTENCY_KEY_HEADER};
+use crate::middleware::{RequestId, SingleFlightLayer};
+use crate::startup::api_doc::{
+    self, PatchParticipantsRequest, PostMsgRequest, SignMsgRequest,
+    SignRawMsgRequest, UploadMsgParams, UploadMsgResponse, UserSearch,
+};
+use crate::storage::{self, SharedStorage};
+use crate::webhook::WebhookEvent;
 use crate::{domain::user::User, startup::AppState};
 
+/// Machine-readable code of domain errors, it isn't localized
+pub const ERROR_CODE_HEADER: &str = "X-Error-Code";
+
+/// Upper bound of `with_keys` on user creation
+const MAX_INITIAL_KEYS: usize = 16;
+/// Messages created by a single bulk request
+const MAX_BULK_MSGS: usize = 100;
+
 #[derive(thiserror::Error)]
 pub enum ErrorResponse {
     #[error(transparent)]
@@ -27,6 +51,12 @@ pub enum ErrorResponse {
     NotFoundError(#[source] anyhow::Error),
     #[error("Conflict error")]
     ConflictError(#[source] anyhow::Error),
+    #[error("Payload too large")]
+    PayloadTooLarge(#[source] anyhow::Error),
+    #[error("Precondition failed")]
+    PreconditionFailed(#[source] anyhow::Error),
+    #[error("Forbidden")]
+    Forbidden(#[source] anyhow::Error),
 }
 
 crate::impl_debug!(ErrorResponse);
@@ -40,10 +70,12 @@ impl IntoResponse for ErrorResponse {
                 StatusCode::INTERNAL_SERVER_ERROR.into_response()
             }
             // We use middleware to make json response from BadRequest
-            ErrorResponse::BadRequest(e) => Response::builder()
-                .status(StatusCode::BAD_REQUEST)
-                .body(Body::from(e.to_string()))
-                .unwrap_or(StatusCode::BAD_REQUEST.into_response()),
+            ErrorResponse::BadRequest(e) => {
+                text_error(StatusCode::BAD_REQUEST, &e)
+            }
+            ErrorResponse::Forbidden(e) => {
+                text_error(StatusCode::FORBIDDEN, &e)
+            }
             ErrorResponse::NotFoundError(param) => Response::builder()
                 .status(StatusCode::NOT_FOUND)
                 .header("Content-Type", "application/json")
@@ -52,177 +84,2117 @@ impl IntoResponse for ErrorResponse {
             ErrorResponse::ConflictError(_) => {
                 StatusCode::CONFLICT.into_response()
             }
+            ErrorResponse::PayloadTooLarge(_) => {
+                StatusCode::PAYLOAD_TOO_LARGE.into_response()
+            }
+            ErrorResponse::PreconditionFailed(_) => {
+                StatusCode::PRECONDITION_FAILED.into_response()
+            }
+        }
+    }
+}
+
+/// `error` text with `status`, domain errors carry their code
+fn text_error(status: StatusCode, error: &anyhow::Error) -> Response {
+    let mut response = Response::builder()
+        .status(status)
+        .body(Body::from(error.to_string()))
+        .unwrap_or(status.into_response());
+    // Lets `localize_errors` translate the body
+    if let Some(error) = error.downcast_ref::<multisig::Error>() {
+        response.headers_mut().insert(
+            ERROR_CODE_HEADER,
+            http::HeaderValue::from_static(error.code()),
+        );
+        response.extensions_mut().insert(error.clone());
+    }
+    response
+}
+
+impl From<multisig::Error> for ErrorResponse {
+    fn from(value: multisig::Error) -> Self {
+        match value {
+            multisig::Error::SigningStarted | multisig::Error::Finalized => {
+                ErrorResponse::ConflictError(value.into())
+            }
+            multisig::Error::DeadlinePassed => {
+                ErrorResponse::Forbidden(value.into())
+            }
+            multisig::Error::VersionMismatch(_) => {
+                ErrorResponse::PreconditionFailed(value.into())
+            }
+            _ => ErrorResponse::BadRequest(value.into()),
+        }
+    }
+}
+
+impl From<crypto::AddressError> for ErrorResponse {
+    fn from(value: crypto::AddressError) -> Self {
+        match value {
+            crypto::AddressError::WrongVersion(_) => ErrorResponse::BadRequest(
+                anyhow!("unsupported address type: {value}"),
+            ),
+            crypto::AddressError::WrongNetwork(_) => {
+                ErrorResponse::BadRequest(anyhow!("network mismatch: {value}"))
+            }
+            _ => ErrorResponse::BadRequest(anyhow!("invalid key: {value}")),
         }
     }
 }
 
-pub fn router() -> Router<AppState> {
+impl From<crypto::SignedMessageError> for ErrorResponse {
+    fn from(value: crypto::SignedMessageError) -> Self {
+        ErrorResponse::BadRequest(anyhow!("invalid signature: {value}"))
+    }
+}
+
+impl From<crypto::SecretError> for ErrorResponse {
+    fn from(value: crypto::SecretError) -> Self {
+        ErrorResponse::BadRequest(anyhow!("invalid secret: {value}"))
+    }
+}
+
+/// Envelope for successful responses of versioned (v2+) api
+#[derive(Debug, Serialize)]
+pub struct ApiResponse<T> {
+    pub data: T,
+    pub request_id: uuid::Uuid,
+    #[serde(with = "time::serde::rfc3339")]
+    pub timestamp: OffsetDateTime,
+}
+
+impl<T> ApiResponse<T> {
+    pub fn new(request_id: Option<RequestId>, data: T) -> Self {
+        ApiResponse {
+            data,
+            request_id: request_id
+                .map(|id| id.0)
+                .unwrap_or_else(uuid::Uuid::new_v4),
+            timestamp: OffsetDateTime::now_utc(),
+        }
+    }
+}
+
+impl<T: Serialize> IntoResponse for ApiResponse<T> {
+    fn into_response(self) -> Response {
+        Json(self).into_response()
+    }
+}
+
+/// Routes of v2 api, same as v1 but successful json bodies are wrapped
+/// into `ApiResponse` envelope.
+pub fn router_v2(admin: Router<AppState>) -> Router<AppState> {
+    router(admin).layer(axum::middleware::from_fn(wrap_in_envelope))
+}
+
+/// Nested under `/admin`, startup layers `admin` with peer restrictions
+/// which need the state.
+pub fn admin_router() -> Router<AppState> {
+    Router::new()
+        .route("/recent-requests", routing::get(recent_requests))
+        .route("/metrics", routing::get(metrics))
+}
+
+pub fn router(admin: Router<AppState>) -> Router<AppState> {
     Router::new()
         .route("/user", routing::post(new_user))
         .route("/user/{username}", routing::get(get_user))
+        .route("/user/{username}", routing::patch(rename_user))
         .route("/users", routing::get(list_users))
+        .route("/users/search", routing::get(search_users))
         .route("/user/{username}/keypair", routing::post(new_keypair))
         .route("/msg", routing::post(new_msg))
+        .route("/msg/upload", routing::post(upload_msg))
+        .route("/msg/preview", routing::post(preview_msg))
         .route("/msg/{msg_id}", routing::post(sign_msg))
         .route("/msg/{msg_id}", routing::get(verify_msg_signature))
+        .route("/msg/{msg_id}/verify", routing::post(verify_msg_content))
+        .route("/msg/by-hash/{msg_hash}", routing::get(get_msg_by_hash))
+        .route("/msg/{msg_id}/sign-raw", routing::post(sign_msg_raw))
+        .route(
+            "/msg/{msg_id}/participants",
+            routing::patch(patch_participants),
+        )
+        .route("/msg/{msg_id}/finalize", routing::post(finalize_msg))
+        .route("/msg/{msg_id}/clone", routing::post(clone_msg))
+        .route("/msg/{msg_id}/content", routing::get(get_msg_content))
+        .route(
+            "/msg/{msg_id}/verify-against",
+            routing::post(verify_msg_against),
+        )
+        .route("/msgs", routing::get(list_msgs))
+        .route("/msgs", routing::post(new_msgs))
+        .route("/msgs/batch-get", routing::post(batch_get_msgs))
+        .route("/msgs/verify-all", routing::post(verify_all_msgs))
+        .route("/address/from-secret", routing::post(address_from_secret))
+        .route("/verifymessage", routing::post(verify_message))
+        // Inside the v2 envelope, so every request keeps its own id
+        .layer(SingleFlightLayer::default())
+        // Outside of it: admin responses depend on the peer, which isn't
+        // a part of the coalescing key
+        .nest("/admin", admin)
 }
 
+#[utoipa::path(
+    post,
+    path = "/api/v1/user",
+    params(api_doc::Username),
+    responses(
+        (status = 200, description = "User created, with its keys if `with_keys` is passed", body = Option<api_doc::User>),
+        (status = 400, response = api_doc::BadRequestResponse),
+        (status = 401, response = api_doc::UnauthorizedResponse),
+        (status = 409, response = api_doc::ConflictErrorResponse),
+        (status = 500, response = api_doc::InternalErrorResponse),
+    ),
+    security(("api_key" = [])),
+    tag = "protected"
+)]
 async fn new_user(
     State(state): State<AppState>,
-    Query(api_doc::Username { name }): Query<api_doc::Username>,
-) -> Result<StatusCode, ErrorResponse> {
-    let user = name
+    Query(api_doc::Username { name, with_keys }): Query<api_doc::Username>,
+) -> Result<Response, ErrorResponse> {
+    let mut user = name
         .map(|n| User {
             name: n,
             ..Default::default()
         })
         .unwrap_or_default();
-    state.storage.store_user(user).await?;
-    Ok(StatusCode::OK)
+    let Some(key_count) = with_keys else {
+        state.storage.store_user(user).await?;
+        return Ok(StatusCode::OK.into_response());
+    };
+    if key_count > MAX_INITIAL_KEYS {
+        return Err(ErrorResponse::BadRequest(anyhow!(
+            "at most {MAX_INITIAL_KEYS} keys can be generated at once"
+        )));
+    }
+    for _ in 0..key_count {
+        user.add_keypair(generate_keypair(&state).await?);
+    }
+    state.storage.store_user(user.clone()).await?;
+    Ok(Json(user_dto(user, state.settings.network)).into_response())
 }
 
+#[utoipa::path(
+    get,
+    path = "/api/v1/user/{username}",
+    params(
+        ("username" = String, Path, description = "User name"),
+        api_doc::UserParams,
+    ),
+    responses(
+        (status = 200, description = "User, `null` if not found", content(
+            (api_doc::User = "application/json"),
+            (api_doc::User = "application/cbor"),
+            (api_doc::User = "application/msgpack"),
+        )),
+        (status = 500, response = api_doc::InternalErrorResponse),
+    ),
+    tag = "open"
+)]
 async fn get_user(
     State(state): State<AppState>,
     Path(username): Path<String>,
-) -> Result<Json<Option<api_doc::User>>, ErrorResponse> {
-    let user =
-        state
-            .storage
-            .get_user(&username)
-            .await?
-            .map(|u| api_doc::User {
-                id: u.id,
-                name: u.name,
-                keys: u
-                    .keys
-                    .values()
-                    .map(|k| crypto::bt_addr_from_pk(&k.public_key()))
-                    .collect(),
-            });
-    Ok(Json(user))
+    Query(params): Query<api_doc::UserParams>,
+    encoding: ResponseEncoding,
+) -> Result<Encoded<Option<api_doc::User>>, ErrorResponse> {
+    let Some(user) = state.storage.get_user(&username).await? else {
+        return Ok(Encoded(encoding, None));
+    };
+    let key_stats = match params.with_stats {
+        true => Some(
+            key_stats(&state.storage, &user, state.settings.network).await?,
+        ),
+        false => None,
+    };
+    let user = api_doc::User {
+        key_stats,
+        ..user_dto(user, state.settings.network)
+    };
+    Ok(Encoded(encoding, Some(user)))
 }
 
-async fn list_users(
+/// Signed messages count of every key of `user`, in `user_dto` keys order
+async fn key_stats(
+    storage: &SharedStorage,
+    user: &User,
+    network: crypto::Network,
+) -> Result<Vec<api_doc::KeyStats>, ErrorResponse> {
+    let msgs = storage.all_messages().await?;
+    let mut keys = user.keys.iter().collect::<Vec<_>>();
+    keys.sort_by_key(|(key_id, _)| **key_id);
+    Ok(keys
+        .into_iter()
+        .map(|(_, keypair)| {
+            let pubkey = keypair.public_key();
+            api_doc::KeyStats {
+                address: crypto::bt_addr_from_pk(&pubkey, network),
+                signed_count: msgs
+                    .iter()
+                    .filter(|msg| {
+                        msg.signature.signatures().any(|(pk, _)| pk.eq(&pubkey))
+                    })
+                    .count(),
+            }
+        })
+        .collect())
+}
+
+#[utoipa::path(
+    patch,
+    path = "/api/v1/user/{username}",
+    params(("username" = String, Path, description = "User name")),
+    request_body = api_doc::RenameUserRequest,
+    responses(
+        (status = 200, description = "Renamed user, keys and id are kept", body = api_doc::User),
+        (status = 401, response = api_doc::UnauthorizedResponse),
+        (status = 404, response = api_doc::NotFoundResponse),
+        (status = 409, response = api_doc::ConflictErrorResponse),
+        (status = 422, response = api_doc::ValidationErrorResponse),
+        (status = 500, response = api_doc::InternalErrorResponse),
+    ),
+    security(("api_key" = [])),
+    tag = "protected"
+)]
+async fn rename_user(
     State(state): State<AppState>,
-) -> Result<Json<Vec<api_doc::User>>, ErrorResponse> {
-    let users = state
+    Path(username): Path<String>,
+    ValidatedJson(req): ValidatedJson<api_doc::RenameUserRequest>,
+) -> Result<Json<api_doc::User>, ErrorResponse> {
+    let mut user = state
         .storage
-        .all_users()
+        .get_user(&username)
         .await?
-        .into_iter()
-        .map(|u| api_doc::User {
-            id: u.id,
-            name: u.name,
-            keys: u
-                .keys
-                .values()
-                .map(|k| crypto::bt_addr_from_pk(&k.public_key()))
-                .collect(),
-        })
-        .collect();
-    Ok(Json(users))
+        .ok_or(ErrorResponse::NotFoundError(anyhow!("user not found")))?;
+    state.storage.rename_user(&user.id, &req.name).await?;
+    user.name = req.name;
+    Ok(Json(user_dto(user, state.settings.network)))
+}
+
+impl Validate for api_doc::RenameUserRequest {
+    fn validate(&self, _: &Settings) -> Result<(), Vec<FieldError>> {
+        match self.name.trim().is_empty() {
+            true => Err(vec![FieldError::new("name", "must not be empty")]),
+            false => Ok(()),
+        }
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/users",
+    responses(
+        (status = 200, description = "All users", content(
+            (Vec<api_doc::User> = "application/json"),
+            (Vec<api_doc::User> = "application/cbor"),
+            (Vec<api_doc::User> = "application/msgpack"),
+        )),
+        (status = 500, response = api_doc::InternalErrorResponse),
+    ),
+    tag = "open"
+)]
+async fn list_users(
+    State(state): State<AppState>,
+    encoding: ResponseEncoding,
+) -> Result<Encoded<Vec<api_doc::User>>, ErrorResponse> {
+    let mut users = state.storage.all_users().await?;
+    users.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(Encoded(
+        encoding,
+        users
+            .into_iter()
+            .map(|u| user_dto(u, state.settings.network))
+            .collect(),
+    ))
 }
 
+#[utoipa::path(
+    get,
+    path = "/api/v1/users/search",
+    params(UserSearch),
+    responses(
+        (status = 200, description = "Users with matching names", content(
+            (Vec<api_doc::User> = "application/json"),
+            (Vec<api_doc::User> = "application/cbor"),
+            (Vec<api_doc::User> = "application/msgpack"),
+        )),
+        (status = 500, response = api_doc::InternalErrorResponse),
+    ),
+    tag = "open"
+)]
+async fn search_users(
+    State(state): State<AppState>,
+    Query(search): Query<UserSearch>,
+    encoding: ResponseEncoding,
+) -> Result<Encoded<Vec<api_doc::User>>, ErrorResponse> {
+    let users = state.storage.search_users(&search.q, search.limit).await?;
+    Ok(Encoded(
+        encoding,
+        users
+            .into_iter()
+            .map(|u| user_dto(u, state.settings.network))
+            .collect(),
+    ))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/user/{username}/keypair",
+    params(("username" = String, Path, description = "User name")),
+    responses(
+        (status = 200, description = "Address of the new key", body = String),
+        (status = 401, response = api_doc::UnauthorizedResponse),
+        (status = 404, response = api_doc::NotFoundResponse),
+        (status = 500, response = api_doc::InternalErrorResponse),
+    ),
+    security(("api_key" = [])),
+    tag = "protected"
+)]
 async fn new_keypair(
     State(state): State<AppState>,
     Path(username): Path<String>,
 ) -> Result<String, ErrorResponse> {
-    let mut user = state
+    let user = state
         .storage
         .get_user(&username)
         .await?
         .ok_or(ErrorResponse::NotFoundError(anyhow!("user not found")))?;
-    let keypair = crypto::new_keypair(&state.secp)
-        .context("failed to generate keypair")?;
-    user.add_keypair(keypair);
-    state.storage.update_user(user).await?;
-    Ok(crypto::bt_addr_from_pk(&keypair.public_key()))
+    let keypair = generate_keypair(&state).await?;
+    let address =
+        crypto::bt_addr_from_pk(&keypair.public_key(), state.settings.network);
+    state
+        .storage
+        .update_user(
+            &user.id,
+            Box::new(move |user| user.add_keypair(keypair.clone())),
+        )
+        .await?;
+    Ok(address)
 }
 
+#[utoipa::path(
+    post,
+    path = "/api/v1/msg",
+    params(
+        ("Idempotency-Key" = Option<String>, Header, description = "Return the same message id for retried requests"),
+    ),
+    request_body = PostMsgRequest,
+    responses(
+        (status = 200, description = "New message with its effective threshold", body = api_doc::CreatedMsg),
+        (status = 400, response = api_doc::BadRequestResponse),
+        (status = 401, response = api_doc::UnauthorizedResponse),
+        (status = 404, response = api_doc::NotFoundResponse),
+        (status = 409, response = api_doc::ConflictErrorResponse),
+        (status = 422, response = api_doc::ValidationErrorResponse),
+        (status = 500, response = api_doc::InternalErrorResponse),
+        (status = 503, description = "Stored messages limit is reached"),
+    ),
+    security(("api_key" = [])),
+    tag = "protected"
+)]
 async fn new_msg(
     State(state): State<AppState>,
-    Json(req): Json<PostMsgRequest>,
-) -> Result<String, ErrorResponse> {
-    let selected_pubkeys = extract_selected_keypairs(&state, req.keys)
+    headers: HeaderMap,
+    ValidatedJson(mut req): ValidatedJson<PostMsgRequest>,
+) -> Result<Json<api_doc::CreatedMsg>, ErrorResponse> {
+    let idempotency = match headers.get(IDEMPOTENCY_KEY_HEADER) {
+        Some(key) => {
+            let key = key
+                .to_str()
+                .context("bad idempotency key")
+                .map_err(ErrorResponse::BadRequest)?
+                .to_string();
+            let body = serde_json::to_vec(&req)
+                .context("failed to serialize request")?;
+            let request_hash = sha256::Hash::hash(&body);
+            match state.idempotency.claim(&key, &request_hash)? {
+                Claim::Created(msg_id) => {
+                    return created_msg(&state.storage, &msg_id).await
+                }
+                Claim::Reserved(reservation) => Some(reservation),
+            }
+        }
+        None => None,
+    };
+
+    let keypairs = match req.open {
+        true => Vec::new(),
+        false => {
+            extract_selected_keypairs(&state, std::mem::take(&mut req.keys))
+                .await?
+        }
+    };
+    let msg = msg_from_request(&state.settings, req, keypairs)?;
+    let msg_id = state.storage.store_msg(msg).await?;
+    if let Some(reservation) = idempotency {
+        reservation.complete(msg_id)?;
+    }
+    created_msg(&state.storage, &msg_id).await
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/msg/preview",
+    request_body = PostMsgRequest,
+    responses(
+        (status = 200, description = "Message which would be created, nothing is stored", body = api_doc::MsgPreview),
+        (status = 400, response = api_doc::BadRequestResponse),
+        (status = 401, response = api_doc::UnauthorizedResponse),
+        (status = 404, response = api_doc::NotFoundResponse),
+        (status = 422, response = api_doc::ValidationErrorResponse),
+        (status = 500, response = api_doc::InternalErrorResponse),
+    ),
+    security(("api_key" = [])),
+    tag = "protected"
+)]
+/// Dry run of `new_msg`, so clients can check participants and the
+/// threshold interactively
+async fn preview_msg(
+    State(state): State<AppState>,
+    ValidatedJson(mut req): ValidatedJson<PostMsgRequest>,
+) -> Result<Json<api_doc::MsgPreview>, ErrorResponse> {
+    let keypairs = match req.open {
+        true => Vec::new(),
+        false => {
+            extract_selected_keypairs(&state, std::mem::take(&mut req.keys))
+                .await?
+        }
+    };
+    let msg = msg_from_request(&state.settings, req, keypairs)?;
+    let (content, content_encoding) = match msg.content_display() {
+        ContentDisplay::Text(text) => (text, api_doc::ContentEncoding::Text),
+        ContentDisplay::Base64(b64) => (b64, api_doc::ContentEncoding::Base64),
+    };
+    Ok(Json(api_doc::MsgPreview {
+        participants: msg
+            .signature
+            .participants()
+            .map(|pk| crypto::bt_addr_from_pk(pk, state.settings.network))
+            .collect(),
+        required: msg.count_required,
+        content,
+        content_encoding,
+        content_stored: msg.has_content(),
+    }))
+}
+
+/// Message described by validated `req`, `keypairs` are selected by
+/// `req.keys` unless it is open
+fn msg_from_request(
+    settings: &Settings,
+    req: PostMsgRequest,
+    keypairs: Vec<SecretKeypair>,
+) -> Result<Message, ErrorResponse> {
+    let content = request_content(req.content, req.content_is_digest)?;
+    let msg = match req.open {
+        true => Message::new_open(
+            content,
+            req.required_signature_count.unwrap_or_default(),
+        )?,
+        false => {
+            let mut selected_pubkeys = keypairs
+                .into_iter()
+                .map(|k| k.public_key())
+                .collect::<Vec<_>>();
+            for hex in &req.pubkeys {
+                selected_pubkeys.push(parse_compressed_pubkey(hex)?);
+            }
+            check_participant_count(settings, selected_pubkeys.len())?;
+            let required_signature_count =
+                req.required_signature_count.unwrap_or(
+                    settings
+                        .default_threshold_policy
+                        .required_count(selected_pubkeys.len()),
+                );
+            Message::new(
+                content,
+                selected_pubkeys,
+                Some(required_signature_count),
+            )?
+        }
+    }
+    .with_nonce(req.nonce)
+    .with_tags(req.tags)
+    .with_sign_deadline(req.sign_deadline);
+    let msg = match req.content_is_digest {
+        true => msg.into_digest()?,
+        false => msg,
+    };
+    Ok(match settings.store_content {
+        true => msg,
+        false => msg.without_content(),
+    })
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/msgs",
+    request_body = Vec<PostMsgRequest>,
+    responses(
+        (status = 200, description = "Outcome of every message, in request order", body = Vec<api_doc::CreateMsgOutcome>),
+        (status = 400, response = api_doc::BadRequestResponse),
+        (status = 401, response = api_doc::UnauthorizedResponse),
+        (status = 500, response = api_doc::InternalErrorResponse),
+        (status = 503, description = "Stored messages limit is reached"),
+    ),
+    security(("api_key" = [])),
+    tag = "protected"
+)]
+async fn new_msgs(
+    State(state): State<AppState>,
+    Json(reqs): Json<Vec<PostMsgRequest>>,
+) -> Result<Json<Vec<api_doc::CreateMsgOutcome>>, ErrorResponse> {
+    if reqs.len() > MAX_BULK_MSGS {
+        return Err(ErrorResponse::BadRequest(anyhow!(
+            "at most {MAX_BULK_MSGS} messages can be created at once"
+        )));
+    }
+    // Keys of every message are parsed upfront, so users are scanned once
+    let parsed = reqs
+        .into_iter()
+        .map(|req| {
+            let invalid =
+                |errors: Vec<FieldError>| api_doc::CreateMsgOutcome::Failed {
+                    status: StatusCode::UNPROCESSABLE_ENTITY.as_u16(),
+                    error: fields_message(&errors),
+                };
+            req.validate(&state.settings).map_err(invalid)?;
+            let pkhs = match req.open {
+                true => Vec::new(),
+                false => parse_keys(&req.keys, state.settings.network)
+                    .map_err(invalid)?,
+            };
+            Ok((req, pkhs))
+        })
+        .collect::<Vec<_>>();
+    let all_pkhs = parsed
+        .iter()
+        .filter_map(|item| item.as_ref().ok())
+        .flat_map(|(_, pkhs)| pkhs.iter().copied())
+        .collect::<Vec<_>>();
+    let mut found = find_keypairs(&state, &all_pkhs).await?.into_iter();
+
+    let mut outcomes = Vec::with_capacity(parsed.len());
+    for item in parsed {
+        let (req, pkhs) = match item {
+            Ok(item) => item,
+            Err(outcome) => {
+                outcomes.push(outcome);
+                continue;
+            }
+        };
+        let found = found.by_ref().take(pkhs.len()).collect::<Vec<_>>();
+        // Middleware checks the limit once per request, items of the batch
+        // may cross it
+        if let Some(max_messages) = state.settings.max_messages {
+            if state.storage.count_messages().await? >= max_messages {
+                outcomes.push(api_doc::CreateMsgOutcome::Failed {
+                    status: StatusCode::SERVICE_UNAVAILABLE.as_u16(),
+                    error: "stored messages limit is reached".to_string(),
+                });
+                continue;
+            }
+        }
+        let created = async {
+            let keypairs = selected_keypairs(&req.keys, &pkhs, found)?;
+            let msg = msg_from_request(&state.settings, req, keypairs)?;
+            let msg_id = state.storage.store_msg(msg).await?;
+            created_msg(&state.storage, &msg_id).await
+        };
+        outcomes.push(match created.await {
+            Ok(Json(created)) => api_doc::CreateMsgOutcome::Created(created),
+            Err(e) => failed_outcome(&e),
+        });
+    }
+    Ok(Json(outcomes))
+}
+
+/// Item of a bulk response with the status and message of `error`,
+/// internal errors are only logged
+fn failed_outcome(error: &ErrorResponse) -> api_doc::CreateMsgOutcome {
+    let (status, error) = match error {
+        ErrorResponse::UnexpectedError(_) | ErrorResponse::InternalError(_) => {
+            tracing::error!("{:?}", error);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "internal error".to_string(),
+            )
+        }
+        ErrorResponse::BadRequest(e) => {
+            (StatusCode::BAD_REQUEST, e.to_string())
+        }
+        ErrorResponse::NotFoundError(e) => {
+            (StatusCode::NOT_FOUND, e.to_string())
+        }
+        ErrorResponse::ConflictError(e) => {
+            (StatusCode::CONFLICT, e.to_string())
+        }
+        ErrorResponse::PayloadTooLarge(e) => {
+            (StatusCode::PAYLOAD_TOO_LARGE, e.to_string())
+        }
+        ErrorResponse::PreconditionFailed(e) => {
+            (StatusCode::PRECONDITION_FAILED, e.to_string())
+        }
+        ErrorResponse::Forbidden(e) => (StatusCode::FORBIDDEN, e.to_string()),
+    };
+    api_doc::CreateMsgOutcome::Failed {
+        status: status.as_u16(),
+        error,
+    }
+}
+
+/// `field: message` of every error, separated by `; `
+fn fields_message(errors: &[FieldError]) -> String {
+    errors
+        .iter()
+        .map(|e| format!("{}: {}", e.field, e.message))
+        .collect::<Vec<_>>()
+        .join("; ")
+}
+
+/// Threshold of the stored message, which may be an earlier one with the
+/// same idempotency key
+async fn created_msg(
+    storage: &SharedStorage,
+    msg_id: &uuid::Uuid,
+) -> Result<Json<api_doc::CreatedMsg>, ErrorResponse> {
+    let msg = storage
+        .get_msg(msg_id)
+        .await?
+        .ok_or(ErrorResponse::NotFoundError(anyhow!("no message found")))?;
+    Ok(Json(api_doc::CreatedMsg {
+        id: msg.id,
+        required: msg.count_required,
+        participants: msg.signature.total_count(),
+    }))
+}
+
+impl Validate for PostMsgRequest {
+    fn validate(&self, settings: &Settings) -> Result<(), Vec<FieldError>> {
+        let mut errors = Vec::new();
+        if self.content.len() > settings.max_upload_bytes {
+            errors.push(FieldError::new(
+                "content",
+                format!("at most {} bytes allowed", settings.max_upload_bytes),
+            ));
+        }
+        if self.content_is_digest {
+            if self.content.parse::<sha256::Hash>().is_err() {
+                errors.push(FieldError::new(
+                    "content",
+                    "digest must be 64 hex characters",
+                ));
+            }
+            if self.nonce.is_some() {
+                errors.push(FieldError::new(
+                    "nonce",
+                    "can't be combined with contentIsDigest",
+                ));
+            }
+        }
+        if self
+            .sign_deadline
+            .is_some_and(|deadline| deadline <= OffsetDateTime::now_utc())
+        {
+            errors
+                .push(FieldError::new("signDeadline", "must be in the future"));
+        }
+        let participants = self.keys.len() + self.pubkeys.len();
+        match (self.open, participants == 0) {
+            (true, false) => errors.push(FieldError::new(
+                "keys",
+                "open message can't have fixed participants",
+            )),
+            (false, true) => errors
+                .push(FieldError::new("keys", "at least one key is required")),
+            _ => (),
+        }
+        if let Err(key_errors) = parse_keys(&self.keys, settings.network) {
+            errors.extend(key_errors);
+        }
+        if self
+            .pubkeys
+            .iter()
+            .any(|hex| parse_compressed_pubkey(hex).is_err())
+        {
+            errors.push(FieldError::new(
+                "pubkeys",
+                "must be compressed public keys in hex",
+            ));
+        }
+        if self.tags.iter().any(|tag| tag.is_empty()) {
+            errors.push(FieldError::new("tags", "tags must not be empty"));
+        }
+        match (self.open, self.required_signature_count) {
+            (true, None) => errors.push(FieldError::new(
+                "requiredSignatureCount",
+                "open message requires requiredSignatureCount",
+            )),
+            (_, Some(0)) => errors.push(FieldError::new(
+                "requiredSignatureCount",
+                "must be positive",
+            )),
+            (false, Some(count)) if count > participants => {
+                errors.push(FieldError::new(
+                    "requiredSignatureCount",
+                    format!("at most {participants} keys can sign"),
+                ))
+            }
+            _ => (),
+        }
+        match errors.is_empty() {
+            true => Ok(()),
+            false => Err(errors),
+        }
+    }
+}
+
+impl UploadMsgParams {
+    fn key_list(&self) -> Vec<String> {
+        self.keys
+            .split(',')
+            .map(str::trim)
+            .filter(|k| !k.is_empty())
+            .map(str::to_string)
+            .collect()
+    }
+}
+
+impl Validate for UploadMsgParams {
+    fn validate(&self, settings: &Settings) -> Result<(), Vec<FieldError>> {
+        let keys = self.key_list();
+        let mut errors = parse_keys(&keys, settings.network)
+            .err()
+            .unwrap_or_default();
+        let participants = keys.len();
+        match self.required_signature_count {
+            Some(0) => errors.push(FieldError::new(
+                "required_signature_count",
+                "must be positive",
+            )),
+            Some(count) if count > participants => {
+                errors.push(FieldError::new(
+                    "required_signature_count",
+                    format!("at most {participants} keys can sign"),
+                ))
+            }
+            _ => (),
+        }
+        match errors.is_empty() {
+            true => Ok(()),
+            false => Err(errors),
+        }
+    }
+}
+
+impl Validate for api_doc::VerifyAgainstRequest {
+    fn validate(&self, settings: &Settings) -> Result<(), Vec<FieldError>> {
+        let errors = self
+            .keys
+            .iter()
+            .filter_map(|key| {
+                expected_pkh(key, settings.network)
+                    .err()
+                    .map(|e| FieldError::new("keys", format!("{key}: {e}")))
+            })
+            .collect::<Vec<_>>();
+        match errors.is_empty() {
+            true => Ok(()),
+            false => Err(errors),
+        }
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/msg/upload",
+    params(UploadMsgParams),
+    request_body(content = Vec<u8>, content_type = "application/octet-stream"),
+    responses(
+        (status = 200, description = "New message with its content hash", body = UploadMsgResponse),
+        (status = 400, response = api_doc::BadRequestResponse),
+        (status = 401, response = api_doc::UnauthorizedResponse),
+        (status = 404, response = api_doc::NotFoundResponse),
+        (status = 413, description = "Content exceeds `max_upload_bytes`"),
+        (status = 422, response = api_doc::ValidationErrorResponse),
+        (status = 500, response = api_doc::InternalErrorResponse),
+        (status = 503, description = "Stored messages limit is reached"),
+    ),
+    security(("api_key" = [])),
+    tag = "protected"
+)]
+async fn upload_msg(
+    State(state): State<AppState>,
+    ValidatedQuery(params): ValidatedQuery<UploadMsgParams>,
+    headers: HeaderMap,
+    body: Body,
+) -> Result<Json<UploadMsgResponse>, ErrorResponse> {
+    let max_bytes = state.settings.max_upload_bytes;
+    let selected_pubkeys = extract_selected_keypairs(&state, params.key_list())
         .await?
         .into_iter()
         .map(|k| k.public_key())
-        .collect();
+        .collect::<Vec<_>>();
+    check_participant_count(&state.settings, selected_pubkeys.len())?;
+
+    let content_length = headers
+        .get(http::header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<usize>().ok());
+    if content_length.is_some_and(|len| len > max_bytes) {
+        return Err(ErrorResponse::PayloadTooLarge(anyhow!(
+            "content is larger than {max_bytes} bytes"
+        )));
+    }
+
+    let mut content = Vec::with_capacity(content_length.unwrap_or_default());
+    let mut engine = sha256::Hash::engine();
+    let mut stream = body.into_data_stream();
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk
+            .context("failed to read content")
+            .map_err(ErrorResponse::BadRequest)?;
+        if content.len() + chunk.len() > max_bytes {
+            return Err(ErrorResponse::PayloadTooLarge(anyhow!(
+                "content is larger than {max_bytes} bytes"
+            )));
+        }
+        engine.input(&chunk);
+        content.extend_from_slice(&chunk);
+    }
+    let content_hash = sha256::Hash::from_engine(engine);
+
+    let required_signature_count = params.required_signature_count.unwrap_or(
+        state
+            .settings
+            .default_threshold_policy
+            .required_count(selected_pubkeys.len()),
+    );
     let msg = Message::new(
-        req.content.as_bytes(),
+        content,
         selected_pubkeys,
-        req.required_signature_count,
-    );
-    let msg_id = msg.id.to_string();
-    state.storage.store_msg(msg).await?;
-    Ok(msg_id)
+        Some(required_signature_count),
+    )?;
+    let msg = match state.settings.store_content {
+        true => msg,
+        false => msg.without_content(),
+    };
+    let msg_id = state.storage.store_msg(msg).await?;
+    Ok(Json(UploadMsgResponse {
+        id: msg_id,
+        sha256: content_hash.to_string(),
+    }))
 }
 
+#[utoipa::path(
+    post,
+    path = "/api/v1/msg/{msg_id}",
+    request_body = SignMsgRequest,
+    responses(
+        (status = 200, description = "Outcome of every key, in request order, or of every participant key of `username`", body = Vec<api_doc::KeySignOutcome>),
+        (status = 400, response = api_doc::BadRequestResponse),
+        (status = 401, response = api_doc::UnauthorizedResponse),
+        (status = 403, response = api_doc::ForbiddenResponse),
+        (status = 404, response = api_doc::NotFoundResponse),
+        (status = 409, response = api_doc::ConflictErrorResponse),
+        (status = 412, response = api_doc::PreconditionFailedResponse),
+        (status = 500, response = api_doc::InternalErrorResponse),
+    ),
+    params(
+        ("msg_id" = uuid::Uuid, Path, description = "Message id"),
+        ("If-Match" = Option<String>, Header, description = "`ETag` of the message as it was shown, signing is refused if it changed since"),
+    ),
+    security(("api_key" = [])),
+    tag = "protected"
+)]
 async fn sign_msg(
     State(state): State<AppState>,
     Path(msg_id): Path<uuid::Uuid>,
+    headers: HeaderMap,
     Json(req): Json<SignMsgRequest>,
-) -> Result<String, ErrorResponse> {
-    let selected_keypairs = extract_selected_keypairs(&state, req.keys).await?;
-    for keypair in selected_keypairs {
-        let secp = state.secp.clone();
-        state
+) -> Result<Json<Vec<api_doc::KeySignOutcome>>, ErrorResponse> {
+    let msg = state
+        .storage
+        .get_msg(&msg_id)
+        .await?
+        .ok_or(ErrorResponse::NotFoundError(anyhow!("no message found")))?;
+    // Checked again on the first update, the message may change meanwhile
+    let if_match_tags = if_match_tags(&headers);
+    if !if_match(&if_match_tags, &msg) {
+        return Err(multisig::Error::VersionMismatch(msg.version).into());
+    }
+    msg.check_sign_deadline(OffsetDateTime::now_utc())?;
+    let selected_keypairs = match req.username {
+        Some(_) if !req.keys.is_empty() => {
+            return Err(ErrorResponse::BadRequest(anyhow!(
+                "either keys or username must be passed"
+            )));
+        }
+        Some(username) => {
+            participant_keypairs(&state.storage, &username, &msg).await?
+        }
+        None => extract_selected_keypairs(&state, req.keys).await?,
+    };
+    let address =
+        |pk: &PublicKey| crypto::bt_addr_from_pk(pk, state.settings.network);
+    // Content and nonce never change, so signatures are made upfront
+    let digest = msg.digest();
+    let secp = state.secp.clone();
+    let signatures = blocking(move || {
+        selected_keypairs
+            .iter()
+            .map(|k| {
+                let pubkey = k.public_key();
+                match msg.signature.accepts(&pubkey) {
+                    true => Ok((
+                        pubkey,
+                        Some(crypto::sign_digest(&secp, &digest, k)?),
+                    )),
+                    false => Ok((pubkey, None)),
+                }
+            })
+            .collect::<Result<Vec<_>, multisig::Error>>()
+    })
+    .await??;
+    let mut outcomes = Vec::with_capacity(signatures.len());
+    // Precondition holds for the message as it was before this request
+    // changed it
+    let mut precondition = Some(if_match_tags);
+    for (pubkey, signature) in signatures {
+        let Some(signature) = signature else {
+            outcomes.push(api_doc::KeySignOutcome {
+                address: address(&pubkey),
+                outcome: api_doc::SignOutcome::NotParticipant,
+            });
+            continue;
+        };
+        // Already signed is reported as error, so version isn't bumped
+        let tags = precondition.clone();
+        let signed = state
             .storage
             .update_msg(
                 &msg_id,
                 Box::new(move |msg| {
-                    msg.signature.sign(&secp, &msg.content, &keypair)?;
-                    Ok(())
+                    if let Some(tags) = &tags {
+                        if !if_match(tags, msg) {
+                            return Err(multisig::Error::VersionMismatch(
+                                msg.version,
+                            ));
+                        }
+                    }
+                    msg.check_sign_deadline(OffsetDateTime::now_utc())?;
+                    match msg.signature.add_signature(&pubkey, signature)? {
+                        multisig::SignOutcome::Signed => Ok(()),
+                        multisig::SignOutcome::AlreadySigned => {
+                            Err(multisig::Error::AlreadySigned)
+                        }
+                    }
                 }),
             )
-            .await?;
+            .await;
+        let outcome = match signed {
+            Ok(()) => {
+                precondition = None;
+                api_doc::SignOutcome::Signed
+            }
+            Err(storage::Error::Multisig(multisig::Error::AlreadySigned)) => {
+                api_doc::SignOutcome::AlreadySigned
+            }
+            // Participants were changed meanwhile
+            Err(storage::Error::Multisig(
+                multisig::Error::PublicKeyNotFound,
+            )) => api_doc::SignOutcome::NotParticipant,
+            Err(e) => return Err(e.into()),
+        };
+        outcomes.push(api_doc::KeySignOutcome {
+            address: address(&pubkey),
+            outcome,
+        });
+    }
+    if outcomes
+        .iter()
+        .any(|o| o.outcome == api_doc::SignOutcome::Signed)
+    {
+        notify_signed(&state, &msg_id).await?;
     }
+    Ok(Json(outcomes))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/msg/{msg_id}/sign-raw",
+    params(("msg_id" = uuid::Uuid, Path, description = "Message id")),
+    request_body = SignRawMsgRequest,
+    responses(
+        (status = 200, description = "Signature attached"),
+        (status = 400, response = api_doc::BadRequestResponse),
+        (status = 401, response = api_doc::UnauthorizedResponse),
+        (status = 403, response = api_doc::ForbiddenResponse),
+        (status = 404, response = api_doc::NotFoundResponse),
+        (status = 409, response = api_doc::ConflictErrorResponse),
+        (status = 500, response = api_doc::InternalErrorResponse),
+    ),
+    security(("api_key" = [])),
+    tag = "protected"
+)]
+async fn sign_msg_raw(
+    State(state): State<AppState>,
+    Path(msg_id): Path<uuid::Uuid>,
+    Json(req): Json<SignRawMsgRequest>,
+) -> Result<String, ErrorResponse> {
+    let pkh = crypto::pkh_from_bt_addr(&req.address, state.settings.network)?;
+    let signature = crypto::Signature::from_der_hex(&req.signature_der_hex)
+        .context("invalid DER signature")
+        .map_err(ErrorResponse::BadRequest)?
+        .into();
+    let pubkey = req
+        .pubkey_hex
+        .map(|hex| {
+            let pubkey = hex
+                .parse::<PublicKey>()
+                .context("invalid public key")
+                .map_err(ErrorResponse::BadRequest)?;
+            if hash160::Hash::hash(&pubkey.serialize()).ne(&pkh) {
+                return Err(ErrorResponse::BadRequest(anyhow!(
+                    "public key doesn't match the address"
+                )));
+            }
+            Ok(pubkey)
+        })
+        .transpose()?;
+    let secp = state.secp.clone();
+    state
+        .storage
+        .update_msg(
+            &msg_id,
+            Box::new(move |msg| {
+                msg.check_sign_deadline(OffsetDateTime::now_utc())?;
+                let digest = msg.digest();
+                match pubkey {
+                    Some(pubkey) => msg.signature.sign_raw_by_pubkey_digest(
+                        &secp, &digest, &pubkey, signature,
+                    ),
+                    None => msg
+                        .signature
+                        .sign_raw_digest(&secp, &digest, &pkh, signature),
+                }
+            }),
+        )
+        .await?;
+    notify_signed(&state, &msg_id).await?;
     Ok(String::new())
 }
 
-async fn verify_msg_signature(
+#[utoipa::path(
+    patch,
+    path = "/api/v1/msg/{msg_id}/participants",
+    params(("msg_id" = uuid::Uuid, Path, description = "Message id")),
+    request_body = PatchParticipantsRequest,
+    responses(
+        (status = 200, description = "Participants changed"),
+        (status = 400, response = api_doc::BadRequestResponse),
+        (status = 401, response = api_doc::UnauthorizedResponse),
+        (status = 404, response = api_doc::NotFoundResponse),
+        (status = 409, response = api_doc::ConflictErrorResponse),
+        (status = 500, response = api_doc::InternalErrorResponse),
+    ),
+    security(("api_key" = [])),
+    tag = "protected"
+)]
+async fn patch_participants(
+    State(state): State<AppState>,
+    Path(msg_id): Path<uuid::Uuid>,
+    Json(req): Json<PatchParticipantsRequest>,
+) -> Result<StatusCode, ErrorResponse> {
+    let add = extract_selected_keypairs(&state, req.add)
+        .await?
+        .into_iter()
+        .map(|k| k.public_key())
+        .collect::<Vec<_>>();
+    let remove = req
+        .remove
+        .iter()
+        .map(|address| {
+            crypto::pkh_from_bt_addr(address, state.settings.network)
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let max_participants = state.settings.max_participants;
+    state
+        .storage
+        .update_msg(
+            &msg_id,
+            Box::new(move |msg| {
+                // Amended on a copy, so a rejected change leaves no trace
+                let mut amended = msg.clone();
+                amended.amend_participants(add.clone(), &remove)?;
+                match max_participants {
+                    Some(max) if amended.signature.total_count() > max => {
+                        Err(multisig::Error::TooManyParticipants(max))
+                    }
+                    _ => {
+                        *msg = amended;
+                        Ok(())
+                    }
+                }
+            }),
+        )
+        .await?;
+    Ok(StatusCode::OK)
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/msg/{msg_id}/finalize",
+    params(("msg_id" = uuid::Uuid, Path, description = "Message id")),
+    responses(
+        (status = 200, description = "RFC 3339 time of finalization", body = String),
+        (status = 400, response = api_doc::BadRequestResponse),
+        (status = 401, response = api_doc::UnauthorizedResponse),
+        (status = 404, response = api_doc::NotFoundResponse),
+        (status = 409, response = api_doc::ConflictErrorResponse),
+        (status = 500, response = api_doc::InternalErrorResponse),
+    ),
+    security(("api_key" = [])),
+    tag = "protected"
+)]
+async fn finalize_msg(
     State(state): State<AppState>,
     Path(msg_id): Path<uuid::Uuid>,
 ) -> Result<String, ErrorResponse> {
+    let now = OffsetDateTime::now_utc();
+    let secp = state.secp.clone();
+    state
+        .storage
+        .update_msg(&msg_id, Box::new(move |msg| msg.finalize(&secp, now)))
+        .await?;
+    Ok(now
+        .format(&time::format_description::well_known::Rfc3339)
+        .context("failed to format time")?)
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/msg/{msg_id}/clone",
+    params(("msg_id" = uuid::Uuid, Path, description = "Message id")),
+    responses(
+        (status = 200, description = "Id of the new message, with the same content, participants and threshold", body = String),
+        (status = 401, response = api_doc::UnauthorizedResponse),
+        (status = 404, response = api_doc::NotFoundResponse),
+        (status = 500, response = api_doc::InternalErrorResponse),
+        (status = 503, description = "Stored messages limit is reached"),
+    ),
+    security(("api_key" = [])),
+    tag = "protected"
+)]
+async fn clone_msg(
+    State(storage): State<SharedStorage>,
+    Path(msg_id): Path<uuid::Uuid>,
+) -> Result<String, ErrorResponse> {
+    let msg = storage
+        .get_msg(&msg_id)
+        .await?
+        .ok_or(ErrorResponse::NotFoundError(anyhow!("no message found")))?;
+    let clone_id = storage.store_msg(msg.restart()).await?;
+    Ok(clone_id.to_string())
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/msg/{msg_id}",
+    params(("msg_id" = uuid::Uuid, Path, description = "Message id")),
+    responses(
+        (status = 200, description = "`success` or the reason of failure", content(
+            (String = "text/plain"),
+            (api_doc::VerificationResult = "application/json"),
+        )),
+        (status = 400, description = "Only the digest is stored, content must be posted to `/verify`"),
+        (status = 404, response = api_doc::NotFoundResponse),
+        (status = 500, response = api_doc::InternalErrorResponse),
+    ),
+    tag = "open"
+)]
+/// Responds with json if it is accepted, plain text otherwise
+async fn verify_msg_signature(
+    State(state): State<AppState>,
+    Path(msg_id): Path<uuid::Uuid>,
+    headers: HeaderMap,
+) -> Result<Response, ErrorResponse> {
     let msg = state
         .storage
         .get_msg(&msg_id)
         .await?
         .ok_or(ErrorResponse::NotFoundError(anyhow!("no message found")))?;
-    match msg
-        .signature
-        .verify(&state.secp, &msg.content, msg.count_required)
+    if !msg.has_content() {
+        return Err(ErrorResponse::BadRequest(anyhow!(
+            "content is required, only its digest is stored"
+        )));
+    }
+    verification_response(&state, msg, &headers).await
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/msg/{msg_id}/verify",
+    params(("msg_id" = uuid::Uuid, Path, description = "Message id")),
+    request_body = api_doc::VerifyContentRequest,
+    responses(
+        (status = 200, description = "`success` or the reason of failure", content(
+            (String = "text/plain"),
+            (api_doc::VerificationResult = "application/json"),
+        )),
+        (status = 400, response = api_doc::BadRequestResponse),
+        (status = 401, response = api_doc::UnauthorizedResponse),
+        (status = 404, response = api_doc::NotFoundResponse),
+        (status = 500, response = api_doc::InternalErrorResponse),
+    ),
+    security(("api_key" = [])),
+    tag = "protected"
+)]
+/// Same as `GET`, but supplied content must match the message first.
+/// The way to verify messages the server keeps only the digest of.
+async fn verify_msg_content(
+    State(state): State<AppState>,
+    Path(msg_id): Path<uuid::Uuid>,
+    headers: HeaderMap,
+    Json(req): Json<api_doc::VerifyContentRequest>,
+) -> Result<Response, ErrorResponse> {
+    let msg = state
+        .storage
+        .get_msg(&msg_id)
+        .await?
+        .ok_or(ErrorResponse::NotFoundError(anyhow!("no message found")))?;
+    let content = request_content(req.content, msg.content_is_digest)?;
+    if msg.digest_of(&content).ne(&msg.digest()) {
+        return Err(ErrorResponse::BadRequest(anyhow!(
+            "content doesn't match the message"
+        )));
+    }
+    verification_response(&state, msg, &headers).await
+}
+
+/// Verification result of `msg`, cached per version, as plain text or
+/// json if it is accepted
+async fn verification_response(
+    state: &AppState,
+    msg: Message,
+    headers: &HeaderMap,
+) -> Result<Response, ErrorResponse> {
+    let verification = match state.verification_cache.get(&msg.id, msg.version)
     {
-        Ok(()) => Ok("success".to_string()),
-        Err(e) => Ok(format!("{e}")),
+        Some(cached) => cached,
+        None => {
+            let (secp, multisig, digest, count_required) = (
+                state.secp.clone(),
+                msg.signature.clone(),
+                msg.digest(),
+                msg.count_required,
+            );
+            let verification = blocking(move || {
+                multisig.verify_digest(&secp, &digest, count_required)
+            })
+            .await?;
+            state.verification_cache.insert(
+                msg.id,
+                msg.version,
+                verification.clone(),
+            );
+            verification
+        }
+    };
+    let locale = Locale::from_headers(headers);
+    let etag = [(http::header::ETAG, msg_etag(&msg))];
+    if !accepts_json(headers) {
+        return match verification {
+            Ok(()) => Ok((etag, "success").into_response()),
+            Err(e) => {
+                Ok((etag, i18n::multisig_error(&e, locale)).into_response())
+            }
+        };
+    }
+    Ok((
+        etag,
+        Json(api_doc::VerificationResult {
+            success: verification.is_ok(),
+            code: verification.as_ref().err().map(|e| e.code().to_string()),
+            reason: verification
+                .as_ref()
+                .err()
+                .map(|e| i18n::multisig_error(e, locale)),
+            signed: msg.signature.signed_count(),
+            required: msg.count_required,
+        }),
+    )
+        .into_response())
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/msg/by-hash/{msg_hash}",
+    params(
+        ("msg_hash" = String, Path, description = "Hex sha256 of the preimage, or the content itself for digest messages"),
+        api_doc::ByHashParams,
+    ),
+    responses(
+        (status = 200, content(
+            (api_doc::MessageDetail = "application/json"),
+            (api_doc::MessageDetail = "application/cbor"),
+            (api_doc::MessageDetail = "application/msgpack"),
+        )),
+        (status = 400, response = api_doc::BadRequestResponse),
+        (status = 404, response = api_doc::NotFoundResponse),
+        (status = 500, response = api_doc::InternalErrorResponse),
+    ),
+    tag = "open"
+)]
+async fn get_msg_by_hash(
+    State(storage): State<SharedStorage>,
+    Path(msg_hash): Path<String>,
+    Query(params): Query<api_doc::ByHashParams>,
+    encoding: ResponseEncoding,
+) -> Result<Response, ErrorResponse> {
+    let msg_hash = msg_hash
+        .parse::<sha256::Hash>()
+        .context("invalid sha256 hex")
+        .map_err(ErrorResponse::BadRequest)?;
+    let key = message::lookup_key(msg_hash.to_byte_array(), params.digest);
+    let msg = storage
+        .get_msg_by_hash(&sha256::Hash::from_byte_array(key))
+        .await?
+        .ok_or(ErrorResponse::NotFoundError(anyhow!("no message found")))?;
+    let etag = [(http::header::ETAG, msg_etag(&msg))];
+    Ok((etag, Encoded(encoding, msg_detail(msg))).into_response())
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/msg/{msg_id}/content",
+    params(("msg_id" = uuid::Uuid, Path, description = "Message id")),
+    responses(
+        (status = 200, description = "Content as it was submitted", body = Vec<u8>, content_type = "application/octet-stream"),
+        (status = 404, description = "No message found, or only its digest is stored"),
+        (status = 500, response = api_doc::InternalErrorResponse),
+    ),
+    tag = "open"
+)]
+async fn get_msg_content(
+    State(storage): State<SharedStorage>,
+    Path(msg_id): Path<uuid::Uuid>,
+) -> Result<Response, ErrorResponse> {
+    let msg = storage
+        .get_msg(&msg_id)
+        .await?
+        .ok_or(ErrorResponse::NotFoundError(anyhow!("no message found")))?;
+    if !msg.has_content() {
+        return Err(ErrorResponse::NotFoundError(anyhow!(
+            "content isn't stored, only its digest"
+        )));
+    }
+    let disposition = format!("attachment; filename=\"{msg_id}.bin\"");
+    Ok((
+        [
+            (
+                http::header::CONTENT_TYPE,
+                http::HeaderValue::from_static("application/octet-stream"),
+            ),
+            (
+                http::header::CONTENT_DISPOSITION,
+                http::HeaderValue::from_str(&disposition)
+                    .context("invalid content disposition")?,
+            ),
+        ],
+        msg.content,
+    )
+        .into_response())
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/msgs",
+    params(api_doc::MsgsFilter),
+    responses(
+        (status = 200, description = "All messages, or those with `tag`", content(
+            (Vec<api_doc::MessageDetail> = "application/json"),
+            (Vec<api_doc::MessageDetail> = "application/cbor"),
+            (Vec<api_doc::MessageDetail> = "application/msgpack"),
+        )),
+        (status = 500, response = api_doc::InternalErrorResponse),
+    ),
+    tag = "open"
+)]
+async fn list_msgs(
+    State(storage): State<SharedStorage>,
+    Query(filter): Query<api_doc::MsgsFilter>,
+    encoding: ResponseEncoding,
+) -> Result<Encoded<Vec<api_doc::MessageDetail>>, ErrorResponse> {
+    let msgs = match filter.tag {
+        Some(tag) => storage.msgs_by_tag(&tag).await?,
+        None => storage.all_messages().await?,
+    };
+    let msgs = msgs.into_iter().map(msg_detail).collect();
+    Ok(Encoded(encoding, msgs))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/msgs/batch-get",
+    request_body = api_doc::BatchGetMsgsRequest,
+    responses(
+        (status = 200, description = "Messages in the order of `ids`, `null` for unknown ones", content(
+            (Vec<Option<api_doc::MessageDetail>> = "application/json"),
+            (Vec<Option<api_doc::MessageDetail>> = "application/cbor"),
+            (Vec<Option<api_doc::MessageDetail>> = "application/msgpack"),
+        )),
+        (status = 401, response = api_doc::UnauthorizedResponse),
+        (status = 500, response = api_doc::InternalErrorResponse),
+    ),
+    security(("api_key" = [])),
+    tag = "protected"
+)]
+/// Fetch several messages at once, e.g. for a dashboard.
+async fn batch_get_msgs(
+    State(storage): State<SharedStorage>,
+    encoding: ResponseEncoding,
+    Json(req): Json<api_doc::BatchGetMsgsRequest>,
+) -> Result<Encoded<Vec<Option<api_doc::MessageDetail>>>, ErrorResponse> {
+    let msgs = storage.get_msgs(&req.ids).await?;
+    let msgs = msgs.into_iter().map(|m| m.map(msg_detail)).collect();
+    Ok(Encoded(encoding, msgs))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/msg/{msg_id}/verify-against",
+    params(("msg_id" = uuid::Uuid, Path, description = "Message id")),
+    request_body = api_doc::VerifyAgainstRequest,
+    responses(
+        (status = 200, description = "Which of supplied keys signed the message", body = api_doc::VerifyAgainstResult),
+        (status = 400, response = api_doc::BadRequestResponse),
+        (status = 401, response = api_doc::UnauthorizedResponse),
+        (status = 404, response = api_doc::NotFoundResponse),
+        (status = 422, response = api_doc::ValidationErrorResponse),
+        (status = 500, response = api_doc::InternalErrorResponse),
+    ),
+    security(("api_key" = [])),
+    tag = "protected"
+)]
+/// Signatures are checked against supplied keys instead of recorded
+/// participants, so a tampered participant list shows up
+async fn verify_msg_against(
+    State(state): State<AppState>,
+    Path(msg_id): Path<uuid::Uuid>,
+    ValidatedJson(req): ValidatedJson<api_doc::VerifyAgainstRequest>,
+) -> Result<Json<api_doc::VerifyAgainstResult>, ErrorResponse> {
+    let network = state.settings.network;
+    let expected = req
+        .keys
+        .iter()
+        .map(|key| expected_pkh(key, network))
+        .collect::<Result<Vec<_>, _>>()?;
+    let msg = state
+        .storage
+        .get_msg(&msg_id)
+        .await?
+        .ok_or(ErrorResponse::NotFoundError(anyhow!("no message found")))?;
+    let participants = msg
+        .signature
+        .participants()
+        .map(|pk| hash160::Hash::hash(&pk.serialize()))
+        .collect::<std::collections::HashSet<_>>();
+    let (secp, multisig, digest) =
+        (state.secp.clone(), msg.signature.clone(), msg.digest());
+    let signers =
+        blocking(move || multisig.valid_signers(&secp, &digest)).await?;
+    let signer_hashes = signers
+        .iter()
+        .map(|pk| hash160::Hash::hash(&pk.serialize()))
+        .collect::<Vec<_>>();
+
+    let (matched, unmatched) = req
+        .keys
+        .iter()
+        .zip(&expected)
+        .partition::<Vec<_>, _>(|(_, pkh)| signer_hashes.contains(pkh));
+    let unexpected = signers
+        .iter()
+        .zip(&signer_hashes)
+        .filter(|(_, pkh)| !expected.contains(pkh))
+        .map(|(pk, _)| crypto::bt_addr_from_pk(pk, network))
+        .collect();
+    let participants_match = expected
+        .iter()
+        .copied()
+        .collect::<std::collections::HashSet<_>>()
+        .eq(&participants);
+    Ok(Json(api_doc::VerifyAgainstResult {
+        matched: matched.into_iter().map(|(key, _)| key.clone()).collect(),
+        unmatched: unmatched.into_iter().map(|(key, _)| key.clone()).collect(),
+        unexpected,
+        participants_match,
+    }))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/msgs/verify-all",
+    responses(
+        (status = 200, body = api_doc::VerifyAllResponse),
+        (status = 401, response = api_doc::UnauthorizedResponse),
+        (status = 500, response = api_doc::InternalErrorResponse),
+    ),
+    security(("api_key" = [])),
+    tag = "protected"
+)]
+/// Re-verify signatures of all stored messages, e.g. after import.
+async fn verify_all_msgs(
+    State(storage): State<SharedStorage>,
+    State(secp): State<Secp256k1<All>>,
+) -> Result<Json<api_doc::VerifyAllResponse>, ErrorResponse> {
+    let msgs = storage.all_messages().await?;
+    let (msgs, integrity) = blocking(move || {
+        let integrity = message::check_integrity(&secp, &msgs);
+        (msgs, integrity)
+    })
+    .await?;
+    let mut summary = api_doc::VerifyAllResponse::default();
+    for (msg, integrity) in msgs.iter().zip(integrity) {
+        match integrity {
+            Integrity::Complete => summary.complete += 1,
+            Integrity::Pending => summary.pending += 1,
+            Integrity::Invalid => {
+                summary.invalid += 1;
+                summary.invalid_ids.push(msg.id);
+            }
+        }
+    }
+    Ok(Json(summary))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/address/from-secret",
+    request_body = api_doc::AddressFromSecretRequest,
+    responses(
+        (status = 200, description = "Address of the public key, uncompressed one for legacy WIFs", body = String),
+        (status = 400, response = api_doc::BadRequestResponse),
+        (status = 401, response = api_doc::UnauthorizedResponse),
+        (status = 404, description = "`enable_secret_tools` is off"),
+        (status = 500, response = api_doc::InternalErrorResponse),
+    ),
+    security(("api_key" = [])),
+    tag = "protected"
+)]
+/// Derive address of a secret key without importing it, to check key
+/// ownership while debugging.
+async fn address_from_secret(
+    State(state): State<AppState>,
+    Json(req): Json<api_doc::AddressFromSecretRequest>,
+) -> Result<String, ErrorResponse> {
+    if !state.settings.enable_secret_tools {
+        return Err(ErrorResponse::NotFoundError(anyhow!(
+            "secret tools are disabled"
+        )));
     }
+    let (seckey, compressed) =
+        crypto::parse_secret_key(&req.secret, state.settings.network)?;
+    Ok(crypto::address_from_secret(
+        &seckey,
+        compressed,
+        &state.secp,
+        state.settings.network,
+    ))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/verifymessage",
+    request_body = api_doc::VerifyMessageRequest,
+    responses(
+        (status = 200, description = "Whether the address signed the message", body = api_doc::VerifyMessageResult),
+        (status = 400, response = api_doc::BadRequestResponse),
+        (status = 401, response = api_doc::UnauthorizedResponse),
+        (status = 500, response = api_doc::InternalErrorResponse),
+    ),
+    security(("api_key" = [])),
+    tag = "protected"
+)]
+/// Check a signature of Bitcoin Core `signmessage`, same as its
+/// `verifymessage`: unrecoverable signature is not valid, malformed
+/// address or base64 is an error.
+async fn verify_message(
+    State(state): State<AppState>,
+    Json(req): Json<api_doc::VerifyMessageRequest>,
+) -> Result<Json<api_doc::VerifyMessageResult>, ErrorResponse> {
+    let network = state.settings.network;
+    crypto::pkh_from_bt_addr(&req.address, network)?;
+    let valid = match crypto::recover_address_from_signed_message(
+        &state.secp,
+        req.message.as_bytes(),
+        &req.signature,
+        network,
+    ) {
+        Ok(address) => address == req.address,
+        Err(e @ crypto::SignedMessageError::BadEncoding) => {
+            return Err(e.into())
+        }
+        Err(_) => false,
+    };
+    Ok(Json(api_doc::VerifyMessageResult { valid }))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/admin/recent-requests",
+    responses(
+        (status = 200, description = "Captured exchanges, oldest first", body = Vec<api_doc::CapturedExchange>),
+        (status = 401, response = api_doc::UnauthorizedResponse),
+        (status = 404, description = "`capture_requests` is off"),
+    ),
+    security(("api_key" = [])),
+    tag = "protected"
+)]
+/// Recent requests with their responses, to reproduce client bugs
+async fn recent_requests(
+    State(capture): State<Option<RequestCapture>>,
+) -> Result<Json<Vec<api_doc::CapturedExchange>>, ErrorResponse> {
+    let capture = capture.ok_or(ErrorResponse::NotFoundError(anyhow!(
+        "request capture is disabled"
+    )))?;
+    Ok(Json(capture.recent()))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/admin/metrics",
+    responses(
+        (status = 200, description = "Storage gauges in Prometheus text format", body = String, content_type = "text/plain"),
+        (status = 401, response = api_doc::UnauthorizedResponse),
+        (status = 404, description = "`metrics_interval_secs` is off"),
+    ),
+    security(("api_key" = [])),
+    tag = "protected"
+)]
+/// Metrics to scrape, see `storage::metrics`
+async fn metrics(
+    State(metrics): State<Option<PrometheusHandle>>,
+) -> Result<String, ErrorResponse> {
+    let metrics = metrics.ok_or(ErrorResponse::NotFoundError(anyhow!(
+        "metrics are disabled"
+    )))?;
+    Ok(metrics.render())
 }
 
 // ───── Helpers ──────────────────────────────────────────────────────────── //
 
+/// Run CPU-heavy crypto on the blocking pool, off the async workers
+async fn blocking<T, F>(f: F) -> Result<T, ErrorResponse>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    Ok(tokio::task::spawn_blocking(f)
+        .await
+        .context("blocking task failed")?)
+}
+
+/// Strong `ETag` of the message, changes with every update
+fn msg_etag(msg: &Message) -> http::HeaderValue {
+    http::HeaderValue::from_str(&format!("\"{}\"", msg.version))
+        .expect("quoted number is a valid header value")
+}
+
+/// Entity tags listed by `If-Match`, empty if there is no such header
+fn if_match_tags(headers: &HeaderMap) -> Vec<String> {
+    headers
+        .get_all(http::header::IF_MATCH)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|tag| tag.trim().to_string())
+        .collect()
+}
+
+/// Whether `If-Match` `tags`, if any, list the current `ETag` of the
+/// message
+fn if_match(tags: &[String], msg: &Message) -> bool {
+    let etag = msg_etag(msg);
+    tags.is_empty() || tags.iter().any(|tag| tag == "*" || etag == tag)
+}
+
+/// Public key hash of an expected participant given as an address or a
+/// compressed public key in hex
+fn expected_pkh(
+    key: &str,
+    network: crypto::Network,
+) -> Result<hash160::Hash, ErrorResponse> {
+    match key.len() {
+        66 => parse_compressed_pubkey(key)
+            .map(|pk| hash160::Hash::hash(&pk.serialize())),
+        _ => Ok(crypto::pkh_from_bt_addr(key, network)?),
+    }
+}
+
+/// Participant the service holds no secret for
+fn parse_compressed_pubkey(hex: &str) -> Result<PublicKey, ErrorResponse> {
+    if hex.len() != 66 {
+        return Err(ErrorResponse::BadRequest(anyhow!(
+            "public key must be compressed"
+        )));
+    }
+    hex.parse::<PublicKey>()
+        .context("invalid public key")
+        .map_err(ErrorResponse::BadRequest)
+}
+
+/// Whether `Accept` header explicitly asks for json
+fn accepts_json(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(http::header::ACCEPT)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|media| {
+            media.split(';').next().is_some_and(|m| {
+                m.trim().eq_ignore_ascii_case("application/json")
+            })
+        })
+}
+
+/// Generate keypair on the blocking pool, at most `keygen_concurrency`
+/// at once
+async fn generate_keypair(
+    state: &AppState,
+) -> Result<SecretKeypair, ErrorResponse> {
+    let _permit = state
+        .keygen_permits
+        .acquire()
+        .await
+        .context("keypair generation is shut down")?;
+    let secp = state.secp.clone();
+    let keypair =
+        tokio::task::spawn_blocking(move || crypto::new_keypair(&secp))
+            .await
+            .context("keypair generation task failed")?
+            .context("failed to generate keypair")?;
+    Ok(keypair)
+}
+
+/// Keys are ordered by their ids, i.e. by creation order
+fn user_dto(user: User, network: crypto::Network) -> api_doc::User {
+    let mut keys = user.keys.into_iter().collect::<Vec<_>>();
+    keys.sort_by_key(|(key_id, _)| *key_id);
+    api_doc::User {
+        id: user.id,
+        name: user.name,
+        keys: keys
+            .into_iter()
+            .map(|(_, k)| crypto::bt_addr_from_pk(&k.public_key(), network))
+            .collect(),
+        created_at: user.created_at,
+        updated_at: user.updated_at,
+        key_stats: None,
+    }
+}
+
+/// Wrap successful json body into `ApiResponse`. Any other body, e.g.
+/// plain text or message content, and json which doesn't parse are
+/// passed through as is.
+async fn wrap_in_envelope(req: Request, next: Next) -> Response {
+    let request_id = req.extensions().get::<RequestId>().copied();
+    let response = next.run(req).await;
+    let is_json = response
+        .headers()
+        .get(http::header::CONTENT_TYPE)
+        .is_some_and(|v| v.as_bytes().starts_with(b"application/json"));
+    if !response.status().is_success() || !is_json {
+        return response;
+    }
+    let (mut parts, body) = response.into_parts();
+    // Json is serialized by handlers, it is already in memory
+    let bytes = match axum::body::to_bytes(body, usize::MAX).await {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            return ErrorResponse::InternalError(anyhow!(
+                "failed to read response body: {e}"
+            ))
+            .into_response()
+        }
+    };
+    let data = match serde_json::from_slice::<serde_json::Value>(&bytes) {
+        Ok(data) => data,
+        Err(_) => return Response::from_parts(parts, Body::from(bytes)),
+    };
+    parts.headers.remove(http::header::CONTENT_TYPE);
+    parts.headers.remove(http::header::CONTENT_LENGTH);
+    let enveloped = ApiResponse::new(request_id, data).into_response();
+    let (enveloped_parts, body) = enveloped.into_parts();
+    parts.headers.extend(enveloped_parts.headers);
+    Response::from_parts(parts, body)
+}
+
+/// Content of a request as it is signed, `content` is hex if it is a
+/// digest
+fn request_content(
+    content: String,
+    is_digest: bool,
+) -> Result<Vec<u8>, ErrorResponse> {
+    match is_digest {
+        true => Ok(content
+            .parse::<sha256::Hash>()
+            .context("invalid digest hex")
+            .map_err(ErrorResponse::BadRequest)?
+            .to_byte_array()
+            .to_vec()),
+        false => Ok(content.into_bytes()),
+    }
+}
+
+fn msg_detail(msg: Message) -> api_doc::MessageDetail {
+    let (content, content_encoding) = match msg.content_display() {
+        ContentDisplay::Text(text) => (text, api_doc::ContentEncoding::Text),
+        ContentDisplay::Base64(b64) => (b64, api_doc::ContentEncoding::Base64),
+    };
+    api_doc::MessageDetail {
+        id: msg.id,
+        content,
+        content_encoding,
+        content_stored: msg.has_content(),
+        nonce: msg.nonce,
+        count_required: msg.count_required,
+        progress: msg.signature.progress(msg.count_required),
+        tags: msg.tags,
+        created_at: msg.created_at,
+        updated_at: msg.updated_at,
+        sign_deadline: msg.sign_deadline,
+    }
+}
+
+/// Post `MsgSigned` event if webhook is configured
+async fn notify_signed(
+    state: &AppState,
+    msg_id: &uuid::Uuid,
+) -> Result<(), ErrorResponse> {
+    let Some(webhook) = state.webhook.as_ref() else {
+        return Ok(());
+    };
+    if let Some(msg) = state.storage.get_msg(msg_id).await? {
+        webhook.notify(WebhookEvent::MsgSigned {
+            msg_id: msg.id,
+            signed: msg.signature.signed_count(),
+            required: msg.count_required,
+        });
+    }
+    Ok(())
+}
+
 async fn extract_selected_keypairs(
     state: &AppState,
     keys: Vec<String>,
-) -> Result<Vec<Keypair>, ErrorResponse> {
-    let mut all_keypairs = state
-        .storage
-        .all_users()
+) -> Result<Vec<SecretKeypair>, ErrorResponse> {
+    let pkhs = parse_keys(&keys, state.settings.network).map_err(|errors| {
+        ErrorResponse::BadRequest(anyhow!(fields_message(&errors)))
+    })?;
+    let found = find_keypairs(state, &pkhs).await?;
+    selected_keypairs(&keys, &pkhs, found)
+}
+
+/// Bounds the cost of verifying and storing a message
+fn check_participant_count(
+    settings: &Settings,
+    count: usize,
+) -> Result<(), multisig::Error> {
+    match settings.max_participants {
+        Some(max) if count > max => {
+            Err(multisig::Error::TooManyParticipants(max))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Keys of `username` which may sign `msg`, ordered by creation
+async fn participant_keypairs(
+    storage: &SharedStorage,
+    username: &str,
+    msg: &Message,
+) -> Result<Vec<SecretKeypair>, ErrorResponse> {
+    let user = storage
+        .get_user(username)
         .await?
+        .ok_or(ErrorResponse::NotFoundError(anyhow!("no user found")))?;
+    let mut keys = user.keys.into_iter().collect::<Vec<_>>();
+    keys.sort_by_key(|(key_id, _)| *key_id);
+    Ok(keys
         .into_iter()
-        .flat_map(|u| u.keys.into_values())
-        .map(|k| (hash160::Hash::hash(&k.public_key().serialize()), k))
-        .collect::<HashMap<_, _>>();
-    let selected_keypairs = keys
-        .into_iter()
+        .map(|(_, keypair)| keypair)
+        .filter(|keypair| msg.signature.accepts(&keypair.public_key()))
+        .collect())
+}
+
+/// Public key hashes of addresses in `keys`. Every malformed key is
+/// reported, not only the first one.
+fn parse_keys(
+    keys: &[String],
+    network: crypto::Network,
+) -> Result<Vec<hash160::Hash>, Vec<FieldError>> {
+    let (pkhs, errors): (Vec<_>, Vec<_>) = keys
+        .iter()
         .map(|key| {
-            let pkh = crypto::pkh_from_bt_addr(&key).map_err(|e| {
-                ErrorResponse::BadRequest(anyhow!("invalid key: {}", e))
-            })?;
-            let keypair = all_keypairs.remove(&pkh).ok_or(
+            crypto::pkh_from_bt_addr(key, network)
+                .map_err(|e| FieldError::new("keys", format!("{key}: {e}")))
+        })
+        .partition(Result::is_ok);
+    if !errors.is_empty() {
+        return Err(errors.into_iter().filter_map(Result::err).collect());
+    }
+    Ok(pkhs.into_iter().filter_map(Result::ok).collect())
+}
+
+/// Keypairs with `pkhs`, positional. Users are scanned once, and only
+/// until every keypair is found.
+async fn find_keypairs(
+    state: &AppState,
+    pkhs: &[hash160::Hash],
+) -> Result<Vec<Option<SecretKeypair>>, ErrorResponse> {
+    let mut found: Vec<Option<SecretKeypair>> = vec![None; pkhs.len()];
+    let mut users = state.storage.stream_users();
+    while found.iter().any(Option::is_none) {
+        let Some(user) = users.next().await.transpose()? else {
+            break;
+        };
+        for keypair in user.keys.into_values() {
+            let hash = hash160::Hash::hash(&keypair.public_key().serialize());
+            for (pkh, slot) in pkhs.iter().zip(&mut found) {
+                // Addresses come from the request, so don't leak how much
+                // of the hash matched
+                if slot.is_none()
+                    && crypto::constant_time_eq(
+                        hash.as_byte_array(),
+                        pkh.as_byte_array(),
+                    )
+                {
+                    *slot = Some(keypair.clone());
+                }
+            }
+        }
+    }
+    Ok(found)
+}
+
+/// `NotFoundError` for the first of `keys` without a keypair, a repeated
+/// key isn't found the second time
+fn selected_keypairs(
+    keys: &[String],
+    pkhs: &[hash160::Hash],
+    found: Vec<Option<SecretKeypair>>,
+) -> Result<Vec<SecretKeypair>, ErrorResponse> {
+    let mut seen = std::collections::HashSet::new();
+    keys.iter()
+        .zip(pkhs)
+        .zip(found)
+        .map(|((key, pkh), keypair)| {
+            keypair.filter(|_| seen.insert(*pkh)).ok_or(
                 ErrorResponse::NotFoundError(anyhow!("key not found: {}", key)),
-            )?;
-            Ok::<Keypair, ErrorResponse>(keypair)
+            )
         })
-        .collect::<Result<Vec<_>, _>>()?;
-    Ok(selected_keypairs)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use axum::extract::{Query, State};
+
+    use crate::domain::message::Message;
+    use crate::encoding::ResponseEncoding;
+    use crate::startup::api_doc::MsgsFilter;
+    use crate::storage::in_memory::InMemoryStorage;
+    use crate::storage::SharedStorage;
+    use crate::{crypto, startup::AppState};
+
+    use super::{failed_outcome, list_msgs, router, ErrorResponse};
+
+    #[tokio::test]
+    async fn handler_extracts_storage_only(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let storage: SharedStorage = Arc::new(InMemoryStorage::default());
+        let secp = secp256k1::Secp256k1::new();
+        let pubkey = crypto::new_keypair(&secp)?.public_key();
+        let msg_id = storage
+            .store_msg(Message::new(b"Hello world!", vec![pubkey], None)?)
+            .await?;
+
+        // No `AppState` is needed to call the handler
+        let filter = Query(MsgsFilter { tag: None });
+        let msgs = list_msgs(State(storage), filter, ResponseEncoding::Json)
+            .await?
+            .1;
+        assert_eq!(msgs.len(), 1);
+        assert_eq!(msgs[0].id, msg_id);
+
+        // And the router state provides the narrowed one
+        let _ = axum::Router::<AppState>::new()
+            .route("/msgs", axum::routing::get(list_msgs));
+        Ok(())
+    }
+
+    #[test]
+    fn internal_errors_of_bulk_items_are_not_detailed(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let error = ErrorResponse::UnexpectedError(anyhow::anyhow!(
+            "storage connection to 10.0.0.5 refused"
+        ));
+        let outcome = serde_json::to_value(failed_outcome(&error))?;
+        assert_eq!(outcome["status"], 500);
+        assert_eq!(outcome["error"], "internal error");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn admin_responses_are_not_coalesced(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        use tower::ServiceExt;
+
+        let state = AppState {
+            settings: Arc::new(crate::config::Settings::builder().build()),
+            storage: Arc::new(InMemoryStorage::default()),
+            secp: secp256k1::Secp256k1::new(),
+            idempotency: crate::idempotency::IdempotencyCache::new(
+                std::time::Duration::from_secs(60),
+            ),
+            verification_cache:
+                crate::verification_cache::VerificationCache::new(1),
+            webhook: None,
+            keygen_permits: Arc::new(tokio::sync::Semaphore::new(1)),
+            capture: None,
+            metrics: None,
+        };
+        // Response depends on the peer, as with peer restrictions
+        let admin = axum::Router::new().route(
+            "/peer",
+            axum::routing::get(|headers: http::HeaderMap| async move {
+                tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+                headers["x-peer"].to_str().unwrap_or_default().to_owned()
+            }),
+        );
+        let app = router(admin).with_state(state);
+        let get = |peer: &'static str| {
+            let app = app.clone();
+            async move {
+                let request = http::Request::get("/admin/peer")
+                    .header("x-peer", peer)
+                    .body(axum::body::Body::empty())?;
+                let response = app.oneshot(request).await?;
+                let body =
+                    axum::body::to_bytes(response.into_body(), usize::MAX)
+                        .await?;
+                Ok::<_, Box<dyn std::error::Error>>(body)
+            }
+        };
+
+        let (allowed, disallowed) =
+            tokio::try_join!(get("10.1.2.3"), get("192.0.2.1"))?;
+        assert_eq!(allowed.as_ref(), b"10.1.2.3");
+        assert_eq!(disallowed.as_ref(), b"192.0.2.1");
+        Ok(())
+    }
 }
@@ -0,0 +1,52 @@
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+use crate::startup::api_doc::CapturedExchange;
+
+/// Longer bodies are truncated
+pub const MAX_CAPTURED_BODY_BYTES: usize = 16 * 1024;
+
+/// Headers whose values never reach the buffer
+pub const REDACTED_HEADERS: [&str; 3] =
+    ["x-api-key", "authorization", "cookie"];
+
+/// Response extension of exchanges which aren't recorded, startup sets
+/// it on admin routes so the buffer doesn't capture reads of itself
+#[derive(Debug, Clone, Copy)]
+pub struct Uncaptured;
+
+/// Last `capacity` request/response pairs, oldest are dropped first.
+/// Meant for reproducing client bugs, never enabled in production.
+#[derive(Debug, Clone)]
+pub struct RequestCapture {
+    capacity: usize,
+    entries: Arc<Mutex<VecDeque<CapturedExchange>>>,
+}
+
+impl RequestCapture {
+    pub fn new(capacity: usize) -> Self {
+        RequestCapture {
+            capacity,
+            entries: Arc::new(Mutex::new(VecDeque::with_capacity(capacity))),
+        }
+    }
+
+    /// Poisoned lock drops the exchange
+    pub fn record(&self, exchange: CapturedExchange) {
+        if let Ok(mut entries) = self.entries.lock() {
+            if entries.len() == self.capacity {
+                entries.pop_front();
+            }
+            entries.push_back(exchange);
+        }
+    }
+
+    /// Captured exchanges, oldest first
+    pub fn recent(&self) -> Vec<CapturedExchange> {
+        self.entries
+            .lock()
+            .map(|entries| entries.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+}
@@ -0,0 +1,73 @@
+//! Offline verification of exported multisig bundles, without storage
+//! or http.
+
+use secp256k1::{Secp256k1, VerifyOnly};
+
+use crate::domain::multisig::{self, Multisig};
+
+/// Verifies multisig bundles against content, holds a verification-only
+/// context, so it's cheaper to create than the signing one.
+///
+/// ```
+/// use multisig_ecdsa::client::MultisigVerifier;
+/// use multisig_ecdsa::crypto;
+/// use multisig_ecdsa::domain::multisig::Multisig;
+///
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let secp = secp256k1::Secp256k1::new();
+/// let keypairs = [crypto::new_keypair(&secp)?, crypto::new_keypair(&secp)?];
+/// let mut multisig =
+///     Multisig::new(keypairs.iter().map(|k| k.public_key()).collect());
+/// multisig.sign(&secp, b"Hello world!", &keypairs[0])?;
+/// let bundle = multisig.to_bundle(1);
+///
+/// // Somewhere else, with the bundle only
+/// let verifier = MultisigVerifier::new();
+/// assert!(verifier.verify_bundle(&bundle, b"Hello world!", 1).is_ok());
+/// assert!(verifier.verify_bundle(&bundle, b"Goodbye world!", 1).is_err());
+/// // Threshold is the verifier's, not the one the bundle claims
+/// assert!(verifier.verify_bundle(&bundle, b"Hello world!", 2).is_err());
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct MultisigVerifier {
+    secp: Secp256k1<VerifyOnly>,
+}
+
+impl Default for MultisigVerifier {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MultisigVerifier {
+    pub fn new() -> Self {
+        MultisigVerifier {
+            secp: Secp256k1::verification_only(),
+        }
+    }
+
+    /// Check that at least `count_required` of the collected signatures
+    /// match `content`.
+    pub fn verify(
+        &self,
+        multisig: &Multisig,
+        content: &[u8],
+        count_required: usize,
+    ) -> Result<(), multisig::Error> {
+        multisig.verify(&self.secp, content, count_required)
+    }
+
+    /// Same as `verify` for a bundle. Bundle comes from elsewhere, so the
+    /// threshold it carries isn't trusted, `count_required` is checked.
+    pub fn verify_bundle(
+        &self,
+        bundle: &[u8],
+        content: &[u8],
+        count_required: usize,
+    ) -> Result<(), multisig::Error> {
+        let (multisig, _) = Multisig::from_bundle(bundle)?;
+        self.verify(&multisig, content, count_required)
+    }
+}
@@ -1,26 +1,483 @@
+use std::collections::HashMap;
 use std::net::Ipv4Addr;
+use std::path::{Path, PathBuf};
 
 use anyhow::Context;
+use ipnet::IpNet;
 use serde::Deserialize;
 
+use crate::crypto::Network;
+use crate::domain::message::ThresholdPolicy;
+use crate::middleware::{RequestTracing, TraceVerbosity};
+
 #[derive(Deserialize, Debug, Clone)]
 pub struct Settings {
     pub app_port: u16,
     pub app_ip: Ipv4Addr,
+    /// How long `Idempotency-Key` of created messages are remembered
+    #[serde(default = "default_idempotency_ttl_secs")]
+    pub idempotency_ttl_secs: u64,
+    /// `EnvFilter` directives, `RUST_LOG` takes precedence if set
+    #[serde(default = "default_log_filter")]
+    pub log_filter: String,
+    /// When set, requests which mutate state must carry it in `X-API-Key`
+    #[serde(default)]
+    pub api_key: Option<String>,
+    /// Applied to messages created without `required_signature_count`
+    #[serde(default)]
+    pub default_threshold_policy: ThresholdPolicy,
+    /// Upper bound of streamed message content, in bytes
+    #[serde(default = "default_max_upload_bytes")]
+    pub max_upload_bytes: usize,
+    /// Network of generated and accepted addresses
+    #[serde(default)]
+    pub network: Network,
+    /// Soft cap of stored messages, new ones are rejected with 503 above it
+    #[serde(default)]
+    pub max_messages: Option<usize>,
+    /// Cap of participants of a message, larger lists are rejected with 400
+    #[serde(default)]
+    pub max_participants: Option<usize>,
+    /// Where to post notifications about message signing, if anywhere
+    #[serde(default)]
+    pub webhook_url: Option<String>,
+    /// Base url the api is reachable at, listed as the OpenAPI server.
+    /// Paths are relative to the docs host if it isn't set.
+    #[serde(default)]
+    pub public_base_url: Option<String>,
+    /// Attempts to deliver a webhook notification before it's dropped
+    #[serde(default = "default_webhook_max_attempts")]
+    pub webhook_max_attempts: u32,
+    /// Initial delay between webhook delivery attempts
+    #[serde(default = "default_webhook_backoff_ms")]
+    pub webhook_backoff_ms: u64,
+    /// Request logging verbosity by route prefix, e.g. `/api/v1/msg`
+    #[serde(default)]
+    pub trace_verbosity: HashMap<String, TraceVerbosity>,
+    /// Layer which traces requests, `trace_verbosity` applies to
+    /// `custom` only
+    #[serde(default)]
+    pub request_tracing: RequestTracing,
+    /// Log bodies of 403 responses with `standard` request tracing, the
+    /// custom layer always does
+    #[serde(default)]
+    pub log_forbidden_bodies: bool,
+    /// Keypairs generated at the same time, others wait in a queue
+    #[serde(default = "default_keygen_concurrency")]
+    pub keygen_concurrency: usize,
+    /// Messages whose last verification result is kept, the oldest cached
+    /// one is evicted to make room
+    #[serde(default = "default_verification_cache_capacity")]
+    pub verification_cache_capacity: usize,
+    /// Files of the frontend, `index.html` is served for unknown paths.
+    /// Relative path is resolved against the config file which sets it.
+    /// Nothing is served if unset.
+    #[serde(default)]
+    pub static_dir: Option<PathBuf>,
+    /// `Content-Security-Policy` sent with every response
+    #[serde(default = "default_content_security_policy")]
+    pub content_security_policy: String,
+    /// Serve endpoints which take secret keys, e.g. to derive an address
+    /// while debugging key ownership. Secrets are never stored or logged.
+    #[serde(default)]
+    pub enable_secret_tools: bool,
+    /// Keep that many recent request/response pairs for
+    /// `/admin/recent-requests`. Debugging aid, ignored in production.
+    #[serde(default)]
+    pub capture_requests: Option<usize>,
+    /// Peers allowed to reach `/admin/` routes, e.g. `10.0.0.0/8` or
+    /// `fd00::/8`, checked regardless of `api_key`. Unrestricted if unset.
+    #[serde(default)]
+    pub admin_allowed_cidrs: Option<Vec<IpNet>>,
+    /// Load balancer in front of the service, e.g. `10.0.0.1/32`. Client
+    /// address of its requests is taken from `Forwarded` or
+    /// `X-Forwarded-For`, which are rejected from any other peer.
+    #[serde(default)]
+    pub trusted_proxy: Option<IpNet>,
+    /// Keep message content, otherwise only its digest is stored and
+    /// verification requires the content to be supplied again
+    #[serde(default = "default_store_content")]
+    pub store_content: bool,
+    /// Refresh storage gauges that often and export them at
+    /// `/admin/metrics`, neither happens if unset
+    #[serde(default)]
+    pub metrics_interval_secs: Option<u64>,
+    /// Tokio worker threads, `None` uses the default of one per CPU core
+    #[serde(default)]
+    pub worker_threads: Option<usize>,
+}
+
+fn default_log_filter() -> String {
+    "info,axum::rejection=trace".to_string()
+}
+
+fn default_max_upload_bytes() -> usize {
+    64 * 1024 * 1024
+}
+
+fn default_webhook_max_attempts() -> u32 {
+    5
+}
+
+fn default_webhook_backoff_ms() -> u64 {
+    500
+}
+
+fn default_keygen_concurrency() -> usize {
+    4
+}
+
+fn default_verification_cache_capacity() -> usize {
+    10_000
+}
+
+fn default_content_security_policy() -> String {
+    "default-src 'self'; frame-ancestors 'none'".to_string()
+}
+
+fn default_store_content() -> bool {
+    true
+}
+
+fn default_idempotency_ttl_secs() -> u64 {
+    24 * 60 * 60
+}
+
+fn yaml_file(
+    file: &str,
+) -> config::File<config::FileSourceFile, config::FileFormat> {
+    config::File::new(file, config::FileFormat::Yaml)
+}
+
+/// Whether `static_dir` comes from `file`
+fn sets_static_dir(file: &str) -> bool {
+    config::Config::builder()
+        .add_source(yaml_file(file))
+        .build()
+        .is_ok_and(|c| c.get_string("static_dir").is_ok())
 }
 
 impl Settings {
+    /// Load settings from `APP_CONFIG_FILE`, which may list several
+    /// colon-separated files, later ones override earlier.
     pub fn load_configuration() -> Result<Settings, anyhow::Error> {
-        let config_file = std::env::var("APP_CONFIG_FILE")
+        let config_files = std::env::var("APP_CONFIG_FILE")
             .unwrap_or("config/config.yaml".to_string());
+        Self::load_from_files(config_files.split(':'))
+    }
 
-        config::Config::builder()
-            .add_source(config::File::new(
-                &config_file,
-                config::FileFormat::Yaml,
-            ))
+    /// Merge yaml files in order, values of later files win.
+    pub fn load_from_files<I, P>(files: I) -> Result<Settings, anyhow::Error>
+    where
+        I: IntoIterator<Item = P>,
+        P: AsRef<str>,
+    {
+        let files = files
+            .into_iter()
+            .filter(|f| !f.as_ref().is_empty())
+            .collect::<Vec<_>>();
+        let mut settings: Settings = files
+            .iter()
+            .fold(config::Config::builder(), |builder, file| {
+                builder.add_source(yaml_file(file.as_ref()))
+            })
             .build()?
             .try_deserialize()
-            .context("Failed to build config from local config file.")
+            .context("Failed to build config from local config files.")?;
+        if let Some(static_dir) =
+            settings.static_dir.as_mut().filter(|d| d.is_relative())
+        {
+            let base = files
+                .iter()
+                .rev()
+                .find(|f| sets_static_dir(f.as_ref()))
+                .and_then(|f| Path::new(f.as_ref()).parent());
+            if let Some(base) = base {
+                *static_dir = base.join(&static_dir);
+            }
+        }
+        Ok(settings)
+    }
+
+    /// Check values which deserialize fine but can't work. All problems
+    /// are reported at once.
+    pub fn validate(&self) -> Result<(), anyhow::Error> {
+        let mut problems = Vec::new();
+        if let Err(e) = tracing_subscriber::EnvFilter::try_new(&self.log_filter)
+        {
+            problems.push(format!("log_filter: {e}"));
+        }
+        if self.idempotency_ttl_secs == 0 {
+            problems.push("idempotency_ttl_secs must be positive".to_string());
+        }
+        if self.max_upload_bytes == 0 {
+            problems.push("max_upload_bytes must be positive".to_string());
+        }
+        if self.keygen_concurrency == 0 {
+            problems.push("keygen_concurrency must be positive".to_string());
+        }
+        if self.verification_cache_capacity == 0 {
+            problems.push(
+                "verification_cache_capacity must be positive".to_string(),
+            );
+        }
+        if self.capture_requests == Some(0) {
+            problems.push("capture_requests must be positive".to_string());
+        }
+        if self.metrics_interval_secs == Some(0) {
+            problems.push("metrics_interval_secs must be positive".to_string());
+        }
+        if self.worker_threads == Some(0) {
+            problems.push("worker_threads must be positive".to_string());
+        }
+        if self.max_messages == Some(0) {
+            problems.push("max_messages must be positive".to_string());
+        }
+        if self.max_participants == Some(0) {
+            problems.push("max_participants must be positive".to_string());
+        }
+        if matches!(&self.api_key, Some(key) if key.is_empty()) {
+            problems.push("api_key must not be empty".to_string());
+        }
+        if let Some(url) = &self.public_base_url {
+            if let Err(e) = reqwest::Url::parse(url) {
+                problems.push(format!("public_base_url: {e}"));
+            }
+        }
+        if let Some(url) = &self.webhook_url {
+            if let Err(e) = reqwest::Url::parse(url) {
+                problems.push(format!("webhook_url: {e}"));
+            }
+            if self.webhook_max_attempts == 0 {
+                problems.push("webhook_max_attempts must be positive".into());
+            }
+        }
+        for prefix in self.trace_verbosity.keys() {
+            if !prefix.starts_with('/') {
+                problems.push(format!(
+                    "trace_verbosity: route prefix must start with `/`: {prefix}"
+                ));
+            }
+        }
+        if let Some(dir) = self.static_dir.as_ref().filter(|d| !d.is_dir()) {
+            problems.push(format!(
+                "static_dir: not a directory: {}",
+                dir.display()
+            ));
+        }
+        if let Err(e) =
+            http::HeaderValue::from_str(&self.content_security_policy)
+        {
+            problems.push(format!("content_security_policy: {e}"));
+        }
+        match problems.is_empty() {
+            true => Ok(()),
+            false => Err(anyhow::anyhow!(
+                "Invalid configuration:\n\t{}",
+                problems.join("\n\t")
+            )),
+        }
+    }
+
+    /// Construct settings in code, without a config file.
+    ///
+    /// Defaults to the loopback address and port `0` (OS-assigned).
+    pub fn builder() -> SettingsBuilder {
+        SettingsBuilder::default()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SettingsBuilder {
+    settings: Settings,
+}
+
+impl Default for SettingsBuilder {
+    fn default() -> Self {
+        SettingsBuilder {
+            settings: Settings {
+                app_port: 0,
+                app_ip: Ipv4Addr::LOCALHOST,
+                idempotency_ttl_secs: default_idempotency_ttl_secs(),
+                log_filter: default_log_filter(),
+                api_key: None,
+                default_threshold_policy: ThresholdPolicy::default(),
+                max_upload_bytes: default_max_upload_bytes(),
+                network: Network::default(),
+                max_messages: None,
+                max_participants: None,
+                webhook_url: None,
+                public_base_url: None,
+                webhook_max_attempts: default_webhook_max_attempts(),
+                webhook_backoff_ms: default_webhook_backoff_ms(),
+                trace_verbosity: HashMap::new(),
+                request_tracing: RequestTracing::default(),
+                log_forbidden_bodies: false,
+                keygen_concurrency: default_keygen_concurrency(),
+                verification_cache_capacity:
+                    default_verification_cache_capacity(),
+                static_dir: None,
+                content_security_policy: default_content_security_policy(),
+                enable_secret_tools: false,
+                capture_requests: None,
+                admin_allowed_cidrs: None,
+                trusted_proxy: None,
+                store_content: default_store_content(),
+                metrics_interval_secs: None,
+                worker_threads: None,
+            },
+        }
+    }
+}
+
+impl SettingsBuilder {
+    pub fn app_port(mut self, app_port: u16) -> Self {
+        self.settings.app_port = app_port;
+        self
+    }
+
+    pub fn app_ip(mut self, app_ip: Ipv4Addr) -> Self {
+        self.settings.app_ip = app_ip;
+        self
+    }
+
+    pub fn idempotency_ttl_secs(mut self, secs: u64) -> Self {
+        self.settings.idempotency_ttl_secs = secs;
+        self
+    }
+
+    pub fn log_filter(mut self, log_filter: impl Into<String>) -> Self {
+        self.settings.log_filter = log_filter.into();
+        self
+    }
+
+    pub fn api_key(mut self, api_key: impl Into<String>) -> Self {
+        self.settings.api_key = Some(api_key.into());
+        self
+    }
+
+    pub fn default_threshold_policy(mut self, policy: ThresholdPolicy) -> Self {
+        self.settings.default_threshold_policy = policy;
+        self
+    }
+
+    pub fn max_upload_bytes(mut self, bytes: usize) -> Self {
+        self.settings.max_upload_bytes = bytes;
+        self
+    }
+
+    pub fn network(mut self, network: Network) -> Self {
+        self.settings.network = network;
+        self
+    }
+
+    pub fn max_messages(mut self, max_messages: usize) -> Self {
+        self.settings.max_messages = Some(max_messages);
+        self
+    }
+
+    pub fn max_participants(mut self, max_participants: usize) -> Self {
+        self.settings.max_participants = Some(max_participants);
+        self
+    }
+
+    pub fn webhook_url(mut self, url: impl Into<String>) -> Self {
+        self.settings.webhook_url = Some(url.into());
+        self
+    }
+
+    pub fn public_base_url(mut self, url: impl Into<String>) -> Self {
+        self.settings.public_base_url = Some(url.into());
+        self
+    }
+
+    pub fn webhook_max_attempts(mut self, attempts: u32) -> Self {
+        self.settings.webhook_max_attempts = attempts;
+        self
+    }
+
+    pub fn webhook_backoff_ms(mut self, ms: u64) -> Self {
+        self.settings.webhook_backoff_ms = ms;
+        self
+    }
+
+    pub fn trace_verbosity(
+        mut self,
+        route_prefix: impl Into<String>,
+        verbosity: TraceVerbosity,
+    ) -> Self {
+        self.settings
+            .trace_verbosity
+            .insert(route_prefix.into(), verbosity);
+        self
+    }
+
+    pub fn request_tracing(mut self, tracing: RequestTracing) -> Self {
+        self.settings.request_tracing = tracing;
+        self
+    }
+
+    pub fn log_forbidden_bodies(mut self, log: bool) -> Self {
+        self.settings.log_forbidden_bodies = log;
+        self
+    }
+
+    pub fn keygen_concurrency(mut self, permits: usize) -> Self {
+        self.settings.keygen_concurrency = permits;
+        self
+    }
+
+    pub fn verification_cache_capacity(mut self, capacity: usize) -> Self {
+        self.settings.verification_cache_capacity = capacity;
+        self
+    }
+
+    pub fn static_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.settings.static_dir = Some(dir.into());
+        self
+    }
+
+    pub fn content_security_policy(mut self, csp: impl Into<String>) -> Self {
+        self.settings.content_security_policy = csp.into();
+        self
+    }
+
+    pub fn enable_secret_tools(mut self, enable: bool) -> Self {
+        self.settings.enable_secret_tools = enable;
+        self
+    }
+
+    pub fn capture_requests(mut self, capacity: usize) -> Self {
+        self.settings.capture_requests = Some(capacity);
+        self
+    }
+
+    pub fn admin_allowed_cidrs(mut self, cidrs: Vec<IpNet>) -> Self {
+        self.settings.admin_allowed_cidrs = Some(cidrs);
+        self
+    }
+
+    pub fn trusted_proxy(mut self, proxy: IpNet) -> Self {
+        self.settings.trusted_proxy = Some(proxy);
+        self
+    }
+
+    pub fn store_content(mut self, store: bool) -> Self {
+        self.settings.store_content = store;
+        self
+    }
+
+    pub fn metrics_interval_secs(mut self, secs: u64) -> Self {
+        self.settings.metrics_interval_secs = Some(secs);
+        self
+    }
+
+    pub fn worker_threads(mut self, threads: usize) -> Self {
+        self.settings.worker_threads = Some(threads);
+        self
+    }
+
+    pub fn build(self) -> Settings {
+        self.settings
     }
 }
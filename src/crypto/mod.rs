@@ -1,48 +1,325 @@
+use std::sync::Arc;
+
 use base58::FromBase58;
 use base58::ToBase58;
 use rand::Rng;
 use secp256k1::ecdsa;
 use secp256k1::hashes::hash160;
 use secp256k1::hashes::Hash;
-use secp256k1::All;
-use secp256k1::Keypair;
 use secp256k1::Message;
 use secp256k1::PublicKey;
 use secp256k1::Secp256k1;
 use secp256k1::SecretKey;
 use secp256k1::Signing;
+use secp256k1::Verification;
 
 use secrecy::ExposeSecret;
+use secrecy::SecretBox;
+use subtle::ConstantTimeEq;
+
+/// Keypair which holds secret key bytes in a `SecretBox`, so they are
+/// zeroized when the last copy is dropped.
+#[derive(Clone)]
+pub struct SecretKeypair {
+    public_key: PublicKey,
+    secret: Arc<SecretBox<[u8; 32]>>,
+}
+
+impl SecretKeypair {
+    pub fn public_key(&self) -> PublicKey {
+        self.public_key
+    }
+
+    /// Expose secret key only for the duration of `f`.
+    pub fn with_secret_key<T>(
+        &self,
+        f: impl FnOnce(&SecretKey) -> T,
+    ) -> Result<T, secp256k1::Error> {
+        let mut seckey =
+            SecretKey::from_byte_array(self.secret.expose_secret())?;
+        let result = f(&seckey);
+        seckey.non_secure_erase();
+        Ok(result)
+    }
+}
+
+impl PartialEq for SecretKeypair {
+    fn eq(&self, other: &Self) -> bool {
+        self.public_key.eq(&other.public_key)
+    }
+}
+
+impl Eq for SecretKeypair {}
+
+impl std::fmt::Debug for SecretKeypair {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SecretKeypair")
+            .field("public_key", &self.public_key)
+            .field("secret", &self.secret)
+            .finish()
+    }
+}
+
+/// ECDSA signature with every wire encoding the service speaks. Serde
+/// uses DER hex, as `sign-raw` accepts it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Signature(ecdsa::Signature);
+
+impl Signature {
+    pub fn to_der(&self) -> Vec<u8> {
+        self.0.serialize_der().to_vec()
+    }
+
+    pub fn to_der_hex(&self) -> String {
+        self.0.serialize_der().to_string()
+    }
+
+    /// 64 bytes of `r` followed by `s`, both big-endian
+    pub fn to_compact(&self) -> [u8; 64] {
+        self.0.serialize_compact()
+    }
+
+    pub fn from_der(der: &[u8]) -> Result<Self, secp256k1::Error> {
+        ecdsa::Signature::from_der(der).map(Self)
+    }
+
+    pub fn from_der_hex(hex: &str) -> Result<Self, secp256k1::Error> {
+        hex.parse::<ecdsa::Signature>().map(Self)
+    }
+
+    pub fn from_compact(compact: &[u8]) -> Result<Self, secp256k1::Error> {
+        ecdsa::Signature::from_compact(compact).map(Self)
+    }
+}
+
+impl From<ecdsa::Signature> for Signature {
+    fn from(value: ecdsa::Signature) -> Self {
+        Self(value)
+    }
+}
+
+impl From<Signature> for ecdsa::Signature {
+    fn from(value: Signature) -> Self {
+        value.0
+    }
+}
+
+impl serde::Serialize for Signature {
+    fn serialize<S: serde::Serializer>(
+        &self,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_der_hex())
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Signature {
+    fn deserialize<D: serde::Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Self, D::Error> {
+        let hex = <std::borrow::Cow<str>>::deserialize(deserializer)?;
+        Self::from_der_hex(&hex).map_err(serde::de::Error::custom)
+    }
+}
+
+/// sha256 of `msg`, which is what `sign` and `verify` actually sign
+pub fn digest(msg: &[u8]) -> [u8; 32] {
+    secp256k1::hashes::sha256::Hash::hash(msg).to_byte_array()
+}
+
+/// Compares byte slices in time independent of their contents, for checks
+/// of attacker-controlled input against derived values. Only the lengths
+/// are compared eagerly, they are not secret.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.ct_eq(b).into()
+}
 
 pub fn sign<C: Signing>(
     secp: &Secp256k1<C>,
     msg: &[u8],
-    seckey: &SecretKey,
+    keypair: &SecretKeypair,
 ) -> Result<ecdsa::Signature, secp256k1::Error> {
-    let msg = secp256k1::hashes::sha256::Hash::hash(msg);
-    let msg = Message::from_digest_slice(msg.as_ref())?;
-    Ok(secp.sign_ecdsa(&msg, seckey))
+    sign_digest(secp, &digest(msg), keypair)
 }
 
-pub fn verify(
-    secp: &Secp256k1<All>,
+pub fn verify<C: Verification>(
+    secp: &Secp256k1<C>,
     msg: &[u8],
     signature: &ecdsa::Signature,
     pubkey: &PublicKey,
 ) -> Result<(), secp256k1::Error> {
-    let msg = secp256k1::hashes::sha256::Hash::hash(msg);
-    let msg = Message::from_digest_slice(msg.as_ref())?;
+    verify_digest(secp, &digest(msg), signature, pubkey)
+}
+
+/// Sign 32 bytes as is, e.g. a Bitcoin sighash computed elsewhere
+pub fn sign_digest<C: Signing>(
+    secp: &Secp256k1<C>,
+    digest: &[u8; 32],
+    keypair: &SecretKeypair,
+) -> Result<ecdsa::Signature, secp256k1::Error> {
+    let msg = Message::from_digest(*digest);
+    keypair.with_secret_key(|seckey| secp.sign_ecdsa(&msg, seckey))
+}
+
+pub fn verify_digest<C: Verification>(
+    secp: &Secp256k1<C>,
+    digest: &[u8; 32],
+    signature: &ecdsa::Signature,
+    pubkey: &PublicKey,
+) -> Result<(), secp256k1::Error> {
+    let msg = Message::from_digest(*digest);
     secp.verify_ecdsa(&msg, signature, pubkey)
 }
 
-pub fn bt_addr_from_pk(pubkey: &PublicKey) -> String {
+/// Whether `signature` can be valid at all: it is low-S and a public key
+/// is recoverable from it. Such a signature is valid over some digest, so
+/// failing verification it is most likely over another digest. Malformed
+/// one isn't valid over any digest.
+pub fn is_well_formed<C: Verification>(
+    secp: &Secp256k1<C>,
+    signature: &ecdsa::Signature,
+) -> bool {
+    use secp256k1::ecdsa::{RecoverableSignature, RecoveryId};
+
+    let mut normalized = *signature;
+    normalized.normalize_s();
+    if normalized != *signature {
+        return false;
+    }
+    // Recovery fails only if `r` isn't a point of the curve, whatever
+    // the digest is
+    let msg = Message::from_digest([1; 32]);
+    let compact = signature.serialize_compact();
+    (0..4).any(|id| {
+        RecoveryId::try_from(id)
+            .and_then(|id| RecoverableSignature::from_compact(&compact, id))
+            .and_then(|signature| secp.recover_ecdsa(&msg, &signature))
+            .is_ok()
+    })
+}
+
+/// Content signed by `demo_multisig`
+pub const DEMO_CONTENT: &[u8] = b"Hello world!";
+
+/// Generate `n` keypairs and sign `DEMO_CONTENT` with each of them, see
+/// `examples/demo.rs`.
+pub fn demo_multisig(
+    n: usize,
+) -> Result<Vec<(PublicKey, ecdsa::Signature)>, secp256k1::Error> {
+    let secp = Secp256k1::new();
+    (0..n)
+        .map(|_| {
+            let keypair = new_keypair(&secp)?;
+            let signature = sign(&secp, DEMO_CONTENT, &keypair)?;
+            Ok((keypair.public_key(), signature))
+        })
+        .collect()
+}
+
+/// Verify many independent signatures, sharing the context between
+/// threads. Results are positional.
+pub fn verify_batch<C: Verification>(
+    secp: &Secp256k1<C>,
+    items: &[([u8; 32], ecdsa::Signature, PublicKey)],
+) -> Vec<Result<(), secp256k1::Error>> {
+    let threads = std::thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1);
+    let chunk_size = items.len().div_ceil(threads).max(1);
+    std::thread::scope(|s| {
+        let handles = items
+            .chunks(chunk_size)
+            .map(|chunk| {
+                s.spawn(move || {
+                    chunk
+                        .iter()
+                        .map(|(digest, sig, pk)| {
+                            verify_digest(secp, digest, sig, pk)
+                        })
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect::<Vec<_>>();
+        handles
+            .into_iter()
+            .flat_map(|h| match h.join() {
+                Ok(results) => results,
+                Err(e) => std::panic::resume_unwind(e),
+            })
+            .collect()
+    })
+}
+
+/// Network of P2PKH addresses, distinguished by the version byte.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Network {
+    #[default]
+    Bitcoin,
+    Testnet,
+}
+
+impl Network {
+    pub fn p2pkh_version(self) -> u8 {
+        match self {
+            Network::Bitcoin => 0x00,
+            Network::Testnet => 0x6f,
+        }
+    }
+
+    fn from_p2pkh_version(version: u8) -> Option<Network> {
+        match version {
+            0x00 => Some(Network::Bitcoin),
+            0x6f => Some(Network::Testnet),
+            _ => None,
+        }
+    }
+
+    fn from_wif_version(version: u8) -> Option<Network> {
+        match version {
+            0x80 => Some(Network::Bitcoin),
+            0xef => Some(Network::Testnet),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for Network {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Network::Bitcoin => f.write_str("bitcoin"),
+            Network::Testnet => f.write_str("testnet"),
+        }
+    }
+}
+
+/// P2PKH address of the 33-byte compressed public key. Every key hash
+/// in the service (participants lookup, raw signing) is computed over
+/// that form, so addresses always match stored keys.
+pub fn bt_addr_from_pk(pubkey: &PublicKey, network: Network) -> String {
+    let compressed: [u8; 33] = pubkey.serialize();
+    p2pkh_address(&compressed, network)
+}
+
+/// P2PKH address of the 65-byte uncompressed public key, as legacy
+/// wallets derive it. It differs from `bt_addr_from_pk` for the same key
+/// and isn't accepted as a participant address.
+pub fn bt_addr_from_pk_uncompressed(
+    pubkey: &PublicKey,
+    network: Network,
+) -> String {
+    let uncompressed: [u8; 65] = pubkey.serialize_uncompressed();
+    p2pkh_address(&uncompressed, network)
+}
+
+fn p2pkh_address(serialized_pubkey: &[u8], network: Network) -> String {
     use secp256k1::hashes::sha256::Hash as Sha256;
 
     // Create PKH
-    let pubkey_hash = hash160::Hash::hash(&pubkey.serialize());
+    let pubkey_hash = hash160::Hash::hash(serialized_pubkey);
 
-    // Add `0` before bytes
-    let mut with_version = vec![0x00];
+    // Add version byte before bytes
+    let mut with_version = vec![network.p2pkh_version()];
     with_version.extend_from_slice(&pubkey_hash.to_byte_array());
 
     let hash = Sha256::hash(&with_version).hash_again();
@@ -56,50 +333,532 @@ pub fn bt_addr_from_pk(pubkey: &PublicKey) -> String {
     with_version.to_base58()
 }
 
-pub fn pkh_from_bt_addr(address: &str) -> Result<hash160::Hash, &'static str> {
+#[derive(thiserror::Error, PartialEq, Eq)]
+pub enum AddressError {
+    #[error("Invalid base58 encoding")]
+    BadBase58,
+    #[error("Invalid address length: {0}")]
+    WrongLength(usize),
+    #[error("Not a P2PKH address, version: {0:#04x}")]
+    WrongVersion(u8),
+    #[error("Invalid checksum")]
+    BadChecksum,
+    #[error("Address is for {0} network")]
+    WrongNetwork(Network),
+}
+
+crate::impl_debug!(AddressError);
+
+/// Decode P2PKH address into its network and public key hash.
+fn decode_bt_addr(
+    address: &str,
+) -> Result<(Network, hash160::Hash), AddressError> {
     use secp256k1::hashes::sha256::Hash as Sha256;
 
     // Base58 Decoding
-    let decoded = address
-        .from_base58()
-        .map_err(|_| "Invalid base58 encoding")?;
+    let decoded = address.from_base58().map_err(|_| AddressError::BadBase58)?;
 
     // Length Check
-    if decoded.len() != 25 {
-        return Err("Invalid address length");
-    }
+    let decoded: [u8; 25] = decoded
+        .try_into()
+        .map_err(|d: Vec<u8>| AddressError::WrongLength(d.len()))?;
 
     // Version Byte Check
     let version = decoded[0];
-    if version != 0x00 {
-        // Check for P2PKH version
-        return Err("Not a P2PKH address");
-    }
+    let network = Network::from_p2pkh_version(version)
+        .ok_or(AddressError::WrongVersion(version))?;
 
     // Checksum Verification
     let checksum = &decoded[21..]; // Last 4 bytes
     let data_without_checksum = &decoded[..21];
-    let expected_checksum =
-        Sha256::hash(data_without_checksum).hash_again()[..4].to_vec();
+    let expected_checksum = Sha256::hash(data_without_checksum).hash_again();
 
-    if checksum != expected_checksum {
-        return Err("Invalid checksum");
+    if !constant_time_eq(checksum, &expected_checksum[..4]) {
+        return Err(AddressError::BadChecksum);
     }
 
     // Extract Public Key Hash
-    let pubkey_hash = hash160::Hash::from_byte_array(
-        decoded[1..21]
-            .try_into()
-            .map_err(|_| "failed to build hash from bytes")?,
-    );
+    let mut pubkey_hash = [0u8; 20];
+    pubkey_hash.copy_from_slice(&decoded[1..21]);
+    Ok((network, hash160::Hash::from_byte_array(pubkey_hash)))
+}
+
+/// Detect network of P2PKH address from its version byte.
+pub fn address_network(address: &str) -> Result<Network, AddressError> {
+    decode_bt_addr(address).map(|(network, _)| network)
+}
+
+/// Extract public key hash, address must belong to `network`.
+pub fn pkh_from_bt_addr(
+    address: &str,
+    network: Network,
+) -> Result<hash160::Hash, AddressError> {
+    let (found, pkh) = decode_bt_addr(address)?;
+    if found != network {
+        return Err(AddressError::WrongNetwork(found));
+    }
+    Ok(pkh)
+}
+
+/// P2PKH address of the public key of `seckey`, of its uncompressed form
+/// unless `compressed`, as wallets derive it for the WIF of that key
+pub fn address_from_secret<C: Signing>(
+    seckey: &SecretKey,
+    compressed: bool,
+    secp: &Secp256k1<C>,
+    network: Network,
+) -> String {
+    let pubkey = PublicKey::from_secret_key(secp, seckey);
+    match compressed {
+        true => bt_addr_from_pk(&pubkey, network),
+        false => bt_addr_from_pk_uncompressed(&pubkey, network),
+    }
+}
+
+/// Magic prefix, with its length, of messages signed by Bitcoin Core
+/// `signmessage`
+const SIGNED_MESSAGE_MAGIC: &[u8] = b"\x18Bitcoin Signed Message:\n";
+
+/// Double sha256 of `msg` behind the signed message magic and its
+/// compact size length, which is what `signmessage` signs.
+pub fn signed_message_digest(msg: &[u8]) -> [u8; 32] {
+    use secp256k1::hashes::sha256::Hash as Sha256;
+
+    let mut data = SIGNED_MESSAGE_MAGIC.to_vec();
+    match msg.len() {
+        len @ 0..0xfd => data.push(len as u8),
+        len @ 0xfd..=0xffff => {
+            data.push(0xfd);
+            data.extend_from_slice(&(len as u16).to_le_bytes());
+        }
+        len @ 0x10000..=0xffff_ffff => {
+            data.push(0xfe);
+            data.extend_from_slice(&(len as u32).to_le_bytes());
+        }
+        len => {
+            data.push(0xff);
+            data.extend_from_slice(&(len as u64).to_le_bytes());
+        }
+    }
+    data.extend_from_slice(msg);
+    Sha256::hash(&data).hash_again().to_byte_array()
+}
 
-    Ok(pubkey_hash)
+#[derive(thiserror::Error, PartialEq, Eq)]
+pub enum SignedMessageError {
+    #[error("Malformed base64 encoding")]
+    BadEncoding,
+    #[error("Invalid signature length: {0}")]
+    WrongLength(usize),
+    #[error("Invalid header byte: {0}")]
+    WrongHeader(u8),
+    #[error("Public key is not recoverable")]
+    Unrecoverable,
+}
+
+crate::impl_debug!(SignedMessageError);
+
+/// Address which signed `msg` with a base64 signature of Bitcoin Core
+/// `signmessage`. Header byte is 27 + recovery id, plus 4 if the address
+/// is of the compressed public key.
+pub fn recover_address_from_signed_message<C: Verification>(
+    secp: &Secp256k1<C>,
+    msg: &[u8],
+    signature: &str,
+    network: Network,
+) -> Result<String, SignedMessageError> {
+    use base64::Engine;
+    use secp256k1::ecdsa::{RecoverableSignature, RecoveryId};
+
+    let signature = base64::engine::general_purpose::STANDARD
+        .decode(signature)
+        .map_err(|_| SignedMessageError::BadEncoding)?;
+    let signature: [u8; 65] = signature
+        .try_into()
+        .map_err(|s: Vec<u8>| SignedMessageError::WrongLength(s.len()))?;
+    let header = signature[0];
+    if !(27..=34).contains(&header) {
+        return Err(SignedMessageError::WrongHeader(header));
+    }
+    let recovery_id = RecoveryId::try_from(i32::from((header - 27) & 3))
+        .map_err(|_| SignedMessageError::WrongHeader(header))?;
+    let pubkey =
+        RecoverableSignature::from_compact(&signature[1..], recovery_id)
+            .and_then(|signature| {
+                let digest = signed_message_digest(msg);
+                secp.recover_ecdsa(&Message::from_digest(digest), &signature)
+            })
+            .map_err(|_| SignedMessageError::Unrecoverable)?;
+    if header >= 31 {
+        Ok(bt_addr_from_pk(&pubkey, network))
+    } else {
+        Ok(bt_addr_from_pk_uncompressed(&pubkey, network))
+    }
+}
+
+/// Errors never include the secret itself
+#[derive(thiserror::Error, PartialEq, Eq)]
+pub enum SecretError {
+    #[error("Neither 64 hex digits nor base58 WIF")]
+    BadEncoding,
+    #[error("Invalid WIF length: {0}")]
+    WrongLength(usize),
+    #[error("Not a WIF private key, version: {0:#04x}")]
+    WrongVersion(u8),
+    #[error("Invalid checksum")]
+    BadChecksum,
+    #[error("Secret key is for {0} network")]
+    WrongNetwork(Network),
+    #[error("Secret key is out of range")]
+    OutOfRange,
+}
+
+crate::impl_debug!(SecretError);
+
+/// Parse secret key given as 64 hex digits or as WIF, which must belong
+/// to `network`, with whether its public key is compressed. Only legacy
+/// WIFs (`5...` on mainnet) are for the uncompressed one.
+pub fn parse_secret_key(
+    secret: &str,
+    network: Network,
+) -> Result<(SecretKey, bool), SecretError> {
+    use secp256k1::hashes::sha256::Hash as Sha256;
+
+    if secret.len() == 64 && secret.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return secret
+            .parse::<SecretKey>()
+            .map(|seckey| (seckey, true))
+            .map_err(|_| SecretError::OutOfRange);
+    }
+
+    // version, key, compression flag if the key is compressed, checksum
+    let decoded = secret.from_base58().map_err(|_| SecretError::BadEncoding)?;
+    if decoded.len() != 37 && !(decoded.len() == 38 && decoded[33] == 0x01) {
+        return Err(SecretError::WrongLength(decoded.len()));
+    }
+    let found = Network::from_wif_version(decoded[0])
+        .ok_or(SecretError::WrongVersion(decoded[0]))?;
+    let (payload, checksum) = decoded.split_at(decoded.len() - 4);
+    if !constant_time_eq(&Sha256::hash(payload).hash_again()[..4], checksum) {
+        return Err(SecretError::BadChecksum);
+    }
+    if found != network {
+        return Err(SecretError::WrongNetwork(found));
+    }
+    let seckey = SecretKey::from_slice(&payload[1..33])
+        .map_err(|_| SecretError::OutOfRange)?;
+    Ok((seckey, decoded.len() == 38))
 }
 
 pub fn new_keypair(
     secp: &Secp256k1<secp256k1::All>,
-) -> Result<Keypair, secp256k1::Error> {
+) -> Result<SecretKeypair, secp256k1::Error> {
     let mut rng = rand::rng();
-    let secret_key = secrecy::SecretBox::init_with(|| rng.random::<[u8; 32]>());
-    Keypair::from_seckey_slice(secp, secret_key.expose_secret())
+    let secret = SecretBox::init_with(|| rng.random::<[u8; 32]>());
+    let mut seckey = SecretKey::from_byte_array(secret.expose_secret())?;
+    let public_key = PublicKey::from_secret_key(secp, &seckey);
+    seckey.non_secure_erase();
+    Ok(SecretKeypair {
+        public_key,
+        secret: Arc::new(secret),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use secp256k1::hashes::sha256;
+
+    use super::*;
+
+    #[test]
+    fn bitcoin_core_signed_message_is_recovered(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        // `bitcoin-cli signmessage` of Bitcoin Core functional tests
+        let secp = Secp256k1::verification_only();
+        let signature = "INbVnW4e6PeRmsv2Qgu8NuopvrVjkcxob+sX8OcZG0SALhWybUjzMLPdAsXI46YZGb0KQTRii+wWIQzRpG/U+S0=";
+        let msg = b"This is just a test message";
+        assert_eq!(
+            recover_address_from_signed_message(
+                &secp,
+                msg,
+                signature,
+                Network::Testnet
+            )?,
+            "mpLQjfK79b7CCV4VMJWEWAj5Mpx8Up5zxB"
+        );
+        assert_ne!(
+            recover_address_from_signed_message(
+                &secp,
+                b"This is just a test message!",
+                signature,
+                Network::Testnet
+            )?,
+            "mpLQjfK79b7CCV4VMJWEWAj5Mpx8Up5zxB"
+        );
+        assert_eq!(
+            recover_address_from_signed_message(
+                &secp,
+                msg,
+                "not base64",
+                Network::Testnet
+            ),
+            Err(SignedMessageError::BadEncoding)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn constant_time_eq_compares_bytes() {
+        assert!(constant_time_eq(b"", b""));
+        assert!(constant_time_eq(b"checksum", b"checksum"));
+        assert!(!constant_time_eq(b"checksum", b"checksun"));
+        assert!(!constant_time_eq(b"checksum", b"check"));
+        assert!(!constant_time_eq(b"", b"\0"));
+    }
+
+    #[test]
+    fn secret_keypair_signs_without_leaking_secret(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let secp = Secp256k1::new();
+        let keypair = new_keypair(&secp)?;
+        let secret_hex = keypair
+            .with_secret_key(|seckey| seckey.display_secret().to_string())?;
+        assert!(!format!("{keypair:?}").contains(&secret_hex));
+
+        let signature = sign(&secp, b"Hello world!", &keypair)?;
+        verify(&secp, b"Hello world!", &signature, &keypair.public_key())?;
+        Ok(())
+    }
+
+    #[test]
+    fn digest_is_signed_as_is() -> Result<(), Box<dyn std::error::Error>> {
+        // RFC 6979 vector for secret key 1, the signature is also accepted
+        // by `openssl pkeyutl -verify` over the raw digest
+        let secp = Secp256k1::new();
+        let mut secret = [0u8; 32];
+        secret[31] = 1;
+        let seckey = SecretKey::from_byte_array(&secret)?;
+        let keypair = SecretKeypair {
+            public_key: PublicKey::from_secret_key(&secp, &seckey),
+            secret: Arc::new(SecretBox::new(Box::new(secret))),
+        };
+        let digest = sha256::Hash::hash(b"Satoshi Nakamoto").to_byte_array();
+        let expected = "3045022100934b1ea10a4b3c1757e2b0c017d0b6143ce3c9a7\
+                        e6a4a49860d7a6ab210ee3d802202442ce9d2b916064108014\
+                        783e923ec36b49743e2ffa1c4496f01a512aafd9e5";
+
+        let signature = sign_digest(&secp, &digest, &keypair)?;
+        assert_eq!(Signature::from(signature).to_der_hex(), expected);
+        verify_digest(&secp, &digest, &signature, &keypair.public_key())?;
+        // Plain `sign` hashes once more
+        assert_ne!(sign(&secp, &digest, &keypair)?, signature);
+        assert_eq!(sign(&secp, b"Satoshi Nakamoto", &keypair)?, signature);
+        Ok(())
+    }
+
+    #[test]
+    fn address_is_derived_from_wif_or_hex_secret(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let secp = Secp256k1::signing_only();
+        let wif = "KwDiBf89QgGbjEhKnhXJuH7LrciVrZi3qYjgd9M7rFU73sVHnoWn";
+        let (seckey, compressed) = parse_secret_key(wif, Network::Bitcoin)?;
+        assert!(compressed);
+        assert_eq!(
+            address_from_secret(&seckey, compressed, &secp, Network::Bitcoin),
+            "1BgGZ9tcN4rm9KBzDn7KprQz87SZ26SAMH"
+        );
+        // Uncompressed WIF of the same key has its own address
+        let uncompressed =
+            "5HpHagT65TZzG1PH3CSu63k8DbpvD8s5ip4nEB3kEsreAnchuDf";
+        let (parsed, compressed) =
+            parse_secret_key(uncompressed, Network::Bitcoin)?;
+        assert_eq!((parsed, compressed), (seckey, false));
+        assert_eq!(
+            address_from_secret(&parsed, compressed, &secp, Network::Bitcoin),
+            "1EHNa6Q4Jz2uvNExL497mE43ikXhwF6kZm"
+        );
+        // Hex has no compression flag, it's taken as compressed
+        let hex = format!("{:064x}", 1);
+        assert_eq!(parse_secret_key(&hex, Network::Bitcoin)?, (seckey, true));
+
+        assert_eq!(
+            parse_secret_key(wif, Network::Testnet),
+            Err(SecretError::WrongNetwork(Network::Bitcoin))
+        );
+        let mut corrupted = wif.to_string();
+        corrupted.replace_range(10..11, "R");
+        assert_eq!(
+            parse_secret_key(&corrupted, Network::Bitcoin),
+            Err(SecretError::BadChecksum)
+        );
+        assert_eq!(
+            parse_secret_key(&"0".repeat(64), Network::Bitcoin),
+            Err(SecretError::OutOfRange)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn single_context_signs_and_verifies(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        use crate::domain::multisig::Multisig;
+
+        let secp: Secp256k1<secp256k1::All> = Secp256k1::new();
+        let keypair = new_keypair(&secp)?;
+        let signature = sign(&secp, DEMO_CONTENT, &keypair)?;
+        verify(&secp, DEMO_CONTENT, &signature, &keypair.public_key())?;
+        let item = (digest(DEMO_CONTENT), signature, keypair.public_key());
+        assert_eq!(verify_batch(&secp, &[item]), [Ok(())]);
+
+        let mut multisig = Multisig::new(vec![keypair.public_key()]);
+        multisig.sign(&secp, DEMO_CONTENT, &keypair)?;
+        multisig.verify(&secp, DEMO_CONTENT, 1)?;
+        Ok(())
+    }
+
+    #[test]
+    fn demo_multisig_signs_with_every_keypair(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let secp = Secp256k1::verification_only();
+        let signed = demo_multisig(3)?;
+        assert_eq!(signed.len(), 3);
+        for (i, (pubkey, signature)) in signed.iter().enumerate() {
+            verify(&secp, DEMO_CONTENT, signature, pubkey)?;
+            assert!(signed[..i].iter().all(|(pk, _)| pk.ne(pubkey)));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn verify_batch_reports_per_item_results(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let secp = Secp256k1::new();
+        let keypair = new_keypair(&secp)?;
+        let other_keypair = new_keypair(&secp)?;
+        let signature = sign(&secp, b"Hello world!", &keypair)?;
+        let items = vec![
+            (digest(b"Hello world!"), signature, keypair.public_key()),
+            (digest(b"other msg"), signature, keypair.public_key()),
+            (
+                digest(b"Hello world!"),
+                signature,
+                other_keypair.public_key(),
+            ),
+            (digest(b"Hello world!"), signature, keypair.public_key()),
+        ];
+        assert_eq!(
+            verify_batch(&secp, &items),
+            vec![
+                Ok(()),
+                Err(secp256k1::Error::IncorrectSignature),
+                Err(secp256k1::Error::IncorrectSignature),
+                Ok(()),
+            ]
+        );
+        assert!(verify_batch(&secp, &[]).is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn malformed_addresses_yield_typed_errors(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let secp = Secp256k1::new();
+        let address = bt_addr_from_pk(
+            &new_keypair(&secp)?.public_key(),
+            Network::Bitcoin,
+        );
+        assert!(pkh_from_bt_addr(&address, Network::Bitcoin).is_ok());
+
+        assert_eq!(
+            pkh_from_bt_addr("0OIl", Network::Bitcoin),
+            Err(AddressError::BadBase58)
+        );
+        assert_eq!(
+            pkh_from_bt_addr(&[1u8, 2, 3].to_base58(), Network::Bitcoin),
+            Err(AddressError::WrongLength(3))
+        );
+
+        let mut decoded = address.from_base58().map_err(|_| "bad base58")?;
+        decoded[24] ^= 0xff;
+        assert_eq!(
+            pkh_from_bt_addr(&decoded.to_base58(), Network::Bitcoin),
+            Err(AddressError::BadChecksum)
+        );
+
+        // P2SH version with valid checksum
+        let mut p2sh = vec![0x05];
+        p2sh.extend_from_slice(&decoded[1..21]);
+        let checksum = sha256::Hash::hash(&p2sh).hash_again();
+        p2sh.extend_from_slice(&checksum[..4]);
+        assert_eq!(
+            pkh_from_bt_addr(&p2sh.to_base58(), Network::Bitcoin),
+            Err(AddressError::WrongVersion(0x05))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn compressed_and_uncompressed_addresses_differ(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let secp = Secp256k1::new();
+        let pubkey = new_keypair(&secp)?.public_key();
+        let compressed = bt_addr_from_pk(&pubkey, Network::Bitcoin);
+        let uncompressed =
+            bt_addr_from_pk_uncompressed(&pubkey, Network::Bitcoin);
+        assert_ne!(compressed, uncompressed);
+        assert_eq!(
+            pkh_from_bt_addr(&compressed, Network::Bitcoin)?,
+            hash160::Hash::hash(&pubkey.serialize())
+        );
+        assert_eq!(
+            pkh_from_bt_addr(&uncompressed, Network::Bitcoin)?,
+            hash160::Hash::hash(&pubkey.serialize_uncompressed())
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn address_network_is_detected() -> Result<(), Box<dyn std::error::Error>> {
+        let secp = Secp256k1::new();
+        let pubkey = new_keypair(&secp)?.public_key();
+        let mainnet = bt_addr_from_pk(&pubkey, Network::Bitcoin);
+        let testnet = bt_addr_from_pk(&pubkey, Network::Testnet);
+
+        assert_eq!(address_network(&mainnet), Ok(Network::Bitcoin));
+        assert_eq!(address_network(&testnet), Ok(Network::Testnet));
+        assert_eq!(
+            pkh_from_bt_addr(&testnet, Network::Testnet),
+            pkh_from_bt_addr(&mainnet, Network::Bitcoin)
+        );
+        assert_eq!(
+            pkh_from_bt_addr(&testnet, Network::Bitcoin),
+            Err(AddressError::WrongNetwork(Network::Testnet))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn signature_round_trips_through_every_encoding(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let secp = Secp256k1::new();
+        let keypair = new_keypair(&secp)?;
+        let signature =
+            Signature::from(sign(&secp, b"Hello world!", &keypair)?);
+
+        let der_hex = signature.to_der_hex();
+        let compact = signature.to_compact();
+        assert_eq!(Signature::from_der_hex(&der_hex)?, signature);
+        assert_eq!(Signature::from_der(&signature.to_der())?, signature);
+        assert_eq!(Signature::from_compact(&compact)?, signature);
+        // Same signature in different encodings
+        assert_eq!(Signature::from_compact(&compact)?.to_der_hex(), der_hex);
+        assert_eq!(Signature::from_der_hex(&der_hex)?.to_compact(), compact);
+
+        let json = serde_json::to_string(&signature)?;
+        assert_eq!(json, format!("\"{der_hex}\""));
+        assert_eq!(serde_json::from_str::<Signature>(&json)?, signature);
+        assert!(serde_json::from_str::<Signature>("\"zz\"").is_err());
+        assert!(Signature::from_compact(&compact[..63]).is_err());
+        Ok(())
+    }
 }
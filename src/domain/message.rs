@@ -1,39 +1,399 @@
-use secp256k1::PublicKey;
+use std::borrow::Cow;
 
-use super::multisig::Multisig;
+use secp256k1::{PublicKey, Secp256k1, Verification};
+use time::OffsetDateTime;
+
+use crate::crypto;
+
+use secp256k1::hashes::hash160;
+
+use super::multisig::{self, Multisig};
+
+/// Result of re-verification of stored message signatures
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Integrity {
+    /// All collected signatures are valid and there are enough of them
+    Complete,
+    /// All collected signatures are valid, more are required
+    Pending,
+    /// Some collected signature doesn't match the content
+    Invalid,
+}
+
+/// How many signatures are required when it isn't specified explicitly
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ThresholdPolicy {
+    /// Every participant (n-of-n)
+    #[default]
+    All,
+    /// Half of participants rounded up, plus one: `ceil(n/2) + 1`. For
+    /// odd `n` that is one more than a bare majority, e.g. 4 of 5.
+    Majority,
+    /// Any single participant (1-of-n)
+    Any,
+}
+
+impl ThresholdPolicy {
+    pub fn required_count(&self, participants: usize) -> usize {
+        match self {
+            ThresholdPolicy::All => participants,
+            ThresholdPolicy::Majority => participants.div_ceil(2) + 1,
+            ThresholdPolicy::Any => 1,
+        }
+        .min(participants)
+    }
+}
+
+/// Leads the preimage of messages with nonce. Content starting with it is
+/// rejected, so plain content never hashes like some nonce preimage.
+pub const NONCE_PREIMAGE_TAG: &[u8] = b"multisig_ecdsa/nonce\0";
+
+/// Mixed into by-hash lookup keys of digest messages
+const DIGEST_LOOKUP_TAG: &[u8] = b"multisig_ecdsa/digest\0";
+
+/// By-hash index key of a message with `digest`. Digest message with
+/// content `sha256(x)` has the digest of plain `x`, so its key is tagged
+/// to keep the two apart.
+pub fn lookup_key(digest: [u8; 32], content_is_digest: bool) -> [u8; 32] {
+    match content_is_digest {
+        false => digest,
+        true => crypto::digest(&[DIGEST_LOOKUP_TAG, &digest].concat()),
+    }
+}
+
+/// Printable form of message content
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ContentDisplay {
+    /// Content is valid UTF-8
+    Text(String),
+    /// Binary content, standard base64 with padding
+    Base64(String),
+}
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Message {
     pub id: uuid::Uuid,
+    /// Empty if only the digest is stored
     pub content: Vec<u8>,
+    /// Kept instead of `content` when the server doesn't retain plaintext
+    pub stored_digest: Option<[u8; 32]>,
+    /// Makes the signed preimage unique for repeated content
+    pub nonce: Option<u64>,
+    /// Content is a 32 byte digest which is signed as is, not hashed
+    pub content_is_digest: bool,
     /// Signatures with public keys
     pub signature: Multisig,
     /// Min required signatures count for approve message
     pub count_required: usize,
+    /// Bumped by storage on every update
+    pub version: u64,
+    /// Start of the signing round, `restart` resets it
+    pub created_at: OffsetDateTime,
+    /// Set by storage on every update
+    pub updated_at: OffsetDateTime,
+    /// Set once the message is complete and frozen, storage rejects
+    /// updates of finalized messages
+    pub finalized_at: Option<OffsetDateTime>,
+    /// Free-form categories, e.g. `treasury`, set on creation
+    pub tags: Vec<String>,
+    /// Signatures aren't accepted after it, the message is still kept and
+    /// verified with what it has
+    pub sign_deadline: Option<OffsetDateTime>,
 }
 
 impl Message {
+    /// Repeated participants are counted once, required count above them
+    /// is clamped to their number.
     pub fn new(
-        content: &[u8],
+        content: impl Into<Vec<u8>>,
         pubkeys: Vec<PublicKey>,
         required_signature_count: Option<usize>,
+    ) -> Result<Message, multisig::Error> {
+        if pubkeys.is_empty() {
+            return Err(multisig::Error::NoParticipants);
+        }
+        if required_signature_count == Some(0) {
+            return Err(multisig::Error::ZeroThreshold);
+        }
+        let content = content.into();
+        if content.starts_with(NONCE_PREIMAGE_TAG) {
+            return Err(multisig::Error::ReservedContent);
+        }
+        let signature = Multisig::new(pubkeys);
+        let now = OffsetDateTime::now_utc();
+        Ok(Message {
+            content,
+            stored_digest: None,
+            nonce: None,
+            content_is_digest: false,
+            count_required: required_signature_count
+                .unwrap_or(signature.total_count())
+                .min(signature.total_count()),
+            signature,
+            id: uuid::Uuid::new_v4(),
+            version: 0,
+            created_at: now,
+            updated_at: now,
+            finalized_at: None,
+            tags: Vec::new(),
+            sign_deadline: None,
+        })
+    }
+
+    /// Message which any key may sign, `required_signature_count`
+    /// distinct signers approve it.
+    pub fn new_open(
+        content: impl Into<Vec<u8>>,
+        required_signature_count: usize,
+    ) -> Result<Message, multisig::Error> {
+        if required_signature_count == 0 {
+            return Err(multisig::Error::ZeroThreshold);
+        }
+        let content = content.into();
+        if content.starts_with(NONCE_PREIMAGE_TAG) {
+            return Err(multisig::Error::ReservedContent);
+        }
+        let now = OffsetDateTime::now_utc();
+        Ok(Message {
+            content,
+            stored_digest: None,
+            nonce: None,
+            content_is_digest: false,
+            count_required: required_signature_count,
+            signature: Multisig::new_open(),
+            id: uuid::Uuid::new_v4(),
+            version: 0,
+            created_at: now,
+            updated_at: now,
+            finalized_at: None,
+            tags: Vec::new(),
+            sign_deadline: None,
+        })
+    }
+
+    pub fn with_nonce(mut self, nonce: Option<u64>) -> Message {
+        self.nonce = nonce;
+        self
+    }
+
+    pub fn with_sign_deadline(
+        mut self,
+        sign_deadline: Option<OffsetDateTime>,
     ) -> Message {
+        self.sign_deadline = sign_deadline;
+        self
+    }
+
+    /// `DeadlinePassed` if signatures aren't accepted at `now`
+    pub fn check_sign_deadline(
+        &self,
+        now: OffsetDateTime,
+    ) -> Result<(), multisig::Error> {
+        match self.sign_deadline {
+            Some(deadline) if now > deadline => {
+                Err(multisig::Error::DeadlinePassed)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Repeated tags are kept once
+    pub fn with_tags(mut self, mut tags: Vec<String>) -> Message {
+        let mut seen = std::collections::HashSet::new();
+        tags.retain(|tag| seen.insert(tag.clone()));
+        self.tags = tags;
+        self
+    }
+
+    /// Sign content as is, it must be a 32 byte digest, e.g. a Bitcoin
+    /// sighash. Nonce can't be combined with it. Signatures are over the
+    /// same bytes as of plain content hashing to the digest, so they are
+    /// valid for both.
+    pub fn into_digest(mut self) -> Result<Message, multisig::Error> {
+        if self.content.len() != 32 || self.nonce.is_some() {
+            return Err(multisig::Error::InvalidDigest);
+        }
+        self.content_is_digest = true;
+        Ok(self)
+    }
+
+    /// Drop content and keep only its digest, signing and verification
+    /// work over the digest as before.
+    pub fn without_content(mut self) -> Message {
+        self.stored_digest = Some(self.digest());
+        self.content = Vec::new();
+        self
+    }
+
+    pub fn has_content(&self) -> bool {
+        self.stored_digest.is_none()
+    }
+
+    /// What signatures are over: sha256 of the preimage, or content
+    /// itself if it is a digest
+    pub fn digest(&self) -> [u8; 32] {
+        self.stored_digest
+            .unwrap_or_else(|| self.digest_of(&self.content))
+    }
+
+    /// Key of the message in the by-hash index, see `lookup_key`
+    pub fn lookup_key(&self) -> [u8; 32] {
+        lookup_key(self.digest(), self.content_is_digest)
+    }
+
+    /// Digest `content` would have with nonce and mode of this message,
+    /// to check re-supplied content of messages without one.
+    pub fn digest_of(&self, content: &[u8]) -> [u8; 32] {
+        match <[u8; 32]>::try_from(content) {
+            Ok(digest) if self.content_is_digest => digest,
+            _ => crypto::digest(&self.preimage_of(content)),
+        }
+    }
+
+    /// Bytes which are hashed and signed: content as is without nonce,
+    /// otherwise
+    ///
+    /// ```text
+    /// preimage := NONCE_PREIMAGE_TAG, content length: u64 BE, content,
+    ///             nonce: u64 BE
+    /// ```
+    pub fn preimage(&self) -> Cow<'_, [u8]> {
+        self.preimage_of(&self.content)
+    }
+
+    fn preimage_of<'a>(&self, content: &'a [u8]) -> Cow<'a, [u8]> {
+        match self.nonce {
+            None => Cow::Borrowed(content),
+            Some(nonce) => {
+                let mut preimage = Vec::with_capacity(
+                    NONCE_PREIMAGE_TAG.len() + 16 + content.len(),
+                );
+                preimage.extend_from_slice(NONCE_PREIMAGE_TAG);
+                preimage
+                    .extend_from_slice(&(content.len() as u64).to_be_bytes());
+                preimage.extend_from_slice(content);
+                preimage.extend_from_slice(&nonce.to_be_bytes());
+                Cow::Owned(preimage)
+            }
+        }
+    }
+
+    pub fn content_display(&self) -> ContentDisplay {
+        use base64::Engine;
+
+        match std::str::from_utf8(&self.content) {
+            Ok(text) => ContentDisplay::Text(text.to_string()),
+            Err(_) => ContentDisplay::Base64(
+                base64::engine::general_purpose::STANDARD.encode(&self.content),
+            ),
+        }
+    }
+
+    /// Freeze message which has enough valid signatures
+    pub fn finalize<C: Verification>(
+        &mut self,
+        secp: &Secp256k1<C>,
+        now: OffsetDateTime,
+    ) -> Result<(), multisig::Error> {
+        if self.finalized_at.is_some() {
+            return Err(multisig::Error::Finalized);
+        }
+        self.signature.verify_digest(
+            secp,
+            &self.digest(),
+            self.count_required,
+        )?;
+        self.finalized_at = Some(now);
+        Ok(())
+    }
+
+    /// Waits for signatures: not finalized and below the threshold
+    pub fn is_pending(&self) -> bool {
+        self.finalized_at.is_none()
+            && self.signature.signed_count() < self.count_required
+    }
+
+    /// New signing round over the same content, participants, threshold
+    /// and tags, with a fresh id
+    pub fn restart(&self) -> Message {
+        let now = OffsetDateTime::now_utc();
         Message {
-            content: content.to_vec(),
-            count_required: required_signature_count
-                .unwrap_or(pubkeys.len())
-                .max(pubkeys.len()),
-            signature: Multisig::new(pubkeys),
             id: uuid::Uuid::new_v4(),
+            content: self.content.clone(),
+            stored_digest: self.stored_digest,
+            nonce: self.nonce,
+            content_is_digest: self.content_is_digest,
+            signature: self.signature.without_signatures(),
+            count_required: self.count_required,
+            version: 0,
+            created_at: now,
+            updated_at: now,
+            finalized_at: None,
+            tags: self.tags.clone(),
+            // Deadline belongs to the previous round
+            sign_deadline: None,
         }
     }
+
+    /// Change participants set before signing started, required count is
+    /// capped by the new participants count.
+    pub fn amend_participants(
+        &mut self,
+        add: Vec<PublicKey>,
+        remove: &[hash160::Hash],
+    ) -> Result<(), multisig::Error> {
+        let mut signature = self.signature.clone();
+        for pubkey in add {
+            signature.add_pubkey(pubkey)?;
+        }
+        for pubkey_hash in remove {
+            signature.remove_pubkey(pubkey_hash)?;
+        }
+        self.count_required = self.count_required.min(signature.total_count());
+        self.signature = signature;
+        Ok(())
+    }
+}
+
+/// Re-verify every collected signature of `msgs` in one batch.
+/// Results are positional.
+pub fn check_integrity<C: Verification>(
+    secp: &Secp256k1<C>,
+    msgs: &[Message],
+) -> Vec<Integrity> {
+    let items = msgs
+        .iter()
+        .flat_map(|m| {
+            let digest = m.digest();
+            m.signature
+                .signatures()
+                .map(move |(pk, sig)| (digest, *sig, *pk))
+        })
+        .collect::<Vec<_>>();
+    let results = crypto::verify_batch(secp, &items);
+    let mut offset = 0;
+    msgs.iter()
+        .map(|m| {
+            let sig_count = m.signature.signed_count();
+            let all_valid = results[offset..offset + sig_count]
+                .iter()
+                .all(Result::is_ok);
+            offset += sig_count;
+            match (all_valid, sig_count >= m.count_required) {
+                (false, _) => Integrity::Invalid,
+                (true, true) => Integrity::Complete,
+                (true, false) => Integrity::Pending,
+            }
+        })
+        .collect()
 }
 
 #[cfg(test)]
 mod tests {
     use crate::{crypto, domain::multisig};
 
-    use super::Message;
+    use super::{ContentDisplay, Message};
 
     #[test]
     fn signature_with_correct_keys_works(
@@ -41,7 +401,7 @@ mod tests {
         let secp = secp256k1::Secp256k1::new();
         let keypairs = generate_keypairs(&secp, 3)?;
         let mut msg =
-            Message::new(b"Hello world!", extract_pubkeys(&keypairs), None);
+            Message::new(b"Hello world!", extract_pubkeys(&keypairs), None)?;
         for keypair in keypairs {
             assert!(msg.signature.sign(&secp, &msg.content, &keypair).is_ok());
         }
@@ -58,7 +418,7 @@ mod tests {
         let secp = secp256k1::Secp256k1::new();
         let keypairs = generate_keypairs(&secp, 3)?;
         let mut msg =
-            Message::new(b"Hello world!", extract_pubkeys(&keypairs), None);
+            Message::new(b"Hello world!", extract_pubkeys(&keypairs), None)?;
         for keypair in keypairs.iter().take(2) {
             assert!(msg.signature.sign(&secp, &msg.content, keypair).is_ok());
         }
@@ -80,7 +440,7 @@ mod tests {
         let secp = secp256k1::Secp256k1::new();
         let keypairs = generate_keypairs(&secp, 3)?;
         let mut msg =
-            Message::new(b"Hello world!", extract_pubkeys(&keypairs), None);
+            Message::new(b"Hello world!", extract_pubkeys(&keypairs), None)?;
         for keypair in keypairs.iter().take(2) {
             assert!(msg.signature.sign(&secp, &msg.content, keypair).is_ok());
         }
@@ -98,16 +458,75 @@ mod tests {
         let secp = secp256k1::Secp256k1::new();
         let keypairs = generate_keypairs(&secp, 3)?;
         let mut msg =
-            Message::new(b"Hello world!", extract_pubkeys(&keypairs), None);
+            Message::new(b"Hello world!", extract_pubkeys(&keypairs), None)?;
         for keypair in keypairs.iter().take(3) {
             assert!(msg.signature.sign(&secp, b"other msg", keypair).is_ok());
         }
         assert_eq!(
             msg.signature
                 .verify(&secp, &msg.content, msg.count_required),
-            Err(multisig::Error::Secp256k1(
-                secp256k1::Error::IncorrectSignature
-            )),
+            Err(multisig::Error::ContentMismatch),
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn edited_content_is_content_mismatch(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let secp = secp256k1::Secp256k1::new();
+        let keypairs = generate_keypairs(&secp, 3)?;
+        let mut msg =
+            Message::new(b"Hello world!", extract_pubkeys(&keypairs), None)?;
+        for keypair in &keypairs {
+            msg.signature.sign(&secp, &msg.content, keypair)?;
+        }
+        msg.content = b"Hello world?".to_vec();
+        assert_eq!(
+            msg.signature
+                .verify(&secp, &msg.content, msg.count_required),
+            Err(multisig::Error::ContentMismatch),
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn garbage_signature_is_invalid_signature(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let secp = secp256k1::Secp256k1::new();
+        let keypairs = generate_keypairs(&secp, 3)?;
+        let mut msg =
+            Message::new(b"Hello world!", extract_pubkeys(&keypairs), None)?;
+        for keypair in &keypairs[..2] {
+            msg.signature.sign(&secp, &msg.content, keypair)?;
+        }
+        // `r` of `0x0303..03` isn't a point of the curve, so the signature
+        // is valid over no digest at all
+        let garbage = secp256k1::ecdsa::Signature::from_compact(&[3; 64])?;
+        msg.signature
+            .add_signature(&keypairs[2].public_key(), garbage)?;
+        assert_eq!(
+            msg.signature
+                .verify(&secp, &msg.content, msg.count_required),
+            Err(multisig::Error::InvalidSignature(keypairs[2].public_key())),
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn malformed_signature_is_reported_before_content_mismatch(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let secp = secp256k1::Secp256k1::new();
+        let keypairs = generate_keypairs(&secp, 2)?;
+        let mut msg =
+            Message::new(b"Hello world!", extract_pubkeys(&keypairs), None)?;
+        msg.signature.sign(&secp, b"other msg", &keypairs[0])?;
+        let garbage = secp256k1::ecdsa::Signature::from_compact(&[3; 64])?;
+        msg.signature
+            .add_signature(&keypairs[1].public_key(), garbage)?;
+        assert_eq!(
+            msg.signature
+                .verify(&secp, &msg.content, msg.count_required),
+            Err(multisig::Error::InvalidSignature(keypairs[1].public_key())),
         );
         Ok(())
     }
@@ -120,7 +539,7 @@ mod tests {
         let pubkeys = extract_pubkeys(&keypairs);
         let content = b"Hello world!";
         let required_count = 2;
-        let mut msg = Message::new(content, pubkeys, Some(required_count));
+        let mut msg = Message::new(content, pubkeys, Some(required_count))?;
 
         for keypair in &keypairs {
             msg.signature.sign(&secp, content, keypair)?;
@@ -137,7 +556,7 @@ mod tests {
         let keypairs = generate_keypairs(&secp, 3)?;
         let pubkeys = extract_pubkeys(&keypairs);
         let content = b"";
-        let mut msg = Message::new(content, pubkeys, None);
+        let mut msg = Message::new(content, pubkeys, None)?;
 
         for keypair in &keypairs {
             msg.signature.sign(&secp, content, keypair)?;
@@ -148,10 +567,672 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn progress_reflects_collected_signatures(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let secp = secp256k1::Secp256k1::new();
+        let keypairs = generate_keypairs(&secp, 4)?;
+        let mut msg =
+            Message::new(b"Hello world!", extract_pubkeys(&keypairs), Some(3))?;
+        let count_required = 3;
+        assert_eq!(msg.signature.progress(count_required), 0.0);
+        for keypair in keypairs.iter().take(2) {
+            msg.signature.sign(&secp, &msg.content, keypair)?;
+        }
+        assert_eq!(msg.signature.progress(count_required), 2.0 / 3.0);
+        for keypair in keypairs.iter().skip(2) {
+            msg.signature.sign(&secp, &msg.content, keypair)?;
+        }
+        assert_eq!(msg.signature.progress(count_required), 1.0);
+        Ok(())
+    }
+
+    #[test]
+    fn raw_signature_from_participant_works(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        use secp256k1::hashes::{hash160, Hash};
+
+        let secp = secp256k1::Secp256k1::new();
+        let keypairs = generate_keypairs(&secp, 2)?;
+        let mut msg =
+            Message::new(b"Hello world!", extract_pubkeys(&keypairs), None)?;
+        for keypair in &keypairs {
+            // Signed "offline", service never sees the secret key
+            let signature = crypto::sign(&secp, &msg.content, keypair)?;
+            let pkh = hash160::Hash::hash(&keypair.public_key().serialize());
+            msg.signature
+                .sign_raw(&secp, &msg.content, &pkh, signature)?;
+        }
+        assert!(msg
+            .signature
+            .verify(&secp, &msg.content, msg.count_required)
+            .is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn raw_signature_over_other_content_fail(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        use secp256k1::hashes::{hash160, Hash};
+
+        let secp = secp256k1::Secp256k1::new();
+        let keypairs = generate_keypairs(&secp, 2)?;
+        let mut msg =
+            Message::new(b"Hello world!", extract_pubkeys(&keypairs), None)?;
+        let signature = crypto::sign(&secp, b"other msg", &keypairs[0])?;
+        let pkh = hash160::Hash::hash(&keypairs[0].public_key().serialize());
+        assert_eq!(
+            msg.signature.sign_raw(&secp, &msg.content, &pkh, signature),
+            Err(multisig::Error::Secp256k1(
+                secp256k1::Error::IncorrectSignature
+            )),
+        );
+        assert_eq!(msg.signature.progress(msg.count_required), 0.0);
+        Ok(())
+    }
+
+    #[test]
+    fn integrity_check_flags_corrupted_signature(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        use super::{check_integrity, Integrity};
+
+        let secp = secp256k1::Secp256k1::new();
+        let keypairs = generate_keypairs(&secp, 2)?;
+        let pubkeys = extract_pubkeys(&keypairs);
+        let mut complete =
+            Message::new(b"Hello world!", pubkeys.clone(), None)?;
+        let mut pending = Message::new(b"Hello world!", pubkeys.clone(), None)?;
+        let mut corrupted = Message::new(b"Hello world!", pubkeys, None)?;
+        for keypair in &keypairs {
+            complete.signature.sign(&secp, &complete.content, keypair)?;
+            corrupted
+                .signature
+                .sign(&secp, &corrupted.content, keypair)?;
+        }
+        pending
+            .signature
+            .sign(&secp, &pending.content, &keypairs[0])?;
+        // Content changed after signing
+        corrupted.content = b"Goodbye world!".to_vec();
+
+        assert_eq!(
+            check_integrity(&secp, &[complete, corrupted, pending]),
+            vec![Integrity::Complete, Integrity::Invalid, Integrity::Pending]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn adding_participant_before_signing_works(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let secp = secp256k1::Secp256k1::new();
+        let keypairs = generate_keypairs(&secp, 3)?;
+        let pubkeys = extract_pubkeys(&keypairs);
+        let mut msg =
+            Message::new(b"Hello world!", pubkeys[..2].to_vec(), None)?;
+        msg.amend_participants(vec![pubkeys[2]], &[])?;
+        assert_eq!(msg.signature.total_count(), 3);
+        assert!(msg.signature.is_participant(&pubkeys[2]));
+        assert_eq!(
+            msg.amend_participants(vec![pubkeys[2]], &[]),
+            Err(multisig::Error::PublicKeyExists)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn removing_participant_after_signing_fail(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        use secp256k1::hashes::{hash160, Hash};
+
+        let secp = secp256k1::Secp256k1::new();
+        let keypairs = generate_keypairs(&secp, 3)?;
+        let pubkeys = extract_pubkeys(&keypairs);
+        let mut msg = Message::new(b"Hello world!", pubkeys.clone(), None)?;
+        msg.signature.sign(&secp, &msg.content, &keypairs[0])?;
+
+        let signed = hash160::Hash::hash(&pubkeys[0].serialize());
+        assert_eq!(
+            msg.amend_participants(vec![], &[signed]),
+            Err(multisig::Error::AlreadySigned)
+        );
+        let unsigned = hash160::Hash::hash(&pubkeys[1].serialize());
+        assert_eq!(
+            msg.amend_participants(vec![], &[unsigned]),
+            Err(multisig::Error::SigningStarted)
+        );
+        assert_eq!(msg.signature.total_count(), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn removing_participant_caps_required_count(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        use secp256k1::hashes::{hash160, Hash};
+
+        let secp = secp256k1::Secp256k1::new();
+        let keypairs = generate_keypairs(&secp, 3)?;
+        let pubkeys = extract_pubkeys(&keypairs);
+        let mut msg = Message::new(b"Hello world!", pubkeys.clone(), None)?;
+        let removed = hash160::Hash::hash(&pubkeys[2].serialize());
+        msg.amend_participants(vec![], &[removed])?;
+        assert_eq!(msg.signature.total_count(), 2);
+        assert_eq!(msg.count_required, 2);
+        Ok(())
+    }
+
+    #[test]
+    fn threshold_policies_for_three_participants() {
+        use super::ThresholdPolicy;
+
+        assert_eq!(ThresholdPolicy::All.required_count(3), 3);
+        assert_eq!(ThresholdPolicy::Majority.required_count(3), 3);
+        assert_eq!(ThresholdPolicy::Majority.required_count(4), 3);
+        assert_eq!(ThresholdPolicy::Majority.required_count(5), 4);
+        // Capped by participants
+        assert_eq!(ThresholdPolicy::Majority.required_count(1), 1);
+        assert_eq!(ThresholdPolicy::Any.required_count(3), 1);
+        assert_eq!(ThresholdPolicy::Any.required_count(0), 0);
+    }
+
+    #[test]
+    fn nonce_makes_signatures_of_same_content_distinct(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let secp = secp256k1::Secp256k1::new();
+        let keypairs = generate_keypairs(&secp, 1)?;
+        let mut first =
+            Message::new(b"Approve", extract_pubkeys(&keypairs), None)?
+                .with_nonce(Some(1));
+        let mut second =
+            Message::new(b"Approve", extract_pubkeys(&keypairs), None)?
+                .with_nonce(Some(2));
+        assert_ne!(first.preimage(), second.preimage());
+
+        let (first_preimage, second_preimage) = (
+            first.preimage().into_owned(),
+            second.preimage().into_owned(),
+        );
+        first.signature.sign(&secp, &first_preimage, &keypairs[0])?;
+        second
+            .signature
+            .sign(&secp, &second_preimage, &keypairs[0])?;
+        let first_sig = first.signature.signatures().next().map(|(_, s)| *s);
+        let second_sig = second.signature.signatures().next().map(|(_, s)| *s);
+        assert_ne!(first_sig, second_sig);
+
+        // Signature doesn't carry over to the other nonce
+        assert!(first
+            .signature
+            .verify(&secp, &second_preimage, first.count_required)
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn nonce_preimage_is_not_content_with_nonce_appended(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let secp = secp256k1::Secp256k1::new();
+        let pubkeys = extract_pubkeys(&generate_keypairs(&secp, 1)?);
+        let with_nonce = Message::new(b"Approve", pubkeys.clone(), None)?
+            .with_nonce(Some(1));
+        let appended =
+            Message::new(b"Approve\0\0\0\0\0\0\0\x01", pubkeys.clone(), None)?;
+        assert_ne!(with_nonce.digest(), appended.digest());
+
+        // Nor can plain content spell out a nonce preimage
+        assert_eq!(
+            Message::new(with_nonce.preimage(), pubkeys, None),
+            Err(multisig::Error::ReservedContent)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn message_without_content_is_signed_over_its_digest(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let secp = secp256k1::Secp256k1::new();
+        let keypairs = generate_keypairs(&secp, 2)?;
+        let full = Message::new(b"Approve", extract_pubkeys(&keypairs), None)?
+            .with_nonce(Some(7));
+        let digest = full.digest();
+        let mut msg = full.without_content();
+        assert!(!msg.has_content());
+        assert!(msg.content.is_empty());
+        assert_eq!(msg.digest(), digest);
+        assert_eq!(msg.restart().digest(), digest);
+
+        // Re-supplied content is checked with the nonce of the message
+        assert_eq!(msg.digest_of(b"Approve"), digest);
+        assert_ne!(msg.digest_of(b"Decline"), digest);
+
+        for keypair in &keypairs {
+            let signature = crypto::sign_digest(&secp, &digest, keypair)?;
+            msg.signature
+                .add_signature(&keypair.public_key(), signature)?;
+        }
+        msg.signature.verify_digest(
+            &secp,
+            &msg.digest(),
+            msg.count_required,
+        )?;
+        Ok(())
+    }
+
+    #[test]
+    fn utf8_content_displayed_as_text() -> Result<(), Box<dyn std::error::Error>>
+    {
+        let secp = secp256k1::Secp256k1::new();
+        let pubkeys = extract_pubkeys(&generate_keypairs(&secp, 1)?);
+        let msg = Message::new("Привет, мир!", pubkeys, None)?;
+        assert_eq!(
+            msg.content_display(),
+            ContentDisplay::Text("Привет, мир!".to_string())
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn binary_content_displayed_as_base64(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let secp = secp256k1::Secp256k1::new();
+        let pubkeys = extract_pubkeys(&generate_keypairs(&secp, 1)?);
+        let msg = Message::new(vec![0xff, 0x00, 0xfe], pubkeys, None)?;
+        assert_eq!(
+            msg.content_display(),
+            ContentDisplay::Base64("/wD+".to_string())
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn message_without_participants_rejected() {
+        assert_eq!(
+            Message::new(b"Hello world!", vec![], None),
+            Err(multisig::Error::NoParticipants)
+        );
+    }
+
+    #[test]
+    fn duplicate_participants_deduplicated(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let secp = secp256k1::Secp256k1::new();
+        let pubkeys = extract_pubkeys(&generate_keypairs(&secp, 2)?);
+        let msg = Message::new(
+            b"Hello world!",
+            vec![pubkeys[0], pubkeys[1], pubkeys[0]],
+            None,
+        )?;
+        assert_eq!(msg.signature.total_count(), 2);
+        assert_eq!(msg.count_required, 2);
+        Ok(())
+    }
+
+    #[test]
+    fn bundle_roundtrip_keeps_signatures_and_threshold(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let secp = secp256k1::Secp256k1::new();
+        let keypairs = generate_keypairs(&secp, 3)?;
+        let mut msg =
+            Message::new(b"Hello world!", extract_pubkeys(&keypairs), Some(2))?;
+        msg.signature.sign(&secp, &msg.content, &keypairs[1])?;
+
+        let bundle = msg.signature.to_bundle(msg.count_required);
+        let (multisig, count_required) =
+            multisig::Multisig::from_bundle(&bundle)?;
+        assert_eq!(multisig, msg.signature);
+        assert_eq!(count_required, 2);
+
+        assert_eq!(
+            multisig::Multisig::from_bundle(&bundle[..bundle.len() - 1]),
+            Err(multisig::Error::MalformedBundle("unexpected end"))
+        );
+
+        let mut zero = bundle.clone();
+        zero[..4].copy_from_slice(&0u32.to_be_bytes());
+        assert_eq!(
+            multisig::Multisig::from_bundle(&zero),
+            Err(multisig::Error::MalformedBundle("zero threshold"))
+        );
+        let mut excessive = bundle.clone();
+        excessive[..4].copy_from_slice(&4u32.to_be_bytes());
+        assert_eq!(
+            multisig::Multisig::from_bundle(&excessive),
+            Err(multisig::Error::MalformedBundle(
+                "threshold above participants"
+            ))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn repeated_participant_signs_once(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let secp = secp256k1::Secp256k1::new();
+        let keypairs = generate_keypairs(&secp, 2)?;
+        let pubkeys = extract_pubkeys(&keypairs);
+        let mut multisig =
+            multisig::Multisig::new(vec![pubkeys[0], pubkeys[0], pubkeys[1]]);
+        assert_eq!(multisig.total_count(), 2);
+        assert_eq!(
+            multisig.sign(&secp, b"Hello world!", &keypairs[0])?,
+            multisig::SignOutcome::Signed
+        );
+        assert_eq!(
+            multisig.sign(&secp, b"Hello world!", &keypairs[0])?,
+            multisig::SignOutcome::AlreadySigned
+        );
+        assert_eq!(multisig.signed_count(), 1);
+        assert_eq!(
+            multisig.verify(&secp, b"Hello world!", 2),
+            Err(multisig::Error::NotEnoughSignatures(1, 2))
+        );
+
+        // Bundle listing the signed participant twice
+        let mut single = multisig::Multisig::new(vec![pubkeys[0]]);
+        single.sign(&secp, b"Hello world!", &keypairs[0])?;
+        let entry = &single.to_bundle(1)[8..];
+        let mut bundle = [2u32.to_be_bytes(), 2u32.to_be_bytes()].concat();
+        bundle.extend_from_slice(entry);
+        bundle.extend_from_slice(entry);
+        assert_eq!(
+            multisig::Multisig::from_bundle(&bundle),
+            Err(multisig::Error::MalformedBundle("repeated public key"))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn partially_signed_multisig_counts(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let secp = secp256k1::Secp256k1::new();
+        let keypairs = generate_keypairs(&secp, 3)?;
+        let pubkeys = extract_pubkeys(&keypairs);
+        let mut msg = Message::new(b"Hello world!", pubkeys.clone(), None)?;
+        assert_eq!(msg.signature.signed_count(), 0);
+        assert_eq!(msg.signature.remaining_signers(), pubkeys);
+
+        msg.signature.sign(&secp, &msg.content, &keypairs[1])?;
+        assert_eq!(msg.signature.total_count(), 3);
+        assert_eq!(msg.signature.signed_count(), 1);
+        assert_eq!(
+            msg.signature.remaining_signers(),
+            vec![pubkeys[0], pubkeys[2]]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn open_message_collects_signatures_from_unknown_keys(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        use secp256k1::hashes::{hash160, Hash};
+
+        let secp = secp256k1::Secp256k1::new();
+        let keypairs = generate_keypairs(&secp, 3)?;
+        let mut msg = Message::new_open(b"Hello world!", 2)?;
+        assert!(msg.signature.accepts(&keypairs[0].public_key()));
+
+        msg.signature.sign(&secp, &msg.content, &keypairs[0])?;
+        assert_eq!(
+            msg.signature
+                .verify(&secp, &msg.content, msg.count_required),
+            Err(multisig::Error::NotEnoughSignatures(1, 2))
+        );
+        // Repeated signature of the same key doesn't count twice
+        let signature = crypto::sign(&secp, &msg.content, &keypairs[0])?;
+        msg.signature.sign_raw_by_pubkey(
+            &secp,
+            &msg.content,
+            &keypairs[0].public_key(),
+            signature,
+        )?;
+        assert_eq!(msg.signature.signed_count(), 1);
+
+        let signature = crypto::sign(&secp, &msg.content, &keypairs[1])?;
+        msg.signature.sign_raw_by_pubkey(
+            &secp,
+            &msg.content,
+            &keypairs[1].public_key(),
+            signature,
+        )?;
+        assert_eq!(msg.signature.total_count(), 2);
+        assert!(msg
+            .signature
+            .verify(&secp, &msg.content, msg.count_required)
+            .is_ok());
+
+        // Unknown key can't sign by its hash only
+        let signature = crypto::sign(&secp, &msg.content, &keypairs[2])?;
+        let pkh = hash160::Hash::hash(&keypairs[2].public_key().serialize());
+        assert_eq!(
+            msg.signature.sign_raw(&secp, &msg.content, &pkh, signature),
+            Err(multisig::Error::PublicKeyRequired)
+        );
+        assert_eq!(
+            Message::new_open(b"Hello world!", 0),
+            Err(multisig::Error::ZeroThreshold)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn zero_required_signature_count_is_rejected(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let secp = secp256k1::Secp256k1::new();
+        let keypairs = generate_keypairs(&secp, 2)?;
+        assert_eq!(
+            Message::new(b"Hello world!", extract_pubkeys(&keypairs), Some(0)),
+            Err(multisig::Error::ZeroThreshold)
+        );
+        let msg =
+            Message::new(b"Hello world!", extract_pubkeys(&keypairs), Some(5))?;
+        assert_eq!(msg.count_required, 2);
+        Ok(())
+    }
+
+    #[test]
+    fn merged_partial_multisigs_meet_threshold(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let secp = secp256k1::Secp256k1::new();
+        let keypairs = generate_keypairs(&secp, 3)?;
+        let msg =
+            Message::new(b"Hello world!", extract_pubkeys(&keypairs), Some(2))?;
+        let mut left = msg.signature.clone();
+        left.sign(&secp, &msg.content, &keypairs[0])?;
+        let mut right = msg.signature.clone();
+        right.sign(&secp, &msg.content, &keypairs[0])?;
+        right.sign(&secp, &msg.content, &keypairs[2])?;
+        assert!(left.verify(&secp, &msg.content, 2).is_err());
+
+        let digest = crypto::digest(&msg.content);
+        left.merge(&secp, &digest, &right)?;
+        assert_eq!(left.signed_count(), 2);
+        assert!(left.verify(&secp, &msg.content, 2).is_ok());
+
+        let other = Message::new(
+            b"Hello world!",
+            extract_pubkeys(&keypairs[..2]),
+            None,
+        )?;
+        assert_eq!(
+            left.merge(&secp, &digest, &other.signature),
+            Err(multisig::Error::ParticipantsMismatch)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn merge_rejects_invalid_signatures(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let secp = secp256k1::Secp256k1::new();
+        let keypairs = generate_keypairs(&secp, 3)?;
+        let msg =
+            Message::new(b"Hello world!", extract_pubkeys(&keypairs), Some(2))?;
+        let mut left = msg.signature.clone();
+        let mut right = msg.signature.clone();
+        right.sign(&secp, &msg.content, &keypairs[0])?;
+        let forged = crypto::sign(&secp, b"other msg", &keypairs[1])?;
+        right.add_signature(&keypairs[1].public_key(), forged)?;
+
+        assert_eq!(
+            left.merge(&secp, &crypto::digest(&msg.content), &right),
+            Err(multisig::Error::InvalidSignature(keypairs[1].public_key()))
+        );
+        assert_eq!(left.signed_count(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn verification_stops_at_threshold(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let secp = secp256k1::Secp256k1::new();
+        let keypairs = generate_keypairs(&secp, 10)?;
+        let mut msg =
+            Message::new(b"Hello world!", extract_pubkeys(&keypairs), Some(2))?;
+        for keypair in &keypairs[..2] {
+            msg.signature.sign(&secp, &msg.content, keypair)?;
+        }
+        // Signatures past the threshold are over other content, so they
+        // fail if they are checked at all
+        for keypair in &keypairs[2..] {
+            let signature = crypto::sign(&secp, b"other msg", keypair)?;
+            msg.signature
+                .add_signature(&keypair.public_key(), signature)?;
+        }
+        assert_eq!(msg.signature.signed_count(), 10);
+
+        assert!(msg.signature.verify(&secp, &msg.content, 2).is_ok());
+        assert_eq!(
+            msg.signature.verify(&secp, &msg.content, 3),
+            Err(multisig::Error::ContentMismatch)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_signatures_ahead_of_threshold_are_skipped(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let secp = secp256k1::Secp256k1::new();
+        let keypairs = generate_keypairs(&secp, 4)?;
+        let mut msg =
+            Message::new(b"Hello world!", extract_pubkeys(&keypairs), Some(2))?;
+        let signature = crypto::sign(&secp, b"other msg", &keypairs[0])?;
+        msg.signature
+            .add_signature(&keypairs[0].public_key(), signature)?;
+        for keypair in &keypairs[1..3] {
+            msg.signature.sign(&secp, &msg.content, keypair)?;
+        }
+
+        assert!(msg.signature.verify(&secp, &msg.content, 2).is_ok());
+        assert_eq!(
+            msg.signature.verify(&secp, &msg.content, 3),
+            Err(multisig::Error::ContentMismatch)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn only_complete_message_is_finalized(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let secp = secp256k1::Secp256k1::new();
+        let keypairs = generate_keypairs(&secp, 2)?;
+        let mut msg =
+            Message::new(b"Hello world!", extract_pubkeys(&keypairs), Some(1))?;
+        let now = time::OffsetDateTime::now_utc();
+        assert_eq!(
+            msg.finalize(&secp, now),
+            Err(multisig::Error::NotEnoughSignatures(0, 1))
+        );
+
+        msg.signature.sign(&secp, &msg.content, &keypairs[0])?;
+        msg.finalize(&secp, now)?;
+        assert_eq!(msg.finalized_at, Some(now));
+        assert_eq!(msg.finalize(&secp, now), Err(multisig::Error::Finalized));
+        Ok(())
+    }
+
+    #[test]
+    fn digest_message_signs_content_as_is(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let secp = secp256k1::Secp256k1::new();
+        let keypair = crypto::new_keypair(&secp)?;
+        let digest = [7u8; 32];
+        let msg = Message::new(digest, vec![keypair.public_key()], None)?;
+        assert_eq!(msg.digest(), crypto::digest(&digest));
+        let mut msg = msg.into_digest()?;
+        assert_eq!(msg.digest(), digest);
+
+        let signature = crypto::sign_digest(&secp, &digest, &keypair)?;
+        msg.signature.sign_raw_by_pubkey_digest(
+            &secp,
+            &msg.digest(),
+            &keypair.public_key(),
+            signature,
+        )?;
+        msg.signature
+            .verify_digest(&secp, &digest, msg.count_required)?;
+
+        let short = Message::new([7u8; 31], vec![keypair.public_key()], None)?;
+        assert_eq!(short.into_digest(), Err(multisig::Error::InvalidDigest));
+        let with_nonce =
+            Message::new(digest, vec![keypair.public_key()], None)?
+                .with_nonce(Some(1));
+        assert_eq!(
+            with_nonce.into_digest(),
+            Err(multisig::Error::InvalidDigest)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn sign_deadline_is_inclusive() -> Result<(), Box<dyn std::error::Error>> {
+        let secp = secp256k1::Secp256k1::new();
+        let keypair = crypto::new_keypair(&secp)?;
+        let deadline =
+            time::OffsetDateTime::from_unix_timestamp(1_735_732_800)?;
+        let msg =
+            Message::new(b"Hello world!", vec![keypair.public_key()], None)?
+                .with_sign_deadline(Some(deadline));
+        let nanosecond = time::Duration::nanoseconds(1);
+        assert_eq!(msg.check_sign_deadline(deadline - nanosecond), Ok(()));
+        assert_eq!(msg.check_sign_deadline(deadline), Ok(()));
+        assert_eq!(
+            msg.check_sign_deadline(deadline + nanosecond),
+            Err(multisig::Error::DeadlinePassed)
+        );
+        let open = msg.with_sign_deadline(None);
+        assert_eq!(open.check_sign_deadline(deadline + nanosecond), Ok(()));
+        Ok(())
+    }
+
+    #[test]
+    fn restarted_message_keeps_participants_only(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let secp = secp256k1::Secp256k1::new();
+        let keypairs = generate_keypairs(&secp, 2)?;
+        let mut msg =
+            Message::new(b"Hello world!", extract_pubkeys(&keypairs), Some(2))?
+                .with_nonce(Some(7));
+        let preimage = msg.preimage().into_owned();
+        msg.signature.sign(&secp, &preimage, &keypairs[0])?;
+
+        let restarted = msg.restart();
+        assert_ne!(restarted.id, msg.id);
+        assert_eq!(restarted.digest(), msg.digest());
+        assert_eq!(restarted.count_required, 2);
+        assert_eq!(restarted.signature.signed_count(), 0);
+        assert_eq!(
+            restarted.signature.remaining_signers(),
+            extract_pubkeys(&keypairs)
+        );
+        // Original is untouched
+        assert_eq!(msg.signature.signed_count(), 1);
+        Ok(())
+    }
+
     // Helpers
 
     fn extract_pubkeys(
-        keypairs: &[secp256k1::Keypair],
+        keypairs: &[crypto::SecretKeypair],
     ) -> Vec<secp256k1::PublicKey> {
         keypairs.iter().map(|k| k.public_key()).collect()
     }
@@ -159,7 +1240,7 @@ mod tests {
     fn generate_keypairs(
         secp: &secp256k1::Secp256k1<secp256k1::All>,
         count: usize,
-    ) -> Result<Vec<secp256k1::Keypair>, secp256k1::Error> {
+    ) -> Result<Vec<crypto::SecretKeypair>, secp256k1::Error> {
         std::iter::repeat_with(|| crypto::new_keypair(secp))
             .take(count)
             .collect::<Result<Vec<_>, _>>()
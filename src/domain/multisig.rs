@@ -1,9 +1,10 @@
+use secp256k1::hashes::{hash160, Hash};
 use secp256k1::All;
-use secp256k1::{ecdsa, Keypair, PublicKey, Secp256k1, Signing};
+use secp256k1::{ecdsa, PublicKey, Secp256k1, Signing, Verification};
 
-use crate::crypto;
+use crate::crypto::{self, SecretKeypair};
 
-#[derive(thiserror::Error, PartialEq, Eq)]
+#[derive(thiserror::Error, Clone, PartialEq, Eq)]
 pub enum Error {
     #[error("No public key found")]
     PublicKeyNotFound,
@@ -11,59 +12,530 @@ pub enum Error {
     Secp256k1(#[from] secp256k1::Error),
     #[error("Not enough signatures, provided: {0}, required: {1}")]
     NotEnoughSignatures(usize, usize),
+    #[error("Public key is a participant already")]
+    PublicKeyExists,
+    #[error("Public key has signed already")]
+    AlreadySigned,
+    #[error("Participants can't be changed after signing started")]
+    SigningStarted,
+    #[error("At least one participant is required")]
+    NoParticipants,
+    #[error("Required signature count must be positive")]
+    ZeroThreshold,
+    #[error("Public key is required to sign open message")]
+    PublicKeyRequired,
+    #[error("Message is finalized")]
+    Finalized,
+    #[error("Digest content must be exactly 32 bytes, without nonce")]
+    InvalidDigest,
+    #[error("Malformed bundle: {0}")]
+    MalformedBundle(&'static str),
+    #[error("Multisigs have different participants")]
+    ParticipantsMismatch,
+    #[error("At most {0} participants are allowed")]
+    TooManyParticipants(usize),
+    #[error(
+        "Signatures are over other content, it may have changed after signing"
+    )]
+    ContentMismatch,
+    #[error("Signature of {0} is invalid")]
+    InvalidSignature(PublicKey),
+    #[error("Signing deadline has passed")]
+    DeadlinePassed,
+    #[error("Content can't start with the nonce preimage tag")]
+    ReservedContent,
+    #[error("Message changed, current version: {0}")]
+    VersionMismatch(u64),
 }
 
 crate::impl_debug!(Error);
 
+/// Result of signing by a participant
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SignOutcome {
+    Signed,
+    /// Participant had signed before, the signature is kept
+    AlreadySigned,
+}
+
+impl Error {
+    /// Stable machine-readable name of the variant
+    pub fn code(&self) -> &'static str {
+        match self {
+            Error::PublicKeyNotFound => "public_key_not_found",
+            Error::Secp256k1(_) => "secp256k1",
+            Error::NotEnoughSignatures(..) => "not_enough_signatures",
+            Error::PublicKeyExists => "public_key_exists",
+            Error::AlreadySigned => "already_signed",
+            Error::SigningStarted => "signing_started",
+            Error::NoParticipants => "no_participants",
+            Error::ZeroThreshold => "zero_threshold",
+            Error::PublicKeyRequired => "public_key_required",
+            Error::Finalized => "finalized",
+            Error::InvalidDigest => "invalid_digest",
+            Error::MalformedBundle(_) => "malformed_bundle",
+            Error::ParticipantsMismatch => "participants_mismatch",
+            Error::TooManyParticipants(_) => "too_many_participants",
+            Error::ContentMismatch => "content_mismatch",
+            Error::InvalidSignature(_) => "invalid_signature",
+            Error::DeadlinePassed => "deadline_passed",
+            Error::ReservedContent => "reserved_content",
+            Error::VersionMismatch(_) => "version_mismatch",
+        }
+    }
+}
+
 #[derive(Debug, Default, Clone, PartialEq, Eq)]
-pub struct Multisig(Vec<(PublicKey, Option<ecdsa::Signature>)>);
+pub struct Multisig {
+    /// Distinct public keys, so nobody signs toward the threshold twice
+    participants: Vec<(PublicKey, Option<ecdsa::Signature>)>,
+    /// Participants aren't known upfront, every distinct signer joins
+    open: bool,
+}
 
 impl Multisig {
+    /// Repeated participants are kept once.
     pub fn new(pubkeys: Vec<PublicKey>) -> Self {
-        Multisig(pubkeys.into_iter().map(|pk| (pk, None)).collect())
+        let mut seen = std::collections::HashSet::new();
+        Multisig {
+            participants: pubkeys
+                .into_iter()
+                .filter(|pk| seen.insert(*pk))
+                .map(|pk| (pk, None))
+                .collect(),
+            open: false,
+        }
+    }
+    /// Multisig without fixed participants, any key may sign once
+    pub fn new_open() -> Self {
+        Multisig {
+            participants: Vec::new(),
+            open: true,
+        }
+    }
+    /// Same participants, nothing signed
+    pub fn without_signatures(&self) -> Self {
+        Multisig {
+            participants: self
+                .participants
+                .iter()
+                .map(|(pk, _)| (*pk, None))
+                .collect(),
+            open: self.open,
+        }
+    }
+    pub fn is_open(&self) -> bool {
+        self.open
+    }
+    /// Participants count
+    pub fn total_count(&self) -> usize {
+        self.participants.len()
+    }
+    /// Collected signatures count
+    pub fn signed_count(&self) -> usize {
+        self.participants
+            .iter()
+            .filter(|(_, s)| s.is_some())
+            .count()
+    }
+    /// Participants who haven't signed yet, in participants order
+    pub fn remaining_signers(&self) -> Vec<PublicKey> {
+        self.participants
+            .iter()
+            .filter(|(_, s)| s.is_none())
+            .map(|(pk, _)| *pk)
+            .collect()
+    }
+    /// Add participant, only allowed before anyone signed
+    pub fn add_pubkey(&mut self, pubkey: PublicKey) -> Result<(), Error> {
+        if self.signatures().next().is_some() {
+            return Err(Error::SigningStarted);
+        }
+        if self.is_participant(&pubkey) {
+            return Err(Error::PublicKeyExists);
+        }
+        self.participants.push((pubkey, None));
+        Ok(())
+    }
+    /// Remove participant with given public key hash, only allowed
+    /// before anyone signed
+    pub fn remove_pubkey(
+        &mut self,
+        pubkey_hash: &hash160::Hash,
+    ) -> Result<PublicKey, Error> {
+        let idx = self
+            .participants
+            .iter()
+            .position(|(pk, _)| {
+                hash160::Hash::hash(&pk.serialize()).eq(pubkey_hash)
+            })
+            .ok_or(Error::PublicKeyNotFound)?;
+        if self.participants[idx].1.is_some() {
+            return Err(Error::AlreadySigned);
+        }
+        if self.signatures().next().is_some() {
+            return Err(Error::SigningStarted);
+        }
+        Ok(self.participants.remove(idx).0)
+    }
+    /// Collected signatures with signer public keys
+    pub fn signatures(
+        &self,
+    ) -> impl Iterator<Item = (&PublicKey, &ecdsa::Signature)> {
+        self.participants
+            .iter()
+            .filter_map(|(pk, s)| s.as_ref().map(|s| (pk, s)))
+    }
+    /// Participant public keys, signed or not
+    pub fn participants(&self) -> impl Iterator<Item = &PublicKey> {
+        self.participants.iter().map(|(pk, _)| pk)
+    }
+    /// Signers whose signature over `digest` is valid, unlike `verify`
+    /// every collected signature is checked
+    pub fn valid_signers<C: Verification>(
+        &self,
+        secp: &Secp256k1<C>,
+        digest: &[u8; 32],
+    ) -> Vec<PublicKey> {
+        self.signatures()
+            .filter(|(pk, s)| {
+                crypto::verify_digest(secp, digest, s, pk).is_ok()
+            })
+            .map(|(pk, _)| *pk)
+            .collect()
+    }
+    /// Whether `pubkey` is one of the participants
+    pub fn is_participant(&self, pubkey: &PublicKey) -> bool {
+        self.participants
+            .iter()
+            .any(|(pk, _)| pk.eq_fast_unstable(pubkey))
+    }
+    /// Whether `pubkey` may sign: it is a participant, or multisig is open
+    pub fn accepts(&self, pubkey: &PublicKey) -> bool {
+        self.open || self.is_participant(pubkey)
     }
     pub fn sign<C: Signing>(
         &mut self,
         secp: &Secp256k1<C>,
         content: &[u8],
-        keypair: &Keypair,
-    ) -> Result<(), Error> {
+        keypair: &SecretKeypair,
+    ) -> Result<SignOutcome, Error> {
+        self.join_if_open(&keypair.public_key());
         let (_, signature) = self
-            .0
+            .participants
             .iter_mut()
             .find(|(pk, _)| pk.eq_fast_unstable(&keypair.public_key()))
             .ok_or(Error::PublicKeyNotFound)?;
         match signature {
             Some(_) => {
                 tracing::warn!("signature alreay exists, skip signing");
-                return Ok(());
+                Ok(SignOutcome::AlreadySigned)
             }
             None => {
-                *signature =
-                    Some(crypto::sign(secp, content, &keypair.secret_key())?)
+                *signature = Some(crypto::sign(secp, content, keypair)?);
+                Ok(SignOutcome::Signed)
+            }
+        }
+    }
+    /// Attach externally produced signature of participant with given
+    /// public key hash. Signature is verified before it is stored.
+    pub fn sign_raw(
+        &mut self,
+        secp: &Secp256k1<All>,
+        content: &[u8],
+        pubkey_hash: &hash160::Hash,
+        signature: ecdsa::Signature,
+    ) -> Result<(), Error> {
+        self.sign_raw_digest(
+            secp,
+            &crypto::digest(content),
+            pubkey_hash,
+            signature,
+        )
+    }
+    /// Same as `sign_raw`, but signature is over `digest` as is
+    pub fn sign_raw_digest(
+        &mut self,
+        secp: &Secp256k1<All>,
+        digest: &[u8; 32],
+        pubkey_hash: &hash160::Hash,
+        signature: ecdsa::Signature,
+    ) -> Result<(), Error> {
+        let not_found = match self.open {
+            true => Error::PublicKeyRequired,
+            false => Error::PublicKeyNotFound,
+        };
+        let (pubkey, stored) = self
+            .participants
+            .iter_mut()
+            .find(|(pk, _)| {
+                hash160::Hash::hash(&pk.serialize()).eq(pubkey_hash)
+            })
+            .ok_or(not_found)?;
+        crypto::verify_digest(secp, digest, &signature, pubkey)?;
+        match stored {
+            Some(_) => {
+                tracing::warn!("signature alreay exists, skip signing");
             }
+            None => *stored = Some(signature),
         }
         Ok(())
     }
-    pub fn verify(
+    /// Attach externally produced signature of participant with given
+    /// public key, signers of open multisig join on the fly. Signature
+    /// is verified before it is stored.
+    pub fn sign_raw_by_pubkey<C: Verification>(
+        &mut self,
+        secp: &Secp256k1<C>,
+        content: &[u8],
+        pubkey: &PublicKey,
+        signature: ecdsa::Signature,
+    ) -> Result<(), Error> {
+        self.sign_raw_by_pubkey_digest(
+            secp,
+            &crypto::digest(content),
+            pubkey,
+            signature,
+        )
+    }
+    /// Same as `sign_raw_by_pubkey`, but signature is over `digest` as is
+    pub fn sign_raw_by_pubkey_digest<C: Verification>(
+        &mut self,
+        secp: &Secp256k1<C>,
+        digest: &[u8; 32],
+        pubkey: &PublicKey,
+        signature: ecdsa::Signature,
+    ) -> Result<(), Error> {
+        crypto::verify_digest(secp, digest, &signature, pubkey)?;
+        self.add_signature(pubkey, signature)?;
+        Ok(())
+    }
+    /// Store signature produced by `crypto::sign` elsewhere, e.g. off the
+    /// async runtime. It isn't verified, so it must come from a trusted
+    /// source.
+    pub(crate) fn add_signature(
+        &mut self,
+        pubkey: &PublicKey,
+        signature: ecdsa::Signature,
+    ) -> Result<SignOutcome, Error> {
+        self.join_if_open(pubkey);
+        let (_, stored) = self
+            .participants
+            .iter_mut()
+            .find(|(pk, _)| pk.eq_fast_unstable(pubkey))
+            .ok_or(Error::PublicKeyNotFound)?;
+        match stored {
+            Some(_) => {
+                tracing::warn!("signature alreay exists, skip signing");
+                Ok(SignOutcome::AlreadySigned)
+            }
+            None => {
+                *stored = Some(signature);
+                Ok(SignOutcome::Signed)
+            }
+        }
+    }
+    /// Take signatures collected by `other` for the same participants,
+    /// e.g. on another node. Signatures already present in `self` are
+    /// kept. Taken signatures are verified over `digest` first, nothing
+    /// is merged if any of them is invalid.
+    pub fn merge<C: Verification>(
+        &mut self,
+        secp: &Secp256k1<C>,
+        digest: &[u8; 32],
+        other: &Multisig,
+    ) -> Result<(), Error> {
+        let same_participants = self.participants.len()
+            == other.participants.len()
+            && other
+                .participants
+                .iter()
+                .all(|(pk, _)| self.is_participant(pk));
+        if !same_participants {
+            return Err(Error::ParticipantsMismatch);
+        }
+        let taken = other
+            .signatures()
+            .filter(|(pubkey, _)| {
+                self.participants.iter().any(|(pk, stored)| {
+                    stored.is_none() && pk.eq_fast_unstable(pubkey)
+                })
+            })
+            .collect::<Vec<_>>();
+        for (pubkey, signature) in &taken {
+            crypto::verify_digest(secp, digest, signature, pubkey)
+                .map_err(|_| Error::InvalidSignature(**pubkey))?;
+        }
+        for (pubkey, signature) in taken {
+            if let Some((_, stored)) = self
+                .participants
+                .iter_mut()
+                .find(|(pk, _)| pk.eq_fast_unstable(pubkey))
+            {
+                *stored = Some(*signature);
+            }
+        }
+        Ok(())
+    }
+    fn join_if_open(&mut self, pubkey: &PublicKey) {
+        if self.open && !self.is_participant(pubkey) {
+            self.participants.push((*pubkey, None));
+        }
+    }
+    /// Share of collected signatures in `0.0..=1.0`, over-satisfied
+    /// multisig is capped at `1.0`
+    pub fn progress(&self, count_required: usize) -> f32 {
+        if count_required == 0 {
+            return 1.0;
+        }
+        let sig_count = self.signed_count();
+        (sig_count as f32 / count_required as f32).min(1.0)
+    }
+    pub fn verify<C: Verification>(
         &self,
-        secp: &Secp256k1<All>,
+        secp: &Secp256k1<C>,
         content: &[u8],
         count_required: usize,
     ) -> Result<(), Error> {
-        let signatures = self
-            .0
-            .iter()
-            .filter_map(|(pk, s)| s.as_ref().map(|s| (pk, s)))
-            .collect::<Vec<_>>();
-        let sig_count = signatures.len();
+        self.verify_digest(secp, &crypto::digest(content), count_required)
+    }
+    /// Same as `verify`, but signatures are over `digest` as is.
+    /// Signatures are checked in participants order until
+    /// `count_required` of them are valid, the rest aren't checked.
+    /// Invalid signatures don't fail verification while enough valid
+    /// ones are collected.
+    ///
+    /// Each failed signature is classified on its own. Malformed one,
+    /// valid over no digest at all, is `InvalidSignature` of its signer
+    /// and it is reported first. Well-formed signatures failing
+    /// verification are over another digest, which is `ContentMismatch`:
+    /// the content changed after signing, or signers signed other
+    /// content.
+    pub fn verify_digest<C: Verification>(
+        &self,
+        secp: &Secp256k1<C>,
+        digest: &[u8; 32],
+        count_required: usize,
+    ) -> Result<(), Error> {
+        if count_required == 0 {
+            return Err(Error::ZeroThreshold);
+        }
+        let sig_count = self.signed_count();
         if sig_count < count_required {
             return Err(Error::NotEnoughSignatures(sig_count, count_required));
         }
-        for (pubkey, signature) in signatures {
-            crypto::verify(secp, content, signature, pubkey)?;
+        let mut valid = 0;
+        let mut failed = Vec::new();
+        for (pubkey, signature) in self.signatures() {
+            if valid >= count_required {
+                break;
+            }
+            match crypto::verify_digest(secp, digest, signature, pubkey) {
+                Ok(()) => valid += 1,
+                Err(_) => failed.push((pubkey, signature)),
+            }
         }
-        tracing::info!("verification successed");
-        Ok(())
+        if valid >= count_required {
+            tracing::info!("verification successed");
+            return Ok(());
+        }
+        let malformed = failed
+            .iter()
+            .find(|(_, signature)| !crypto::is_well_formed(secp, signature));
+        match (malformed, failed.is_empty()) {
+            (Some((pubkey, _)), _) => Err(Error::InvalidSignature(**pubkey)),
+            (None, false) => Err(Error::ContentMismatch),
+            (None, true) => {
+                Err(Error::NotEnoughSignatures(valid, count_required))
+            }
+        }
+    }
+    /// Export participants, collected signatures and threshold:
+    ///
+    /// ```text
+    /// bundle := count_required: u32 BE, participants: u32 BE, entry*
+    /// entry  := compressed pubkey: [u8; 33], der_len: u8, DER signature
+    /// ```
+    ///
+    /// `der_len` is `0` for participants who haven't signed.
+    pub fn to_bundle(&self, count_required: usize) -> Vec<u8> {
+        let mut bundle =
+            Vec::with_capacity(8 + self.participants.len() * (34 + 72));
+        bundle.extend_from_slice(&(count_required as u32).to_be_bytes());
+        bundle
+            .extend_from_slice(&(self.participants.len() as u32).to_be_bytes());
+        for (pubkey, signature) in &self.participants {
+            bundle.extend_from_slice(&pubkey.serialize());
+            match signature {
+                Some(signature) => {
+                    let der = crypto::Signature::from(*signature).to_der();
+                    bundle.push(der.len() as u8);
+                    bundle.extend_from_slice(&der);
+                }
+                None => bundle.push(0),
+            }
+        }
+        bundle
+    }
+    /// Parse bundle produced by `to_bundle`, returns the multisig and
+    /// required signatures count. The count must be positive and at most
+    /// the participants count.
+    pub fn from_bundle(bundle: &[u8]) -> Result<(Multisig, usize), Error> {
+        fn take<'a>(
+            bundle: &mut &'a [u8],
+            len: usize,
+        ) -> Result<&'a [u8], Error> {
+            if bundle.len() < len {
+                return Err(Error::MalformedBundle("unexpected end"));
+            }
+            let (head, tail) = bundle.split_at(len);
+            *bundle = tail;
+            Ok(head)
+        }
+        fn take_u32(bundle: &mut &[u8]) -> Result<usize, Error> {
+            let bytes = take(bundle, 4)?;
+            Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+                as usize)
+        }
+
+        let mut rest = bundle;
+        let count_required = take_u32(&mut rest)?;
+        let participants = take_u32(&mut rest)?;
+        if count_required == 0 {
+            return Err(Error::MalformedBundle("zero threshold"));
+        }
+        if count_required > participants {
+            return Err(Error::MalformedBundle("threshold above participants"));
+        }
+        let mut entries = Vec::with_capacity(participants.min(rest.len() / 34));
+        for _ in 0..participants {
+            let pubkey = PublicKey::from_slice(take(&mut rest, 33)?)
+                .map_err(|_| Error::MalformedBundle("invalid public key"))?;
+            let der_len = take(&mut rest, 1)?[0] as usize;
+            let signature = match der_len {
+                0 => None,
+                _ => Some(
+                    crypto::Signature::from_der(take(&mut rest, der_len)?)
+                        .map_err(|_| {
+                            Error::MalformedBundle("invalid DER signature")
+                        })?
+                        .into(),
+                ),
+            };
+            if entries.iter().any(|(pk, _)| pk == &pubkey) {
+                return Err(Error::MalformedBundle("repeated public key"));
+            }
+            entries.push((pubkey, signature));
+        }
+        if !rest.is_empty() {
+            return Err(Error::MalformedBundle("trailing bytes"));
+        }
+        Ok((
+            Multisig {
+                participants: entries,
+                open: false,
+            },
+            count_required,
+        ))
     }
 }
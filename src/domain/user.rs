@@ -1,7 +1,9 @@
 use std::collections::HashMap;
 
 use fake::Fake;
-use secp256k1::Keypair;
+use time::OffsetDateTime;
+
+use crate::crypto::SecretKeypair;
 
 type KeyId = i32;
 
@@ -9,21 +11,29 @@ type KeyId = i32;
 pub struct User {
     pub id: uuid::Uuid,
     pub name: String,
-    pub keys: HashMap<KeyId, Keypair>,
+    pub keys: HashMap<KeyId, SecretKeypair>,
+    pub created_at: OffsetDateTime,
+    /// Set by storage on every update
+    pub updated_at: OffsetDateTime,
 }
 
 impl Default for User {
     fn default() -> Self {
+        let now = OffsetDateTime::now_utc();
         User {
             name: fake::faker::internet::en::Username().fake(),
             keys: Default::default(),
             id: uuid::Uuid::new_v4(),
+            created_at: now,
+            updated_at: now,
         }
     }
 }
 
 impl User {
-    pub fn add_keypair(&mut self, keypair: Keypair) {
+    /// Next id is derived from existing keys, so call it within
+    /// `Storage::update_user` for concurrent additions to get distinct ids
+    pub fn add_keypair(&mut self, keypair: SecretKeypair) {
         let last_id = self.keys.keys().max().copied().unwrap_or_default();
         self.keys.insert(last_id + 1, keypair);
     }
@@ -0,0 +1,96 @@
+use std::convert::Infallible;
+
+use axum::extract::FromRequestParts;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use http::header::{ACCEPT, CONTENT_TYPE};
+use http::request::Parts;
+use http::{HeaderMap, HeaderValue};
+use serde::Serialize;
+
+use crate::api::ErrorResponse;
+
+pub const CBOR: &str = "application/cbor";
+pub const MSGPACK: &str = "application/msgpack";
+
+/// Encoding of response bodies, negotiated by `Accept`. The first
+/// supported media type wins, JSON is the default.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ResponseEncoding {
+    #[default]
+    Json,
+    Cbor,
+    MsgPack,
+}
+
+impl ResponseEncoding {
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        headers
+            .get_all(ACCEPT)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .filter_map(|media| media.split(';').next())
+            .find_map(|media| match media.trim().to_ascii_lowercase() {
+                m if m == "application/json" => Some(Self::Json),
+                m if m == CBOR => Some(Self::Cbor),
+                m if matches!(
+                    m.as_str(),
+                    MSGPACK
+                        | "application/x-msgpack"
+                        | "application/vnd.msgpack"
+                ) =>
+                {
+                    Some(Self::MsgPack)
+                }
+                _ => None,
+            })
+            .unwrap_or_default()
+    }
+}
+
+impl<S: Send + Sync> FromRequestParts<S> for ResponseEncoding {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        _: &S,
+    ) -> Result<Self, Self::Rejection> {
+        Ok(Self::from_headers(&parts.headers))
+    }
+}
+
+/// Like `Json`, but serialized with the negotiated encoding
+#[derive(Debug)]
+pub struct Encoded<T>(pub ResponseEncoding, pub T);
+
+impl<T: Serialize> IntoResponse for Encoded<T> {
+    fn into_response(self) -> Response {
+        let Encoded(encoding, value) = self;
+        let (content_type, body) = match encoding {
+            ResponseEncoding::Json => return Json(value).into_response(),
+            ResponseEncoding::Cbor => {
+                let mut body = Vec::new();
+                let encoded = ciborium::into_writer(&value, &mut body)
+                    .map(|()| body)
+                    .map_err(|e| anyhow::anyhow!("failed to encode cbor: {e}"));
+                (CBOR, encoded)
+            }
+            ResponseEncoding::MsgPack => {
+                // Structs are maps, as they are in json
+                let encoded = rmp_serde::to_vec_named(&value).map_err(|e| {
+                    anyhow::anyhow!("failed to encode msgpack: {e}")
+                });
+                (MSGPACK, encoded)
+            }
+        };
+        match body {
+            Ok(body) => (
+                [(CONTENT_TYPE, HeaderValue::from_static(content_type))],
+                body,
+            )
+                .into_response(),
+            Err(e) => ErrorResponse::InternalError(e).into_response(),
+        }
+    }
+}
@@ -0,0 +1,103 @@
+use axum::extract::rejection::{JsonRejection, QueryRejection};
+use axum::extract::{FromRequest, FromRequestParts, Query, Request};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use http::StatusCode;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::config::Settings;
+use crate::startup::AppState;
+
+/// Request body checks which go beyond deserialization
+pub trait Validate {
+    /// Report every invalid field, not only the first one
+    fn validate(&self, settings: &Settings) -> Result<(), Vec<FieldError>>;
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FieldError {
+    pub field: &'static str,
+    pub message: String,
+}
+
+impl FieldError {
+    pub fn new(field: &'static str, message: impl Into<String>) -> Self {
+        FieldError {
+            field,
+            message: message.into(),
+        }
+    }
+}
+
+/// Like `Json`, but the body is validated as well. Invalid bodies are
+/// rejected with `422` and a list of offending fields.
+#[derive(Debug)]
+pub struct ValidatedJson<T>(pub T);
+
+pub enum ValidationRejection {
+    Json(JsonRejection),
+    Query(QueryRejection),
+    Fields(Vec<FieldError>),
+}
+
+impl IntoResponse for ValidationRejection {
+    fn into_response(self) -> Response {
+        match self {
+            ValidationRejection::Json(rejection) => rejection.into_response(),
+            ValidationRejection::Query(rejection) => rejection.into_response(),
+            ValidationRejection::Fields(errors) => {
+                tracing::info!(?errors, "request validation failed");
+                (
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    Json(serde_json::json!({ "errors": errors })),
+                )
+                    .into_response()
+            }
+        }
+    }
+}
+
+impl<T> FromRequest<AppState> for ValidatedJson<T>
+where
+    T: DeserializeOwned + Validate,
+{
+    type Rejection = ValidationRejection;
+
+    async fn from_request(
+        req: Request,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let Json(value) = Json::<T>::from_request(req, state)
+            .await
+            .map_err(ValidationRejection::Json)?;
+        value
+            .validate(&state.settings)
+            .map_err(ValidationRejection::Fields)?;
+        Ok(ValidatedJson(value))
+    }
+}
+
+/// Like `Query`, validated and rejected the same way as `ValidatedJson`
+#[derive(Debug)]
+pub struct ValidatedQuery<T>(pub T);
+
+impl<T> FromRequestParts<AppState> for ValidatedQuery<T>
+where
+    T: DeserializeOwned + Validate,
+{
+    type Rejection = ValidationRejection;
+
+    async fn from_request_parts(
+        parts: &mut http::request::Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let Query(value) = Query::<T>::from_request_parts(parts, state)
+            .await
+            .map_err(ValidationRejection::Query)?;
+        value
+            .validate(&state.settings)
+            .map_err(ValidationRejection::Fields)?;
+        Ok(ValidatedQuery(value))
+    }
+}
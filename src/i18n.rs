@@ -0,0 +1,113 @@
+//! Human readable error texts in the language asked by `Accept-Language`.
+//! Only the text is translated, error codes stay the same.
+
+use http::HeaderMap;
+
+use crate::domain::multisig;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Locale {
+    #[default]
+    En,
+    Es,
+}
+
+impl Locale {
+    pub fn tag(self) -> &'static str {
+        match self {
+            Locale::En => "en",
+            Locale::Es => "es",
+        }
+    }
+
+    fn from_tag(tag: &str) -> Option<Locale> {
+        let primary = tag.split('-').next()?.trim();
+        match primary.to_ascii_lowercase().as_str() {
+            "en" => Some(Locale::En),
+            "es" => Some(Locale::Es),
+            _ => None,
+        }
+    }
+
+    /// Most preferred supported language of `Accept-Language`, English
+    /// if there is none
+    pub fn from_headers(headers: &HeaderMap) -> Locale {
+        let mut ranges = headers
+            .get_all(http::header::ACCEPT_LANGUAGE)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .filter_map(|range| {
+                let mut parts = range.split(';');
+                let tag = parts.next()?.trim();
+                let quality =
+                    parts
+                        .find_map(|p| p.trim().strip_prefix("q="))
+                        .map_or(Some(1.0), |q| q.trim().parse::<f32>().ok())?;
+                Some((tag, quality))
+            })
+            .filter(|(_, quality)| *quality > 0.0)
+            .collect::<Vec<_>>();
+        // Stable, so equally preferred languages keep their order
+        ranges.sort_by(|a, b| b.1.total_cmp(&a.1));
+        ranges
+            .into_iter()
+            .find_map(|(tag, _)| Locale::from_tag(tag))
+            .unwrap_or_default()
+    }
+}
+
+/// `error` text in `locale`, English for untranslated variants
+pub fn multisig_error(error: &multisig::Error, locale: Locale) -> String {
+    match (locale, error) {
+        (
+            Locale::Es,
+            multisig::Error::NotEnoughSignatures(provided, required),
+        ) => {
+            format!(
+                "Firmas insuficientes, aportadas: {provided}, requeridas: {required}"
+            )
+        }
+        (Locale::Es, multisig::Error::PublicKeyNotFound) => {
+            "No se encontró la clave pública".to_string()
+        }
+        _ => error.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http::HeaderMap;
+
+    use crate::domain::multisig;
+
+    use super::{multisig_error, Locale};
+
+    #[test]
+    fn most_preferred_supported_language_is_chosen() {
+        let locale = |value: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(
+                http::header::ACCEPT_LANGUAGE,
+                value.parse().expect("valid header"),
+            );
+            Locale::from_headers(&headers)
+        };
+        assert_eq!(locale("es"), Locale::Es);
+        assert_eq!(locale("es-MX,en;q=0.5"), Locale::Es);
+        assert_eq!(locale("fr, en;q=0.4, es;q=0.8"), Locale::Es);
+        assert_eq!(locale("es;q=0, en"), Locale::En);
+        assert_eq!(locale("fr"), Locale::En);
+        assert_eq!(Locale::from_headers(&HeaderMap::new()), Locale::En);
+    }
+
+    #[test]
+    fn untranslated_errors_fall_back_to_english() {
+        let error = multisig::Error::SigningStarted;
+        assert_eq!(multisig_error(&error, Locale::Es), error.to_string());
+        assert_eq!(
+            multisig_error(&multisig::Error::PublicKeyNotFound, Locale::En),
+            "No public key found"
+        );
+    }
+}
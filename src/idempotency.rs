@@ -0,0 +1,182 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+use secp256k1::hashes::sha256;
+
+use crate::api::ErrorResponse;
+
+pub const IDEMPOTENCY_KEY_HEADER: &str = "Idempotency-Key";
+
+#[derive(thiserror::Error)]
+pub enum Error {
+    #[error("idempotency key reused with a different request body")]
+    KeyReused,
+    #[error("request with this idempotency key is in progress")]
+    InProgress,
+    #[error("failed to acquire idempotency cache lock")]
+    Lock,
+}
+
+crate::impl_debug!(Error);
+
+impl From<Error> for ErrorResponse {
+    fn from(value: Error) -> Self {
+        match value {
+            Error::KeyReused | Error::InProgress => {
+                ErrorResponse::ConflictError(value.into())
+            }
+            Error::Lock => ErrorResponse::InternalError(value.into()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Entry {
+    request_hash: sha256::Hash,
+    /// `None` while the message is being created
+    msg_id: Option<uuid::Uuid>,
+    created_at: Instant,
+}
+
+/// Remembers which message was created for a given `Idempotency-Key`,
+/// so client retries don't produce duplicates.
+#[derive(Debug, Clone)]
+pub struct IdempotencyCache {
+    ttl: Duration,
+    entries: Arc<Mutex<HashMap<String, Entry>>>,
+}
+
+/// Outcome of `IdempotencyCache::claim`
+#[derive(Debug)]
+pub enum Claim {
+    /// Message created earlier for that key
+    Created(uuid::Uuid),
+    /// Key is reserved for the caller, who creates the message
+    Reserved(Reservation),
+}
+
+/// Key reserved by a request which creates a message. Released on drop
+/// unless `complete` is called, so failed requests may be retried.
+#[derive(Debug)]
+pub struct Reservation {
+    cache: IdempotencyCache,
+    key: String,
+    completed: bool,
+}
+
+impl IdempotencyCache {
+    pub fn new(ttl: Duration) -> Self {
+        IdempotencyCache {
+            ttl,
+            entries: Default::default(),
+        }
+    }
+
+    /// Returns previously created message id for that key, or reserves
+    /// the key, so concurrent retries never create a message each.
+    /// Fails if the key was used with another request body or another
+    /// request with that key is still creating its message.
+    pub fn claim(
+        &self,
+        key: &str,
+        request_hash: &sha256::Hash,
+    ) -> Result<Claim, Error> {
+        let mut entries = self.entries.lock().map_err(|_| Error::Lock)?;
+        let ttl = self.ttl;
+        entries.retain(|_, e| e.created_at.elapsed() < ttl);
+        match entries.get(key) {
+            Some(e) if e.request_hash.ne(request_hash) => Err(Error::KeyReused),
+            Some(Entry {
+                msg_id: Some(msg_id),
+                ..
+            }) => Ok(Claim::Created(*msg_id)),
+            Some(_) => Err(Error::InProgress),
+            None => {
+                entries.insert(
+                    key.to_string(),
+                    Entry {
+                        request_hash: *request_hash,
+                        msg_id: None,
+                        created_at: Instant::now(),
+                    },
+                );
+                Ok(Claim::Reserved(Reservation {
+                    cache: self.clone(),
+                    key: key.to_string(),
+                    completed: false,
+                }))
+            }
+        }
+    }
+}
+
+impl Reservation {
+    /// Remember the message created for the reserved key
+    pub fn complete(mut self, msg_id: uuid::Uuid) -> Result<(), Error> {
+        let mut entries = self.cache.entries.lock().map_err(|_| Error::Lock)?;
+        if let Some(entry) = entries.get_mut(&self.key) {
+            entry.msg_id = Some(msg_id);
+        }
+        self.completed = true;
+        Ok(())
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        if self.completed {
+            return;
+        }
+        if let Ok(mut entries) = self.cache.entries.lock() {
+            if entries.get(&self.key).is_some_and(|e| e.msg_id.is_none()) {
+                entries.remove(&self.key);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use secp256k1::hashes::{sha256, Hash};
+
+    use super::{Claim, Error, IdempotencyCache};
+
+    #[test]
+    fn reserved_key_is_claimed_once() -> Result<(), Box<dyn std::error::Error>>
+    {
+        let cache = IdempotencyCache::new(std::time::Duration::from_secs(60));
+        let request_hash = sha256::Hash::hash(b"request");
+        let Claim::Reserved(reservation) =
+            cache.claim("retry-1", &request_hash)?
+        else {
+            return Err("key is not reserved".into());
+        };
+        // Concurrent retry while the message is being created
+        assert!(matches!(
+            cache.claim("retry-1", &request_hash),
+            Err(Error::InProgress)
+        ));
+        // Failed request releases the key
+        drop(reservation);
+        let Claim::Reserved(reservation) =
+            cache.claim("retry-1", &request_hash)?
+        else {
+            return Err("released key is not reserved".into());
+        };
+
+        let msg_id = uuid::Uuid::new_v4();
+        reservation.complete(msg_id)?;
+        assert!(matches!(
+            cache.claim("retry-1", &request_hash)?,
+            Claim::Created(id) if id == msg_id
+        ));
+        assert!(matches!(
+            cache.claim("retry-1", &sha256::Hash::hash(b"other")),
+            Err(Error::KeyReused)
+        ));
+        Ok(())
+    }
+}
@@ -1,10 +1,18 @@
 pub mod api;
+pub mod capture;
+pub mod client;
 pub mod config;
 pub mod crypto;
 pub mod domain;
+pub mod encoding;
+pub mod extract;
+pub mod i18n;
+pub mod idempotency;
 pub mod middleware;
 pub mod startup;
 pub mod storage;
+pub mod verification_cache;
+pub mod webhook;
 
 pub fn error_chain_fmt(
     e: &impl std::error::Error,
@@ -22,10 +30,9 @@ pub fn error_chain_fmt(
 #[macro_export]
 macro_rules! impl_debug {
     ($type:ident) => {
-        use $crate::error_chain_fmt;
         impl std::fmt::Debug for $type {
             fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-                error_chain_fmt(self, f)
+                $crate::error_chain_fmt(self, f)
             }
         }
     };
@@ -1,33 +1,39 @@
-use multisig_ecdsa::{config::Settings, startup::Application};
-use tracing::Level;
-use tracing_subscriber::fmt::format::FmtSpan;
+use std::process::ExitCode;
 
-#[tokio::main]
-async fn main() {
-    let subscriber = tracing_subscriber::fmt()
-        .with_timer(tracing_subscriber::fmt::time::ChronoLocal::default())
-        .with_span_events(FmtSpan::NEW | FmtSpan::CLOSE)
-        .with_env_filter(
-            tracing_subscriber::EnvFilter::from_default_env()
-                .add_directive(Level::INFO.into())
-                .add_directive("axum::rejection=trace".parse().unwrap()),
-        )
-        .compact()
-        .with_level(true)
-        .finish();
+use multisig_ecdsa::config::Settings;
+use multisig_ecdsa::startup::{self, Application};
 
-    tracing::subscriber::set_global_default(subscriber)
-        .expect("Failed to set up tracing");
+fn main() -> ExitCode {
+    let config = Settings::load_configuration()
+        .and_then(|config| config.validate().map(|()| config));
 
-    let config =
-        Settings::load_configuration().expect("Failed to load configuration");
-
-    if let Err(e) = Application::build(config)
-        .await
-        .expect("Failed to build application")
-        .run_until_stopped()
-        .await
-    {
-        eprintln!("Error: {}", e);
+    // Validate configuration and exit, without binding a socket
+    if std::env::args().any(|arg| arg.eq("--check-config")) {
+        return match config {
+            Ok(_) => {
+                println!("Configuration is valid");
+                ExitCode::SUCCESS
+            }
+            Err(e) => {
+                eprintln!("{e:#}");
+                ExitCode::FAILURE
+            }
+        };
     }
+
+    let config = config.expect("Failed to load configuration");
+    let runtime =
+        startup::build_runtime(&config).expect("Failed to build runtime");
+    runtime.block_on(async {
+        if let Err(e) = Application::build(config)
+            .await
+            .expect("Failed to build application")
+            .run_until_stopped()
+            .await
+        {
+            eprintln!("Error: {}", e);
+            return ExitCode::FAILURE;
+        }
+        ExitCode::SUCCESS
+    })
 }
@@ -1,17 +1,48 @@
+use std::collections::HashMap;
 use std::fmt::Write;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
 
 use axum::body::Bytes;
+use axum::extract::{ConnectInfo, FromRequestParts, State};
+use axum::middleware::Next;
+use axum::response::IntoResponse;
 use axum::{body::Body, extract::Request, response::Response};
 use futures::future::BoxFuture;
+use futures::FutureExt;
+use futures::StreamExt;
+use http::request::Parts;
 use http::StatusCode;
 use http_body_util::BodyExt;
+use ipnet::IpNet;
 use std::fmt::Display;
 use std::task::Context;
 use std::task::Poll;
 use tower::Layer;
 use tower::Service;
+use tower_http::classify::{ServerErrorsAsFailures, SharedClassifier};
+use tower_http::trace::{
+    DefaultOnRequest, DefaultOnResponse, MakeSpan, TraceLayer,
+};
+use tower_http::LatencyUnit;
 use tracing::Instrument;
 
+use crate::capture::{
+    RequestCapture, Uncaptured, MAX_CAPTURED_BODY_BYTES, REDACTED_HEADERS,
+};
+use crate::crypto;
+use crate::domain::multisig;
+use crate::i18n::{self, Locale};
+use crate::startup::api_doc::CapturedExchange;
+use crate::startup::AppState;
+
+pub const API_KEY_HEADER: &str = "X-API-Key";
+pub const REQUEST_ID_HEADER: &str = "X-Request-Id";
+
+/// Id assigned to every traced request, available in request extensions
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RequestId(pub uuid::Uuid);
+
 /// Create bytes buffer from body
 async fn buffer<B>(body: B) -> Result<Bytes, String>
 where
@@ -28,30 +59,69 @@ where
     Ok(bytes)
 }
 
+/// Values which aren't visible ASCII are rendered as `0x` and hex bytes,
+/// values of `REDACTED_HEADERS` are never written
 fn format_headers(req: &axum::extract::Request) -> String {
     req.headers()
         .iter()
         .fold(String::new(), |mut agg, (name, value)| {
-            if let Err(e) = write!(
-                &mut agg,
-                "\n\t{}:{}",
-                name,
-                value.to_str().unwrap_or("failed to parse")
-            ) {
+            let written = match value.to_str() {
+                _ if REDACTED_HEADERS.contains(&name.as_str()) => {
+                    write!(&mut agg, "\n\t{}:[REDACTED]", name)
+                }
+                Ok(value) => write!(&mut agg, "\n\t{}:{}", name, value),
+                Err(_) => write!(&mut agg, "\n\t{}:0x", name).and_then(|_| {
+                    value
+                        .as_bytes()
+                        .iter()
+                        .try_for_each(|b| write!(&mut agg, "{b:02x}"))
+                }),
+            };
+            if let Err(e) = written {
                 tracing::error!("Failed to format headers: {e}");
             }
             agg
         })
 }
 
+/// Routes whose bodies carry secret keys and are never logged
+fn is_secret_route(path: &str) -> bool {
+    path.ends_with("/address/from-secret")
+}
+
+/// How much of a request is logged
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TraceVerbosity {
+    /// Method and path only
+    Summary,
+    /// Method, path and headers
+    #[default]
+    Headers,
+    /// Method, path, headers and the whole body, which is buffered
+    HeadersAndBody,
+}
+
 #[derive(Clone)]
 pub struct RequestTracingService<S> {
     inner: S,
+    /// Sorted by prefix length, longest first
+    routes: Arc<Vec<(String, TraceVerbosity)>>,
+}
+
+impl<S> RequestTracingService<S> {
+    fn verbosity(&self, path: &str) -> TraceVerbosity {
+        self.routes
+            .iter()
+            .find(|(prefix, _)| path.starts_with(prefix.as_str()))
+            .map(|(_, verbosity)| *verbosity)
+            .unwrap_or_default()
+    }
 }
 
 impl<S> Service<Request> for RequestTracingService<S>
 where
-    S: Service<Request, Response = Response> + Send + 'static,
+    S: Service<Request, Response = Response> + Clone + Send + 'static,
     S::Future: Send + 'static,
     S::Error: Display + std::fmt::Debug + Send,
 {
@@ -66,58 +136,75 @@ where
         self.inner.poll_ready(cx)
     }
 
-    fn call(&mut self, req: Request) -> Self::Future {
-        let span = tracing::info_span!("req_tracing");
+    fn call(&mut self, mut req: Request) -> Self::Future {
+        let request_id = RequestId(uuid::Uuid::new_v4());
+        req.extensions_mut().insert(request_id);
+        let span =
+            tracing::info_span!("req_tracing", request_id = %request_id.0);
 
         let method = req.method().clone();
         let uri = req.uri().clone();
-        span.in_scope(|| {
-            tracing::info!(
-                "Request:\n\t{}: {}{}{}",
-                method.as_str(),
-                uri.path(),
-                if let Some(q) = uri.query() {
-                    format!("?{}", q)
-                } else {
-                    "".to_string()
-                },
-                format_headers(&req)
-            );
-        });
+        let verbosity = self.verbosity(uri.path());
+        let request_line = format!(
+            "{}: {}{}",
+            method.as_str(),
+            uri.path(),
+            if let Some(q) = uri.query() {
+                format!("?{}", q)
+            } else {
+                "".to_string()
+            },
+        );
+        let headers = match verbosity {
+            TraceVerbosity::Summary => String::new(),
+            _ => format_headers(&req),
+        };
 
-        let fut = self.inner.call(req).instrument(span.clone());
+        // Take the service which was driven to readiness
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
 
         Box::pin(
             async move {
-                let result = fut.await;
-                match result {
-                    Ok(res) if res.status().eq(&StatusCode::FORBIDDEN) => {
-                        let (parts, body) = res.into_parts();
+                let req = match verbosity {
+                    TraceVerbosity::HeadersAndBody => {
+                        let (parts, body) = req.into_parts();
                         let bytes = match buffer(body).await {
                             Ok(bytes) => bytes,
                             Err(e) => {
-                                tracing::error!("Error: {e}");
-                                Bytes::new()
-                            }
-                        };
-                        match std::str::from_utf8(&bytes) {
-                            Ok(msg) if !msg.is_empty() => {
                                 tracing::info!(
-                                    "Forbidden request: {}: {}, body: {}",
-                                    method.as_str(),
-                                    uri.path(),
-                                    msg
+                                    "Request:\n\t{request_line}{headers}"
                                 );
-                            }
-                            _ => {
-                                tracing::info!(
-                                    "Forbidden request: {}: {}",
-                                    method.as_str(),
-                                    uri.path()
+                                tracing::error!("Error: {e}");
+                                return Ok(
+                                    StatusCode::BAD_REQUEST.into_response()
                                 );
                             }
-                        }
-                        Ok(Response::from_parts(parts, Body::from(bytes)))
+                        };
+                        let body = match is_secret_route(uri.path()) {
+                            true => "[REDACTED]".into(),
+                            false => String::from_utf8_lossy(&bytes),
+                        };
+                        tracing::info!(
+                            "Request:\n\t{request_line}{headers}\n\tbody: {body}"
+                        );
+                        Request::from_parts(parts, Body::from(bytes))
+                    }
+                    _ => {
+                        tracing::info!("Request:\n\t{request_line}{headers}");
+                        req
+                    }
+                };
+
+                let result = inner.call(req).await.map(|mut res| {
+                    if let Ok(value) = request_id.0.to_string().parse() {
+                        res.headers_mut().insert(REQUEST_ID_HEADER, value);
+                    }
+                    res
+                });
+                match result {
+                    Ok(res) if res.status().eq(&StatusCode::FORBIDDEN) => {
+                        Ok(log_forbidden(&method, uri.path(), res).await)
                     }
                     Err(e) => {
                         tracing::error!("Error: {e}");
@@ -131,13 +218,767 @@ where
     }
 }
 
-#[derive(Clone)]
-pub struct RequestTracingLayer;
+/// Log 403 response along with its body, if there is any
+async fn log_forbidden(
+    method: &http::Method,
+    path: &str,
+    res: Response,
+) -> Response {
+    let (parts, body) = res.into_parts();
+    let bytes = match buffer(body).await {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            tracing::error!("Error: {e}");
+            Bytes::new()
+        }
+    };
+    match std::str::from_utf8(&bytes) {
+        Ok(msg) if !msg.is_empty() => {
+            tracing::info!(
+                "Forbidden request: {}: {}, body: {}",
+                method.as_str(),
+                path,
+                msg
+            );
+        }
+        _ => {
+            tracing::info!("Forbidden request: {}: {}", method.as_str(), path);
+        }
+    }
+    Response::from_parts(parts, Body::from(bytes))
+}
+
+/// Logs every request, verbosity is chosen by the longest matching route
+/// prefix, `TraceVerbosity::Headers` if none matches.
+#[derive(Clone, Default)]
+pub struct RequestTracingLayer {
+    routes: Arc<Vec<(String, TraceVerbosity)>>,
+}
+
+impl RequestTracingLayer {
+    pub fn new(routes: &HashMap<String, TraceVerbosity>) -> Self {
+        let mut routes = routes
+            .iter()
+            .map(|(prefix, verbosity)| (prefix.clone(), *verbosity))
+            .collect::<Vec<_>>();
+        routes.sort_by_key(|(prefix, _)| std::cmp::Reverse(prefix.len()));
+        RequestTracingLayer {
+            routes: Arc::new(routes),
+        }
+    }
+}
 
 impl<S> Layer<S> for RequestTracingLayer {
     type Service = RequestTracingService<S>;
 
     fn layer(&self, inner: S) -> Self::Service {
-        RequestTracingService { inner }
+        RequestTracingService {
+            inner,
+            routes: self.routes.clone(),
+        }
+    }
+}
+
+/// Which layer traces requests
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RequestTracing {
+    /// `RequestTracingLayer`, honours `trace_verbosity`
+    #[default]
+    Custom,
+    /// `tower_http` `TraceLayer`, with the fields log processors expect
+    Standard,
+}
+
+/// Span of `standard_trace_layer`, carries the request id
+#[derive(Debug, Clone, Copy)]
+pub struct RequestIdSpan;
+
+impl<B> MakeSpan<B> for RequestIdSpan {
+    fn make_span(&mut self, req: &http::Request<B>) -> tracing::Span {
+        let request_id = req.extensions().get::<RequestId>().map(|id| id.0);
+        tracing::info_span!(
+            "request",
+            method = %req.method(),
+            uri = %req.uri(),
+            version = ?req.version(),
+            request_id = tracing::field::display(
+                request_id.unwrap_or_default()
+            ),
+        )
+    }
+}
+
+/// `TraceLayer` which logs start and end of every request at `INFO`,
+/// `assign_request_id` must run in front of it
+pub fn standard_trace_layer() -> TraceLayer<
+    SharedClassifier<ServerErrorsAsFailures>,
+    RequestIdSpan,
+    DefaultOnRequest,
+    DefaultOnResponse,
+> {
+    TraceLayer::new_for_http()
+        .make_span_with(RequestIdSpan)
+        .on_request(DefaultOnRequest::new().level(tracing::Level::INFO))
+        .on_response(
+            DefaultOnResponse::new()
+                .level(tracing::Level::INFO)
+                .latency_unit(LatencyUnit::Millis),
+        )
+}
+
+/// Assigns `RequestId` and returns it in `X-Request-Id`, as
+/// `RequestTracingLayer` does.
+pub async fn assign_request_id(mut req: Request, next: Next) -> Response {
+    let request_id = RequestId(uuid::Uuid::new_v4());
+    req.extensions_mut().insert(request_id);
+    let mut res = next.run(req).await;
+    if let Ok(value) = request_id.0.to_string().parse() {
+        res.headers_mut().insert(REQUEST_ID_HEADER, value);
+    }
+    res
+}
+
+/// Logs bodies of 403 responses, as `RequestTracingLayer` does
+pub async fn log_forbidden_bodies(req: Request, next: Next) -> Response {
+    let method = req.method().clone();
+    let path = req.uri().path().to_owned();
+    let res = next.run(req).await;
+    match res.status() {
+        StatusCode::FORBIDDEN => log_forbidden(&method, &path, res).await,
+        _ => res,
+    }
+}
+
+/// Response of a coalesced request, shared by everyone who waited for it
+#[derive(Clone)]
+struct SharedResponse {
+    status: StatusCode,
+    headers: http::HeaderMap,
+    extensions: http::Extensions,
+    body: Bytes,
+}
+
+impl From<SharedResponse> for Response {
+    fn from(shared: SharedResponse) -> Self {
+        let mut response = Response::new(Body::from(shared.body));
+        *response.status_mut() = shared.status;
+        *response.headers_mut() = shared.headers;
+        *response.extensions_mut() = shared.extensions;
+        response
+    }
+}
+
+type SharedFuture = futures::future::Shared<BoxFuture<'static, SharedResponse>>;
+type InFlight = Arc<std::sync::Mutex<HashMap<String, SharedFuture>>>;
+
+/// Removes the leader's key once it is done, completed or not: a panic
+/// of the handler would leave a poisoned future in place otherwise.
+struct InFlightEntry {
+    registry: InFlight,
+    key: String,
+}
+
+impl Drop for InFlightEntry {
+    fn drop(&mut self) {
+        if let Ok(mut in_flight) = self.registry.lock() {
+            in_flight.remove(&self.key);
+        }
+    }
+}
+
+/// Coalesces concurrent identical `GET`s: only the first one reaches the
+/// inner service, the rest get a copy of its response. Requests are
+/// identical if method, URI, `Accept` and `Accept-Language` match.
+#[derive(Clone, Default)]
+pub struct SingleFlightLayer {
+    in_flight: InFlight,
+}
+
+impl<S> Layer<S> for SingleFlightLayer {
+    type Service = SingleFlightService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        SingleFlightService {
+            inner,
+            in_flight: self.in_flight.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct SingleFlightService<S> {
+    inner: S,
+    in_flight: InFlight,
+}
+
+impl<S> Service<Request> for SingleFlightService<S>
+where
+    S: Service<Request, Response = Response, Error = std::convert::Infallible>
+        + Clone
+        + Send
+        + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = Response;
+    type Error = std::convert::Infallible;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request) -> Self::Future {
+        // Take the service which was driven to readiness
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        if req.method().ne(&http::Method::GET) {
+            return Box::pin(inner.call(req));
+        }
+
+        let header = |name| {
+            req.headers()
+                .get(name)
+                .map(|v| String::from_utf8_lossy(v.as_bytes()).into_owned())
+                .unwrap_or_default()
+        };
+        let key = format!(
+            "{} {}\n{}\n{}",
+            req.method(),
+            req.uri(),
+            header(http::header::ACCEPT),
+            header(http::header::ACCEPT_LANGUAGE),
+        );
+        let Ok(mut in_flight) = self.in_flight.lock() else {
+            return Box::pin(inner.call(req));
+        };
+        let shared = match in_flight.get(&key) {
+            Some(shared) => shared.clone(),
+            None => {
+                let entry = InFlightEntry {
+                    registry: self.in_flight.clone(),
+                    key: key.clone(),
+                };
+                let leader = async move {
+                    let Ok(response) = inner.call(req).await;
+                    let (parts, body) = response.into_parts();
+                    let body = buffer(body).await.unwrap_or_else(|e| {
+                        tracing::error!("Error: {e}");
+                        Bytes::new()
+                    });
+                    drop(entry);
+                    SharedResponse {
+                        status: parts.status,
+                        headers: parts.headers,
+                        extensions: parts.extensions,
+                        body,
+                    }
+                };
+                let shared = leader.boxed().shared();
+                in_flight.insert(key, shared.clone());
+                shared
+            }
+        };
+        Box::pin(async move { Ok(shared.await.into()) })
+    }
+}
+
+/// Rejects requests which mutate state (anything but `GET`) without valid
+/// `X-API-Key` header, if api key is configured.
+pub async fn require_api_key(
+    State(state): State<AppState>,
+    req: Request,
+    next: Next,
+) -> Response {
+    if req.method().eq(&http::Method::GET) || has_api_key(&state, &req) {
+        return next.run(req).await;
+    }
+    StatusCode::UNAUTHORIZED.into_response()
+}
+
+/// Same as `require_api_key`, but for every method. Startup layers admin
+/// routes with it, as they expose captured traffic.
+pub async fn require_api_key_always(
+    State(state): State<AppState>,
+    req: Request,
+    next: Next,
+) -> Response {
+    if has_api_key(&state, &req) {
+        return next.run(req).await;
+    }
+    StatusCode::UNAUTHORIZED.into_response()
+}
+
+/// Whether `req` has valid `X-API-Key`, or no api key is configured
+fn has_api_key(state: &AppState, req: &Request) -> bool {
+    let Some(api_key) = state.settings.api_key.as_ref() else {
+        return true;
+    };
+    req.headers().get(API_KEY_HEADER).is_some_and(|provided| {
+        crypto::constant_time_eq(provided.as_bytes(), api_key.as_bytes())
+    })
+}
+
+/// Address of the client, which is the peer unless it is
+/// `trusted_proxy`. IPv4 clients connected over IPv6 (`::ffff:a.b.c.d`)
+/// are reported as IPv4.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClientIp(pub IpAddr);
+
+impl<S: Send + Sync> FromRequestParts<S> for ClientIp {
+    type Rejection = StatusCode;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        _: &S,
+    ) -> Result<Self, Self::Rejection> {
+        if let Some(client) = parts.extensions.get::<ClientIp>() {
+            return Ok(*client);
+        }
+        // Without `resolve_client_ip` in front only the peer is known
+        parts
+            .extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(peer)| ClientIp(peer.ip().to_canonical()))
+            .ok_or(StatusCode::INTERNAL_SERVER_ERROR)
+    }
+}
+
+/// Forwarding headers are untrusted, or name no valid address
+#[derive(Debug, PartialEq, Eq)]
+pub struct ForwardedError;
+
+/// Client address of a request from `peer`. Forwarding headers are only
+/// honoured from `trusted`, and the last hop they list is used, since
+/// that is the one appended by the proxy itself.
+pub fn client_ip(
+    peer: IpAddr,
+    headers: &http::HeaderMap,
+    trusted: Option<&IpNet>,
+) -> Result<IpAddr, ForwardedError> {
+    let peer = peer.to_canonical();
+    let forwarded = last_forwarded_for(headers);
+    let Some(trusted) = trusted else {
+        return Ok(peer);
+    };
+    match (trusted.contains(&peer), forwarded) {
+        (true, Some(forwarded)) => {
+            forwarded.map(|ip| ip.to_canonical()).ok_or(ForwardedError)
+        }
+        (false, Some(_)) => Err(ForwardedError),
+        (_, None) => Ok(peer),
+    }
+}
+
+/// Last hop of `Forwarded`, or of `X-Forwarded-For` if the former is
+/// absent. `Some(None)` if the header is present but malformed.
+fn last_forwarded_for(headers: &http::HeaderMap) -> Option<Option<IpAddr>> {
+    let last = |name: &str, separator: char| {
+        headers
+            .get_all(name)
+            .iter()
+            .next_back()
+            .map(|v| v.to_str().ok()?.rsplit(separator).next())
+    };
+    if let Some(element) = last("forwarded", ',') {
+        return Some(element.and_then(|element| {
+            let node = element.split(';').find_map(|pair| {
+                let (key, value) = pair.trim().split_once('=')?;
+                key.eq_ignore_ascii_case("for").then_some(value)
+            })?;
+            parse_node(node.trim_matches('"'))
+        }));
+    }
+    last("x-forwarded-for", ',')
+        .map(|hop| hop.and_then(|hop| parse_node(hop.trim())))
+}
+
+/// Parses `1.2.3.4`, `1.2.3.4:80`, `2001:db8::1` or `[2001:db8::1]:80`
+fn parse_node(node: &str) -> Option<IpAddr> {
+    node.parse()
+        .ok()
+        .or_else(|| node.parse::<SocketAddr>().ok().map(|a| a.ip()))
+        .or_else(|| {
+            let ip = node.strip_prefix('[')?.strip_suffix(']')?;
+            ip.parse().ok()
+        })
+}
+
+/// Resolves `ClientIp` of every request, see `client_ip`. Requests with
+/// forwarding headers from untrusted peers are rejected with 400.
+pub async fn resolve_client_ip(
+    State(state): State<AppState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    mut req: Request,
+    next: Next,
+) -> Response {
+    let trusted = state.settings.trusted_proxy.as_ref();
+    match client_ip(peer.ip(), req.headers(), trusted) {
+        Ok(ip) => {
+            req.extensions_mut().insert(ClientIp(ip));
+            next.run(req).await
+        }
+        Err(ForwardedError) => {
+            tracing::warn!(%peer, "rejected forwarding headers");
+            StatusCode::BAD_REQUEST.into_response()
+        }
+    }
+}
+
+/// Rejects clients outside of `admin_allowed_cidrs` with 403.
+pub async fn restrict_admin_peers(
+    State(state): State<AppState>,
+    ClientIp(ip): ClientIp,
+    req: Request,
+    next: Next,
+) -> Response {
+    let Some(allowed) = state.settings.admin_allowed_cidrs.as_ref() else {
+        return next.run(req).await;
+    };
+    match allowed.iter().any(|cidr| cidr.contains(&ip)) {
+        true => next.run(req).await,
+        false => {
+            tracing::warn!(%ip, "admin route requested by disallowed peer");
+            StatusCode::FORBIDDEN.into_response()
+        }
+    }
+}
+
+/// Rejects message creation with 503 when storage holds `max_messages`
+/// already. Signing and verification of stored messages are unaffected.
+pub async fn shed_msg_creation(
+    State(state): State<AppState>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let Some(max_messages) = state.settings.max_messages else {
+        return next.run(req).await;
+    };
+    // Path looks like `/api/{version}/{route}`
+    let route = req
+        .uri()
+        .path()
+        .strip_prefix("/api/")
+        .and_then(|p| p.split_once('/'))
+        .map(|(_, route)| route);
+    let creates_msg = req.method().eq(&http::Method::POST)
+        && route.is_some_and(|r| {
+            matches!(r, "msg" | "msgs" | "msg/upload") || r.ends_with("/clone")
+        });
+    if !creates_msg {
+        return next.run(req).await;
+    }
+    match state.storage.count_messages().await {
+        Ok(count) if count >= max_messages => {
+            tracing::warn!(count, max_messages, "shedding message creation");
+            StatusCode::SERVICE_UNAVAILABLE.into_response()
+        }
+        Ok(_) => next.run(req).await,
+        Err(e) => crate::api::ErrorResponse::from(e).into_response(),
+    }
+}
+
+/// Translates bodies of domain errors to the `Accept-Language` language.
+/// Other responses and English requests pass through as is.
+pub async fn localize_errors(req: Request, next: Next) -> Response {
+    let locale = Locale::from_headers(req.headers());
+    let response = next.run(req).await;
+    if locale.eq(&Locale::En) {
+        return response;
+    }
+    let Some(error) = response.extensions().get::<multisig::Error>().cloned()
+    else {
+        return response;
+    };
+    let (mut parts, _) = response.into_parts();
+    parts.headers.remove(http::header::CONTENT_LENGTH);
+    parts.headers.insert(
+        http::header::CONTENT_LANGUAGE,
+        http::HeaderValue::from_static(locale.tag()),
+    );
+    Response::from_parts(
+        parts,
+        Body::from(i18n::multisig_error(&error, locale)),
+    )
+}
+
+/// Record request/response pairs into `RequestCapture` if it is enabled
+pub async fn capture_requests(
+    State(capture): State<Option<RequestCapture>>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let Some(capture) = capture else {
+        return next.run(req).await;
+    };
+    let method = req.method().to_string();
+    let path = req
+        .uri()
+        .path_and_query()
+        .map_or(req.uri().path(), |p| p.as_str())
+        .to_string();
+    let secret = is_secret_route(req.uri().path());
+    let request_headers = captured_headers(req.headers());
+    let (parts, body) = req.into_parts();
+    let (body, request_body, request_body_truncated) =
+        match captured_body(body, secret).await {
+            Ok(captured) => captured,
+            Err(e) => {
+                tracing::error!("Error: {e}");
+                return StatusCode::BAD_REQUEST.into_response();
+            }
+        };
+
+    let response = next.run(Request::from_parts(parts, body)).await;
+    if response.extensions().get::<Uncaptured>().is_some() {
+        return response;
+    }
+    let status = response.status().as_u16();
+    let response_headers = captured_headers(response.headers());
+    let (parts, body) = response.into_parts();
+    let (body, response_body, response_body_truncated) =
+        match captured_body(body, false).await {
+            Ok(captured) => captured,
+            Err(e) => {
+                tracing::error!("Error: {e}");
+                return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+            }
+        };
+    capture.record(CapturedExchange {
+        method,
+        path,
+        request_headers,
+        request_body,
+        request_body_truncated,
+        status,
+        response_headers,
+        response_body,
+        response_body_truncated,
+    });
+    Response::from_parts(parts, body)
+}
+
+fn captured_headers(headers: &http::HeaderMap) -> Vec<(String, String)> {
+    headers
+        .iter()
+        .map(|(name, value)| {
+            let value = match REDACTED_HEADERS.contains(&name.as_str()) {
+                true => "[REDACTED]".to_string(),
+                false => String::from_utf8_lossy(value.as_bytes()).into(),
+            };
+            (name.to_string(), value)
+        })
+        .collect()
+}
+
+/// Buffer up to `MAX_CAPTURED_BODY_BYTES` of the body to capture, with
+/// whether there is more. The body is passed on whole either way, its
+/// rest streams through unbuffered.
+async fn captured_body(
+    body: Body,
+    redact: bool,
+) -> Result<(Body, String, bool), String> {
+    if redact {
+        return Ok((body, "[REDACTED]".to_string(), false));
+    }
+    let mut stream = body.into_data_stream();
+    let mut head = Vec::new();
+    let mut len = 0;
+    while len <= MAX_CAPTURED_BODY_BYTES {
+        let Some(chunk) = stream.next().await else {
+            let bytes = Bytes::from(head.concat());
+            let captured = String::from_utf8_lossy(&bytes).into();
+            return Ok((Body::from(bytes), captured, false));
+        };
+        let chunk = chunk.map_err(|e| format!("failed to read body: {e}"))?;
+        len += chunk.len();
+        head.push(chunk);
+    }
+    let captured = head.concat();
+    let captured =
+        String::from_utf8_lossy(&captured[..MAX_CAPTURED_BODY_BYTES]).into();
+    let rest = futures::stream::iter(head.into_iter().map(Ok)).chain(stream);
+    Ok((Body::from_stream(rest), captured, true))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use axum::body::Body;
+    use axum::extract::Request;
+    use axum::response::Response;
+    use tower::{Layer, ServiceExt};
+
+    use super::{
+        buffer, client_ip, format_headers, ForwardedError, SingleFlightLayer,
+    };
+
+    #[test]
+    fn binary_header_values_are_logged_as_hex(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let req = Request::builder()
+            .header("x-text", "plain")
+            .header(
+                "x-binary",
+                http::HeaderValue::from_bytes(&[0xff, 0x61, 0xab])?,
+            )
+            .body(Body::empty())?;
+        let headers = format_headers(&req);
+        assert!(headers.contains("x-text:plain"));
+        assert!(headers.contains("x-binary:0xff61ab"));
+        Ok(())
+    }
+
+    #[test]
+    fn credential_headers_are_not_logged(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let req = Request::builder()
+            .header("x-api-key", "secret-api-key")
+            .header("authorization", "Bearer secret-token")
+            .header("cookie", "session=secret-session")
+            .header("x-text", "plain")
+            .body(Body::empty())?;
+        let headers = format_headers(&req);
+        assert!(!headers.contains("secret"));
+        assert!(headers.contains("x-api-key:[REDACTED]"));
+        assert!(headers.contains("x-text:plain"));
+        Ok(())
+    }
+
+    #[test]
+    fn client_ip_is_forwarded_only_by_trusted_proxy(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let proxy = "10.0.0.1".parse()?;
+        let trusted = "10.0.0.0/30".parse()?;
+        let headers = |pairs: &[(&'static str, &'static str)]| {
+            let mut headers = http::HeaderMap::new();
+            for (name, value) in pairs {
+                headers.append(*name, http::HeaderValue::from_static(value));
+            }
+            headers
+        };
+        let xff = headers(&[("x-forwarded-for", "1.1.1.1, 203.0.113.7")]);
+        let forwarded = headers(&[(
+            "forwarded",
+            "for=1.1.1.1, for=\"[2001:db8::7]:4711\";proto=https",
+        )]);
+
+        assert_eq!(
+            client_ip(proxy, &xff, Some(&trusted)),
+            Ok("203.0.113.7".parse()?)
+        );
+        assert_eq!(
+            client_ip(proxy, &forwarded, Some(&trusted)),
+            Ok("2001:db8::7".parse()?)
+        );
+        assert_eq!(client_ip(proxy, &headers(&[]), Some(&trusted)), Ok(proxy));
+        assert_eq!(
+            client_ip(
+                proxy,
+                &headers(&[("x-forwarded-for", "unknown")]),
+                Some(&trusted)
+            ),
+            Err(ForwardedError)
+        );
+
+        // Spoofed by a client connecting directly
+        let client = "192.0.2.1".parse()?;
+        assert_eq!(
+            client_ip(client, &xff, Some(&trusted)),
+            Err(ForwardedError)
+        );
+        assert_eq!(
+            client_ip(client, &headers(&[]), Some(&trusted)),
+            Ok(client)
+        );
+        // Without a proxy configured the headers mean nothing
+        assert_eq!(client_ip(proxy, &xff, None), Ok(proxy));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn concurrent_identical_gets_run_handler_once(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let handler = {
+            let calls = calls.clone();
+            tower::service_fn(move |_: Request| {
+                let calls = calls.clone();
+                async move {
+                    let call = calls.fetch_add(1, Ordering::SeqCst);
+                    tokio::time::sleep(Duration::from_millis(50)).await;
+                    Ok::<_, std::convert::Infallible>(Response::new(
+                        Body::from(format!("call {call}")),
+                    ))
+                }
+            })
+        };
+        let service = SingleFlightLayer::default().layer(handler);
+        let get = |uri: &'static str| {
+            let service = service.clone();
+            async move {
+                let request = Request::get(uri).body(Body::empty())?;
+                let Ok(response) = service.oneshot(request).await;
+                let body = buffer(response.into_body()).await?;
+                Ok::<_, Box<dyn std::error::Error>>(body)
+            }
+        };
+
+        let bodies = futures::future::try_join_all(
+            (0..10).map(|_| get("/api/v1/msg/1")),
+        )
+        .await?;
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert!(bodies.iter().all(|body| body.as_ref() == b"call 0"));
+
+        // Completed requests aren't cached, other paths aren't coalesced
+        let (again, other) =
+            tokio::try_join!(get("/api/v1/msg/1"), get("/api/v1/msg/2"))?;
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+        assert_ne!(again, other);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn panicked_leader_is_not_reused(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let handler = {
+            let calls = calls.clone();
+            tower::service_fn(move |_: Request| {
+                let call = calls.fetch_add(1, Ordering::SeqCst);
+                async move {
+                    if call == 0 {
+                        panic!("handler failed");
+                    }
+                    Ok::<_, std::convert::Infallible>(Response::new(
+                        Body::from("recovered"),
+                    ))
+                }
+            })
+        };
+        let service = SingleFlightLayer::default().layer(handler);
+        let get = || {
+            let service = service.clone();
+            async move {
+                let request =
+                    Request::get("/api/v1/msg/1").body(Body::empty())?;
+                let Ok(response) = service.oneshot(request).await;
+                let body = buffer(response.into_body()).await?;
+                Ok::<_, Box<dyn std::error::Error + Send + Sync>>(body)
+            }
+        };
+
+        assert!(tokio::spawn(get()).await.is_err());
+        let body = tokio::spawn(get()).await?.map_err(|e| e.to_string())?;
+        assert_eq!(body.as_ref(), b"recovered");
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+        Ok(())
     }
 }
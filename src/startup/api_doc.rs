@@ -2,90 +2,605 @@
 //! and derive ToResponse to all types we bind as `response = Type`.
 //! We only need ToSchema derived if we set response as `body = Type`.
 
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-//use utoipa::{OpenApi, ToResponse};
-//use utoipauto::utoipauto;
+use utoipa::openapi::security::{ApiKey, ApiKeyValue, SecurityScheme};
+use utoipa::openapi::Server;
+use utoipa::{IntoParams, Modify, OpenApi, ToResponse, ToSchema};
+use utoipauto::utoipauto;
 
 // ───── ErrorResponses ───────────────────────────────────────────────────── //
 
-//#[derive(ToResponse)]
-//#[response(description = "Something happened on the server")]
-//pub struct InternalErrorResponse;
-//
-//// We use middleware to make json response from BadRequest
-//#[allow(dead_code)]
-//#[derive(ToResponse)]
-//#[response(
-//    description = "Request was formed erroneously",
-//    content_type = "application/json",
-//    example = json!({
-//        "caused_by":
-//        "Here will be the reason of a rejection"
-//    }),
-//)]
-//pub struct BadRequestResponse(String);
-//
-//#[derive(ToResponse)]
-//#[response(description = "Conflict error")]
-//pub struct ConflictErrorResponse;
-//
-//// We use ToSchema here, because we write manually in every case,
-//// inlined, description, examples etc.
-//#[allow(dead_code)]
-//#[derive(ToResponse)]
-//#[response(
-//    description = "Not found some data (param name passed)",
-//    content_type = "application/json",
-//    example = json!({
-//        "param": "param_name" }),
-//)]
-//pub struct NotFoundResponse {
-//    param: String,
-//}
+#[derive(ToResponse)]
+#[response(description = "Something happened on the server")]
+pub struct InternalErrorResponse;
+
+// We use middleware to make json response from BadRequest
+#[allow(dead_code)]
+#[derive(ToResponse)]
+#[response(
+    description = "Request was formed erroneously",
+    content_type = "application/json",
+    example = json!({
+        "caused_by":
+        "Here will be the reason of a rejection"
+    }),
+)]
+pub struct BadRequestResponse(String);
+
+#[derive(ToResponse)]
+#[response(description = "Conflict error")]
+pub struct ConflictErrorResponse;
+
+#[derive(ToResponse)]
+#[response(description = "`If-Match` doesn't match the current `ETag`")]
+pub struct PreconditionFailedResponse;
+
+#[derive(ToResponse)]
+#[response(description = "Action isn't allowed anymore, the body tells why")]
+pub struct ForbiddenResponse;
+
+#[derive(ToResponse)]
+#[response(description = "Missing or invalid `X-API-Key` header")]
+pub struct UnauthorizedResponse;
+
+#[allow(dead_code)]
+#[derive(ToResponse)]
+#[response(
+    description = "Request body is well-formed, but some fields are invalid",
+    content_type = "application/json",
+    example = json!({
+        "errors": [{
+            "field": "keys",
+            "message": "at least one key is required"
+        }]
+    }),
+)]
+pub struct ValidationErrorResponse(String);
+
+// We use ToSchema here, because we write manually in every case,
+// inlined, description, examples etc.
+#[allow(dead_code)]
+#[derive(ToResponse)]
+#[response(
+    description = "Not found some data (param name passed)",
+    content_type = "application/json",
+    example = json!({
+        "param": "param_name" }),
+)]
+pub struct NotFoundResponse {
+    param: String,
+}
 
 // ───── Requests ─────────────────────────────────────────────────────────── //
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, IntoParams)]
 pub struct Username {
     pub name: Option<String>,
+    /// Generate that many keys along with the user
+    pub with_keys: Option<usize>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct UserParams {
+    /// Include signed messages count of every key, all messages are
+    /// scanned for it
+    #[serde(default)]
+    pub with_stats: bool,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct UserSearch {
+    /// Name prefix
+    pub q: String,
+    /// Max users to return
+    pub limit: Option<usize>,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct RenameUserRequest {
+    /// Must not be taken by another user
+    pub name: String,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, ToSchema, JsonSchema)]
+#[serde(rename_all = "camelCase")]
 pub struct PostMsgRequest {
     pub content: String,
-    /// Shortened PKHs
+    /// Shortened PKHs of stored keys
+    #[serde(default)]
+    pub keys: Vec<String>,
+    /// Compressed public keys in hex the service doesn't hold, they sign
+    /// with `sign-raw` only
+    #[serde(default)]
+    pub pubkeys: Vec<String>,
+    /// At least `count` signatures to aprove
+    #[serde(alias = "required_signature_count")]
+    pub required_signature_count: Option<usize>,
+    /// Signed preimage becomes a tag, content length, content and the
+    /// nonce, distinguishes messages with the same content
+    #[serde(default)]
+    pub nonce: Option<u64>,
+    /// Any key may sign, `keys` must be empty and
+    /// `required_signature_count` set
+    #[serde(default)]
+    pub open: bool,
+    /// `content` is hex of a 32 byte digest which is signed as is, e.g.
+    /// a Bitcoin sighash
+    #[serde(default, alias = "content_is_digest")]
+    pub content_is_digest: bool,
+    /// Free-form categories to filter messages by
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// Signatures are refused with 403 after that time
+    #[serde(
+        default,
+        alias = "sign_deadline",
+        with = "time::serde::rfc3339::option"
+    )]
+    #[schema(value_type = Option<String>, format = DateTime)]
+    #[schemars(with = "Option<String>")]
+    pub sign_deadline: Option<time::OffsetDateTime>,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct MsgsFilter {
+    /// Only messages with that tag
+    pub tag: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct BatchGetMsgsRequest {
+    pub ids: Vec<uuid::Uuid>,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+#[schema(example = json!({
+    "keys": ["1BoatSLRHtKNngkdXEeobR76b53LETtpyT"]
+}))]
+#[serde(rename_all = "camelCase")]
+pub struct VerifyAgainstRequest {
+    /// Expected participants, addresses or compressed public keys in hex
     pub keys: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct VerifyAgainstResult {
+    /// Supplied keys with a valid signature
+    pub matched: Vec<String>,
+    /// Supplied keys without a valid signature
+    pub unmatched: Vec<String>,
+    /// Addresses of valid signers which aren't supplied
+    pub unexpected: Vec<String>,
+    /// Whether supplied keys are exactly the recorded participants
+    pub participants_match: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+#[schema(example = json!({
+    "address": "mpLQjfK79b7CCV4VMJWEWAj5Mpx8Up5zxB",
+    "message": "This is just a test message",
+    "signature": "INbVnW4e6PeRmsv2Qgu8NuopvrVjkcxob+sX8OcZG0SALhWybUjzMLPdAsXI46YZGb0KQTRii+wWIQzRpG/U+S0="
+}))]
+#[serde(rename_all = "camelCase")]
+pub struct VerifyMessageRequest {
+    /// Expected signer address
+    pub address: String,
+    /// Signed text
+    pub message: String,
+    /// Base64 signature, as produced by `bitcoin-cli signmessage`
+    pub signature: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct VerifyMessageResult {
+    /// Whether the signature recovers to the expected address
+    pub valid: bool,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ByHashParams {
+    /// `msg_hash` is the content of a digest message, not sha256 of a
+    /// plain one
+    #[serde(default)]
+    pub digest: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+#[schema(example = json!({
+    "content": "Hello world!"
+}))]
+pub struct VerifyContentRequest {
+    /// Content to check against the message, hex if the message content
+    /// is a digest. In the body, so it doesn't end up in request logs.
+    pub content: String,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct UploadMsgParams {
+    /// Comma-separated shortened PKHs
+    pub keys: String,
     /// At least `count` signatures to aprove
     pub required_signature_count: Option<usize>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Default, Serialize, Deserialize, ToSchema, JsonSchema)]
+#[serde(rename_all = "camelCase")]
 pub struct SignMsgRequest {
+    /// Shortened PKHs of stored keys
+    #[serde(default)]
     pub keys: Vec<String>,
+    /// Signs with every key of the user which is a participant, instead
+    /// of `keys`
+    #[serde(default)]
+    pub username: Option<String>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct SignRawMsgRequest {
+    /// Shortened PKH of the participant
+    pub address: String,
+    /// DER-encoded signature in hex
+    #[serde(alias = "signature_der_hex")]
+    pub signature_der_hex: String,
+    /// Compressed public key in hex, required to join open messages
+    #[serde(default, alias = "pubkey_hex")]
+    pub pubkey_hex: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct AddressFromSecretRequest {
+    /// Secret key as 64 hex digits or WIF
+    pub secret: String,
+}
+
+impl std::fmt::Debug for AddressFromSecretRequest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AddressFromSecretRequest")
+            .field("secret", &"[REDACTED]")
+            .finish()
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct PatchParticipantsRequest {
+    /// Stored keys to add as participants
+    #[serde(default)]
+    pub add: Vec<String>,
+    /// Participants to remove
+    #[serde(default)]
+    pub remove: Vec<String>,
 }
 
 // ───── Responses ────────────────────────────────────────────────────────── //
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, Deserialize, ToSchema, JsonSchema)]
+#[schema(example = json!({
+    "id": "67e55044-10b1-426f-9247-bb680e5fe0c8",
+    "name": "alice",
+    "keys": ["1BvBMSEYstWetqTFn5Au4m4GFg7xJaNVN2"],
+    "createdAt": "2025-02-01T12:00:00Z",
+    "updatedAt": "2025-02-01T12:05:00Z"
+}))]
+#[serde(rename_all = "camelCase")]
 pub struct User {
     pub id: uuid::Uuid,
     pub name: String,
     /// Key is shortened PKH
     pub keys: Vec<String>,
+    #[serde(with = "time::serde::rfc3339")]
+    #[schema(value_type = String, format = DateTime)]
+    #[schemars(with = "String")]
+    pub created_at: time::OffsetDateTime,
+    #[serde(with = "time::serde::rfc3339")]
+    #[schema(value_type = String, format = DateTime)]
+    #[schemars(with = "String")]
+    pub updated_at: time::OffsetDateTime,
+    /// Activity of every key in `keys` order, only with `with_stats`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_stats: Option<Vec<KeyStats>>,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct KeyStats {
+    /// Shortened PKH of the key
+    pub address: String,
+    /// Messages the key has signed
+    pub signed_count: usize,
+}
+
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    Serialize,
+    Deserialize,
+    ToSchema,
+    JsonSchema,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum ContentEncoding {
+    Text,
+    Base64,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema, JsonSchema)]
+#[schema(example = json!({
+    "id": "67e55044-10b1-426f-9247-bb680e5fe0c8",
+    "content": "Hello world!",
+    "contentEncoding": "text",
+    "contentStored": true,
+    "nonce": null,
+    "countRequired": 2,
+    "progress": 0.5,
+    "tags": ["treasury"],
+    "createdAt": "2025-02-01T12:00:00Z",
+    "updatedAt": "2025-02-01T12:05:00Z"
+}))]
+#[serde(rename_all = "camelCase")]
+pub struct MessageDetail {
+    pub id: uuid::Uuid,
+    /// UTF-8 text, or base64 if content is binary
+    pub content: String,
+    pub content_encoding: ContentEncoding,
+    /// `false` if the server keeps only the digest, `content` is empty then
+    pub content_stored: bool,
+    /// Signed digest is sha256 of `multisig_ecdsa/nonce\0`, big-endian
+    /// u64 content length, content and big-endian nonce
+    pub nonce: Option<u64>,
+    pub count_required: usize,
+    /// Signing progress in `0.0..=1.0`
+    pub progress: f32,
+    pub tags: Vec<String>,
+    /// Start of the signing round
+    #[serde(with = "time::serde::rfc3339")]
+    #[schema(value_type = String, format = DateTime)]
+    #[schemars(with = "String")]
+    pub created_at: time::OffsetDateTime,
+    /// Last signature or other change
+    #[serde(with = "time::serde::rfc3339")]
+    #[schema(value_type = String, format = DateTime)]
+    #[schemars(with = "String")]
+    pub updated_at: time::OffsetDateTime,
+    /// Signatures are refused after that time
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    #[schema(value_type = Option<String>, format = DateTime)]
+    #[schemars(with = "Option<String>")]
+    pub sign_deadline: Option<time::OffsetDateTime>,
+}
+
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum SignOutcome {
+    Signed,
+    /// Key had signed before, its signature is kept
+    AlreadySigned,
+    /// Key isn't a participant of the message, nothing is signed
+    NotParticipant,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+#[schema(example = json!({
+    "address": "1BvBMSEYstWetqTFn5Au4m4GFg7xJaNVN2",
+    "outcome": "already_signed"
+}))]
+#[serde(rename_all = "camelCase")]
+pub struct KeySignOutcome {
+    pub address: String,
+    pub outcome: SignOutcome,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+#[schema(example = json!({
+    "id": "67e55044-10b1-426f-9247-bb680e5fe0c8",
+    "sha256": "c0535e4be2b79ffd93291305436bf889314e4a3faec05ecffcbb7df31ad9e51a"
+}))]
+#[serde(rename_all = "camelCase")]
+pub struct UploadMsgResponse {
+    pub id: uuid::Uuid,
+    /// Hex sha256 of the uploaded content
+    pub sha256: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+#[schema(example = json!({
+    "id": "67e55044-10b1-426f-9247-bb680e5fe0c8",
+    "required": 2,
+    "participants": 3
+}))]
+pub struct CreatedMsg {
+    pub id: uuid::Uuid,
+    /// Effective threshold, after the default policy and clamping
+    pub required: usize,
+    /// Distinct participants, `0` for open messages
+    pub participants: usize,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+#[schema(example = json!({
+    "participants": [
+        "1BvBMSEYstWetqTFn5Au4m4GFg7xJaNVN2",
+        "1BoatSLRHtKNngkdXEeobR76b53LETtpyT"
+    ],
+    "required": 2,
+    "content": "Hello world!",
+    "contentEncoding": "text",
+    "contentStored": true
+}))]
+#[serde(rename_all = "camelCase")]
+pub struct MsgPreview {
+    /// Addresses of participants, empty for open messages
+    pub participants: Vec<String>,
+    /// Effective signatures count to approve
+    pub required: usize,
+    /// UTF-8 text, or base64 if content is binary
+    pub content: String,
+    pub content_encoding: ContentEncoding,
+    /// `false` if the server would keep only the digest
+    pub content_stored: bool,
+}
+
+/// Result of one message of a bulk creation
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+#[serde(tag = "outcome", rename_all = "snake_case")]
+pub enum CreateMsgOutcome {
+    Created(CreatedMsg),
+    /// Message isn't created, `status` is what a single request would get
+    Failed {
+        status: u16,
+        error: String,
+    },
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, ToSchema)]
+#[schema(example = json!({
+    "complete": 3,
+    "pending": 1,
+    "invalid": 1,
+    "invalidIds": ["67e55044-10b1-426f-9247-bb680e5fe0c8"]
+}))]
+#[serde(rename_all = "camelCase")]
+pub struct VerifyAllResponse {
+    /// Messages with enough valid signatures
+    pub complete: usize,
+    /// Messages with valid signatures, waiting for more
+    pub pending: usize,
+    /// Messages with signatures which don't match the content
+    pub invalid: usize,
+    pub invalid_ids: Vec<uuid::Uuid>,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+#[schema(example = json!({
+    "success": false,
+    "code": "not_enough_signatures",
+    "reason": "Not enough signatures, provided: 1, required: 2",
+    "signed": 1,
+    "required": 2
+}))]
+#[serde(rename_all = "camelCase")]
+pub struct VerificationResult {
+    pub success: bool,
+    /// Machine-readable reason of the failure, never localized
+    #[serde(default)]
+    pub code: Option<String>,
+    /// Why verification failed, in the `Accept-Language` language
+    pub reason: Option<String>,
+    /// Collected signatures count
+    pub signed: usize,
+    /// Required signatures count
+    pub required: usize,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+#[schema(example = json!({
+    "version": "0.2.0",
+    "commit": "2361150d0c4a3e1b5f6a7d8e9f0a1b2c3d4e5f60",
+    "builtAt": "2025-02-01T12:00:00Z"
+}))]
+#[serde(rename_all = "camelCase")]
+pub struct VersionInfo {
+    /// Crate version
+    pub version: String,
+    /// Git commit the binary is built from, `unknown` outside a checkout
+    pub commit: String,
+    #[serde(with = "time::serde::rfc3339")]
+    #[schema(value_type = String, format = DateTime)]
+    pub built_at: time::OffsetDateTime,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct CapturedExchange {
+    pub method: String,
+    /// Path with the query
+    pub path: String,
+    /// Secret headers are redacted
+    pub request_headers: Vec<(String, String)>,
+    /// Cut to a few kilobytes, see `request_body_truncated`
+    pub request_body: String,
+    /// Whether the body is longer than captured
+    pub request_body_truncated: bool,
+    pub status: u16,
+    pub response_headers: Vec<(String, String)>,
+    pub response_body: String,
+    pub response_body_truncated: bool,
+}
+
+// ───── JSON Schema ──────────────────────────────────────────────────────── //
+
+/// JSON Schema of a DTO by its type name, for clients which generate
+/// types without OpenAPI
+pub fn json_schema(type_name: &str) -> Option<schemars::schema::RootSchema> {
+    Some(match type_name {
+        "PostMsgRequest" => schemars::schema_for!(PostMsgRequest),
+        "SignMsgRequest" => schemars::schema_for!(SignMsgRequest),
+        "User" => schemars::schema_for!(User),
+        "MessageDetail" => schemars::schema_for!(MessageDetail),
+        _ => return None,
+    })
 }
 
 // ───── Api ──────────────────────────────────────────────────────────────── //
 
-//#[utoipauto]
-//#[derive(OpenApi)]
-//#[openapi(
-//        tags(
-//            (name = "open", description = "Open routes (no authorization)"),
-//        ),
-//        info(
-//            title = "Multisig - OpenAPI 3.0",
-//            version = "0.1.0",
-//            description = "This is a swagger documentation for simple multisig service.",
-//        )
-//    )]
-//pub(super) struct ApiDoc;
+#[utoipauto]
+#[derive(OpenApi)]
+#[openapi(
+        tags(
+            (name = "open", description = "Open routes (no authorization)"),
+            (name = "protected", description = "Routes which require `X-API-Key` if it is configured"),
+        ),
+        modifiers(&SecurityAddon),
+        info(
+            title = "Multisig - OpenAPI 3.0",
+            version = "0.2.0",
+            description = "This is a swagger documentation for simple multisig service.",
+        )
+    )]
+pub(super) struct ApiDoc;
+
+/// Spec served at `/api-docs/openapi.json`, with `public_base_url` as the
+/// server if it is set
+pub(super) fn openapi(
+    public_base_url: Option<&str>,
+) -> utoipa::openapi::OpenApi {
+    let mut openapi = ApiDoc::openapi();
+    if let Some(url) = public_base_url {
+        openapi.servers = Some(vec![Server::new(url)]);
+    }
+    openapi
+}
+
+/// Registers `api_key` security scheme, routes which mutate state
+/// reference it with `security(("api_key" = []))`.
+struct SecurityAddon;
+
+impl Modify for SecurityAddon {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components =
+            openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "api_key",
+            SecurityScheme::ApiKey(ApiKey::Header(ApiKeyValue::new(
+                crate::middleware::API_KEY_HEADER,
+            ))),
+        );
+    }
+}
@@ -1,28 +1,51 @@
+use std::future::Future;
 use std::net::SocketAddr;
 use std::sync::Arc;
+use std::sync::OnceLock;
+use std::time::Duration;
 
 use axum::extract::connect_info::IntoMakeServiceWithConnectInfo;
 use axum::extract::ConnectInfo;
+use axum::extract::FromRef;
+use axum::extract::Path;
 use axum::middleware::AddExtension;
 use axum::routing;
 use axum::serve::Serve;
+use axum::Json;
 use axum::Router;
+use http::header;
+use http::HeaderName;
+use http::HeaderValue;
 use http::StatusCode;
+use metrics_exporter_prometheus::{PrometheusBuilder, PrometheusHandle};
 use secp256k1::All;
 use secp256k1::Secp256k1;
 use tokio::net::TcpListener;
+use tokio::sync::Semaphore;
+use tokio::task::JoinHandle;
 use tower_http::services::ServeDir;
 use tower_http::services::ServeFile;
-//use utoipa::OpenApi;
+use tower_http::set_header::SetResponseHeaderLayer;
+use tracing_subscriber::fmt::format::FmtSpan;
+use tracing_subscriber::EnvFilter;
 //use utoipa_swagger_ui::SwaggerUi;
 
 use crate::api;
+use crate::capture::{RequestCapture, Uncaptured};
 use crate::config::Settings;
-use crate::middleware::RequestTracingLayer;
+use crate::idempotency::IdempotencyCache;
+use crate::middleware::{
+    assign_request_id, capture_requests, localize_errors, log_forbidden_bodies,
+    require_api_key, require_api_key_always, resolve_client_ip,
+    restrict_admin_peers, shed_msg_creation, standard_trace_layer,
+    RequestTracing, RequestTracingLayer,
+};
 use crate::storage::in_memory::InMemoryStorage;
-use crate::storage::Storage;
-
-//use self::api_doc::ApiDoc;
+use crate::storage::metrics::spawn_reporter;
+use crate::storage::retrying::{RetryPolicy, RetryingStorage};
+use crate::storage::SharedStorage;
+use crate::verification_cache::VerificationCache;
+use crate::webhook::WebhookSender;
 
 pub mod api_doc;
 
@@ -33,16 +56,27 @@ type Server = Serve<
 >;
 
 pub struct Application {
-    port: u16,
+    address: SocketAddr,
     server: Server,
+    storage: SharedStorage,
+    metrics_reporter: Option<JoinHandle<()>>,
 }
 
-/// Thread-safe type
-#[derive(Clone)]
+/// Thread-safe type. Handlers may extract a single field with
+/// `State<FieldType>`, e.g. `State<SharedStorage>`.
+#[derive(Clone, FromRef)]
 pub struct AppState {
     pub settings: Arc<Settings>,
-    pub storage: Arc<dyn Storage + Send + Sync>,
+    pub storage: SharedStorage,
     pub secp: Secp256k1<All>,
+    pub idempotency: IdempotencyCache,
+    pub verification_cache: VerificationCache,
+    pub webhook: Option<WebhookSender>,
+    /// Caps concurrent keypair generation, permits are granted in FIFO
+    pub keygen_permits: Arc<Semaphore>,
+    pub capture: Option<RequestCapture>,
+    /// Renders reported metrics, `None` if `metrics_interval_secs` is off
+    pub metrics: Option<PrometheusHandle>,
 }
 
 impl Application {
@@ -52,83 +86,307 @@ impl Application {
     pub async fn build(
         configuration: Settings,
     ) -> Result<Application, anyhow::Error> {
-        let address =
-            format!("{}:{}", configuration.app_ip, configuration.app_port);
-        tracing::info!("running on {} address", address);
+        let storage = Arc::new(RetryingStorage::new(
+            InMemoryStorage::default(),
+            RetryPolicy::default(),
+        ));
+        Self::build_with_storage(configuration, storage).await
+    }
 
-        let listener = TcpListener::bind(address).await?;
-        let port = listener.local_addr()?.port();
+    /// Same as `build`, but on top of given storage
+    pub async fn build_with_storage(
+        configuration: Settings,
+        storage: SharedStorage,
+    ) -> Result<Application, anyhow::Error> {
+        init_tracing(&configuration.log_filter)?;
 
+        let listener =
+            TcpListener::bind((configuration.app_ip, configuration.app_port))
+                .await?;
+        let address = listener.local_addr()?;
+        tracing::info!("running on {} address", address);
+
+        let idempotency = IdempotencyCache::new(Duration::from_secs(
+            configuration.idempotency_ttl_secs,
+        ));
+        let webhook = configuration.webhook_url.clone().map(|url| {
+            WebhookSender::new(
+                url,
+                configuration.webhook_max_attempts,
+                Duration::from_millis(configuration.webhook_backoff_ms),
+            )
+        });
+        let keygen_permits =
+            Arc::new(Semaphore::new(configuration.keygen_concurrency));
+        let metrics = configuration
+            .metrics_interval_secs
+            .map(|_| install_metrics_recorder());
+        let metrics_reporter =
+            configuration.metrics_interval_secs.map(|secs| {
+                spawn_reporter(storage.clone(), Duration::from_secs(secs))
+            });
+        let capture = match is_production() {
+            true => None,
+            false => configuration.capture_requests.map(RequestCapture::new),
+        };
+        let verification_cache =
+            VerificationCache::new(configuration.verification_cache_capacity);
         let app_state = AppState {
             settings: Arc::new(configuration),
-            storage: Arc::new(InMemoryStorage::default()),
+            storage: storage.clone(),
             secp: secp256k1::Secp256k1::new(),
+            idempotency,
+            verification_cache,
+            webhook,
+            keygen_permits,
+            capture,
+            metrics,
         };
 
-        let server = Self::build_server(listener, app_state);
+        let server = Self::build_server(listener, app_state)?;
 
-        Ok(Self { server, port })
+        Ok(Self {
+            server,
+            address,
+            storage,
+            metrics_reporter,
+        })
     }
 
     pub fn port(&self) -> u16 {
-        self.port
+        self.address.port()
+    }
+
+    /// Address the listener is bound to, with the port chosen by the OS
+    /// if `app_port` is `0`
+    pub fn address(&self) -> SocketAddr {
+        self.address
     }
 
     /// This function only returns when the application is stopped.
     pub async fn run_until_stopped(self) -> Result<(), std::io::Error> {
-        self.server
-            .with_graceful_shutdown(shutdown_signal())
-            .await?;
+        self.run_until(shutdown_signal()).await
+    }
+
+    /// Serve until `signal` completes, then flush storage, so buffered
+    /// writes of acknowledged requests aren't lost.
+    pub async fn run_until(
+        self,
+        signal: impl Future<Output = ()> + Send + 'static,
+    ) -> Result<(), std::io::Error> {
+        self.server.with_graceful_shutdown(signal).await?;
+        if let Some(reporter) = self.metrics_reporter {
+            reporter.abort();
+        }
+        match self.storage.flush().await {
+            Ok(()) => tracing::info!("storage flushed"),
+            Err(e) => tracing::error!("failed to flush storage: {e}"),
+        }
         Ok(())
     }
 
     /// Configure `Server`.
-    fn build_server(listener: TcpListener, app_state: AppState) -> Server {
+    fn build_server(
+        listener: TcpListener,
+        app_state: AppState,
+    ) -> Result<Server, anyhow::Error> {
+        let tracing_layer =
+            RequestTracingLayer::new(&app_state.settings.trace_verbosity);
+        let request_tracing = app_state.settings.request_tracing;
+        let log_forbidden = app_state.settings.log_forbidden_bodies;
+        let csp =
+            HeaderValue::from_str(&app_state.settings.content_security_policy)?;
+        let static_dir = app_state.settings.static_dir.clone();
+        let public_base_url = app_state.settings.public_base_url.clone();
+        #[rustfmt::skip]
+        let admin = api::admin_router()
+            .route_layer(axum::middleware::from_fn_with_state(app_state.clone(), restrict_admin_peers))
+            .route_layer(axum::middleware::from_fn_with_state(app_state.clone(), require_api_key_always))
+            .route_layer(axum::middleware::map_response(|mut response: axum::response::Response| async {
+                response.extensions_mut().insert(Uncaptured);
+                response
+            }));
         #[rustfmt::skip]
         let mut router = Router::new()
-            .nest("/api/v1", api::router())
-            .with_state(app_state)
-            .fallback_service(ServeDir::new("dist").fallback(ServeFile::new("dist/index.html")))
-            .layer(RequestTracingLayer)
-            .route("/api/healthcheck", routing::get(healthcheck)); // Do not trace healthchecks
-
-        match std::env::var("ENVIRONMENT").unwrap_or_default().as_str() {
-            "production" => (),
-            _ => {
+            .nest("/api/v1", api::router(admin.clone()))
+            .nest("/api/v2", api::router_v2(admin))
+            .layer(axum::middleware::from_fn(localize_errors))
+            .layer(axum::middleware::from_fn_with_state(app_state.clone(), shed_msg_creation))
+            .layer(axum::middleware::from_fn_with_state(app_state.clone(), require_api_key))
+            .layer(axum::middleware::from_fn_with_state(app_state.clone(), capture_requests))
+            .layer(axum::middleware::from_fn_with_state(app_state.clone(), resolve_client_ip))
+            .with_state(app_state);
+        if let Some(dir) = static_dir {
+            let index = ServeFile::new(dir.join("index.html"));
+            router =
+                router.fallback_service(ServeDir::new(dir).fallback(index));
+        }
+        router = match request_tracing {
+            RequestTracing::Custom => router.layer(tracing_layer),
+            RequestTracing::Standard => {
+                if log_forbidden {
+                    router = router
+                        .layer(axum::middleware::from_fn(log_forbidden_bodies));
+                }
+                router
+                    .layer(standard_trace_layer())
+                    .layer(axum::middleware::from_fn(assign_request_id))
+            }
+        };
+        #[rustfmt::skip]
+        let mut router = router
+            .route("/api/healthcheck", routing::get(healthcheck)) // Do not trace healthchecks
+            .route("/api/version", routing::get(version));
+
+        match is_production() {
+            true => (),
+            false => {
+                let openapi = api_doc::openapi(public_base_url.as_deref());
                 let cors = tower_http::cors::CorsLayer::new()
-                    // allow `GET` and `POST` when accessing the resource
-                    .allow_methods([http::Method::GET, http::Method::POST])
+                    // allow every method of the api when accessing the resource
+                    .allow_methods([
+                        http::Method::GET,
+                        http::Method::POST,
+                        http::Method::PATCH,
+                        http::Method::DELETE,
+                    ])
+                    // and headers the api reads, besides safelisted ones
+                    .allow_headers([
+                        header::CONTENT_TYPE,
+                        header::IF_MATCH,
+                        HeaderName::from_static("x-api-key"),
+                        HeaderName::from_static("idempotency-key"),
+                    ])
                     // allow requests from any origin
                     .allow_origin(tower_http::cors::Any);
                 router = router
                     //    .merge(
                     //        SwaggerUi::new("/swagger-ui")
-                    //            .url("/api-docs/openapi.json", ApiDoc::openapi()),
+                    //            .url("/api-docs/openapi.json", openapi.clone()),
                     //    )
+                    .route(
+                        "/api-docs/openapi.json",
+                        routing::get(|| async move { Json(openapi) }),
+                    )
+                    .route("/api/schema/{type_name}", routing::get(json_schema))
                     .layer(cors);
             }
         }
 
-        axum::serve(
+        // Outermost, so static files and healthchecks get them too
+        #[rustfmt::skip]
+        let router = router
+            .layer(SetResponseHeaderLayer::if_not_present(header::X_CONTENT_TYPE_OPTIONS, HeaderValue::from_static("nosniff")))
+            .layer(SetResponseHeaderLayer::if_not_present(header::X_FRAME_OPTIONS, HeaderValue::from_static("DENY")))
+            .layer(SetResponseHeaderLayer::if_not_present(header::REFERRER_POLICY, HeaderValue::from_static("no-referrer")))
+            .layer(SetResponseHeaderLayer::if_not_present(header::CONTENT_SECURITY_POLICY, csp));
+
+        Ok(axum::serve(
             listener,
             router
                 .into_make_service_with_connect_info::<std::net::SocketAddr>(),
-        )
+        ))
+    }
+}
+
+/// Whether `ENVIRONMENT` is `production`, debugging aids are off then
+fn is_production() -> bool {
+    std::env::var("ENVIRONMENT").is_ok_and(|env| env == "production")
+}
+
+/// Multi-threaded runtime with `worker_threads` workers, if set.
+pub fn build_runtime(
+    config: &Settings,
+) -> Result<tokio::runtime::Runtime, std::io::Error> {
+    let mut builder = tokio::runtime::Builder::new_multi_thread();
+    if let Some(threads) = config.worker_threads {
+        builder.worker_threads(threads);
+    }
+    builder.enable_all().build()
+}
+
+/// Build filter from `RUST_LOG` if it is set, `log_filter` otherwise.
+pub fn env_filter(log_filter: &str) -> Result<EnvFilter, anyhow::Error> {
+    let directives = match std::env::var(EnvFilter::DEFAULT_ENV) {
+        Ok(env) if !env.is_empty() => env,
+        _ => log_filter.to_string(),
+    };
+    Ok(EnvFilter::try_new(directives)?)
+}
+
+/// Set up global tracing subscriber, keeps existing one if it is set.
+fn init_tracing(log_filter: &str) -> Result<(), anyhow::Error> {
+    let subscriber = tracing_subscriber::fmt()
+        .with_timer(tracing_subscriber::fmt::time::ChronoLocal::default())
+        .with_span_events(FmtSpan::NEW | FmtSpan::CLOSE)
+        .with_env_filter(env_filter(log_filter)?)
+        .compact()
+        .with_level(true)
+        .finish();
+    if tracing::subscriber::set_global_default(subscriber).is_err() {
+        tracing::debug!("global tracing subscriber is set already");
     }
+    Ok(())
+}
+
+/// Set up global Prometheus recorder once per process, applications
+/// built later share it.
+fn install_metrics_recorder() -> PrometheusHandle {
+    static HANDLE: OnceLock<PrometheusHandle> = OnceLock::new();
+    HANDLE
+        .get_or_init(|| {
+            let recorder = PrometheusBuilder::new().build_recorder();
+            let handle = recorder.handle();
+            if metrics::set_global_recorder(recorder).is_err() {
+                tracing::warn!("global metrics recorder is set already");
+            }
+            handle
+        })
+        .clone()
 }
 
-//#[utoipa::path(
-//    get,
-//    path = "/api/healthcheck",
-//    responses(
-//        (status = 200, description = "Healthcheck"),
-//    ),
-//    tag = "open"
-//)]
+#[utoipa::path(
+    get,
+    path = "/api/healthcheck",
+    responses(
+        (status = 200, description = "Healthcheck"),
+    ),
+    tag = "open"
+)]
 async fn healthcheck() -> StatusCode {
     StatusCode::OK
 }
 
+/// JSON Schema of a request or response type, for codegen
+async fn json_schema(
+    Path(type_name): Path<String>,
+) -> Result<Json<schemars::schema::RootSchema>, StatusCode> {
+    api_doc::json_schema(&type_name)
+        .map(Json)
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/version",
+    responses(
+        (status = 200, description = "Build metadata", body = api_doc::VersionInfo),
+    ),
+    tag = "open"
+)]
+async fn version() -> Json<api_doc::VersionInfo> {
+    let built_at = env!("BUILD_TIMESTAMP")
+        .parse()
+        .ok()
+        .and_then(|ts| time::OffsetDateTime::from_unix_timestamp(ts).ok())
+        .unwrap_or(time::OffsetDateTime::UNIX_EPOCH);
+    Json(api_doc::VersionInfo {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        commit: env!("GIT_COMMIT_HASH").to_string(),
+        built_at,
+    })
+}
+
 async fn shutdown_signal() {
     let ctrl_c = async {
         tokio::signal::ctrl_c()
@@ -3,76 +3,241 @@ use std::sync::Arc;
 use std::sync::Mutex;
 use std::sync::MutexGuard;
 
+use futures::stream::{self, BoxStream};
+use futures::StreamExt;
+use secp256k1::hashes::sha256;
 use secp256k1::hashes::Hash;
+use time::OffsetDateTime;
+use tokio::sync::broadcast;
 
+use crate::domain::multisig;
 use crate::domain::{message::Message, user::User};
 
-use super::Error;
+use super::{Error, StorageEvent, StorageStats};
+
+/// Events kept for slow subscribers
+const EVENTS_CAPACITY: usize = 256;
 
 #[derive(Debug, Default)]
 struct Inner {
     users: HashMap<uuid::Uuid, User>,
+    /// `User::name` index
+    user_names: HashMap<String, uuid::Uuid>,
     msgs: Vec<Message>,
+    /// `Message::digest` index, ids are in insertion order
+    msg_hashes: HashMap<sha256::Hash, Vec<uuid::Uuid>>,
+    /// `Message::tags` index, ids are in insertion order
+    msg_tags: HashMap<String, Vec<uuid::Uuid>>,
 }
 
-#[derive(Debug, Clone, Default)]
+#[derive(Debug, Clone)]
 pub struct InMemoryStorage {
     inner: Arc<Mutex<Inner>>,
+    events: broadcast::Sender<StorageEvent>,
+}
+
+impl Default for InMemoryStorage {
+    fn default() -> Self {
+        InMemoryStorage {
+            inner: Default::default(),
+            events: broadcast::Sender::new(EVENTS_CAPACITY),
+        }
+    }
 }
 
 impl InMemoryStorage {
-    fn lock(&self) -> Result<MutexGuard<Inner>, Error> {
-        Ok(self.inner.lock().map_err(|e| {
-            anyhow::anyhow!("failed to acquire mutex lock: {e}")
-        })?)
+    /// Called with the lock held, so events are ordered as changes are.
+    /// Having no subscribers isn't an error.
+    fn publish(&self, event: StorageEvent) {
+        let _ = self.events.send(event);
+    }
+
+    /// Every mutation either completes or leaves a record untouched
+    /// (modifiers run on a copy which replaces the record on success), so
+    /// a panic in another holder doesn't corrupt the data and poisoning
+    /// can be recovered.
+    fn lock(&self) -> Result<MutexGuard<'_, Inner>, Error> {
+        let started = std::time::Instant::now();
+        let guard = self.inner.lock().unwrap_or_else(|e| {
+            tracing::warn!("recovering poisoned storage lock");
+            self.inner.clear_poison();
+            e.into_inner()
+        });
+        tracing::debug!(lock_wait = ?started.elapsed(), "lock acquired");
+        Ok(guard)
     }
 }
 
 #[async_trait::async_trait]
 impl super::Storage for InMemoryStorage {
+    #[tracing::instrument(
+        name = "storage.store_user",
+        level = "debug",
+        skip_all
+    )]
     async fn store_user(&self, user: User) -> Result<(), Error> {
         let mut lock = self.lock()?;
-        if lock.users.contains_key(&user.id) {
+        if lock.users.contains_key(&user.id)
+            || lock.user_names.contains_key(&user.name)
+        {
             return Err(Error::UserExists);
         }
+        lock.user_names.insert(user.name.clone(), user.id);
+        self.publish(StorageEvent::UserCreated(user.id));
         lock.users.insert(user.id, user);
         Ok(())
     }
 
+    #[tracing::instrument(name = "storage.get_user", level = "debug", skip_all)]
     async fn get_user(&self, username: &str) -> Result<Option<User>, Error> {
         let lock = self.lock()?;
-        Ok(lock.users.values().find(|&u| u.name.eq(username)).cloned())
+        Ok(lock
+            .user_names
+            .get(username)
+            .and_then(|id| lock.users.get(id))
+            .cloned())
     }
 
-    async fn update_user(&self, user: User) -> Result<(), Error> {
+    #[tracing::instrument(
+        name = "storage.update_user",
+        level = "debug",
+        skip_all
+    )]
+    async fn update_user(
+        &self,
+        user_id: &uuid::Uuid,
+        with: super::UserModifier,
+    ) -> Result<(), Error> {
+        let mut lock = self.lock()?;
+        let user = lock.users.get_mut(user_id).ok_or(Error::NoUser)?;
+        let mut updated = user.clone();
+        with(&mut updated);
+        updated.name = user.name.clone();
+        updated.updated_at = OffsetDateTime::now_utc();
+        *user = updated;
+        self.publish(StorageEvent::UserUpdated(*user_id));
+        Ok(())
+    }
+
+    #[tracing::instrument(
+        name = "storage.rename_user",
+        level = "debug",
+        skip_all
+    )]
+    async fn rename_user(
+        &self,
+        user_id: &uuid::Uuid,
+        new_name: &str,
+    ) -> Result<(), Error> {
         let mut lock = self.lock()?;
-        if !lock.users.contains_key(&user.id) {
-            return Err(Error::NoUser);
+        let inner = &mut *lock;
+        match inner.user_names.get(new_name) {
+            Some(id) if id.eq(user_id) => return Ok(()),
+            Some(_) => return Err(Error::UserExists),
+            None => (),
         }
-        lock.users.entry(user.id).and_modify(|u| *u = user);
+        let user = inner.users.get_mut(user_id).ok_or(Error::NoUser)?;
+        let old_name = std::mem::replace(&mut user.name, new_name.to_string());
+        user.updated_at = OffsetDateTime::now_utc();
+        inner.user_names.remove(&old_name);
+        inner.user_names.insert(new_name.to_string(), *user_id);
+        self.publish(StorageEvent::UserUpdated(*user_id));
         Ok(())
     }
 
+    #[tracing::instrument(
+        name = "storage.remove_user",
+        level = "debug",
+        skip_all
+    )]
     async fn remove_user(&self, user_id: &uuid::Uuid) -> Result<(), Error> {
         let mut lock = self.lock()?;
-        lock.users.remove(user_id);
+        if let Some(user) = lock.users.remove(user_id) {
+            lock.user_names.remove(&user.name);
+            self.publish(StorageEvent::UserDeleted(*user_id));
+        }
         Ok(())
     }
 
+    #[tracing::instrument(
+        name = "storage.all_users",
+        level = "debug",
+        skip_all
+    )]
     async fn all_users(&self) -> Result<Vec<User>, Error> {
         let lock = self.lock()?;
         Ok(lock.users.values().cloned().collect())
     }
 
-    async fn store_msg(&self, msg: Message) -> Result<(), Error> {
+    /// Only ids are taken upfront, every user is cloned once it's polled.
+    /// Users removed in the meantime are skipped.
+    #[tracing::instrument(
+        name = "storage.stream_users",
+        level = "debug",
+        skip_all
+    )]
+    fn stream_users(&self) -> BoxStream<'_, Result<User, Error>> {
+        let ids = match self.lock() {
+            Ok(lock) => lock.users.keys().copied().collect::<Vec<_>>(),
+            Err(e) => return stream::once(async { Err(e) }).boxed(),
+        };
+        stream::iter(ids)
+            .filter_map(move |id| {
+                let user = match self.lock() {
+                    Ok(lock) => lock.users.get(&id).cloned().map(Ok),
+                    Err(e) => Some(Err(e)),
+                };
+                futures::future::ready(user)
+            })
+            .boxed()
+    }
+
+    #[tracing::instrument(
+        name = "storage.search_users",
+        level = "debug",
+        skip_all
+    )]
+    async fn search_users(
+        &self,
+        prefix: &str,
+        limit: Option<usize>,
+    ) -> Result<Vec<User>, Error> {
+        let lock = self.lock()?;
+        let mut users = lock
+            .users
+            .values()
+            .filter(|u| u.name.starts_with(prefix))
+            .cloned()
+            .collect::<Vec<_>>();
+        users.sort_by(|a, b| a.name.cmp(&b.name));
+        users.truncate(limit.unwrap_or(usize::MAX));
+        Ok(users)
+    }
+
+    #[tracing::instrument(
+        name = "storage.store_msg",
+        level = "debug",
+        skip_all
+    )]
+    async fn store_msg(&self, msg: Message) -> Result<uuid::Uuid, Error> {
         let mut lock = self.lock()?;
         if lock.msgs.iter().any(|m| m.eq(&msg)) {
             return Err(Error::MsgExists);
         }
+        lock.msg_hashes
+            .entry(sha256::Hash::from_byte_array(msg.lookup_key()))
+            .or_default()
+            .push(msg.id);
+        for tag in &msg.tags {
+            lock.msg_tags.entry(tag.clone()).or_default().push(msg.id);
+        }
+        let msg_id = msg.id;
         lock.msgs.push(msg);
-        Ok(())
+        self.publish(StorageEvent::MsgCreated(msg_id));
+        Ok(msg_id)
     }
 
+    #[tracing::instrument(name = "storage.get_msg", level = "debug", skip_all)]
     async fn get_msg(
         &self,
         msg_id: &uuid::Uuid,
@@ -82,6 +247,23 @@ impl super::Storage for InMemoryStorage {
         Ok(msg.cloned())
     }
 
+    #[tracing::instrument(name = "storage.get_msgs", level = "debug", skip_all)]
+    async fn get_msgs(
+        &self,
+        msg_ids: &[uuid::Uuid],
+    ) -> Result<Vec<Option<Message>>, Error> {
+        let lock = self.lock()?;
+        Ok(msg_ids
+            .iter()
+            .map(|id| lock.msgs.iter().find(|m| m.id.eq(id)).cloned())
+            .collect())
+    }
+
+    #[tracing::instrument(
+        name = "storage.update_msg",
+        level = "debug",
+        skip_all
+    )]
     async fn update_msg(
         &self,
         msg_id: &uuid::Uuid,
@@ -93,30 +275,469 @@ impl super::Storage for InMemoryStorage {
             .iter_mut()
             .find(|m| msg_id.eq(&m.id))
             .ok_or(Error::NoMsg)?;
-        with(msg)?;
+        if msg.finalized_at.is_some() {
+            return Err(multisig::Error::Finalized.into());
+        }
+        let mut updated = msg.clone();
+        with(&mut updated)?;
+        updated.version += 1;
+        updated.updated_at = OffsetDateTime::now_utc();
+        *msg = updated;
+        self.publish(StorageEvent::MsgUpdated(*msg_id));
         Ok(())
     }
 
-    async fn remove_msg(
+    #[tracing::instrument(
+        name = "storage.get_msg_by_hash",
+        level = "debug",
+        skip_all
+    )]
+    async fn get_msg_by_hash(
         &self,
-        msg_hash: &secp256k1::hashes::sha256::Hash,
-    ) -> Result<(), Error> {
+        msg_hash: &sha256::Hash,
+    ) -> Result<Option<Message>, Error> {
+        let lock = self.lock()?;
+        let Some(msg_id) =
+            lock.msg_hashes.get(msg_hash).and_then(|ids| ids.first())
+        else {
+            return Ok(None);
+        };
+        let msg = lock.msgs.iter().find(|&m| m.id.eq(msg_id));
+        Ok(msg.cloned())
+    }
+
+    #[tracing::instrument(
+        name = "storage.remove_msg",
+        level = "debug",
+        skip_all
+    )]
+    async fn remove_msg(&self, msg_hash: &sha256::Hash) -> Result<(), Error> {
         let mut lock = self.lock()?;
-        let (idx, _) = lock
-            .msgs
-            .iter()
-            .enumerate()
-            .find(|(_, m)| {
-                let h = secp256k1::hashes::sha256::Hash::hash(&m.content);
-                h.eq(msg_hash)
-            })
-            .ok_or(Error::NoMsg)?;
-        lock.msgs.remove(idx);
+        let ids = lock.msg_hashes.get_mut(msg_hash).ok_or(Error::NoMsg)?;
+        let msg_id = ids.remove(0);
+        if ids.is_empty() {
+            lock.msg_hashes.remove(msg_hash);
+        }
+        lock.msg_tags.retain(|_, ids| {
+            ids.retain(|id| id.ne(&msg_id));
+            !ids.is_empty()
+        });
+        lock.msgs.retain(|m| m.id.ne(&msg_id));
+        self.publish(StorageEvent::MsgDeleted(msg_id));
         Ok(())
     }
 
+    #[tracing::instrument(
+        name = "storage.all_messages",
+        level = "debug",
+        skip_all
+    )]
     async fn all_messages(&self) -> Result<Vec<Message>, Error> {
         let lock = self.lock()?;
         Ok(lock.msgs.clone())
     }
+
+    #[tracing::instrument(
+        name = "storage.msgs_by_tag",
+        level = "debug",
+        skip_all
+    )]
+    async fn msgs_by_tag(&self, tag: &str) -> Result<Vec<Message>, Error> {
+        let lock = self.lock()?;
+        let Some(ids) = lock.msg_tags.get(tag) else {
+            return Ok(Vec::new());
+        };
+        Ok(ids
+            .iter()
+            .filter_map(|id| lock.msgs.iter().find(|m| m.id.eq(id)))
+            .cloned()
+            .collect())
+    }
+
+    #[tracing::instrument(
+        name = "storage.count_messages",
+        level = "debug",
+        skip_all
+    )]
+    async fn count_messages(&self) -> Result<usize, Error> {
+        let lock = self.lock()?;
+        Ok(lock.msgs.len())
+    }
+
+    #[tracing::instrument(name = "storage.stats", level = "debug", skip_all)]
+    async fn stats(&self) -> Result<StorageStats, Error> {
+        let lock = self.lock()?;
+        let pending = lock.msgs.iter().filter(|m| m.is_pending());
+        Ok(StorageStats {
+            users: lock.users.len(),
+            msgs: lock.msgs.len(),
+            pending_msgs: pending.clone().count(),
+            oldest_pending_created_at: pending.map(|m| m.created_at).min(),
+        })
+    }
+
+    /// Nothing is buffered
+    async fn flush(&self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn subscribe(&self) -> broadcast::Receiver<StorageEvent> {
+        self.events.subscribe()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{FutureExt, TryStreamExt};
+    use secp256k1::hashes::{sha256, Hash};
+
+    use crate::crypto;
+    use crate::domain::message::Message;
+    use crate::domain::multisig;
+    use crate::domain::user::User;
+    use crate::storage::{Error, Storage, StorageEvent};
+
+    use super::InMemoryStorage;
+
+    #[tokio::test]
+    async fn users_are_streamed() -> Result<(), Box<dyn std::error::Error>> {
+        let storage = InMemoryStorage::default();
+        for name in ["alice", "bob", "carol"] {
+            storage
+                .store_user(User {
+                    name: name.to_string(),
+                    ..Default::default()
+                })
+                .await?;
+        }
+        let mut stream = storage.stream_users();
+        let first = stream.try_next().await?.ok_or("no users streamed")?;
+        // Removed before it is reached
+        let removed = storage
+            .all_users()
+            .await?
+            .into_iter()
+            .find(|u| u.id != first.id)
+            .ok_or("single user stored")?;
+        storage.remove_user(&removed.id).await?;
+
+        let rest = stream.try_collect::<Vec<_>>().await?;
+        assert_eq!(rest.len(), 1);
+        assert!(rest.iter().all(|u| u.id != removed.id));
+        assert_eq!(
+            storage.stream_users().try_collect::<Vec<_>>().await?.len(),
+            2
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn storage_works_after_panicking_modifier(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let storage = InMemoryStorage::default();
+        let msg = Message::new(b"Hello world!", vec![pubkey()], None)?;
+        let msg_id = storage.store_msg(msg).await?;
+
+        let panicked = std::panic::AssertUnwindSafe(
+            storage
+                .update_msg(&msg_id, Box::new(|_| panic!("modifier panicked"))),
+        )
+        .catch_unwind()
+        .await;
+        assert!(panicked.is_err());
+        assert!(storage.inner.is_poisoned());
+
+        assert!(storage.get_msg(&msg_id).await?.is_some());
+        assert!(!storage.inner.is_poisoned());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn failed_modifier_leaves_msg_unchanged(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let storage = InMemoryStorage::default();
+        let msg = Message::new(b"Hello world!", vec![pubkey()], None)?;
+        let msg_id = storage.store_msg(msg).await?;
+        let before = storage.get_msg(&msg_id).await?.ok_or("no message")?;
+
+        let panicked = std::panic::AssertUnwindSafe(storage.update_msg(
+            &msg_id,
+            Box::new(|msg| {
+                msg.tags.push("partial".to_string());
+                panic!("modifier panicked")
+            }),
+        ))
+        .catch_unwind()
+        .await;
+        assert!(panicked.is_err());
+        let result = storage
+            .update_msg(
+                &msg_id,
+                Box::new(|msg| {
+                    msg.count_required = 0;
+                    Err(multisig::Error::ZeroThreshold)
+                }),
+            )
+            .await;
+        assert!(result.is_err());
+
+        let after = storage.get_msg(&msg_id).await?.ok_or("no message")?;
+        assert_eq!(after, before);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn same_content_with_other_nonce_is_indexed_separately(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let storage = InMemoryStorage::default();
+        let first =
+            Message::new(b"Approve", vec![pubkey()], None)?.with_nonce(Some(1));
+        let second =
+            Message::new(b"Approve", vec![pubkey()], None)?.with_nonce(Some(2));
+        let (first_hash, second_hash) = (
+            sha256::Hash::hash(&first.preimage()),
+            sha256::Hash::hash(&second.preimage()),
+        );
+        let (first_id, second_id) = (first.id, second.id);
+        storage.store_msg(first).await?;
+        storage.store_msg(second).await?;
+
+        let found = storage.get_msg_by_hash(&first_hash).await?;
+        assert_eq!(found.map(|m| m.id), Some(first_id));
+        let found = storage.get_msg_by_hash(&second_hash).await?;
+        assert_eq!(found.map(|m| m.id), Some(second_id));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn stored_msg_id_resolves() -> Result<(), Box<dyn std::error::Error>>
+    {
+        let storage = InMemoryStorage::default();
+        let msg = Message::new(b"Hello world!", vec![pubkey()], None)?;
+        let msg_id = storage.store_msg(msg.clone()).await?;
+
+        let found = storage.get_msg(&msg_id).await?;
+        assert_eq!(found.map(|m| m.content), Some(msg.content));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn msgs_are_filtered_by_tag() -> Result<(), Box<dyn std::error::Error>>
+    {
+        let storage = InMemoryStorage::default();
+        let payroll = Message::new(b"Salary", vec![pubkey()], None)?
+            .with_tags(vec!["payroll".into(), "treasury".into()]);
+        let treasury = Message::new(b"Transfer", vec![pubkey()], None)?
+            .with_tags(vec!["treasury".into()]);
+        let (payroll_id, treasury_id) = (payroll.id, treasury.id);
+        let payroll_hash = sha256::Hash::from_byte_array(payroll.digest());
+        storage.store_msg(payroll).await?;
+        storage.store_msg(treasury).await?;
+
+        let ids = |msgs: Vec<Message>| {
+            msgs.into_iter().map(|m| m.id).collect::<Vec<_>>()
+        };
+        assert_eq!(
+            ids(storage.msgs_by_tag("treasury").await?),
+            vec![payroll_id, treasury_id]
+        );
+        assert_eq!(
+            ids(storage.msgs_by_tag("payroll").await?),
+            vec![payroll_id]
+        );
+        assert!(storage.msgs_by_tag("contracts").await?.is_empty());
+
+        storage.remove_msg(&payroll_hash).await?;
+        assert!(storage.msgs_by_tag("payroll").await?.is_empty());
+        assert_eq!(
+            ids(storage.msgs_by_tag("treasury").await?),
+            vec![treasury_id]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn msgs_are_fetched_positionally(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let storage = InMemoryStorage::default();
+        let first = Message::new(b"First", vec![pubkey()], None)?;
+        let second = Message::new(b"Second", vec![pubkey()], None)?;
+        let (first_id, second_id) = (first.id, second.id);
+        storage.store_msg(first).await?;
+        storage.store_msg(second).await?;
+
+        let missing = uuid::Uuid::new_v4();
+        let msgs = storage
+            .get_msgs(&[second_id, missing, first_id, missing])
+            .await?;
+        let ids = msgs
+            .into_iter()
+            .map(|m| m.map(|m| m.id))
+            .collect::<Vec<_>>();
+        assert_eq!(ids, vec![Some(second_id), None, Some(first_id), None]);
+        assert!(storage.get_msgs(&[]).await?.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn updates_advance_updated_at(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let storage = InMemoryStorage::default();
+        let user = User::default();
+        let (user_id, created_at) = (user.id, user.created_at);
+        storage.store_user(user.clone()).await?;
+        storage.update_user(&user_id, Box::new(|_| ())).await?;
+        storage.rename_user(&user_id, "alice").await?;
+        let renamed = storage.get_user("alice").await?.ok_or("no user")?;
+        assert_eq!(renamed.created_at, created_at);
+        assert!(renamed.updated_at > user.updated_at);
+
+        let msg = Message::new(b"Hello world!", vec![pubkey()], None)?;
+        let msg_id = storage.store_msg(msg.clone()).await?;
+        storage.update_msg(&msg_id, Box::new(|_| Ok(()))).await?;
+        let updated = storage.get_msg(&msg_id).await?.ok_or("no msg")?;
+        assert_eq!(updated.created_at, msg.created_at);
+        assert!(updated.updated_at > msg.updated_at);
+
+        // Rejected updates change nothing
+        let rejected = storage
+            .update_msg(
+                &msg_id,
+                Box::new(|_| Err(multisig::Error::SigningStarted)),
+            )
+            .await;
+        assert!(rejected.is_err());
+        let unchanged = storage.get_msg(&msg_id).await?.ok_or("no msg")?;
+        assert_eq!(unchanged.updated_at, updated.updated_at);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn user_names_are_unique() -> Result<(), Box<dyn std::error::Error>> {
+        let storage = InMemoryStorage::default();
+        let alice = User {
+            name: "alice".into(),
+            ..Default::default()
+        };
+        let alice_id = alice.id;
+        storage.store_user(alice).await?;
+        let bob = User {
+            name: "bob".into(),
+            ..Default::default()
+        };
+        let bob_id = bob.id;
+        storage.store_user(bob).await?;
+        let another_alice = User {
+            name: "alice".into(),
+            ..Default::default()
+        };
+        assert!(matches!(
+            storage.store_user(another_alice).await,
+            Err(Error::UserExists)
+        ));
+
+        assert!(matches!(
+            storage.rename_user(&bob_id, "alice").await,
+            Err(Error::UserExists)
+        ));
+        storage.rename_user(&alice_id, "carol").await?;
+        assert!(storage.get_user("alice").await?.is_none());
+        let carol = storage.get_user("carol").await?.ok_or("no carol")?;
+        assert_eq!(carol.id, alice_id);
+        // The old name is free again
+        storage.rename_user(&bob_id, "alice").await?;
+        assert_eq!(
+            storage.get_user("alice").await?.map(|u| u.id),
+            Some(bob_id)
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn mutations_are_published() -> Result<(), Box<dyn std::error::Error>>
+    {
+        let storage = InMemoryStorage::default();
+        let mut events = storage.subscribe();
+        let user = User::default();
+        let user_id = user.id;
+        storage.store_user(user).await?;
+        storage.rename_user(&user_id, "alice").await?;
+        storage.remove_user(&user_id).await?;
+
+        let msg = Message::new(b"Hello world!", vec![pubkey()], None)?;
+        let msg_id = msg.id;
+        let msg_hash = sha256::Hash::from_byte_array(msg.digest());
+        storage.store_msg(msg).await?;
+        storage.update_msg(&msg_id, Box::new(|_| Ok(()))).await?;
+        // Rejected updates change nothing, so they aren't published
+        let rejected = storage
+            .update_msg(
+                &msg_id,
+                Box::new(|_| Err(multisig::Error::NoParticipants)),
+            )
+            .await;
+        assert!(rejected.is_err());
+        storage.remove_msg(&msg_hash).await?;
+
+        let mut received = Vec::new();
+        while let Ok(event) = events.try_recv() {
+            received.push(event);
+        }
+        assert_eq!(
+            received,
+            vec![
+                StorageEvent::UserCreated(user_id),
+                StorageEvent::UserUpdated(user_id),
+                StorageEvent::UserDeleted(user_id),
+                StorageEvent::MsgCreated(msg_id),
+                StorageEvent::MsgUpdated(msg_id),
+                StorageEvent::MsgDeleted(msg_id),
+            ]
+        );
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn concurrent_keypair_additions_get_distinct_ids(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let storage = InMemoryStorage::default();
+        let user = User::default();
+        let user_id = user.id;
+        storage.store_user(user.clone()).await?;
+
+        let secp = secp256k1::Secp256k1::new();
+        let add_keypair = |keypair: crypto::SecretKeypair| {
+            let storage = storage.clone();
+            tokio::spawn(async move {
+                storage
+                    .update_user(
+                        &user_id,
+                        Box::new(move |u| u.add_keypair(keypair.clone())),
+                    )
+                    .await
+            })
+        };
+        let (first, second) = tokio::join!(
+            add_keypair(crypto::new_keypair(&secp)?),
+            add_keypair(crypto::new_keypair(&secp)?)
+        );
+        first??;
+        second??;
+
+        let stored =
+            storage.get_user(&user.name).await?.ok_or("user is gone")?;
+        let mut ids = stored.keys.keys().copied().collect::<Vec<_>>();
+        ids.sort();
+        assert_eq!(ids, vec![1, 2]);
+        Ok(())
+    }
+
+    // Helpers
+
+    fn pubkey() -> secp256k1::PublicKey {
+        let secp = secp256k1::Secp256k1::new();
+        crypto::new_keypair(&secp)
+            .expect("failed to generate keypair")
+            .public_key()
+    }
 }
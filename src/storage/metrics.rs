@@ -0,0 +1,100 @@
+use std::time::Duration;
+
+use time::OffsetDateTime;
+use tokio::task::JoinHandle;
+use tokio::time::MissedTickBehavior;
+
+use super::{Error, SharedStorage};
+
+pub const USERS_GAUGE: &str = "storage_users";
+pub const MESSAGES_GAUGE: &str = "storage_messages";
+pub const PENDING_MESSAGES_GAUGE: &str = "storage_pending_messages";
+/// `0` if nothing is pending
+pub const OLDEST_PENDING_AGE_GAUGE: &str =
+    "storage_oldest_pending_message_age_seconds";
+
+/// Set storage gauges as of `now`
+pub async fn report(
+    storage: &SharedStorage,
+    now: OffsetDateTime,
+) -> Result<(), Error> {
+    let stats = storage.stats().await?;
+    metrics::gauge!(USERS_GAUGE).set(stats.users as f64);
+    metrics::gauge!(MESSAGES_GAUGE).set(stats.msgs as f64);
+    metrics::gauge!(PENDING_MESSAGES_GAUGE).set(stats.pending_msgs as f64);
+    metrics::gauge!(OLDEST_PENDING_AGE_GAUGE).set(
+        stats
+            .oldest_pending_created_at
+            .map(|created_at| (now - created_at).as_seconds_f64().max(0.0))
+            .unwrap_or_default(),
+    );
+    Ok(())
+}
+
+/// Call `report` every `interval`, until the task is aborted. Failed
+/// reports are logged and retried on the next tick.
+pub fn spawn_reporter(
+    storage: SharedStorage,
+    interval: Duration,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut ticks = tokio::time::interval(interval);
+        ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            ticks.tick().await;
+            if let Err(e) = report(&storage, OffsetDateTime::now_utc()).await {
+                tracing::warn!("failed to report storage metrics: {e}");
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use metrics_util::debugging::{DebugValue, DebuggingRecorder};
+    use metrics_util::MetricKind;
+
+    use crate::domain::message::Message;
+    use crate::storage::in_memory::InMemoryStorage;
+    use crate::storage::SharedStorage;
+
+    use super::{report, OLDEST_PENDING_AGE_GAUGE, PENDING_MESSAGES_GAUGE};
+
+    #[tokio::test]
+    async fn gauges_report_oldest_pending_msg(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let storage: SharedStorage = Arc::new(InMemoryStorage::default());
+        let secp = secp256k1::Secp256k1::new();
+        let keypair = crate::crypto::new_keypair(&secp)?;
+        let msg =
+            Message::new(b"Hello world!", vec![keypair.public_key()], None)?;
+        let created_at = msg.created_at;
+        storage.store_msg(msg).await?;
+
+        let recorder = DebuggingRecorder::new();
+        let snapshotter = recorder.snapshotter();
+        let now = created_at + time::Duration::seconds(90);
+        metrics::with_local_recorder(&recorder, || {
+            futures::executor::block_on(report(&storage, now))
+        })?;
+
+        let gauges = snapshotter
+            .snapshot()
+            .into_vec()
+            .into_iter()
+            .filter(|(key, ..)| key.kind() == MetricKind::Gauge)
+            .map(|(key, _, _, value)| (key.key().name().to_string(), value))
+            .collect::<std::collections::HashMap<_, _>>();
+        assert_eq!(
+            gauges.get(PENDING_MESSAGES_GAUGE),
+            Some(&DebugValue::Gauge(1.0.into()))
+        );
+        assert_eq!(
+            gauges.get(OLDEST_PENDING_AGE_GAUGE),
+            Some(&DebugValue::Gauge(90.0.into()))
+        );
+        Ok(())
+    }
+}
@@ -1,12 +1,44 @@
+use std::sync::Arc;
+
+use futures::stream::BoxStream;
+use tokio::sync::broadcast;
+
 use crate::api::ErrorResponse;
 use crate::domain::multisig;
 use crate::domain::{message::Message, user::User};
 
 pub mod in_memory;
+pub mod metrics;
+pub mod retrying;
+
+/// Storage as it is shared between handlers
+pub type SharedStorage = Arc<dyn Storage + Send + Sync>;
 
-type MsgModifier =
+pub type UserModifier = Box<dyn Fn(&mut User) + Send>;
+pub type MsgModifier =
     Box<dyn Fn(&mut Message) -> Result<(), multisig::Error> + Send>;
 
+/// Counters for monitoring, see `Storage::stats`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct StorageStats {
+    pub users: usize,
+    pub msgs: usize,
+    /// See `Message::is_pending`
+    pub pending_msgs: usize,
+    pub oldest_pending_created_at: Option<time::OffsetDateTime>,
+}
+
+/// Change of stored data, published after the change is applied
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StorageEvent {
+    UserCreated(uuid::Uuid),
+    UserUpdated(uuid::Uuid),
+    UserDeleted(uuid::Uuid),
+    MsgCreated(uuid::Uuid),
+    MsgUpdated(uuid::Uuid),
+    MsgDeleted(uuid::Uuid),
+}
+
 #[derive(thiserror::Error)]
 pub enum Error {
     #[error(transparent)]
@@ -35,7 +67,7 @@ impl From<Error> for ErrorResponse {
             Error::NoUser | Error::NoMsg => {
                 ErrorResponse::NotFoundError(value.into())
             }
-            Error::Multisig(error) => ErrorResponse::BadRequest(error.into()),
+            Error::Multisig(error) => error.into(),
         }
     }
 }
@@ -44,28 +76,81 @@ impl From<Error> for ErrorResponse {
 pub trait Storage {
     // CRUD for user
 
+    /// Names are unique, `UserExists` if the name is taken
     async fn store_user(&self, user: User) -> Result<(), Error>;
     async fn get_user(&self, username: &str) -> Result<Option<User>, Error>;
-    async fn update_user(&self, user: User) -> Result<(), Error>;
+    /// Mutate stored user in place, so concurrent updates don't overwrite
+    /// each other, and set `updated_at`. `name` is kept, use
+    /// `rename_user` to change it.
+    async fn update_user(
+        &self,
+        user_id: &uuid::Uuid,
+        with: UserModifier,
+    ) -> Result<(), Error>;
+    /// Sets `updated_at`, `UserExists` if another user has `new_name`
+    /// (`UPDATE users SET name = $2 WHERE id = $1` with a unique index)
+    async fn rename_user(
+        &self,
+        user_id: &uuid::Uuid,
+        new_name: &str,
+    ) -> Result<(), Error>;
     async fn remove_user(&self, user_id: &uuid::Uuid) -> Result<(), Error>;
     async fn all_users(&self) -> Result<Vec<User>, Error>;
+    /// Same users as `all_users`, fetched one by one (a cursor), so they
+    /// needn't fit in memory at once
+    fn stream_users(&self) -> BoxStream<'_, Result<User, Error>>;
+    /// Users whose name starts with `prefix`, ordered by name
+    /// (`WHERE name LIKE 'prefix%' ORDER BY name LIMIT limit`)
+    async fn search_users(
+        &self,
+        prefix: &str,
+        limit: Option<usize>,
+    ) -> Result<Vec<User>, Error>;
 
     // CRUD for msgs
 
-    async fn store_msg(&self, msg: Message) -> Result<(), Error>;
+    /// Returns id of the persisted message, which is the canonical one
+    async fn store_msg(&self, msg: Message) -> Result<uuid::Uuid, Error>;
     async fn get_msg(
         &self,
         msg_id: &uuid::Uuid,
     ) -> Result<Option<Message>, Error>;
-    /// Use that function to add signature
+    /// Messages in the order of `msg_ids`, `None` for missing ones
+    /// (`WHERE id = ANY($1)`)
+    async fn get_msgs(
+        &self,
+        msg_ids: &[uuid::Uuid],
+    ) -> Result<Vec<Option<Message>>, Error>;
+    /// Use that function to add signature, bumps message version and
+    /// `updated_at` if `with` succeeds. Finalized messages are rejected with
+    /// `multisig::Error::Finalized`, `with` isn't called for them.
     async fn update_msg(
         &self,
         msg_id: &uuid::Uuid,
         with: MsgModifier,
     ) -> Result<(), Error>;
+    /// Lookup by `Message::lookup_key`, oldest message wins
+    async fn get_msg_by_hash(
+        &self,
+        msg_hash: &secp256k1::hashes::sha256::Hash,
+    ) -> Result<Option<Message>, Error>;
     async fn remove_msg(
         &self,
         msg_hash: &secp256k1::hashes::sha256::Hash,
     ) -> Result<(), Error>;
     async fn all_messages(&self) -> Result<Vec<Message>, Error>;
+    /// Messages with `tag` among their tags, in insertion order
+    /// (`WHERE tag = $1` over an indexed tags table)
+    async fn msgs_by_tag(&self, tag: &str) -> Result<Vec<Message>, Error>;
+    async fn count_messages(&self) -> Result<usize, Error>;
+    /// Counted in place, without fetching records
+    /// (`SELECT count(*), min(created_at) FILTER (WHERE ...)`)
+    async fn stats(&self) -> Result<StorageStats, Error>;
+
+    /// Persist buffered writes, called once the server is stopped
+    async fn flush(&self) -> Result<(), Error>;
+
+    /// Changes made after the call. Slow receivers lose the oldest
+    /// events, see `broadcast::error::RecvError::Lagged`.
+    fn subscribe(&self) -> broadcast::Receiver<StorageEvent>;
 }
@@ -0,0 +1,429 @@
+use std::future::Future;
+use std::time::Duration;
+
+use crate::domain::{message::Message, user::User};
+
+use futures::stream::BoxStream;
+use tokio::sync::broadcast;
+
+use super::{
+    Error, MsgModifier, Storage, StorageEvent, StorageStats, UserModifier,
+};
+
+/// How `RetryingStorage` retries failed operations
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// Attempts of every operation, including the first one
+    pub max_attempts: u32,
+    /// Delay before the second attempt, doubled for every next one up to
+    /// `2^16` times
+    pub backoff: Duration,
+    /// Retry `store_*`, `remove_*` and `rename_user` too. Backends which
+    /// may fail after a mutation is applied shouldn't enable it.
+    pub retry_mutations: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 3,
+            backoff: Duration::from_millis(50),
+            retry_mutations: false,
+        }
+    }
+}
+
+/// Retries operations of the wrapped storage which failed with
+/// `Error::Internal`, which is how backends report transient failures.
+/// Business errors (`NoUser`, `MsgExists` etc.) are returned as is.
+///
+/// `update_user` and `update_msg` are never retried, their modifiers
+/// can't be replayed.
+#[derive(Debug, Clone)]
+pub struct RetryingStorage<S> {
+    inner: S,
+    policy: RetryPolicy,
+}
+
+impl<S> RetryingStorage<S> {
+    pub fn new(inner: S, policy: RetryPolicy) -> Self {
+        RetryingStorage { inner, policy }
+    }
+
+    async fn retry<T, F, Fut>(&self, mutation: bool, op: F) -> Result<T, Error>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = Result<T, Error>>,
+    {
+        let max_attempts = match mutation && !self.policy.retry_mutations {
+            true => 1,
+            false => self.policy.max_attempts.max(1),
+        };
+        let mut attempt = 1;
+        loop {
+            match op().await {
+                Err(Error::Internal(e)) if attempt < max_attempts => {
+                    tracing::warn!(attempt, "storage operation failed: {e}");
+                    let delay = self
+                        .policy
+                        .backoff
+                        .saturating_mul(1 << (attempt - 1).min(16));
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl<S: Storage + Send + Sync> Storage for RetryingStorage<S> {
+    async fn store_user(&self, user: User) -> Result<(), Error> {
+        self.retry(true, || self.inner.store_user(user.clone()))
+            .await
+    }
+
+    async fn get_user(&self, username: &str) -> Result<Option<User>, Error> {
+        self.retry(false, || self.inner.get_user(username)).await
+    }
+
+    async fn update_user(
+        &self,
+        user_id: &uuid::Uuid,
+        with: UserModifier,
+    ) -> Result<(), Error> {
+        self.inner.update_user(user_id, with).await
+    }
+
+    async fn rename_user(
+        &self,
+        user_id: &uuid::Uuid,
+        new_name: &str,
+    ) -> Result<(), Error> {
+        self.retry(true, || self.inner.rename_user(user_id, new_name))
+            .await
+    }
+
+    async fn remove_user(&self, user_id: &uuid::Uuid) -> Result<(), Error> {
+        self.retry(true, || self.inner.remove_user(user_id)).await
+    }
+
+    async fn all_users(&self) -> Result<Vec<User>, Error> {
+        self.retry(false, || self.inner.all_users()).await
+    }
+
+    /// Not retried, a stream can't be resumed where it failed
+    fn stream_users(&self) -> BoxStream<'_, Result<User, Error>> {
+        self.inner.stream_users()
+    }
+
+    async fn search_users(
+        &self,
+        prefix: &str,
+        limit: Option<usize>,
+    ) -> Result<Vec<User>, Error> {
+        self.retry(false, || self.inner.search_users(prefix, limit))
+            .await
+    }
+
+    async fn store_msg(&self, msg: Message) -> Result<uuid::Uuid, Error> {
+        self.retry(true, || self.inner.store_msg(msg.clone())).await
+    }
+
+    async fn get_msg(
+        &self,
+        msg_id: &uuid::Uuid,
+    ) -> Result<Option<Message>, Error> {
+        self.retry(false, || self.inner.get_msg(msg_id)).await
+    }
+
+    async fn get_msgs(
+        &self,
+        msg_ids: &[uuid::Uuid],
+    ) -> Result<Vec<Option<Message>>, Error> {
+        self.retry(false, || self.inner.get_msgs(msg_ids)).await
+    }
+
+    async fn update_msg(
+        &self,
+        msg_id: &uuid::Uuid,
+        with: MsgModifier,
+    ) -> Result<(), Error> {
+        self.inner.update_msg(msg_id, with).await
+    }
+
+    async fn get_msg_by_hash(
+        &self,
+        msg_hash: &secp256k1::hashes::sha256::Hash,
+    ) -> Result<Option<Message>, Error> {
+        self.retry(false, || self.inner.get_msg_by_hash(msg_hash))
+            .await
+    }
+
+    async fn remove_msg(
+        &self,
+        msg_hash: &secp256k1::hashes::sha256::Hash,
+    ) -> Result<(), Error> {
+        self.retry(true, || self.inner.remove_msg(msg_hash)).await
+    }
+
+    async fn all_messages(&self) -> Result<Vec<Message>, Error> {
+        self.retry(false, || self.inner.all_messages()).await
+    }
+
+    async fn msgs_by_tag(&self, tag: &str) -> Result<Vec<Message>, Error> {
+        self.retry(false, || self.inner.msgs_by_tag(tag)).await
+    }
+
+    async fn count_messages(&self) -> Result<usize, Error> {
+        self.retry(false, || self.inner.count_messages()).await
+    }
+
+    async fn stats(&self) -> Result<StorageStats, Error> {
+        self.retry(false, || self.inner.stats()).await
+    }
+
+    async fn flush(&self) -> Result<(), Error> {
+        self.retry(false, || self.inner.flush()).await
+    }
+
+    fn subscribe(&self) -> broadcast::Receiver<StorageEvent> {
+        self.inner.subscribe()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::time::Duration;
+
+    use futures::stream::BoxStream;
+    use tokio::sync::broadcast;
+
+    use crate::domain::{message::Message, user::User};
+    use crate::storage::in_memory::InMemoryStorage;
+    use crate::storage::{
+        Error, MsgModifier, Storage, StorageEvent, StorageStats, UserModifier,
+    };
+
+    use super::{RetryPolicy, RetryingStorage};
+
+    #[tokio::test]
+    async fn retrying_storage_recovers_from_transient_errors(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let storage = RetryingStorage::new(flaky(2), policy(true));
+        let user = user("alice");
+        storage.store_user(user.clone()).await?;
+        assert_eq!(storage.inner.attempts(), 3);
+
+        assert_eq!(storage.get_user("alice").await?, Some(user));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn retrying_storage_skips_mutations_by_default(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let storage = RetryingStorage::new(flaky(2), policy(false));
+        let result = storage.store_user(user("alice")).await;
+        assert!(matches!(result, Err(Error::Internal(_))));
+        assert_eq!(storage.inner.attempts(), 1);
+
+        // Reads are retried regardless
+        assert_eq!(storage.all_users().await?, vec![]);
+        assert_eq!(storage.inner.attempts(), 3);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn retrying_storage_gives_up_after_max_attempts() {
+        let storage = RetryingStorage::new(flaky(3), policy(true));
+        let result = storage.all_users().await;
+        assert!(matches!(result, Err(Error::Internal(_))));
+        assert_eq!(storage.inner.attempts(), 3);
+    }
+
+    #[tokio::test]
+    async fn retrying_storage_caps_backoff_of_many_attempts(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let policy = RetryPolicy {
+            max_attempts: 40,
+            backoff: Duration::ZERO,
+            retry_mutations: false,
+        };
+        let storage = RetryingStorage::new(flaky(39), policy);
+        assert_eq!(storage.all_users().await?, vec![]);
+        assert_eq!(storage.inner.attempts(), 40);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn retrying_storage_keeps_business_errors(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let storage = RetryingStorage::new(flaky(0), policy(true));
+        let user = user("alice");
+        storage.store_user(user.clone()).await?;
+        let result = storage.store_user(user).await;
+        assert!(matches!(result, Err(Error::UserExists)));
+        assert_eq!(storage.inner.attempts(), 2);
+        Ok(())
+    }
+
+    // Helpers
+
+    fn policy(retry_mutations: bool) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 3,
+            backoff: Duration::from_millis(1),
+            retry_mutations,
+        }
+    }
+
+    fn user(name: &str) -> User {
+        User {
+            name: name.to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn flaky(failures: u32) -> FlakyStorage {
+        FlakyStorage {
+            inner: InMemoryStorage::default(),
+            failures,
+            attempts: AtomicU32::new(0),
+        }
+    }
+
+    /// Fails first `failures` calls with `Error::Internal`
+    struct FlakyStorage {
+        inner: InMemoryStorage,
+        failures: u32,
+        attempts: AtomicU32,
+    }
+
+    impl FlakyStorage {
+        fn attempts(&self) -> u32 {
+            self.attempts.load(Ordering::SeqCst)
+        }
+
+        fn attempt(&self) -> Result<(), Error> {
+            match self.attempts.fetch_add(1, Ordering::SeqCst) < self.failures {
+                true => Err(anyhow::anyhow!("connection reset").into()),
+                false => Ok(()),
+            }
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl Storage for FlakyStorage {
+        async fn store_user(&self, user: User) -> Result<(), Error> {
+            self.attempt()?;
+            self.inner.store_user(user).await
+        }
+        async fn get_user(
+            &self,
+            username: &str,
+        ) -> Result<Option<User>, Error> {
+            self.attempt()?;
+            self.inner.get_user(username).await
+        }
+        async fn update_user(
+            &self,
+            user_id: &uuid::Uuid,
+            with: UserModifier,
+        ) -> Result<(), Error> {
+            self.attempt()?;
+            self.inner.update_user(user_id, with).await
+        }
+        async fn rename_user(
+            &self,
+            user_id: &uuid::Uuid,
+            new_name: &str,
+        ) -> Result<(), Error> {
+            self.attempt()?;
+            self.inner.rename_user(user_id, new_name).await
+        }
+        async fn remove_user(&self, user_id: &uuid::Uuid) -> Result<(), Error> {
+            self.attempt()?;
+            self.inner.remove_user(user_id).await
+        }
+        async fn all_users(&self) -> Result<Vec<User>, Error> {
+            self.attempt()?;
+            self.inner.all_users().await
+        }
+        fn stream_users(&self) -> BoxStream<'_, Result<User, Error>> {
+            self.inner.stream_users()
+        }
+        async fn search_users(
+            &self,
+            prefix: &str,
+            limit: Option<usize>,
+        ) -> Result<Vec<User>, Error> {
+            self.attempt()?;
+            self.inner.search_users(prefix, limit).await
+        }
+        async fn store_msg(&self, msg: Message) -> Result<uuid::Uuid, Error> {
+            self.attempt()?;
+            self.inner.store_msg(msg).await
+        }
+        async fn get_msg(
+            &self,
+            msg_id: &uuid::Uuid,
+        ) -> Result<Option<Message>, Error> {
+            self.attempt()?;
+            self.inner.get_msg(msg_id).await
+        }
+        async fn get_msgs(
+            &self,
+            msg_ids: &[uuid::Uuid],
+        ) -> Result<Vec<Option<Message>>, Error> {
+            self.attempt()?;
+            self.inner.get_msgs(msg_ids).await
+        }
+        async fn update_msg(
+            &self,
+            msg_id: &uuid::Uuid,
+            with: MsgModifier,
+        ) -> Result<(), Error> {
+            self.attempt()?;
+            self.inner.update_msg(msg_id, with).await
+        }
+        async fn get_msg_by_hash(
+            &self,
+            msg_hash: &secp256k1::hashes::sha256::Hash,
+        ) -> Result<Option<Message>, Error> {
+            self.attempt()?;
+            self.inner.get_msg_by_hash(msg_hash).await
+        }
+        async fn remove_msg(
+            &self,
+            msg_hash: &secp256k1::hashes::sha256::Hash,
+        ) -> Result<(), Error> {
+            self.attempt()?;
+            self.inner.remove_msg(msg_hash).await
+        }
+        async fn all_messages(&self) -> Result<Vec<Message>, Error> {
+            self.attempt()?;
+            self.inner.all_messages().await
+        }
+        async fn msgs_by_tag(&self, tag: &str) -> Result<Vec<Message>, Error> {
+            self.attempt()?;
+            self.inner.msgs_by_tag(tag).await
+        }
+        async fn count_messages(&self) -> Result<usize, Error> {
+            self.attempt()?;
+            self.inner.count_messages().await
+        }
+        async fn stats(&self) -> Result<StorageStats, Error> {
+            self.attempt()?;
+            self.inner.stats().await
+        }
+        async fn flush(&self) -> Result<(), Error> {
+            self.attempt()?;
+            self.inner.flush().await
+        }
+        fn subscribe(&self) -> broadcast::Receiver<StorageEvent> {
+            self.inner.subscribe()
+        }
+    }
+}
@@ -0,0 +1,90 @@
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+use crate::domain::multisig;
+
+type Verification = Result<(), multisig::Error>;
+
+#[derive(Debug, Default)]
+struct Entries {
+    results: HashMap<uuid::Uuid, (u64, Verification)>,
+    /// Ids in the order they were first cached, oldest are evicted first
+    order: VecDeque<uuid::Uuid>,
+}
+
+/// Last verification result of up to `capacity` messages, along with the
+/// message version it was computed for. Any update bumps the version, so
+/// stale entries never match.
+#[derive(Debug, Clone)]
+pub struct VerificationCache {
+    capacity: usize,
+    entries: Arc<Mutex<Entries>>,
+}
+
+impl VerificationCache {
+    pub fn new(capacity: usize) -> Self {
+        VerificationCache {
+            capacity,
+            entries: Default::default(),
+        }
+    }
+
+    /// Cached result for exactly that version of the message, if any.
+    /// Poisoned lock is treated as a miss.
+    pub fn get(
+        &self,
+        msg_id: &uuid::Uuid,
+        version: u64,
+    ) -> Option<Verification> {
+        let entries = self.entries.lock().ok()?;
+        match entries.results.get(msg_id) {
+            Some((v, result)) if *v == version => Some(result.clone()),
+            _ => None,
+        }
+    }
+
+    /// Replaces the result of an earlier version in place, evicts the
+    /// oldest message if a new one doesn't fit
+    pub fn insert(
+        &self,
+        msg_id: uuid::Uuid,
+        version: u64,
+        result: Verification,
+    ) {
+        let Ok(mut entries) = self.entries.lock() else {
+            return;
+        };
+        if entries.results.insert(msg_id, (version, result)).is_some() {
+            return;
+        }
+        entries.order.push_back(msg_id);
+        while entries.order.len() > self.capacity {
+            if let Some(evicted) = entries.order.pop_front() {
+                entries.results.remove(&evicted);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::VerificationCache;
+
+    #[test]
+    fn oldest_msg_is_evicted_over_capacity() {
+        let cache = VerificationCache::new(2);
+        let [first, second, third] = [(); 3].map(|_| uuid::Uuid::new_v4());
+        cache.insert(first, 0, Ok(()));
+        cache.insert(second, 0, Ok(()));
+        // Newer version of a cached message takes no extra room
+        cache.insert(first, 1, Ok(()));
+        assert!(cache.get(&second, 0).is_some());
+
+        cache.insert(third, 0, Ok(()));
+        assert!(cache.get(&first, 1).is_none());
+        assert!(cache.get(&second, 0).is_some());
+        assert!(cache.get(&third, 0).is_some());
+    }
+}
@@ -0,0 +1,100 @@
+use std::time::Duration;
+
+use rand::Rng;
+use serde::Serialize;
+
+/// Notification posted to the configured webhook url
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum WebhookEvent {
+    MsgSigned {
+        msg_id: uuid::Uuid,
+        signed: usize,
+        required: usize,
+    },
+}
+
+/// Delivers events in background tasks, retrying failed deliveries.
+///
+/// `429` and `503` responses with `Retry-After` (in seconds) are retried
+/// after the requested delay, other failures after exponential backoff
+/// with jitter. Events are dropped after `max_attempts`.
+#[derive(Debug, Clone)]
+pub struct WebhookSender {
+    client: reqwest::Client,
+    url: String,
+    max_attempts: u32,
+    backoff: Duration,
+}
+
+impl WebhookSender {
+    pub fn new(url: String, max_attempts: u32, backoff: Duration) -> Self {
+        WebhookSender {
+            client: reqwest::Client::new(),
+            url,
+            max_attempts,
+            backoff,
+        }
+    }
+
+    /// Spawn delivery, doesn't wait for it.
+    pub fn notify(&self, event: WebhookEvent) {
+        let sender = self.clone();
+        tokio::spawn(async move { sender.deliver(event).await });
+    }
+
+    #[tracing::instrument(name = "webhook.deliver", skip(self))]
+    async fn deliver(&self, event: WebhookEvent) {
+        for attempt in 1..=self.max_attempts {
+            let retry_after =
+                match self.client.post(&self.url).json(&event).send().await {
+                    Ok(response) if response.status().is_success() => return,
+                    Ok(response) => {
+                        tracing::warn!(
+                            attempt,
+                            status = %response.status(),
+                            "webhook delivery failed"
+                        );
+                        retry_after(&response)
+                    }
+                    Err(e) => {
+                        tracing::warn!(attempt, "webhook delivery failed: {e}");
+                        None
+                    }
+                };
+            if attempt < self.max_attempts {
+                let delay =
+                    retry_after.unwrap_or_else(|| self.backoff_delay(attempt));
+                tokio::time::sleep(delay).await;
+            }
+        }
+        tracing::error!(
+            attempts = self.max_attempts,
+            "dropping webhook notification"
+        );
+    }
+
+    /// `backoff * 2^(attempt - 1)`, plus up to half of it as jitter
+    fn backoff_delay(&self, attempt: u32) -> Duration {
+        let delay = self.backoff.saturating_mul(1 << (attempt - 1).min(16));
+        let jitter = rand::rng().random_range(0.0..0.5);
+        delay.mul_f64(1.0 + jitter)
+    }
+}
+
+/// Delay requested by `429` or `503` response
+fn retry_after(response: &reqwest::Response) -> Option<Duration> {
+    match response.status() {
+        http::StatusCode::TOO_MANY_REQUESTS
+        | http::StatusCode::SERVICE_UNAVAILABLE => response
+            .headers()
+            .get(http::header::RETRY_AFTER)?
+            .to_str()
+            .ok()?
+            .trim()
+            .parse::<u64>()
+            .ok()
+            .map(Duration::from_secs),
+        _ => None,
+    }
+}
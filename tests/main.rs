@@ -1,7 +1,26 @@
+use multisig_ecdsa::capture::MAX_CAPTURED_BODY_BYTES;
 use multisig_ecdsa::config::Settings;
-use multisig_ecdsa::startup::api_doc::{PostMsgRequest, SignMsgRequest};
+use multisig_ecdsa::domain::message::Message;
+use multisig_ecdsa::domain::user::User as DomainUser;
+use multisig_ecdsa::startup::api_doc::{
+    BatchGetMsgsRequest, ContentEncoding, CreateMsgOutcome, CreatedMsg,
+    KeySignOutcome, MessageDetail, MsgPreview, PatchParticipantsRequest,
+    PostMsgRequest, RenameUserRequest, SignMsgRequest, SignOutcome,
+    SignRawMsgRequest, UploadMsgResponse, User, VerificationResult,
+    VerifyAgainstRequest, VerifyAgainstResult, VerifyAllResponse,
+    VerifyContentRequest, VersionInfo,
+};
 use multisig_ecdsa::startup::Application;
+use multisig_ecdsa::storage::in_memory::InMemoryStorage;
+use multisig_ecdsa::storage::{
+    Error as StorageError, MsgModifier, SharedStorage, Storage, StorageEvent,
+    StorageStats, UserModifier,
+};
 use reqwest::StatusCode;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use tracing_subscriber::layer::SubscriberExt;
 
 type MsgId = String;
 
@@ -13,16 +32,31 @@ pub struct TestApp {
 
 impl TestApp {
     pub async fn spawn_app() -> TestApp {
-        let mut config = Settings::load_configuration()
-            .expect("failed to load configuration");
-        config.app_port = 0;
-
+        // Use `RUST_LOG` to see application logs
+        let config = Settings::builder().log_filter("off").build();
+        Self::spawn_app_with(config).await
+    }
+    pub async fn spawn_app_with(config: Settings) -> TestApp {
         let application = Application::build(config.clone())
             .await
             .expect("failed to build application");
-
+        Self::spawn(application, config)
+    }
+    /// Same as `spawn_app_with`, on top of `storage` which tests may
+    /// modify directly
+    pub async fn spawn_app_with_storage(
+        config: Settings,
+        storage: SharedStorage,
+    ) -> TestApp {
+        let application =
+            Application::build_with_storage(config.clone(), storage)
+                .await
+                .expect("failed to build application");
+        Self::spawn(application, config)
+    }
+    fn spawn(application: Application, config: Settings) -> TestApp {
         let port = application.port();
-        let address = format!("http://{}:{}", config.app_ip, port);
+        let address = format!("http://{}", application.address());
 
         tokio::spawn(application.run_until_stopped());
 
@@ -66,12 +100,12 @@ impl TestApp {
             .json(&PostMsgRequest {
                 content: msg.to_string(),
                 keys: keys.to_vec(),
-                required_signature_count: None,
+                ..Default::default()
             })
             .send()
             .await?;
         assert_eq!(create_msg_resp.status(), StatusCode::OK);
-        let msg_id = create_msg_resp.text().await?;
+        let msg_id = create_msg_resp.json::<CreatedMsg>().await?.id.to_string();
         Ok(msg_id)
     }
 }
@@ -92,7 +126,10 @@ async fn test_create_and_verify_message(
     // Sign the message
     let sign_msg_resp = client
         .post(format!("{}/api/v1/msg/{}", addr, msg_id))
-        .json(&SignMsgRequest { keys })
+        .json(&SignMsgRequest {
+            keys,
+            ..Default::default()
+        })
         .send()
         .await?;
     assert_eq!(sign_msg_resp.status(), StatusCode::OK);
@@ -108,6 +145,26 @@ async fn test_create_and_verify_message(
     Ok(())
 }
 
+#[tokio::test]
+async fn test_app_boots_with_builder_settings(
+) -> Result<(), Box<dyn std::error::Error>> {
+    let config = Settings::builder()
+        .app_ip(std::net::Ipv4Addr::LOCALHOST)
+        .app_port(0)
+        .build();
+    let application = Application::build(config).await?;
+    let address = application.address();
+    assert_ne!(address.port(), 0);
+    assert_eq!(address.port(), application.port());
+    assert_eq!(address.ip(), std::net::Ipv4Addr::LOCALHOST);
+    tokio::spawn(application.run_until_stopped());
+
+    let response =
+        reqwest::get(format!("http://{address}/api/healthcheck")).await?;
+    assert_eq!(response.status(), StatusCode::OK);
+    Ok(())
+}
+
 #[tokio::test]
 async fn test_bad_key_fail() -> Result<(), Box<dyn std::error::Error>> {
     let app = TestApp::spawn_app().await;
@@ -120,10 +177,3371 @@ async fn test_bad_key_fail() -> Result<(), Box<dyn std::error::Error>> {
         .json(&PostMsgRequest {
             content: "Hello world!".to_string(),
             keys: vec!["badkey".to_string()],
-            required_signature_count: None,
+            ..Default::default()
+        })
+        .send()
+        .await?;
+    assert_invalid_fields(response, &["keys"]).await
+}
+
+#[tokio::test]
+async fn test_storage_spans_emitted_on_sign(
+) -> Result<(), Box<dyn std::error::Error>> {
+    let spans = SpanNames::default();
+    let subscriber = tracing_subscriber::registry().with(spans.clone());
+    let _guard = tracing::subscriber::set_default(subscriber);
+
+    let app = TestApp::spawn_app().await;
+    let client = reqwest::Client::new();
+    let keys = app.create_user_with_keys(&client).await?;
+    let msg_id = app.create_msg(&client, &keys, "Hello world!").await?;
+    let sign_msg_resp = client
+        .post(format!("{}/api/v1/msg/{}", app.address, msg_id))
+        .json(&SignMsgRequest {
+            keys,
+            ..Default::default()
+        })
+        .send()
+        .await?;
+    assert_eq!(sign_msg_resp.status(), StatusCode::OK);
+
+    assert!(spans.contains("storage.update_msg"));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_idempotent_msg_create_replay_returns_same_id(
+) -> Result<(), Box<dyn std::error::Error>> {
+    let app = TestApp::spawn_app().await;
+    let client = reqwest::Client::new();
+    let keys = app.create_user_with_keys(&client).await?;
+    let req = PostMsgRequest {
+        content: "Hello world!".to_string(),
+        keys,
+        ..Default::default()
+    };
+
+    let mut ids = Vec::new();
+    for _ in 0..2 {
+        let response = client
+            .post(format!("{}/api/v1/msg", app.address))
+            .header("Idempotency-Key", "retry-1")
+            .json(&req)
+            .send()
+            .await?;
+        assert_eq!(response.status(), StatusCode::OK);
+        ids.push(response.json::<CreatedMsg>().await?.id.to_string());
+    }
+    assert_eq!(ids[0], ids[1]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_idempotency_key_reuse_with_other_body_conflicts(
+) -> Result<(), Box<dyn std::error::Error>> {
+    let app = TestApp::spawn_app().await;
+    let client = reqwest::Client::new();
+    let keys = app.create_user_with_keys(&client).await?;
+
+    let mut statuses = Vec::new();
+    for content in ["Hello world!", "Goodbye world!"] {
+        let response = client
+            .post(format!("{}/api/v1/msg", app.address))
+            .header("Idempotency-Key", "retry-1")
+            .json(&PostMsgRequest {
+                content: content.to_string(),
+                keys: keys.clone(),
+                ..Default::default()
+            })
+            .send()
+            .await?;
+        statuses.push(response.status());
+    }
+    assert_eq!(statuses, [StatusCode::OK, StatusCode::CONFLICT]);
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_concurrent_idempotent_retries_store_one_msg(
+) -> Result<(), Box<dyn std::error::Error>> {
+    let app = TestApp::spawn_app().await;
+    let client = reqwest::Client::new();
+    let keys = app.create_user_with_keys(&client).await?;
+    let req = PostMsgRequest {
+        content: "Hello world!".to_string(),
+        keys,
+        ..Default::default()
+    };
+
+    let responses = futures::future::try_join_all((0..16).map(|_| {
+        client
+            .post(format!("{}/api/v1/msg", app.address))
+            .header("Idempotency-Key", "retry-1")
+            .json(&req)
+            .send()
+    }))
+    .await?;
+    let mut ids = std::collections::HashSet::new();
+    for response in responses {
+        match response.status() {
+            StatusCode::OK => {
+                ids.insert(response.json::<CreatedMsg>().await?.id);
+            }
+            // Retried while the first request was in flight
+            status => assert_eq!(status, StatusCode::CONFLICT),
+        }
+    }
+    assert_eq!(ids.len(), 1);
+    let msgs: Vec<serde_json::Value> = client
+        .get(format!("{}/api/v1/msgs", app.address))
+        .send()
+        .await?
+        .json()
+        .await?;
+    assert_eq!(msgs.len(), 1);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_get_msg_by_content_hash() -> Result<(), Box<dyn std::error::Error>>
+{
+    use secp256k1::hashes::{sha256, Hash};
+
+    let app = TestApp::spawn_app().await;
+    let client = reqwest::Client::new();
+    let keys = app.create_user_with_keys(&client).await?;
+    let msg_id = app.create_msg(&client, &keys, "Hello world!").await?;
+
+    let hash = sha256::Hash::hash(b"Hello world!");
+    let response = client
+        .get(format!("{}/api/v1/msg/by-hash/{}", app.address, hash))
+        .send()
+        .await?;
+    assert_eq!(response.status(), StatusCode::OK);
+    let msg: MessageDetail = response.json().await?;
+    assert_eq!(msg.id.to_string(), msg_id);
+    assert_eq!(msg.content, "Hello world!");
+    assert_eq!(msg.content_encoding, ContentEncoding::Text);
+
+    let other_hash = sha256::Hash::hash(b"Goodbye world!");
+    let response = client
+        .get(format!("{}/api/v1/msg/by-hash/{}", app.address, other_hash))
+        .send()
+        .await?;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_sign_reports_outcome_of_every_key(
+) -> Result<(), Box<dyn std::error::Error>> {
+    let app = TestApp::spawn_app().await;
+    let client = reqwest::Client::new();
+    let keys = app.create_user_with_keys(&client).await?;
+    let msg_id = app.create_msg(&client, &keys[..2], "Hello world!").await?;
+    let sign = |keys: Vec<String>| {
+        client
+            .post(format!("{}/api/v1/msg/{}", app.address, msg_id))
+            .json(&SignMsgRequest {
+                keys,
+                ..Default::default()
+            })
+            .send()
+    };
+
+    let first: Vec<KeySignOutcome> =
+        sign(vec![keys[0].clone()]).await?.json().await?;
+    assert_eq!(first[0].outcome, SignOutcome::Signed);
+
+    let response = sign(keys.clone()).await?;
+    assert_eq!(response.status(), StatusCode::OK);
+    let outcomes: Vec<KeySignOutcome> = response.json().await?;
+    let outcomes = outcomes
+        .into_iter()
+        .map(|o| (o.address, o.outcome))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        outcomes,
+        vec![
+            (keys[0].clone(), SignOutcome::AlreadySigned),
+            (keys[1].clone(), SignOutcome::Signed),
+            (keys[2].clone(), SignOutcome::NotParticipant),
+        ]
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_sign_raw_with_foreign_signature_fail(
+) -> Result<(), Box<dyn std::error::Error>> {
+    let app = TestApp::spawn_app().await;
+    let client = reqwest::Client::new();
+    let keys = app.create_user_with_keys(&client).await?;
+    let msg_id = app.create_msg(&client, &keys, "Hello world!").await?;
+
+    // Signed offline with a key which is not the participant's one
+    let secp = secp256k1::Secp256k1::new();
+    let keypair = multisig_ecdsa::crypto::new_keypair(&secp)?;
+    let signature =
+        multisig_ecdsa::crypto::sign(&secp, b"Hello world!", &keypair)?;
+
+    let response = client
+        .post(format!("{}/api/v1/msg/{}/sign-raw", app.address, msg_id))
+        .json(&SignRawMsgRequest {
+            address: keys[0].clone(),
+            signature_der_hex: signature.to_string(),
+            ..Default::default()
         })
         .send()
         .await?;
     assert_eq!(response.status(), StatusCode::BAD_REQUEST);
     Ok(())
 }
+
+#[tokio::test]
+async fn test_warn_log_filter_suppresses_request_logs(
+) -> Result<(), Box<dyn std::error::Error>> {
+    for (log_filter, info_logged) in [("info", true), ("warn", false)] {
+        let events = EventLevels::default();
+        let subscriber = tracing_subscriber::registry()
+            .with(multisig_ecdsa::startup::env_filter(log_filter)?)
+            .with(events.clone());
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let app = TestApp::spawn_app().await;
+        let response = reqwest::Client::new()
+            .get(format!("{}/api/v1/users", app.address))
+            .send()
+            .await?;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        assert_eq!(events.contains(tracing::Level::INFO), info_logged);
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_openapi_declares_api_key_security_scheme(
+) -> Result<(), Box<dyn std::error::Error>> {
+    let app = TestApp::spawn_app().await;
+    let spec: serde_json::Value =
+        reqwest::get(format!("{}/api-docs/openapi.json", app.address))
+            .await?
+            .json()
+            .await?;
+    assert_eq!(
+        spec["components"]["securitySchemes"]["api_key"],
+        serde_json::json!({
+            "type": "apiKey",
+            "in": "header",
+            "name": "X-API-Key",
+        })
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_openapi_lists_public_base_url_as_server(
+) -> Result<(), Box<dyn std::error::Error>> {
+    let app = TestApp::spawn_app().await;
+    let spec: serde_json::Value =
+        reqwest::get(format!("{}/api-docs/openapi.json", app.address))
+            .await?
+            .json()
+            .await?;
+    assert!(spec.get("servers").is_none());
+
+    let config = Settings::builder()
+        .log_filter("off")
+        .public_base_url("https://multisig.example.com")
+        .build();
+    let app = TestApp::spawn_app_with(config).await;
+    let spec: serde_json::Value =
+        reqwest::get(format!("{}/api-docs/openapi.json", app.address))
+            .await?
+            .json()
+            .await?;
+    assert_eq!(spec["servers"][0]["url"], "https://multisig.example.com");
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_cors_preflight_allows_api_methods_and_headers(
+) -> Result<(), Box<dyn std::error::Error>> {
+    let app = TestApp::spawn_app().await;
+    let response = reqwest::Client::new()
+        .request(
+            reqwest::Method::OPTIONS,
+            format!(
+                "{}/api/v1/msg/{}/participants",
+                app.address,
+                uuid::Uuid::new_v4()
+            ),
+        )
+        .header("Origin", "https://example.com")
+        .header("Access-Control-Request-Method", "PATCH")
+        .header(
+            "Access-Control-Request-Headers",
+            "content-type,x-api-key,if-match,idempotency-key",
+        )
+        .send()
+        .await?;
+    assert!(response.status().is_success());
+    let allowed = |name: &str| {
+        response
+            .headers()
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default()
+            .to_lowercase()
+    };
+    let methods = allowed("access-control-allow-methods");
+    assert!(["patch", "delete"].iter().all(|m| methods.contains(m)));
+    let headers = allowed("access-control-allow-headers");
+    assert!(["content-type", "x-api-key", "if-match", "idempotency-key"]
+        .iter()
+        .all(|h| headers.contains(h)));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_json_schema_is_exported() -> Result<(), Box<dyn std::error::Error>>
+{
+    let app = TestApp::spawn_app().await;
+    let response =
+        reqwest::get(format!("{}/api/schema/PostMsgRequest", app.address))
+            .await?;
+    assert_eq!(response.status(), StatusCode::OK);
+    let schema: serde_json::Value = response.json().await?;
+    assert_eq!(schema["title"], "PostMsgRequest");
+    assert!(schema["properties"]["content"].is_object());
+    assert!(schema["properties"]["keys"].is_object());
+    let required = schema["required"].as_array().ok_or("no required")?;
+    assert!(required.contains(&"content".into()));
+    // Open messages have no keys, so they default to empty
+    assert!(!required.contains(&"keys".into()));
+
+    let response =
+        reqwest::get(format!("{}/api/schema/Settings", app.address)).await?;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_openapi_describes_message_detail(
+) -> Result<(), Box<dyn std::error::Error>> {
+    let app = TestApp::spawn_app().await;
+    let spec: serde_json::Value =
+        reqwest::get(format!("{}/api-docs/openapi.json", app.address))
+            .await?
+            .json()
+            .await?;
+    let schema = &spec["components"]["schemas"]["MessageDetail"];
+    for field in [
+        "id",
+        "content",
+        "contentEncoding",
+        "nonce",
+        "countRequired",
+        "progress",
+    ] {
+        assert!(
+            schema["properties"][field].is_object(),
+            "MessageDetail.{field} is not documented"
+        );
+    }
+    assert_eq!(schema["example"]["content"], "Hello world!");
+    for name in ["User", "VerifyAllResponse", "VerificationResult"] {
+        assert!(
+            spec["components"]["schemas"][name].is_object(),
+            "{name} is not documented"
+        );
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_mutating_request_requires_configured_api_key(
+) -> Result<(), Box<dyn std::error::Error>> {
+    let config = Settings::builder()
+        .log_filter("off")
+        .api_key("secret")
+        .build();
+    let app = TestApp::spawn_app_with(config).await;
+    let client = reqwest::Client::new();
+
+    let response = client
+        .post(format!("{}/api/v1/user?name=testuser", app.address))
+        .send()
+        .await?;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    let response = client
+        .post(format!("{}/api/v1/user?name=testuser", app.address))
+        .header("X-API-Key", "secret")
+        .send()
+        .await?;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let response = client
+        .get(format!("{}/api/v1/user/testuser", app.address))
+        .send()
+        .await?;
+    assert_eq!(response.status(), StatusCode::OK);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_openapi_lists_all_api_routes(
+) -> Result<(), Box<dyn std::error::Error>> {
+    let app = TestApp::spawn_app().await;
+    let spec: serde_json::Value =
+        reqwest::get(format!("{}/api-docs/openapi.json", app.address))
+            .await?
+            .json()
+            .await?;
+    let routes = [
+        ("/api/v1/user", "post"),
+        ("/api/v1/user/{username}", "get"),
+        ("/api/v1/user/{username}", "patch"),
+        ("/api/v1/users", "get"),
+        ("/api/v1/users/search", "get"),
+        ("/api/v1/user/{username}/keypair", "post"),
+        ("/api/v1/msg", "post"),
+        ("/api/v1/msg/upload", "post"),
+        ("/api/v1/msg/preview", "post"),
+        ("/api/v1/msg/{msg_id}", "post"),
+        ("/api/v1/msg/{msg_id}", "get"),
+        ("/api/v1/msg/{msg_id}/verify", "post"),
+        ("/api/v1/msg/{msg_id}/sign-raw", "post"),
+        ("/api/v1/msg/by-hash/{msg_hash}", "get"),
+        ("/api/v1/msg/{msg_id}/finalize", "post"),
+        ("/api/v1/msg/{msg_id}/clone", "post"),
+        ("/api/v1/msg/{msg_id}/content", "get"),
+        ("/api/v1/msg/{msg_id}/verify-against", "post"),
+        ("/api/v1/msgs", "get"),
+        ("/api/v1/msgs", "post"),
+        ("/api/v1/msgs/batch-get", "post"),
+        ("/api/v1/verifymessage", "post"),
+        ("/api/version", "get"),
+        ("/api/v1/admin/recent-requests", "get"),
+        ("/api/v1/admin/metrics", "get"),
+    ];
+    for (path, method) in routes {
+        assert!(
+            spec["paths"][path][method].is_object(),
+            "{method} {path} is not documented"
+        );
+    }
+    // Mutating routes reference the api key scheme
+    assert_eq!(
+        spec["paths"]["/api/v1/msg"]["post"]["security"],
+        serde_json::json!([{ "api_key": [] }])
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_v2_wraps_user_list_in_envelope(
+) -> Result<(), Box<dyn std::error::Error>> {
+    let app = TestApp::spawn_app().await;
+    let client = reqwest::Client::new();
+    app.create_user_with_keys(&client).await?;
+
+    let response = client
+        .get(format!("{}/api/v2/users", app.address))
+        .send()
+        .await?;
+    assert_eq!(response.status(), StatusCode::OK);
+    let request_id = response.headers()["X-Request-Id"].to_str()?.to_owned();
+    let body: serde_json::Value = response.json().await?;
+    assert_eq!(body["request_id"], request_id.as_str());
+    assert!(body["timestamp"].is_string());
+    assert_eq!(body["data"][0]["name"], "testuser");
+    assert_eq!(body["data"][0]["keys"].as_array().map(Vec::len), Some(3));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_v2_wraps_created_msg_id_in_envelope(
+) -> Result<(), Box<dyn std::error::Error>> {
+    let app = TestApp::spawn_app().await;
+    let client = reqwest::Client::new();
+    let keys = app.create_user_with_keys(&client).await?;
+
+    let response = client
+        .post(format!("{}/api/v2/msg", app.address))
+        .json(&PostMsgRequest {
+            content: "Hello world!".to_string(),
+            keys,
+            ..Default::default()
+        })
+        .send()
+        .await?;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body: serde_json::Value = response.json().await?;
+    let msg_id = body["data"]["id"].as_str().ok_or("no message id")?;
+    assert!(msg_id.parse::<uuid::Uuid>().is_ok());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_v2_passes_plain_text_through(
+) -> Result<(), Box<dyn std::error::Error>> {
+    let app = TestApp::spawn_app().await;
+    let client = reqwest::Client::new();
+    let keys = app.create_user_with_keys(&client).await?;
+    let msg_id = app.create_msg(&client, &keys, "Hello world!").await?;
+
+    let response = client
+        .get(format!("{}/api/v2/msg/{}", app.address, msg_id))
+        .send()
+        .await?;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(response.headers()["Content-Type"]
+        .to_str()?
+        .starts_with("text/plain"));
+    assert_eq!(
+        response.text().await?,
+        "Not enough signatures, provided: 0, required: 3"
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_verify_all_summarizes_messages(
+) -> Result<(), Box<dyn std::error::Error>> {
+    let app = TestApp::spawn_app().await;
+    let client = reqwest::Client::new();
+    let keys = app.create_user_with_keys(&client).await?;
+    let complete_id = app.create_msg(&client, &keys, "Hello world!").await?;
+    app.create_msg(&client, &keys, "Goodbye world!").await?;
+    let response = client
+        .post(format!("{}/api/v1/msg/{}", app.address, complete_id))
+        .json(&SignMsgRequest {
+            keys,
+            ..Default::default()
+        })
+        .send()
+        .await?;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let summary: VerifyAllResponse = client
+        .post(format!("{}/api/v1/msgs/verify-all", app.address))
+        .send()
+        .await?
+        .json()
+        .await?;
+    assert_eq!(summary.complete, 1);
+    assert_eq!(summary.pending, 1);
+    assert_eq!(summary.invalid, 0);
+    assert!(summary.invalid_ids.is_empty());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_patch_participants_before_and_after_signing(
+) -> Result<(), Box<dyn std::error::Error>> {
+    let app = TestApp::spawn_app().await;
+    let client = reqwest::Client::new();
+    let keys = app.create_user_with_keys(&client).await?;
+    let msg_id = app.create_msg(&client, &keys[..2], "Hello world!").await?;
+    let participants_url =
+        format!("{}/api/v1/msg/{}/participants", app.address, msg_id);
+
+    let response = client
+        .patch(&participants_url)
+        .json(&PatchParticipantsRequest {
+            add: vec![keys[2].clone()],
+            ..Default::default()
+        })
+        .send()
+        .await?;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // Newly added participant can sign
+    let response = client
+        .post(format!("{}/api/v1/msg/{}", app.address, msg_id))
+        .json(&SignMsgRequest {
+            keys: vec![keys[2].clone()],
+            ..Default::default()
+        })
+        .send()
+        .await?;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let response = client
+        .patch(&participants_url)
+        .json(&PatchParticipantsRequest {
+            remove: vec![keys[0].clone()],
+            ..Default::default()
+        })
+        .send()
+        .await?;
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_user_keys_order_is_stable(
+) -> Result<(), Box<dyn std::error::Error>> {
+    let app = TestApp::spawn_app().await;
+    let client = reqwest::Client::new();
+    let keys = app.create_user_with_keys(&client).await?;
+
+    for _ in 0..2 {
+        let user: serde_json::Value = client
+            .get(format!("{}/api/v1/user/testuser", app.address))
+            .send()
+            .await?
+            .json()
+            .await?;
+        // Same order as keys were created
+        assert_eq!(user["keys"], serde_json::json!(keys));
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_verify_negotiates_response_format(
+) -> Result<(), Box<dyn std::error::Error>> {
+    let app = TestApp::spawn_app().await;
+    let client = reqwest::Client::new();
+    let keys = app.create_user_with_keys(&client).await?;
+    let msg_id = app.create_msg(&client, &keys, "Hello world!").await?;
+    let verify_url = format!("{}/api/v1/msg/{}", app.address, msg_id);
+
+    let response = client
+        .get(&verify_url)
+        .header("Accept", "application/json")
+        .send()
+        .await?;
+    assert_eq!(response.status(), StatusCode::OK);
+    let result: VerificationResult = response.json().await?;
+    assert!(!result.success);
+    assert_eq!(result.signed, 0);
+    assert_eq!(result.required, 3);
+    assert!(result.reason.is_some());
+
+    for accept in ["text/plain", "*/*"] {
+        let response = client
+            .get(&verify_url)
+            .header("Accept", accept)
+            .send()
+            .await?;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.text().await?,
+            "Not enough signatures, provided: 0, required: 3"
+        );
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_default_threshold_policy_applies_to_omitted_count(
+) -> Result<(), Box<dyn std::error::Error>> {
+    use multisig_ecdsa::domain::message::ThresholdPolicy;
+
+    for (policy, required) in [
+        (ThresholdPolicy::All, 3),
+        (ThresholdPolicy::Majority, 3),
+        (ThresholdPolicy::Any, 1),
+    ] {
+        let config = Settings::builder()
+            .log_filter("off")
+            .default_threshold_policy(policy)
+            .build();
+        let app = TestApp::spawn_app_with(config).await;
+        let client = reqwest::Client::new();
+        let keys = app.create_user_with_keys(&client).await?;
+        let msg_id = app.create_msg(&client, &keys, "Hello world!").await?;
+
+        let result: VerificationResult = client
+            .get(format!("{}/api/v1/msg/{}", app.address, msg_id))
+            .header("Accept", "application/json")
+            .send()
+            .await?
+            .json()
+            .await?;
+        assert_eq!(result.required, required, "{policy:?}");
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_created_msg_reports_effective_threshold(
+) -> Result<(), Box<dyn std::error::Error>> {
+    use multisig_ecdsa::domain::message::ThresholdPolicy;
+
+    let config = Settings::builder()
+        .log_filter("off")
+        .default_threshold_policy(ThresholdPolicy::Any)
+        .build();
+    let app = TestApp::spawn_app_with(config).await;
+    let client = reqwest::Client::new();
+    let keys = app.create_user_with_keys(&client).await?;
+    let response = client
+        .post(format!("{}/api/v1/msg", app.address))
+        .json(&PostMsgRequest {
+            content: "Hello world!".to_string(),
+            keys,
+            ..Default::default()
+        })
+        .send()
+        .await?;
+    assert_eq!(response.status(), StatusCode::OK);
+    let created: CreatedMsg = response.json().await?;
+    assert_eq!(created.participants, 3);
+    assert_eq!(created.required, 1);
+
+    let result: VerificationResult = client
+        .get(format!("{}/api/v1/msg/{}", app.address, created.id))
+        .header("Accept", "application/json")
+        .send()
+        .await?
+        .json()
+        .await?;
+    assert_eq!(result.required, created.required);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_msgs_are_created_in_bulk(
+) -> Result<(), Box<dyn std::error::Error>> {
+    let app = TestApp::spawn_app().await;
+    let client = reqwest::Client::new();
+    let keys = app.create_user_with_keys(&client).await?;
+    let reqs = (0..5)
+        .map(|i| PostMsgRequest {
+            content: format!("Hello world {i}!"),
+            keys: match i {
+                2 => vec!["not a key".to_string()],
+                _ => keys.clone(),
+            },
+            ..Default::default()
+        })
+        .collect::<Vec<_>>();
+    let response = client
+        .post(format!("{}/api/v1/msgs", app.address))
+        .json(&reqs)
+        .send()
+        .await?;
+    assert_eq!(response.status(), StatusCode::OK);
+    let outcomes: Vec<CreateMsgOutcome> = response.json().await?;
+    assert_eq!(outcomes.len(), 5);
+    assert!(matches!(
+        outcomes[2],
+        CreateMsgOutcome::Failed { status: 422, .. }
+    ));
+    let ids = outcomes
+        .iter()
+        .filter_map(|outcome| match outcome {
+            CreateMsgOutcome::Created(created) => Some(created.id),
+            CreateMsgOutcome::Failed { .. } => None,
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(ids.len(), 4);
+    for id in ids {
+        let response = client
+            .get(format!("{}/api/v1/msg/{id}", app.address))
+            .header("Accept", "application/json")
+            .send()
+            .await?;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_msg_preview_matches_creation_without_storing(
+) -> Result<(), Box<dyn std::error::Error>> {
+    use multisig_ecdsa::domain::message::ThresholdPolicy;
+
+    let config = Settings::builder()
+        .log_filter("off")
+        .default_threshold_policy(ThresholdPolicy::Majority)
+        .build();
+    let app = TestApp::spawn_app_with(config).await;
+    let client = reqwest::Client::new();
+    let keys = app.create_user_with_keys(&client).await?;
+    let req = PostMsgRequest {
+        content: "Hello world!".to_string(),
+        keys: keys.clone(),
+        ..Default::default()
+    };
+    let list_msgs = || async {
+        client
+            .get(format!("{}/api/v1/msgs", app.address))
+            .send()
+            .await?
+            .json::<Vec<MessageDetail>>()
+            .await
+    };
+
+    let response = client
+        .post(format!("{}/api/v1/msg/preview", app.address))
+        .json(&req)
+        .send()
+        .await?;
+    assert_eq!(response.status(), StatusCode::OK);
+    let preview: MsgPreview = response.json().await?;
+    assert_eq!(preview.participants, keys);
+    assert_eq!(preview.content, "Hello world!");
+    assert!(list_msgs().await?.is_empty());
+
+    let created: CreatedMsg = client
+        .post(format!("{}/api/v1/msg", app.address))
+        .json(&req)
+        .send()
+        .await?
+        .json()
+        .await?;
+    assert_eq!(created.required, preview.required);
+    assert_eq!(created.participants, preview.participants.len());
+    assert_eq!(list_msgs().await?.len(), 1);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_explicit_count_overrides_threshold_policy(
+) -> Result<(), Box<dyn std::error::Error>> {
+    use multisig_ecdsa::domain::message::ThresholdPolicy;
+
+    let config = Settings::builder()
+        .log_filter("off")
+        .default_threshold_policy(ThresholdPolicy::Any)
+        .build();
+    let app = TestApp::spawn_app_with(config).await;
+    let client = reqwest::Client::new();
+    let keys = app.create_user_with_keys(&client).await?;
+    let msg_id = client
+        .post(format!("{}/api/v1/msg", app.address))
+        .json(&PostMsgRequest {
+            content: "Hello world!".to_string(),
+            keys,
+            required_signature_count: Some(2),
+            ..Default::default()
+        })
+        .send()
+        .await?
+        .json::<CreatedMsg>()
+        .await?
+        .id;
+
+    let result: VerificationResult = client
+        .get(format!("{}/api/v1/msg/{}", app.address, msg_id))
+        .header("Accept", "application/json")
+        .send()
+        .await?
+        .json()
+        .await?;
+    assert_eq!(result.required, 2);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_upload_large_msg_stores_content_hash(
+) -> Result<(), Box<dyn std::error::Error>> {
+    use secp256k1::hashes::{sha256, Hash};
+
+    let app = TestApp::spawn_app_with(
+        Settings::builder()
+            .log_filter("off")
+            .max_upload_bytes(8 * 1024 * 1024)
+            .build(),
+    )
+    .await;
+    let client = reqwest::Client::new();
+    let keys = app.create_user_with_keys(&client).await?;
+    let content = (0..5 * 1024 * 1024)
+        .map(|i| (i % 251) as u8)
+        .collect::<Vec<_>>();
+    let hash = sha256::Hash::hash(&content);
+
+    let response = client
+        .post(format!(
+            "{}/api/v1/msg/upload?keys={}&required_signature_count=2",
+            app.address,
+            keys.join(",")
+        ))
+        .header("Content-Type", "application/octet-stream")
+        .body(content.clone())
+        .send()
+        .await?;
+    assert_eq!(response.status(), StatusCode::OK);
+    let uploaded: UploadMsgResponse = response.json().await?;
+    assert_eq!(uploaded.sha256, hash.to_string());
+
+    let response = client
+        .get(format!("{}/api/v1/msg/by-hash/{}", app.address, hash))
+        .send()
+        .await?;
+    assert_eq!(response.status(), StatusCode::OK);
+    let msg: MessageDetail = response.json().await?;
+    assert_eq!(msg.id, uploaded.id);
+    assert_eq!(msg.count_required, 2);
+
+    // Content over the limit is rejected
+    let response = client
+        .post(format!(
+            "{}/api/v1/msg/upload?keys={}",
+            app.address, keys[0]
+        ))
+        .body(vec![0u8; 8 * 1024 * 1024 + 1])
+        .send()
+        .await?;
+    assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_upload_rejects_invalid_required_signature_count(
+) -> Result<(), Box<dyn std::error::Error>> {
+    let app = TestApp::spawn_app().await;
+    let client = reqwest::Client::new();
+    let keys = app.create_user_with_keys(&client).await?;
+
+    for count in [0, keys.len() + 1] {
+        let response = client
+            .post(format!(
+                "{}/api/v1/msg/upload?keys={}&required_signature_count={count}",
+                app.address,
+                keys.join(",")
+            ))
+            .body("Hello world!")
+            .send()
+            .await?;
+        assert_invalid_fields(response, &["required_signature_count"]).await?;
+    }
+    let msgs: Vec<serde_json::Value> = client
+        .get(format!("{}/api/v1/msgs", app.address))
+        .send()
+        .await?
+        .json()
+        .await?;
+    assert!(msgs.is_empty());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_testnet_address_rejected_by_mainnet_server(
+) -> Result<(), Box<dyn std::error::Error>> {
+    use base58::{FromBase58, ToBase58};
+    use multisig_ecdsa::crypto::{self, Network};
+    use secp256k1::hashes::{sha256, Hash};
+
+    let app = TestApp::spawn_app().await;
+    let client = reqwest::Client::new();
+    let keys = app.create_user_with_keys(&client).await?;
+    assert_eq!(crypto::address_network(&keys[0]), Ok(Network::Bitcoin));
+
+    // Same key hash, testnet version byte
+    let decoded = keys[0].from_base58().map_err(|_| "bad base58")?;
+    let mut testnet = vec![Network::Testnet.p2pkh_version()];
+    testnet.extend_from_slice(&decoded[1..21]);
+    let checksum = sha256::Hash::hash(&testnet).hash_again();
+    testnet.extend_from_slice(&checksum[..4]);
+    let testnet = testnet.to_base58();
+    assert_eq!(crypto::address_network(&testnet), Ok(Network::Testnet));
+
+    let response = client
+        .post(format!("{}/api/v1/msg", app.address))
+        .json(&PostMsgRequest {
+            content: "Hello world!".to_string(),
+            keys: vec![testnet],
+            ..Default::default()
+        })
+        .send()
+        .await?;
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    assert!(response.text().await?.contains("testnet"));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_msg_over_max_participants_is_rejected(
+) -> Result<(), Box<dyn std::error::Error>> {
+    let app = TestApp::spawn_app_with(
+        Settings::builder()
+            .log_filter("off")
+            .max_participants(3)
+            .build(),
+    )
+    .await;
+    let client = reqwest::Client::new();
+    let keys = app.create_user_with_keys(&client).await?;
+    let secp = secp256k1::Secp256k1::new();
+    let external = multisig_ecdsa::crypto::new_keypair(&secp)?;
+    let create = |pubkeys: Vec<String>| {
+        client
+            .post(format!("{}/api/v1/msg", app.address))
+            .json(&PostMsgRequest {
+                content: "Hello world!".to_string(),
+                keys: keys.clone(),
+                pubkeys,
+                ..Default::default()
+            })
+            .send()
+    };
+
+    let response = create(vec![external.public_key().to_string()]).await?;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let response = create(vec![]).await?;
+    assert_eq!(response.status(), StatusCode::OK);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_bulk_creation_stops_at_max_messages(
+) -> Result<(), Box<dyn std::error::Error>> {
+    let app = TestApp::spawn_app_with(
+        Settings::builder()
+            .log_filter("off")
+            .max_messages(2)
+            .build(),
+    )
+    .await;
+    let client = reqwest::Client::new();
+    let keys = app.create_user_with_keys(&client).await?;
+    let reqs = (0..4)
+        .map(|i| PostMsgRequest {
+            content: format!("Hello world {i}!"),
+            keys: keys.clone(),
+            ..Default::default()
+        })
+        .collect::<Vec<_>>();
+    let response = client
+        .post(format!("{}/api/v1/msgs", app.address))
+        .json(&reqs)
+        .send()
+        .await?;
+    assert_eq!(response.status(), StatusCode::OK);
+    let outcomes: Vec<CreateMsgOutcome> = response.json().await?;
+    assert!(matches!(outcomes[0], CreateMsgOutcome::Created(_)));
+    assert!(matches!(outcomes[1], CreateMsgOutcome::Created(_)));
+    for outcome in &outcomes[2..] {
+        assert!(matches!(
+            outcome,
+            CreateMsgOutcome::Failed { status: 503, .. }
+        ));
+    }
+    let msgs: Vec<MessageDetail> = client
+        .get(format!("{}/api/v1/msgs", app.address))
+        .send()
+        .await?
+        .json()
+        .await?;
+    assert_eq!(msgs.len(), 2);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_msg_creation_shed_over_max_messages(
+) -> Result<(), Box<dyn std::error::Error>> {
+    let app = TestApp::spawn_app_with(
+        Settings::builder()
+            .log_filter("off")
+            .max_messages(1)
+            .build(),
+    )
+    .await;
+    let client = reqwest::Client::new();
+    let keys = app.create_user_with_keys(&client).await?;
+    let msg_id = app.create_msg(&client, &keys, "Hello world!").await?;
+
+    let response = client
+        .post(format!("{}/api/v1/msg", app.address))
+        .json(&PostMsgRequest {
+            content: "Goodbye world!".to_string(),
+            keys: keys.clone(),
+            ..Default::default()
+        })
+        .send()
+        .await?;
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+    // Existing messages are still served
+    let response = client
+        .post(format!("{}/api/v1/msg/{}", app.address, msg_id))
+        .json(&SignMsgRequest {
+            keys,
+            ..Default::default()
+        })
+        .send()
+        .await?;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = client
+        .get(format!("{}/api/v1/msg/{}", app.address, msg_id))
+        .send()
+        .await?;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.text().await?, "success");
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_signing_invalidates_cached_verification(
+) -> Result<(), Box<dyn std::error::Error>> {
+    let app = TestApp::spawn_app().await;
+    let client = reqwest::Client::new();
+    let keys = app.create_user_with_keys(&client).await?;
+    let msg_id = app.create_msg(&client, &keys, "Hello world!").await?;
+    let verify = || async {
+        client
+            .get(format!("{}/api/v1/msg/{}", app.address, msg_id))
+            .header("Accept", "application/json")
+            .send()
+            .await?
+            .json::<VerificationResult>()
+            .await
+    };
+
+    // Second verify of the same version is served from the cache
+    for _ in 0..2 {
+        let result = verify().await?;
+        assert!(!result.success);
+        assert_eq!(result.signed, 0);
+    }
+
+    let response = client
+        .post(format!("{}/api/v1/msg/{}", app.address, msg_id))
+        .json(&SignMsgRequest {
+            keys,
+            ..Default::default()
+        })
+        .send()
+        .await?;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let result = verify().await?;
+    assert!(result.success);
+    assert_eq!(result.signed, 3);
+    Ok(())
+}
+
+#[test]
+fn test_later_config_file_overrides_earlier(
+) -> Result<(), Box<dyn std::error::Error>> {
+    let config = Settings::load_from_files([
+        "tests/fixtures/base.yaml",
+        "tests/fixtures/override.yaml",
+    ])?;
+    assert_eq!(config.app_port, 9090);
+    assert_eq!(config.log_filter, "debug");
+    // Values missing from the override are kept
+    assert_eq!(config.app_ip, std::net::Ipv4Addr::LOCALHOST);
+    assert_eq!(config.max_upload_bytes, 1024);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_webhook_retried_until_delivered(
+) -> Result<(), Box<dyn std::error::Error>> {
+    use axum::response::IntoResponse;
+    use std::time::{Duration, Instant};
+
+    // Answers 503 with `Retry-After`, then 503 without it, then 200
+    let attempts = Arc::new(Mutex::new(Vec::<Instant>::new()));
+    let receiver = axum::Router::new().route(
+        "/hook",
+        axum::routing::post({
+            let attempts = attempts.clone();
+            move || async move {
+                let mut attempts = attempts.lock().unwrap();
+                attempts.push(Instant::now());
+                match attempts.len() {
+                    1 => (
+                        StatusCode::SERVICE_UNAVAILABLE,
+                        [("Retry-After", "1")],
+                    )
+                        .into_response(),
+                    2 => StatusCode::SERVICE_UNAVAILABLE.into_response(),
+                    _ => StatusCode::OK.into_response(),
+                }
+            }
+        }),
+    );
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let hook_addr = listener.local_addr()?;
+    tokio::spawn(async move { axum::serve(listener, receiver).await });
+
+    let app = TestApp::spawn_app_with(
+        Settings::builder()
+            .log_filter("off")
+            .webhook_url(format!("http://{hook_addr}/hook"))
+            .webhook_backoff_ms(100)
+            .build(),
+    )
+    .await;
+    let client = reqwest::Client::new();
+    let keys = app.create_user_with_keys(&client).await?;
+    let msg_id = app.create_msg(&client, &keys, "Hello world!").await?;
+    let response = client
+        .post(format!("{}/api/v1/msg/{}", app.address, msg_id))
+        .json(&SignMsgRequest {
+            keys,
+            ..Default::default()
+        })
+        .send()
+        .await?;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while attempts.lock().unwrap().len() < 3 && Instant::now() < deadline {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    let attempts = attempts.lock().unwrap().clone();
+    assert_eq!(attempts.len(), 3);
+    // `Retry-After` is honored, then backoff with up to 50% jitter is used
+    assert!(attempts[1] - attempts[0] >= Duration::from_secs(1));
+    let backoff = attempts[2] - attempts[1];
+    assert!(backoff >= Duration::from_millis(100));
+    assert!(backoff < Duration::from_secs(1));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_search_users_by_name_prefix(
+) -> Result<(), Box<dyn std::error::Error>> {
+    let app = TestApp::spawn_app().await;
+    let client = reqwest::Client::new();
+    for name in ["alice", "albert", "bob", "alex"] {
+        let response = client
+            .post(format!("{}/api/v1/user?name={}", app.address, name))
+            .send()
+            .await?;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+    let search = |query: &'static str| {
+        let client = client.clone();
+        let url = format!("{}/api/v1/users/search?{}", app.address, query);
+        async move {
+            client
+                .get(url)
+                .send()
+                .await?
+                .json::<Vec<serde_json::Value>>()
+                .await
+        }
+    };
+    let names = |users: Vec<serde_json::Value>| {
+        users
+            .into_iter()
+            .map(|u| u["name"].as_str().unwrap_or_default().to_string())
+            .collect::<Vec<_>>()
+    };
+
+    assert_eq!(names(search("q=al").await?), ["albert", "alex", "alice"]);
+    assert_eq!(names(search("q=al&limit=2").await?), ["albert", "alex"]);
+    assert!(search("q=zed").await?.is_empty());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_summary_trace_verbosity_omits_headers(
+) -> Result<(), Box<dyn std::error::Error>> {
+    use multisig_ecdsa::middleware::TraceVerbosity;
+
+    let messages = EventMessages::default();
+    let subscriber = tracing_subscriber::registry()
+        .with(multisig_ecdsa::startup::env_filter("info")?)
+        .with(messages.clone());
+    let _guard = tracing::subscriber::set_default(subscriber);
+
+    let app = TestApp::spawn_app_with(
+        Settings::builder()
+            .log_filter("off")
+            .trace_verbosity("/api/v1/users", TraceVerbosity::Summary)
+            .build(),
+    )
+    .await;
+    let client = reqwest::Client::new();
+    for path in ["/api/v1/users", "/api/v1/msgs"] {
+        let response = client
+            .get(format!("{}{}", app.address, path))
+            .header("X-Trace-Probe", path)
+            .send()
+            .await?;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    // Headers are logged for the default verbosity only
+    assert!(messages.contains("/api/v1/users"));
+    assert!(!messages.contains("x-trace-probe:/api/v1/users"));
+    assert!(messages.contains("x-trace-probe:/api/v1/msgs"));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_standard_request_tracing_traces_requests(
+) -> Result<(), Box<dyn std::error::Error>> {
+    use multisig_ecdsa::middleware::RequestTracing;
+
+    let messages = EventMessages::default();
+    let spans = SpanNames::default();
+    let subscriber = tracing_subscriber::registry()
+        .with(multisig_ecdsa::startup::env_filter("info")?)
+        .with(messages.clone())
+        .with(spans.clone());
+    let _guard = tracing::subscriber::set_default(subscriber);
+
+    let app = TestApp::spawn_app_with(
+        Settings::builder()
+            .log_filter("off")
+            .request_tracing(RequestTracing::Standard)
+            .log_forbidden_bodies(true)
+            .capture_requests(8)
+            .admin_allowed_cidrs(vec!["10.0.0.0/8".parse()?])
+            .build(),
+    )
+    .await;
+    let client = reqwest::Client::new();
+    let response = client
+        .get(format!("{}/api/v1/msgs", app.address))
+        .send()
+        .await?;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(response.headers().contains_key("x-request-id"));
+    let response = client
+        .get(format!("{}/api/v1/admin/recent-requests", app.address))
+        .send()
+        .await?;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+    assert!(spans.contains("request"));
+    assert!(messages.contains("started processing request"));
+    assert!(messages.contains("finished processing request"));
+    assert!(messages
+        .contains("Forbidden request: GET: /api/v1/admin/recent-requests"));
+    // The custom layer is replaced, not stacked
+    assert!(!messages.contains("Request:"));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_create_user_with_initial_keys(
+) -> Result<(), Box<dyn std::error::Error>> {
+    let app = TestApp::spawn_app().await;
+    let client = reqwest::Client::new();
+    let response = client
+        .post(format!(
+            "{}/api/v1/user?name=alice&with_keys=2",
+            app.address
+        ))
+        .send()
+        .await?;
+    assert_eq!(response.status(), StatusCode::OK);
+    let user: serde_json::Value = response.json().await?;
+    assert_eq!(user["name"], "alice");
+    let keys = user["keys"].as_array().ok_or("no keys")?;
+    assert_eq!(keys.len(), 2);
+
+    // Keys are stored along with the user
+    let stored: serde_json::Value = client
+        .get(format!("{}/api/v1/user/alice", app.address))
+        .send()
+        .await?
+        .json()
+        .await?;
+    assert_eq!(&stored["keys"], &user["keys"]);
+    Ok(())
+}
+
+#[test]
+fn test_check_config_rejects_bad_settings() {
+    assert!(Settings::builder().build().validate().is_ok());
+
+    let config = Settings::builder()
+        .log_filter("info,=")
+        .max_upload_bytes(0)
+        .webhook_url("not a url")
+        .build();
+    let error = config
+        .validate()
+        .expect_err("config is invalid")
+        .to_string();
+    for field in ["log_filter", "max_upload_bytes", "webhook_url"] {
+        assert!(error.contains(field), "{field} isn't reported: {error}");
+    }
+
+    // Unparseable bind address fails on load
+    assert!(
+        Settings::load_from_files(["tests/fixtures/bad_address.yaml"]).is_err()
+    );
+}
+
+#[tokio::test]
+async fn test_open_msg_collects_signatures_from_unknown_keys(
+) -> Result<(), Box<dyn std::error::Error>> {
+    let app = TestApp::spawn_app().await;
+    let client = reqwest::Client::new();
+    let response = client
+        .post(format!("{}/api/v1/msg", app.address))
+        .json(&PostMsgRequest {
+            content: "Hello world!".to_string(),
+            keys: vec![],
+            required_signature_count: Some(2),
+            open: true,
+            ..Default::default()
+        })
+        .send()
+        .await?;
+    assert_eq!(response.status(), StatusCode::OK);
+    let msg_id = response.json::<CreatedMsg>().await?.id.to_string();
+
+    // Keys the service has never seen, signed offline
+    let secp = secp256k1::Secp256k1::new();
+    for _ in 0..2 {
+        let keypair = multisig_ecdsa::crypto::new_keypair(&secp)?;
+        let signature =
+            multisig_ecdsa::crypto::sign(&secp, b"Hello world!", &keypair)?;
+        let response = client
+            .post(format!("{}/api/v1/msg/{}/sign-raw", app.address, msg_id))
+            .json(&SignRawMsgRequest {
+                address: multisig_ecdsa::crypto::bt_addr_from_pk(
+                    &keypair.public_key(),
+                    app.config.network,
+                ),
+                signature_der_hex: signature.to_string(),
+                pubkey_hex: Some(keypair.public_key().to_string()),
+            })
+            .send()
+            .await?;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    let response = client
+        .get(format!("{}/api/v1/msg/{}", app.address, msg_id))
+        .send()
+        .await?;
+    assert_eq!(response.text().await?, "success");
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_responses_carry_security_headers(
+) -> Result<(), Box<dyn std::error::Error>> {
+    let app = TestApp::spawn_app_with(
+        Settings::builder()
+            .log_filter("off")
+            .content_security_policy("default-src 'none'")
+            .build(),
+    )
+    .await;
+    let client = reqwest::Client::new();
+
+    for url in [
+        format!("{}/api/healthcheck", app.address),
+        format!("{}/api/v1/users", app.address),
+    ] {
+        let response = client.get(url).send().await?;
+        let headers = response.headers();
+        assert_eq!(headers["x-content-type-options"], "nosniff");
+        assert_eq!(headers["x-frame-options"], "DENY");
+        assert_eq!(headers["content-security-policy"], "default-src 'none'");
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_msg_without_keys_is_unprocessable(
+) -> Result<(), Box<dyn std::error::Error>> {
+    let app = TestApp::spawn_app().await;
+    let response = reqwest::Client::new()
+        .post(format!("{}/api/v1/msg", app.address))
+        .json(&PostMsgRequest {
+            content: "Hello world!".to_string(),
+            keys: vec![],
+            ..Default::default()
+        })
+        .send()
+        .await?;
+    assert_invalid_fields(response, &["keys"]).await
+}
+
+#[tokio::test]
+async fn test_msg_with_out_of_range_threshold_is_unprocessable(
+) -> Result<(), Box<dyn std::error::Error>> {
+    let app = TestApp::spawn_app().await;
+    let client = reqwest::Client::new();
+    let keys = app.create_user_with_keys(&client).await?;
+    for count in [0, keys.len() + 1] {
+        let response = client
+            .post(format!("{}/api/v1/msg", app.address))
+            .json(&PostMsgRequest {
+                content: "Hello world!".to_string(),
+                keys: keys.clone(),
+                required_signature_count: Some(count),
+                ..Default::default()
+            })
+            .send()
+            .await?;
+        assert_invalid_fields(response, &["requiredSignatureCount"]).await?;
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_msg_with_long_content_is_unprocessable(
+) -> Result<(), Box<dyn std::error::Error>> {
+    let app = TestApp::spawn_app_with(
+        Settings::builder()
+            .log_filter("off")
+            .max_upload_bytes(8)
+            .build(),
+    )
+    .await;
+    let client = reqwest::Client::new();
+    let keys = app.create_user_with_keys(&client).await?;
+    let response = client
+        .post(format!("{}/api/v1/msg", app.address))
+        .json(&PostMsgRequest {
+            content: "Hello world!".to_string(),
+            keys,
+            ..Default::default()
+        })
+        .send()
+        .await?;
+    assert_invalid_fields(response, &["content"]).await
+}
+
+#[tokio::test]
+async fn test_finalized_msg_rejects_signing(
+) -> Result<(), Box<dyn std::error::Error>> {
+    let app = TestApp::spawn_app().await;
+    let client = reqwest::Client::new();
+    let keys = app.create_user_with_keys(&client).await?;
+    let msg_id = app.create_msg(&client, &keys, "Hello world!").await?;
+    let finalize_url =
+        format!("{}/api/v1/msg/{}/finalize", app.address, msg_id);
+
+    // Incomplete message can't be finalized
+    let response = client.post(&finalize_url).send().await?;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    let response = client
+        .post(format!("{}/api/v1/msg/{}", app.address, msg_id))
+        .json(&SignMsgRequest {
+            keys: keys.clone(),
+            ..Default::default()
+        })
+        .send()
+        .await?;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = client.post(&finalize_url).send().await?;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(!response.text().await?.is_empty());
+
+    let response = client
+        .post(format!("{}/api/v1/msg/{}", app.address, msg_id))
+        .json(&SignMsgRequest {
+            keys,
+            ..Default::default()
+        })
+        .send()
+        .await?;
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+    let response = client.post(&finalize_url).send().await?;
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+
+    let response = client
+        .get(format!("{}/api/v1/msg/{}", app.address, msg_id))
+        .send()
+        .await?;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.text().await?, "success");
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_keypair_generation_completes_with_single_permit(
+) -> Result<(), Box<dyn std::error::Error>> {
+    let app = TestApp::spawn_app_with(
+        Settings::builder()
+            .log_filter("off")
+            .keygen_concurrency(1)
+            .build(),
+    )
+    .await;
+    let client = reqwest::Client::new();
+    let requests = (0..8).map(|i| {
+        client
+            .post(format!(
+                "{}/api/v1/user?name=user{i}&with_keys=4",
+                app.address
+            ))
+            .send()
+    });
+    for response in futures::future::join_all(requests).await {
+        let user: serde_json::Value = response?.json().await?;
+        assert_eq!(user["keys"].as_array().map(Vec::len), Some(4));
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_concurrent_verification_does_not_starve_healthcheck(
+) -> Result<(), Box<dyn std::error::Error>> {
+    let app = TestApp::spawn_app().await;
+    let client = reqwest::Client::new();
+    let keys = app.create_user_with_keys(&client).await?;
+    for i in 0..8 {
+        let msg_id =
+            app.create_msg(&client, &keys, &format!("msg {i}")).await?;
+        let response = client
+            .post(format!("{}/api/v1/msg/{}", app.address, msg_id))
+            .json(&SignMsgRequest {
+                keys: keys.clone(),
+                ..Default::default()
+            })
+            .send()
+            .await?;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    let verifications = (0..32).map(|_| {
+        client
+            .post(format!("{}/api/v1/msgs/verify-all", app.address))
+            .send()
+    });
+    let healthcheck = tokio::time::timeout(
+        std::time::Duration::from_secs(5),
+        client
+            .get(format!("{}/api/healthcheck", app.address))
+            .send(),
+    );
+    let (verifications, healthcheck) =
+        tokio::join!(futures::future::join_all(verifications), healthcheck);
+    assert_eq!(healthcheck??.status(), StatusCode::OK);
+    for response in verifications {
+        let summary: VerifyAllResponse = response?.json().await?;
+        assert_eq!(summary.complete, 8);
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_static_dir_files_are_served(
+) -> Result<(), Box<dyn std::error::Error>> {
+    let dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+    std::fs::create_dir_all(&dir)?;
+    std::fs::write(dir.join("hello.txt"), "Hello world!")?;
+    std::fs::write(dir.join("index.html"), "<html></html>")?;
+    let app = TestApp::spawn_app_with(
+        Settings::builder()
+            .log_filter("off")
+            .static_dir(&dir)
+            .build(),
+    )
+    .await;
+
+    let response = reqwest::get(format!("{}/hello.txt", app.address)).await?;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.text().await?, "Hello world!");
+    // Unknown paths fall back to the index
+    let response = reqwest::get(format!("{}/some/page", app.address)).await?;
+    assert_eq!(response.text().await?, "<html></html>");
+    std::fs::remove_dir_all(&dir)?;
+
+    // Nothing is served without static dir
+    let app = TestApp::spawn_app().await;
+    let response = reqwest::get(format!("{}/hello.txt", app.address)).await?;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    Ok(())
+}
+
+#[test]
+fn test_static_dir_is_relative_to_config_file(
+) -> Result<(), Box<dyn std::error::Error>> {
+    let config = Settings::load_from_files([
+        "tests/fixtures/base.yaml",
+        "tests/fixtures/static_dir.yaml",
+        "tests/fixtures/override.yaml",
+    ])?;
+    assert_eq!(
+        config.static_dir.as_deref(),
+        Some(std::path::Path::new("tests/fixtures/www"))
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_digest_msg_is_signed_and_found_by_digest(
+) -> Result<(), Box<dyn std::error::Error>> {
+    let app = TestApp::spawn_app().await;
+    let client = reqwest::Client::new();
+    let keys = app.create_user_with_keys(&client).await?;
+    let digest =
+        "a0dc65ffca799873cbea0ac274015b9526505daaaed385155425f7337704883e";
+    let msg_request = |content: &str| PostMsgRequest {
+        content: content.to_string(),
+        keys: keys.clone(),
+        content_is_digest: true,
+        ..Default::default()
+    };
+
+    let response = client
+        .post(format!("{}/api/v1/msg", app.address))
+        .json(&msg_request("not a digest"))
+        .send()
+        .await?;
+    assert_invalid_fields(response, &["content"]).await?;
+
+    let response = client
+        .post(format!("{}/api/v1/msg", app.address))
+        .json(&msg_request(digest))
+        .send()
+        .await?;
+    assert_eq!(response.status(), StatusCode::OK);
+    let msg_id = response.json::<CreatedMsg>().await?.id.to_string();
+    let response = client
+        .post(format!("{}/api/v1/msg/{}", app.address, msg_id))
+        .json(&SignMsgRequest {
+            keys: keys.clone(),
+            ..Default::default()
+        })
+        .send()
+        .await?;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = client
+        .get(format!("{}/api/v1/msg/{}", app.address, msg_id))
+        .send()
+        .await?;
+    assert_eq!(response.text().await?, "success");
+
+    let detail: MessageDetail = client
+        .get(format!(
+            "{}/api/v1/msg/by-hash/{}?digest=true",
+            app.address, digest
+        ))
+        .send()
+        .await?
+        .json()
+        .await?;
+    assert_eq!(detail.id.to_string(), msg_id);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_digest_msg_of_plain_content_hash_is_found_apart(
+) -> Result<(), Box<dyn std::error::Error>> {
+    use secp256k1::hashes::{sha256, Hash};
+
+    let app = TestApp::spawn_app().await;
+    let client = reqwest::Client::new();
+    let keys = app.create_user_with_keys(&client).await?;
+    let plain_id = app.create_msg(&client, &keys, "Hello world!").await?;
+    let hash = sha256::Hash::hash(b"Hello world!");
+    let response = client
+        .post(format!("{}/api/v1/msg", app.address))
+        .json(&PostMsgRequest {
+            content: hash.to_string(),
+            keys,
+            content_is_digest: true,
+            ..Default::default()
+        })
+        .send()
+        .await?;
+    assert_eq!(response.status(), StatusCode::OK);
+    let digest_id = response.json::<CreatedMsg>().await?.id.to_string();
+
+    let by_hash = |query: &'static str| {
+        client
+            .get(format!(
+                "{}/api/v1/msg/by-hash/{}{}",
+                app.address, hash, query
+            ))
+            .send()
+    };
+    let plain: MessageDetail = by_hash("").await?.json().await?;
+    assert_eq!(plain.id.to_string(), plain_id);
+    let digest: MessageDetail = by_hash("?digest=true").await?.json().await?;
+    assert_eq!(digest.id.to_string(), digest_id);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_cloned_msg_starts_unsigned(
+) -> Result<(), Box<dyn std::error::Error>> {
+    let app = TestApp::spawn_app().await;
+    let client = reqwest::Client::new();
+    let keys = app.create_user_with_keys(&client).await?;
+    let msg_id = app.create_msg(&client, &keys, "Hello world!").await?;
+    let response = client
+        .post(format!("{}/api/v1/msg/{}", app.address, msg_id))
+        .json(&SignMsgRequest {
+            keys: keys[..1].to_vec(),
+            ..Default::default()
+        })
+        .send()
+        .await?;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let response = client
+        .post(format!("{}/api/v1/msg/{}/clone", app.address, msg_id))
+        .send()
+        .await?;
+    assert_eq!(response.status(), StatusCode::OK);
+    let clone_id = response.text().await?;
+    assert_ne!(clone_id, msg_id);
+
+    let verification = |id: String| {
+        client
+            .get(format!("{}/api/v1/msg/{}", app.address, id))
+            .header("Accept", "application/json")
+            .send()
+    };
+    let original: VerificationResult =
+        verification(msg_id).await?.json().await?;
+    let clone: VerificationResult =
+        verification(clone_id.clone()).await?.json().await?;
+    assert_eq!((original.signed, clone.signed), (1, 0));
+    assert_eq!(clone.required, original.required);
+
+    // The same participants may sign the clone
+    let response = client
+        .post(format!("{}/api/v1/msg/{}", app.address, clone_id))
+        .json(&SignMsgRequest {
+            keys,
+            ..Default::default()
+        })
+        .send()
+        .await?;
+    assert_eq!(response.status(), StatusCode::OK);
+    let clone: VerificationResult =
+        verification(clone_id).await?.json().await?;
+    assert!(clone.success);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_errors_are_localized_by_accept_language(
+) -> Result<(), Box<dyn std::error::Error>> {
+    use multisig_ecdsa::crypto;
+
+    let app = TestApp::spawn_app().await;
+    let client = reqwest::Client::new();
+    let keys = app.create_user_with_keys(&client).await?;
+    let msg_id = app.create_msg(&client, &keys, "Hello world!").await?;
+
+    let response = client
+        .get(format!("{}/api/v1/msg/{}", app.address, msg_id))
+        .header("Accept-Language", "es")
+        .send()
+        .await?;
+    assert_eq!(
+        response.text().await?,
+        "Firmas insuficientes, aportadas: 0, requeridas: 3"
+    );
+    let result: VerificationResult = client
+        .get(format!("{}/api/v1/msg/{}", app.address, msg_id))
+        .header("Accept", "application/json")
+        .header("Accept-Language", "es-ES,en;q=0.5")
+        .send()
+        .await?
+        .json()
+        .await?;
+    assert_eq!(result.code.as_deref(), Some("not_enough_signatures"));
+    assert!(result.reason.ok_or("no reason")?.starts_with("Firmas"));
+
+    // Signed by a key which isn't a participant
+    let secp = secp256k1::Secp256k1::new();
+    let keypair = crypto::new_keypair(&secp)?;
+    let signature = crypto::sign(&secp, b"Hello world!", &keypair)?;
+    let request = SignRawMsgRequest {
+        address: crypto::bt_addr_from_pk(
+            &keypair.public_key(),
+            crypto::Network::Bitcoin,
+        ),
+        signature_der_hex: signature.to_string(),
+        ..Default::default()
+    };
+    for (language, text) in [
+        ("es", "No se encontró la clave pública"),
+        ("fr, en;q=0.5", "No public key found"),
+    ] {
+        let response = client
+            .post(format!("{}/api/v1/msg/{}/sign-raw", app.address, msg_id))
+            .header("Accept-Language", language)
+            .json(&request)
+            .send()
+            .await?;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(response.headers()["x-error-code"], "public_key_not_found");
+        assert_eq!(response.text().await?, text);
+    }
+    Ok(())
+}
+
+#[test]
+fn test_app_runs_on_single_worker_runtime(
+) -> Result<(), Box<dyn std::error::Error>> {
+    let config = Settings::builder()
+        .log_filter("off")
+        .worker_threads(1)
+        .build();
+    let runtime = multisig_ecdsa::startup::build_runtime(&config)?;
+    assert_eq!(runtime.metrics().num_workers(), 1);
+    runtime.block_on(async {
+        let app = TestApp::spawn_app_with(config).await;
+        let response =
+            reqwest::get(format!("{}/api/healthcheck", app.address)).await?;
+        assert_eq!(response.status(), StatusCode::OK);
+        Ok(())
+    })
+}
+
+#[tokio::test]
+async fn test_sign_with_stale_etag_fail(
+) -> Result<(), Box<dyn std::error::Error>> {
+    let app = TestApp::spawn_app().await;
+    let client = reqwest::Client::new();
+    let keys = app.create_user_with_keys(&client).await?;
+    let msg_id = app.create_msg(&client, &keys, "Hello world!").await?;
+    let msg_url = format!("{}/api/v1/msg/{}", app.address, msg_id);
+
+    let response = client.get(&msg_url).send().await?;
+    let stale_etag = response.headers()["etag"].clone();
+
+    let response = client
+        .post(&msg_url)
+        .header("If-Match", &stale_etag)
+        .json(&SignMsgRequest {
+            keys: keys[..1].to_vec(),
+            ..Default::default()
+        })
+        .send()
+        .await?;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // Signature above changed the message
+    let response = client
+        .post(&msg_url)
+        .header("If-Match", &stale_etag)
+        .json(&SignMsgRequest {
+            keys: keys[1..].to_vec(),
+            ..Default::default()
+        })
+        .send()
+        .await?;
+    assert_eq!(response.status(), StatusCode::PRECONDITION_FAILED);
+
+    let response = client.get(&msg_url).send().await?;
+    let etag = response.headers()["etag"].clone();
+    assert_ne!(etag, stale_etag);
+    assert!(response.text().await?.starts_with("Not enough signatures"));
+    let response = client
+        .post(&msg_url)
+        .header("If-Match", etag)
+        .json(&SignMsgRequest {
+            keys: keys[1..].to_vec(),
+            ..Default::default()
+        })
+        .send()
+        .await?;
+    assert_eq!(response.status(), StatusCode::OK);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_address_from_secret() -> Result<(), Box<dyn std::error::Error>> {
+    let wif = "KwDiBf89QgGbjEhKnhXJuH7LrciVrZi3qYjgd9M7rFU73sVHnoWn";
+    let client = reqwest::Client::new();
+
+    let app = TestApp::spawn_app().await;
+    let response = client
+        .post(format!("{}/api/v1/address/from-secret", app.address))
+        .json(&serde_json::json!({ "secret": wif }))
+        .send()
+        .await?;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    let app = TestApp::spawn_app_with(
+        Settings::builder()
+            .log_filter("off")
+            .enable_secret_tools(true)
+            .build(),
+    )
+    .await;
+    let response = client
+        .post(format!("{}/api/v1/address/from-secret", app.address))
+        .json(&serde_json::json!({ "secret": wif }))
+        .send()
+        .await?;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.text().await?, "1BgGZ9tcN4rm9KBzDn7KprQz87SZ26SAMH");
+    // Legacy WIF of the same key, as wallets show it
+    let response = client
+        .post(format!("{}/api/v1/address/from-secret", app.address))
+        .json(&serde_json::json!({
+            "secret": "5HpHagT65TZzG1PH3CSu63k8DbpvD8s5ip4nEB3kEsreAnchuDf"
+        }))
+        .send()
+        .await?;
+    assert_eq!(response.text().await?, "1EHNa6Q4Jz2uvNExL497mE43ikXhwF6kZm");
+
+    let response = client
+        .post(format!("{}/api/v1/address/from-secret", app.address))
+        .json(&serde_json::json!({ "secret": &wif[1..] }))
+        .send()
+        .await?;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    assert!(!response.text().await?.contains(&wif[1..]));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_verifymessage_checks_bitcoin_core_signature(
+) -> Result<(), Box<dyn std::error::Error>> {
+    // `bitcoin-cli signmessage` of Bitcoin Core functional tests
+    let address = "mpLQjfK79b7CCV4VMJWEWAj5Mpx8Up5zxB";
+    let message = "This is just a test message";
+    let signature = "INbVnW4e6PeRmsv2Qgu8NuopvrVjkcxob+sX8OcZG0SALhWybUjzMLPdAsXI46YZGb0KQTRii+wWIQzRpG/U+S0=";
+    let client = reqwest::Client::new();
+    let app = TestApp::spawn_app_with(
+        Settings::builder()
+            .log_filter("off")
+            .network(multisig_ecdsa::crypto::Network::Testnet)
+            .build(),
+    )
+    .await;
+    let verify = |message: &str, signature: &str| {
+        client
+            .post(format!("{}/api/v1/verifymessage", app.address))
+            .json(&serde_json::json!({
+                "address": address,
+                "message": message,
+                "signature": signature,
+            }))
+            .send()
+    };
+
+    let response = verify(message, signature).await?;
+    assert_eq!(response.status(), StatusCode::OK);
+    let result: serde_json::Value = response.json().await?;
+    assert_eq!(result, serde_json::json!({ "valid": true }));
+
+    let response = verify("Not the signed message", signature).await?;
+    let result: serde_json::Value = response.json().await?;
+    assert_eq!(result, serde_json::json!({ "valid": false }));
+
+    let response = verify(message, "not base64").await?;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_msgs_are_filtered_by_tag(
+) -> Result<(), Box<dyn std::error::Error>> {
+    let app = TestApp::spawn_app().await;
+    let client = reqwest::Client::new();
+    let keys = app.create_user_with_keys(&client).await?;
+
+    let mut ids = HashMap::new();
+    for (content, tags) in [
+        ("Salaries", vec!["payroll", "treasury"]),
+        ("Transfer", vec!["treasury"]),
+        ("Lease", vec!["contracts"]),
+    ] {
+        let response = client
+            .post(format!("{}/api/v1/msg", app.address))
+            .json(&PostMsgRequest {
+                content: content.to_string(),
+                keys: keys.clone(),
+                tags: tags.into_iter().map(String::from).collect(),
+                ..Default::default()
+            })
+            .send()
+            .await?;
+        assert_eq!(response.status(), StatusCode::OK);
+        ids.insert(content, response.json::<CreatedMsg>().await?.id);
+    }
+
+    let treasury: Vec<MessageDetail> = client
+        .get(format!("{}/api/v1/msgs?tag=treasury", app.address))
+        .send()
+        .await?
+        .json()
+        .await?;
+    assert_eq!(
+        treasury.iter().map(|m| m.id).collect::<Vec<_>>(),
+        vec![ids["Salaries"], ids["Transfer"]]
+    );
+    assert_eq!(treasury[0].tags, vec!["payroll", "treasury"]);
+
+    let all: Vec<MessageDetail> = client
+        .get(format!("{}/api/v1/msgs", app.address))
+        .send()
+        .await?
+        .json()
+        .await?;
+    assert_eq!(all.len(), 3);
+    let unknown: Vec<MessageDetail> = client
+        .get(format!("{}/api/v1/msgs?tag=unknown", app.address))
+        .send()
+        .await?
+        .json()
+        .await?;
+    assert!(unknown.is_empty());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_concurrent_keypair_adds_both_survive(
+) -> Result<(), Box<dyn std::error::Error>> {
+    let app = TestApp::spawn_app().await;
+    let client = reqwest::Client::new();
+    let response = client
+        .post(format!("{}/api/v1/user?name=alice", app.address))
+        .send()
+        .await?;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let add_keypair = || {
+        client
+            .post(format!("{}/api/v1/user/alice/keypair", app.address))
+            .send()
+    };
+    let (first, second) = tokio::join!(add_keypair(), add_keypair());
+    let (first, second) = (first?.text().await?, second?.text().await?);
+
+    let user: User = client
+        .get(format!("{}/api/v1/user/alice", app.address))
+        .send()
+        .await?
+        .json()
+        .await?;
+    assert_eq!(user.keys.len(), 2);
+    assert!(user.keys.contains(&first));
+    assert!(user.keys.contains(&second));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_dtos_use_camel_case() -> Result<(), Box<dyn std::error::Error>> {
+    let app = TestApp::spawn_app().await;
+    let client = reqwest::Client::new();
+    let keys = app.create_user_with_keys(&client).await?;
+    app.create_msg(&client, &keys, "Hello world!").await?;
+
+    let msgs: serde_json::Value = client
+        .get(format!("{}/api/v1/msgs", app.address))
+        .send()
+        .await?
+        .json()
+        .await?;
+    assert_eq!(msgs[0]["countRequired"], 3);
+    assert_eq!(msgs[0]["contentEncoding"], "text");
+    assert!(msgs[0].get("count_required").is_none());
+
+    let response = client
+        .post(format!("{}/api/v1/msg", app.address))
+        .json(&serde_json::json!({
+            "content": "Hello world!",
+            "keys": keys,
+            "requiredSignatureCount": 2,
+        }))
+        .send()
+        .await?;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // Clients of 0.1 send snake_case, it is still understood
+    let response = client
+        .post(format!("{}/api/v1/msg", app.address))
+        .json(&serde_json::json!({
+            "content": "Goodbye world!",
+            "keys": keys,
+            "required_signature_count": 1,
+        }))
+        .send()
+        .await?;
+    assert_eq!(response.status(), StatusCode::OK);
+    let created: CreatedMsg = response.json().await?;
+    assert_eq!(created.required, 1);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_version_reports_build_metadata(
+) -> Result<(), Box<dyn std::error::Error>> {
+    let app = TestApp::spawn_app().await;
+    let response = reqwest::get(format!("{}/api/version", app.address)).await?;
+    assert_eq!(response.status(), StatusCode::OK);
+    let info: VersionInfo = response.json().await?;
+    assert_eq!(info.version, env!("CARGO_PKG_VERSION"));
+    assert!(!info.commit.is_empty());
+    assert!(info.built_at <= time::OffsetDateTime::now_utc());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_storage_gauges_are_exported(
+) -> Result<(), Box<dyn std::error::Error>> {
+    let url = |app: &TestApp| format!("{}/api/v1/admin/metrics", app.address);
+    let app = TestApp::spawn_app().await;
+    let client = reqwest::Client::new();
+    let response = client.get(url(&app)).send().await?;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    let app = TestApp::spawn_app_with(
+        Settings::builder()
+            .log_filter("off")
+            .metrics_interval_secs(1)
+            .build(),
+    )
+    .await;
+    let response = client
+        .post(format!("{}/api/v1/user?name=testuser", app.address))
+        .send()
+        .await?;
+    assert_eq!(response.status(), StatusCode::OK);
+    let mut exported = String::new();
+    for _ in 0..30 {
+        let response = client.get(url(&app)).send().await?;
+        assert_eq!(response.status(), StatusCode::OK);
+        exported = response.text().await?;
+        if exported.contains("storage_users 1") {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    }
+    assert!(exported.contains("storage_users 1"), "{exported}");
+    assert!(
+        exported.contains("storage_pending_messages 0"),
+        "{exported}"
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_recent_requests_are_captured(
+) -> Result<(), Box<dyn std::error::Error>> {
+    let app = TestApp::spawn_app_with(
+        Settings::builder()
+            .log_filter("off")
+            .api_key("secret-key")
+            .capture_requests(8)
+            .build(),
+    )
+    .await;
+    let client = reqwest::Client::new();
+    let response = client
+        .post(format!("{}/api/v1/user?name=testuser", app.address))
+        .header("X-API-Key", "secret-key")
+        .send()
+        .await?;
+    assert_eq!(response.status(), StatusCode::OK);
+    let address = client
+        .post(format!("{}/api/v1/user/testuser/keypair", app.address))
+        .header("X-API-Key", "secret-key")
+        .send()
+        .await?
+        .text()
+        .await?;
+    let msg_id = client
+        .post(format!("{}/api/v1/msg", app.address))
+        .header("X-API-Key", "secret-key")
+        .json(&serde_json::json!({ "content": "Hello world!", "keys": [address] }))
+        .send()
+        .await?
+        .json::<CreatedMsg>()
+        .await?
+        .id;
+    // Long bodies pass whole, but only their head is captured
+    let long_content = "a".repeat(MAX_CAPTURED_BODY_BYTES + 1);
+    let long_msg_id = client
+        .post(format!("{}/api/v1/msg", app.address))
+        .header("X-API-Key", "secret-key")
+        .json(
+            &serde_json::json!({ "content": long_content, "keys": [address] }),
+        )
+        .send()
+        .await?
+        .json::<CreatedMsg>()
+        .await?
+        .id;
+    let downloaded = client
+        .get(format!("{}/api/v1/msg/{long_msg_id}/content", app.address))
+        .send()
+        .await?
+        .text()
+        .await?;
+    assert_eq!(downloaded, long_content);
+
+    let url = format!("{}/api/v1/admin/recent-requests", app.address);
+    assert_eq!(
+        client.get(&url).send().await?.status(),
+        StatusCode::UNAUTHORIZED
+    );
+    let captured: Vec<serde_json::Value> = client
+        .get(&url)
+        .header("X-API-Key", "secret-key")
+        .send()
+        .await?
+        .json()
+        .await?;
+    assert_eq!(captured.len(), 5);
+    let create = &captured[2];
+    assert_eq!(create["method"], "POST");
+    assert_eq!(create["path"], "/api/v1/msg");
+    assert_eq!(create["status"], 200);
+    let response_body =
+        create["responseBody"].as_str().ok_or("no response body")?;
+    assert_eq!(
+        serde_json::from_str::<CreatedMsg>(response_body)?.id,
+        msg_id
+    );
+    let request_body = create["requestBody"].as_str().ok_or("no body")?;
+    assert!(request_body.contains("Hello world!"));
+    assert_eq!(create["requestBodyTruncated"], false);
+    assert_eq!(create["responseBodyTruncated"], false);
+    let create_long = &captured[3];
+    assert_eq!(create_long["status"], 200);
+    assert_eq!(create_long["requestBodyTruncated"], true);
+    let request_body = create_long["requestBody"].as_str().ok_or("no body")?;
+    assert_eq!(request_body.len(), MAX_CAPTURED_BODY_BYTES);
+    let download = &captured[4];
+    assert_eq!(download["responseBodyTruncated"], true);
+    assert_eq!(
+        download["responseBody"].as_str(),
+        Some(&long_content[..MAX_CAPTURED_BODY_BYTES])
+    );
+    let api_key = create["requestHeaders"]
+        .as_array()
+        .ok_or("no headers")?
+        .iter()
+        .find(|h| h[0] == "x-api-key")
+        .ok_or("no api key header")?;
+    assert_eq!(api_key[1], "[REDACTED]");
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_msg_with_external_participants_signed_via_sign_raw(
+) -> Result<(), Box<dyn std::error::Error>> {
+    use multisig_ecdsa::crypto;
+
+    let app = TestApp::spawn_app().await;
+    let client = reqwest::Client::new();
+    // The service never sees these keypairs
+    let secp = secp256k1::Secp256k1::new();
+    let keypairs = [crypto::new_keypair(&secp)?, crypto::new_keypair(&secp)?];
+
+    let response = client
+        .post(format!("{}/api/v1/msg", app.address))
+        .json(&PostMsgRequest {
+            content: "Hello world!".to_string(),
+            keys: vec![],
+            pubkeys: keypairs
+                .iter()
+                .map(|k| k.public_key().to_string())
+                .collect(),
+            ..Default::default()
+        })
+        .send()
+        .await?;
+    assert_eq!(response.status(), StatusCode::OK);
+    let msg_id = response.json::<CreatedMsg>().await?.id.to_string();
+
+    for keypair in &keypairs {
+        let signature = crypto::sign(&secp, b"Hello world!", keypair)?;
+        let response = client
+            .post(format!("{}/api/v1/msg/{}/sign-raw", app.address, msg_id))
+            .json(&SignRawMsgRequest {
+                address: crypto::bt_addr_from_pk(
+                    &keypair.public_key(),
+                    crypto::Network::Bitcoin,
+                ),
+                signature_der_hex: signature.to_string(),
+                ..Default::default()
+            })
+            .send()
+            .await?;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+    let response = client
+        .get(format!("{}/api/v1/msg/{}", app.address, msg_id))
+        .send()
+        .await?;
+    assert_eq!(response.text().await?, "success");
+
+    // Uncompressed keys aren't accepted as participants
+    let uncompressed = keypairs[0]
+        .public_key()
+        .serialize_uncompressed()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect::<String>();
+    let response = client
+        .post(format!("{}/api/v1/msg", app.address))
+        .json(&serde_json::json!({
+            "content": "Hello world!",
+            "pubkeys": [uncompressed],
+        }))
+        .send()
+        .await?;
+    assert_invalid_fields(response, &["pubkeys"]).await
+}
+
+#[tokio::test]
+async fn test_every_bad_key_is_reported(
+) -> Result<(), Box<dyn std::error::Error>> {
+    let app = TestApp::spawn_app().await;
+    let client = reqwest::Client::new();
+    let mut keys = app.create_user_with_keys(&client).await?;
+    keys[0] = "badkey".to_string();
+    // Testnet address on mainnet
+    keys[2] = "mipcBbFg9gMiCh81Kj8tqqdgoZub1ZJRfn".to_string();
+
+    let response = client
+        .post(format!("{}/api/v1/msg", app.address))
+        .json(&PostMsgRequest {
+            content: "Hello world!".to_string(),
+            keys: keys.clone(),
+            ..Default::default()
+        })
+        .send()
+        .await?;
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    let body: serde_json::Value = response.json().await?;
+    let errors = body["errors"].as_array().ok_or("no errors list")?;
+    assert_eq!(errors.len(), 2);
+    assert!(errors.iter().all(|e| e["field"] == "keys"));
+    let messages = errors
+        .iter()
+        .filter_map(|e| e["message"].as_str())
+        .collect::<Vec<_>>();
+    assert!(messages[0].starts_with("badkey: "));
+    assert!(messages[1].contains("testnet"));
+
+    // Key errors come along with the rest of field errors
+    let response = client
+        .post(format!("{}/api/v1/msg", app.address))
+        .json(&PostMsgRequest {
+            content: "Hello world!".to_string(),
+            keys: vec![keys[0].clone()],
+            required_signature_count: Some(2),
+            ..Default::default()
+        })
+        .send()
+        .await?;
+    assert_invalid_fields(response, &["keys", "requiredSignatureCount"])
+        .await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_batch_get_keeps_positions(
+) -> Result<(), Box<dyn std::error::Error>> {
+    let app = TestApp::spawn_app().await;
+    let client = reqwest::Client::new();
+    let keys = app.create_user_with_keys(&client).await?;
+    let first = app.create_msg(&client, &keys, "First").await?.parse()?;
+    let second = app.create_msg(&client, &keys, "Second").await?.parse()?;
+    let missing = uuid::Uuid::new_v4();
+
+    let msgs: Vec<Option<MessageDetail>> = client
+        .post(format!("{}/api/v1/msgs/batch-get", app.address))
+        .json(&BatchGetMsgsRequest {
+            ids: vec![second, missing, first],
+        })
+        .send()
+        .await?
+        .json()
+        .await?;
+    let ids: Vec<_> = msgs.iter().map(|m| m.as_ref().map(|m| m.id)).collect();
+    assert_eq!(ids, vec![Some(second), None, Some(first)]);
+    assert_eq!(msgs[0].as_ref().map(|m| m.content.as_str()), Some("Second"));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_user_key_stats_count_signed_msgs(
+) -> Result<(), Box<dyn std::error::Error>> {
+    let app = TestApp::spawn_app().await;
+    let client = reqwest::Client::new();
+    let keys = app.create_user_with_keys(&client).await?;
+    for content in ["Hello world!", "Goodbye world!"] {
+        let msg_id = app.create_msg(&client, &keys, content).await?;
+        let response = client
+            .post(format!("{}/api/v1/msg/{}", app.address, msg_id))
+            .json(&SignMsgRequest {
+                keys: keys[..1].to_vec(),
+                ..Default::default()
+            })
+            .send()
+            .await?;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+    let get_user = |query: &'static str| {
+        client
+            .get(format!("{}/api/v1/user/testuser{query}", app.address))
+            .send()
+    };
+
+    let user: User = get_user("?with_stats=true").await?.json().await?;
+    let stats = user.key_stats.ok_or("no key stats")?;
+    assert_eq!(
+        stats
+            .iter()
+            .map(|s| (s.address.as_str(), s.signed_count))
+            .collect::<Vec<_>>(),
+        [
+            (keys[0].as_str(), 2),
+            (keys[1].as_str(), 0),
+            (keys[2].as_str(), 0)
+        ]
+    );
+
+    // Stats are computed only on request
+    let user: User = get_user("").await?.json().await?;
+    assert!(user.key_stats.is_none());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_signing_advances_updated_at(
+) -> Result<(), Box<dyn std::error::Error>> {
+    let app = TestApp::spawn_app().await;
+    let client = reqwest::Client::new();
+    let keys = app.create_user_with_keys(&client).await?;
+    let msg_id = app.create_msg(&client, &keys, "Hello world!").await?;
+    let detail = || async {
+        let msgs: Vec<Option<MessageDetail>> = client
+            .post(format!("{}/api/v1/msgs/batch-get", app.address))
+            .json(&BatchGetMsgsRequest {
+                ids: vec![msg_id.parse()?],
+            })
+            .send()
+            .await?
+            .json()
+            .await?;
+        msgs.into_iter()
+            .next()
+            .flatten()
+            .ok_or_else(|| Box::<dyn std::error::Error>::from("no message"))
+    };
+
+    let before = detail().await?;
+    assert_eq!(before.created_at, before.updated_at);
+    let response = client
+        .post(format!("{}/api/v1/msg/{}", app.address, msg_id))
+        .json(&SignMsgRequest {
+            keys: keys[..1].to_vec(),
+            ..Default::default()
+        })
+        .send()
+        .await?;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let after = detail().await?;
+    assert_eq!(after.created_at, before.created_at);
+    assert!(after.updated_at > before.updated_at);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_signing_is_refused_after_deadline(
+) -> Result<(), Box<dyn std::error::Error>> {
+    let storage = InMemoryStorage::default();
+    let app = TestApp::spawn_app_with_storage(
+        Settings::builder().log_filter("off").build(),
+        Arc::new(storage.clone()),
+    )
+    .await;
+    let client = reqwest::Client::new();
+    let keys = app.create_user_with_keys(&client).await?;
+    let create = |sign_deadline| {
+        client
+            .post(format!("{}/api/v1/msg", app.address))
+            .json(&PostMsgRequest {
+                content: "Hello world!".to_string(),
+                keys: keys.clone(),
+                sign_deadline: Some(sign_deadline),
+                ..Default::default()
+            })
+            .send()
+    };
+    let sign = |msg_id: uuid::Uuid, key: &String| {
+        client
+            .post(format!("{}/api/v1/msg/{}", app.address, msg_id))
+            .json(&SignMsgRequest {
+                keys: vec![key.clone()],
+                ..Default::default()
+            })
+            .send()
+    };
+
+    let past = time::OffsetDateTime::now_utc() - time::Duration::seconds(1);
+    assert_invalid_fields(create(past).await?, &["signDeadline"]).await?;
+
+    let deadline = time::OffsetDateTime::now_utc() + time::Duration::hours(1);
+    let created: CreatedMsg = create(deadline).await?.json().await?;
+    assert_eq!(sign(created.id, &keys[0]).await?.status(), StatusCode::OK);
+
+    // The boundary is covered by `Message::check_sign_deadline` tests, here
+    // the deadline is moved far into the past instead of waiting for it
+    storage
+        .update_msg(
+            &created.id,
+            Box::new(move |msg| {
+                msg.sign_deadline = Some(past);
+                Ok(())
+            }),
+        )
+        .await?;
+    let response = sign(created.id, &keys[1]).await?;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    assert_eq!(response.headers()["x-error-code"], "deadline_passed");
+    assert!(response.text().await?.contains("deadline"));
+
+    // The message is kept and verified with the signature it has
+    let result: VerificationResult = client
+        .get(format!("{}/api/v1/msg/{}", app.address, created.id))
+        .header("Accept", "application/json")
+        .send()
+        .await?
+        .json()
+        .await?;
+    assert_eq!(result.signed, 1);
+    assert!(!result.success);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_msg_is_signed_with_participant_keys_of_user(
+) -> Result<(), Box<dyn std::error::Error>> {
+    let app = TestApp::spawn_app().await;
+    let client = reqwest::Client::new();
+    let keys = app.create_user_with_keys(&client).await?;
+    let response = client
+        .post(format!("{}/api/v1/user?name=bob", app.address))
+        .send()
+        .await?;
+    assert_eq!(response.status(), StatusCode::OK);
+    let bob_key = client
+        .post(format!("{}/api/v1/user/bob/keypair", app.address))
+        .send()
+        .await?
+        .text()
+        .await?;
+    let participants = [keys[0].clone(), keys[1].clone(), bob_key];
+    let msg_id = app
+        .create_msg(&client, &participants, "Hello world!")
+        .await?;
+
+    let response = client
+        .post(format!("{}/api/v1/msg/{}", app.address, msg_id))
+        .json(&SignMsgRequest {
+            keys: vec![],
+            username: Some("testuser".to_string()),
+        })
+        .send()
+        .await?;
+    assert_eq!(response.status(), StatusCode::OK);
+    let outcomes: Vec<KeySignOutcome> = response.json().await?;
+    // The third key of the user isn't a participant
+    assert_eq!(
+        outcomes.iter().map(|o| &o.address).collect::<Vec<_>>(),
+        [&keys[0], &keys[1]]
+    );
+    assert!(outcomes.iter().all(|o| o.outcome == SignOutcome::Signed));
+
+    let result: VerificationResult = client
+        .get(format!("{}/api/v1/msg/{}", app.address, msg_id))
+        .header("Accept", "application/json")
+        .send()
+        .await?
+        .json()
+        .await?;
+    assert_eq!(result.signed, 2);
+
+    let response = client
+        .post(format!("{}/api/v1/msg/{}", app.address, msg_id))
+        .json(&SignMsgRequest {
+            keys: vec![keys[0].clone()],
+            username: Some("testuser".to_string()),
+        })
+        .send()
+        .await?;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_msg_is_verified_against_supplied_keys(
+) -> Result<(), Box<dyn std::error::Error>> {
+    let app = TestApp::spawn_app().await;
+    let client = reqwest::Client::new();
+    let keys = app.create_user_with_keys(&client).await?;
+    let msg_id = app.create_msg(&client, &keys[..2], "Hello world!").await?;
+    let response = client
+        .post(format!("{}/api/v1/msg/{}", app.address, msg_id))
+        .json(&SignMsgRequest {
+            keys: keys[..2].to_vec(),
+            ..Default::default()
+        })
+        .send()
+        .await?;
+    assert_eq!(response.status(), StatusCode::OK);
+    let verify_against = |keys: Vec<String>| {
+        client
+            .post(format!(
+                "{}/api/v1/msg/{}/verify-against",
+                app.address, msg_id
+            ))
+            .json(&VerifyAgainstRequest { keys })
+            .send()
+    };
+
+    let response = verify_against(keys[..2].to_vec()).await?;
+    assert_eq!(response.status(), StatusCode::OK);
+    let result: VerifyAgainstResult = response.json().await?;
+    assert_eq!(result.matched, keys[..2]);
+    assert!(result.unmatched.is_empty());
+    assert!(result.unexpected.is_empty());
+    assert!(result.participants_match);
+
+    let response =
+        verify_against(vec![keys[0].clone(), keys[2].clone()]).await?;
+    assert_eq!(response.status(), StatusCode::OK);
+    let result: VerifyAgainstResult = response.json().await?;
+    assert_eq!(result.matched, [keys[0].clone()]);
+    assert_eq!(result.unmatched, [keys[2].clone()]);
+    assert_eq!(result.unexpected, [keys[1].clone()]);
+    assert!(!result.participants_match);
+
+    let response = verify_against(vec!["not a key".to_string()]).await?;
+    assert_invalid_fields(response, &["keys"]).await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_hash_only_mode_requires_content_to_verify(
+) -> Result<(), Box<dyn std::error::Error>> {
+    let config = Settings::builder()
+        .log_filter("off")
+        .store_content(false)
+        .build();
+    let app = TestApp::spawn_app_with(config).await;
+    let client = reqwest::Client::new();
+    let keys = app.create_user_with_keys(&client).await?;
+    let msg_id = app.create_msg(&client, &keys, "Hello world!").await?;
+
+    let response = client
+        .post(format!("{}/api/v1/msg/{}", app.address, msg_id))
+        .json(&SignMsgRequest {
+            keys,
+            ..Default::default()
+        })
+        .send()
+        .await?;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let msgs: Vec<MessageDetail> = client
+        .get(format!("{}/api/v1/msgs", app.address))
+        .send()
+        .await?
+        .json()
+        .await?;
+    assert!(!msgs[0].content_stored);
+    assert!(msgs[0].content.is_empty());
+
+    let response = client
+        .get(format!("{}/api/v1/msg/{}", app.address, msg_id))
+        .send()
+        .await?;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let verify = |content: &str| {
+        client
+            .post(format!("{}/api/v1/msg/{}/verify", app.address, msg_id))
+            .json(&VerifyContentRequest {
+                content: content.to_string(),
+            })
+            .send()
+    };
+    let response = verify("Goodbye world!").await?;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let response = verify("Hello world!").await?;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.text().await?, "success");
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_stored_content_is_checked_if_supplied(
+) -> Result<(), Box<dyn std::error::Error>> {
+    let app = TestApp::spawn_app().await;
+    let client = reqwest::Client::new();
+    let keys = app.create_user_with_keys(&client).await?;
+    let msg_id = app.create_msg(&client, &keys, "Hello world!").await?;
+
+    let msgs: Vec<MessageDetail> = client
+        .get(format!("{}/api/v1/msgs", app.address))
+        .send()
+        .await?
+        .json()
+        .await?;
+    assert!(msgs[0].content_stored);
+    assert_eq!(msgs[0].content, "Hello world!");
+
+    let url = format!("{}/api/v1/msg/{}", app.address, msg_id);
+    let response = client.get(&url).send().await?;
+    assert_eq!(response.status(), StatusCode::OK);
+    let verify = |content: &str| {
+        client
+            .post(format!("{url}/verify"))
+            .json(&VerifyContentRequest {
+                content: content.to_string(),
+            })
+            .send()
+    };
+    let response = verify("Goodbye world!").await?;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let response = verify("Hello world!").await?;
+    assert_eq!(response.status(), StatusCode::OK);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_admin_routes_reject_disallowed_peers(
+) -> Result<(), Box<dyn std::error::Error>> {
+    let cidrs = |cidrs: &[&str]| {
+        cidrs
+            .iter()
+            .map(|c| c.parse())
+            .collect::<Result<Vec<_>, _>>()
+    };
+    let client = reqwest::Client::new();
+
+    // Test client connects from 127.0.0.1
+    let app = TestApp::spawn_app_with(
+        Settings::builder()
+            .log_filter("off")
+            .capture_requests(8)
+            .admin_allowed_cidrs(cidrs(&["10.0.0.0/8", "fd00::/8"])?)
+            .build(),
+    )
+    .await;
+    for version in ["v1", "v2"] {
+        let response = client
+            .get(format!(
+                "{}/api/{version}/admin/recent-requests",
+                app.address
+            ))
+            .send()
+            .await?;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+    // Other routes are unaffected
+    let response = client
+        .get(format!("{}/api/v1/msgs", app.address))
+        .send()
+        .await?;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let app = TestApp::spawn_app_with(
+        Settings::builder()
+            .log_filter("off")
+            .capture_requests(8)
+            .admin_allowed_cidrs(cidrs(&["127.0.0.0/8", "::1/128"])?)
+            .build(),
+    )
+    .await;
+    let response = client
+        .get(format!("{}/api/v1/admin/recent-requests", app.address))
+        .send()
+        .await?;
+    assert_eq!(response.status(), StatusCode::OK);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_client_ip_is_forwarded_by_trusted_proxy(
+) -> Result<(), Box<dyn std::error::Error>> {
+    let client = reqwest::Client::new();
+    let admin_allowed = vec!["10.0.0.0/8".parse()?];
+    let recent_requests = |app: &TestApp, forwarded_for: Option<&str>| {
+        let mut request =
+            client.get(format!("{}/api/v1/admin/recent-requests", app.address));
+        if let Some(ip) = forwarded_for {
+            request = request.header("X-Forwarded-For", ip);
+        }
+        request.send()
+    };
+
+    // Test client connects from 127.0.0.1, acting as the proxy
+    let app = TestApp::spawn_app_with(
+        Settings::builder()
+            .log_filter("off")
+            .capture_requests(8)
+            .admin_allowed_cidrs(admin_allowed.clone())
+            .trusted_proxy("127.0.0.1/32".parse()?)
+            .build(),
+    )
+    .await;
+    let response = recent_requests(&app, Some("10.1.2.3")).await?;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = recent_requests(&app, Some("192.0.2.1")).await?;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    let response = recent_requests(&app, None).await?;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+    // Someone else is the proxy, so the header is spoofed
+    let app = TestApp::spawn_app_with(
+        Settings::builder()
+            .log_filter("off")
+            .capture_requests(8)
+            .admin_allowed_cidrs(admin_allowed)
+            .trusted_proxy("10.0.0.1/32".parse()?)
+            .build(),
+    )
+    .await;
+    let response = recent_requests(&app, Some("10.1.2.3")).await?;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_msg_content_is_downloaded_as_is(
+) -> Result<(), Box<dyn std::error::Error>> {
+    let client = reqwest::Client::new();
+    // Not valid utf-8
+    let content = (0..=255).rev().collect::<Vec<u8>>();
+    let upload = |app: &TestApp, keys: &[String]| {
+        client
+            .post(format!(
+                "{}/api/v1/msg/upload?keys={}",
+                app.address,
+                keys.join(",")
+            ))
+            .header("Content-Type", "application/octet-stream")
+            .body(content.clone())
+            .send()
+    };
+
+    let app = TestApp::spawn_app().await;
+    let keys = app.create_user_with_keys(&client).await?;
+    let uploaded: UploadMsgResponse = upload(&app, &keys).await?.json().await?;
+    let response = client
+        .get(format!(
+            "{}/api/v1/msg/{}/content",
+            app.address, uploaded.id
+        ))
+        .send()
+        .await?;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        response.headers()["content-type"],
+        "application/octet-stream"
+    );
+    assert_eq!(
+        response.headers()["content-disposition"],
+        format!("attachment; filename=\"{}.bin\"", uploaded.id).as_str()
+    );
+    assert_eq!(response.bytes().await?.as_ref(), content.as_slice());
+
+    // v2 envelope leaves the download alone
+    let response = client
+        .get(format!(
+            "{}/api/v2/msg/{}/content",
+            app.address, uploaded.id
+        ))
+        .send()
+        .await?;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        response.headers()["content-type"],
+        "application/octet-stream"
+    );
+    assert!(response.headers().contains_key("content-disposition"));
+    assert_eq!(response.bytes().await?.as_ref(), content.as_slice());
+
+    let response = client
+        .get(format!(
+            "{}/api/v1/msg/{}/content",
+            app.address,
+            uuid::Uuid::new_v4()
+        ))
+        .send()
+        .await?;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    // Only the digest is kept
+    let app = TestApp::spawn_app_with(
+        Settings::builder()
+            .log_filter("off")
+            .store_content(false)
+            .build(),
+    )
+    .await;
+    let keys = app.create_user_with_keys(&client).await?;
+    let uploaded: UploadMsgResponse = upload(&app, &keys).await?.json().await?;
+    let response = client
+        .get(format!(
+            "{}/api/v1/msg/{}/content",
+            app.address, uploaded.id
+        ))
+        .send()
+        .await?;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_rename_user_keeps_keys() -> Result<(), Box<dyn std::error::Error>>
+{
+    let app = TestApp::spawn_app().await;
+    let client = reqwest::Client::new();
+    let before: User = client
+        .post(format!(
+            "{}/api/v1/user?name=alice&with_keys=2",
+            app.address
+        ))
+        .send()
+        .await?
+        .json()
+        .await?;
+
+    let response = client
+        .patch(format!("{}/api/v1/user/alice", app.address))
+        .json(&RenameUserRequest {
+            name: "carol".to_string(),
+        })
+        .send()
+        .await?;
+    assert_eq!(response.status(), StatusCode::OK);
+    let renamed: User = response.json().await?;
+    assert_eq!(renamed.name, "carol");
+
+    let after: Option<User> = client
+        .get(format!("{}/api/v1/user/carol", app.address))
+        .send()
+        .await?
+        .json()
+        .await?;
+    let after = after.ok_or("renamed user not found")?;
+    assert_eq!(after.id, before.id);
+    let mut keys = (before.keys, after.keys);
+    keys.0.sort();
+    keys.1.sort();
+    assert_eq!(keys.0, keys.1);
+    let old: Option<User> = client
+        .get(format!("{}/api/v1/user/alice", app.address))
+        .send()
+        .await?
+        .json()
+        .await?;
+    assert!(old.is_none());
+    // The name index no longer resolves the old name
+    let response = client
+        .post(format!("{}/api/v1/user/alice/keypair", app.address))
+        .send()
+        .await?;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_rename_user_to_taken_name_conflicts(
+) -> Result<(), Box<dyn std::error::Error>> {
+    let app = TestApp::spawn_app().await;
+    let client = reqwest::Client::new();
+    for name in ["alice", "bob"] {
+        let response = client
+            .post(format!("{}/api/v1/user?name={name}", app.address))
+            .send()
+            .await?;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    let rename = |name: &str| {
+        client
+            .patch(format!("{}/api/v1/user/alice", app.address))
+            .json(&RenameUserRequest {
+                name: name.to_string(),
+            })
+            .send()
+    };
+    assert_eq!(rename("bob").await?.status(), StatusCode::CONFLICT);
+    assert_invalid_fields(rename(" ").await?, &["name"]).await?;
+    let response = client
+        .patch(format!("{}/api/v1/user/nobody", app.address))
+        .json(&RenameUserRequest {
+            name: "dave".to_string(),
+        })
+        .send()
+        .await?;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_storage_is_flushed_on_shutdown(
+) -> Result<(), Box<dyn std::error::Error>> {
+    let storage = FlushTrackingStorage::default();
+    let flushed = storage.flushed.clone();
+    let config = Settings::builder().log_filter("off").build();
+    let application =
+        Application::build_with_storage(config, Arc::new(storage)).await?;
+    let address = format!("http://{}", application.address());
+    let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+    let server = tokio::spawn(application.run_until(async {
+        stopped.await.ok();
+    }));
+
+    let response = reqwest::Client::new()
+        .post(format!("{address}/api/v1/user?name=alice"))
+        .send()
+        .await?;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(!flushed.load(Ordering::SeqCst));
+
+    stop.send(()).map_err(|_| "server stopped early")?;
+    server.await??;
+    assert!(flushed.load(Ordering::SeqCst));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_user_list_is_encoded_as_accepted(
+) -> Result<(), Box<dyn std::error::Error>> {
+    let app = TestApp::spawn_app().await;
+    let client = reqwest::Client::new();
+    let keys = app.create_user_with_keys(&client).await?;
+    let list_users = |accept: &'static str| {
+        client
+            .get(format!("{}/api/v1/users", app.address))
+            .header("Accept", accept)
+            .send()
+    };
+
+    let response = list_users("application/json").await?;
+    assert_eq!(response.headers()["content-type"], "application/json");
+    let json: Vec<User> = response.json().await?;
+    assert_eq!(json[0].keys.len(), keys.len());
+
+    let response = list_users("application/cbor").await?;
+    assert_eq!(response.headers()["content-type"], "application/cbor");
+    let cbor: Vec<User> = ciborium::from_reader(&response.bytes().await?[..])?;
+    assert_eq!(cbor[0].id, json[0].id);
+    assert_eq!(cbor[0].keys, json[0].keys);
+
+    let response = list_users("application/msgpack, application/json").await?;
+    assert_eq!(response.headers()["content-type"], "application/msgpack");
+    let msgpack: Vec<User> = rmp_serde::from_slice(&response.bytes().await?)?;
+    assert_eq!(msgpack[0].id, json[0].id);
+    assert_eq!(msgpack[0].name, json[0].name);
+
+    // Unsupported types fall back to json
+    let response = list_users("text/csv").await?;
+    assert_eq!(response.headers()["content-type"], "application/json");
+    Ok(())
+}
+
+// ───── Helpers ──────────────────────────────────────────────────────────── //
+
+/// Records names of all created spans
+#[derive(Clone, Default)]
+struct SpanNames(Arc<Mutex<Vec<&'static str>>>);
+
+impl SpanNames {
+    fn contains(&self, name: &str) -> bool {
+        self.0.lock().unwrap().iter().any(|n| n.eq(&name))
+    }
+}
+
+/// Records levels of all emitted events
+#[derive(Clone, Default)]
+struct EventLevels(Arc<Mutex<Vec<tracing::Level>>>);
+
+impl EventLevels {
+    fn contains(&self, level: tracing::Level) -> bool {
+        self.0.lock().unwrap().contains(&level)
+    }
+}
+
+/// Records `message` fields of all emitted events
+#[derive(Clone, Default)]
+struct EventMessages(Arc<Mutex<Vec<String>>>);
+
+impl EventMessages {
+    fn contains(&self, pattern: &str) -> bool {
+        self.0.lock().unwrap().iter().any(|m| m.contains(pattern))
+    }
+}
+
+impl tracing::field::Visit for EventMessages {
+    fn record_debug(
+        &mut self,
+        field: &tracing::field::Field,
+        value: &dyn std::fmt::Debug,
+    ) {
+        if field.name() == "message" {
+            self.0.lock().unwrap().push(format!("{value:?}"));
+        }
+    }
+}
+
+impl<S: tracing::Subscriber> tracing_subscriber::Layer<S> for EventMessages {
+    fn on_event(
+        &self,
+        event: &tracing::Event<'_>,
+        _: tracing_subscriber::layer::Context<'_, S>,
+    ) {
+        event.record(&mut self.clone());
+    }
+}
+
+impl<S: tracing::Subscriber> tracing_subscriber::Layer<S> for EventLevels {
+    fn on_event(
+        &self,
+        event: &tracing::Event<'_>,
+        _: tracing_subscriber::layer::Context<'_, S>,
+    ) {
+        self.0.lock().unwrap().push(*event.metadata().level());
+    }
+}
+
+impl<S: tracing::Subscriber> tracing_subscriber::Layer<S> for SpanNames {
+    fn on_new_span(
+        &self,
+        attrs: &tracing::span::Attributes<'_>,
+        _: &tracing::span::Id,
+        _: tracing_subscriber::layer::Context<'_, S>,
+    ) {
+        self.0.lock().unwrap().push(attrs.metadata().name());
+    }
+}
+
+/// Response is `422` naming exactly `fields`
+async fn assert_invalid_fields(
+    response: reqwest::Response,
+    fields: &[&str],
+) -> Result<(), Box<dyn std::error::Error>> {
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    let body: serde_json::Value = response.json().await?;
+    let found = body["errors"]
+        .as_array()
+        .ok_or("no errors list")?
+        .iter()
+        .filter_map(|e| e["field"].as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(found, fields);
+    Ok(())
+}
+
+/// In-memory storage which records whether it was flushed
+#[derive(Default)]
+struct FlushTrackingStorage {
+    inner: InMemoryStorage,
+    flushed: Arc<AtomicBool>,
+}
+
+#[async_trait::async_trait]
+impl Storage for FlushTrackingStorage {
+    async fn store_user(&self, user: DomainUser) -> Result<(), StorageError> {
+        self.inner.store_user(user).await
+    }
+    async fn get_user(
+        &self,
+        username: &str,
+    ) -> Result<Option<DomainUser>, StorageError> {
+        self.inner.get_user(username).await
+    }
+    async fn update_user(
+        &self,
+        user_id: &uuid::Uuid,
+        with: UserModifier,
+    ) -> Result<(), StorageError> {
+        self.inner.update_user(user_id, with).await
+    }
+    async fn rename_user(
+        &self,
+        user_id: &uuid::Uuid,
+        new_name: &str,
+    ) -> Result<(), StorageError> {
+        self.inner.rename_user(user_id, new_name).await
+    }
+    async fn remove_user(
+        &self,
+        user_id: &uuid::Uuid,
+    ) -> Result<(), StorageError> {
+        self.inner.remove_user(user_id).await
+    }
+    async fn all_users(&self) -> Result<Vec<DomainUser>, StorageError> {
+        self.inner.all_users().await
+    }
+    fn stream_users(
+        &self,
+    ) -> futures::stream::BoxStream<'_, Result<DomainUser, StorageError>> {
+        self.inner.stream_users()
+    }
+    async fn search_users(
+        &self,
+        prefix: &str,
+        limit: Option<usize>,
+    ) -> Result<Vec<DomainUser>, StorageError> {
+        self.inner.search_users(prefix, limit).await
+    }
+    async fn store_msg(
+        &self,
+        msg: Message,
+    ) -> Result<uuid::Uuid, StorageError> {
+        self.inner.store_msg(msg).await
+    }
+    async fn get_msg(
+        &self,
+        msg_id: &uuid::Uuid,
+    ) -> Result<Option<Message>, StorageError> {
+        self.inner.get_msg(msg_id).await
+    }
+    async fn get_msgs(
+        &self,
+        msg_ids: &[uuid::Uuid],
+    ) -> Result<Vec<Option<Message>>, StorageError> {
+        self.inner.get_msgs(msg_ids).await
+    }
+    async fn update_msg(
+        &self,
+        msg_id: &uuid::Uuid,
+        with: MsgModifier,
+    ) -> Result<(), StorageError> {
+        self.inner.update_msg(msg_id, with).await
+    }
+    async fn get_msg_by_hash(
+        &self,
+        msg_hash: &secp256k1::hashes::sha256::Hash,
+    ) -> Result<Option<Message>, StorageError> {
+        self.inner.get_msg_by_hash(msg_hash).await
+    }
+    async fn remove_msg(
+        &self,
+        msg_hash: &secp256k1::hashes::sha256::Hash,
+    ) -> Result<(), StorageError> {
+        self.inner.remove_msg(msg_hash).await
+    }
+    async fn all_messages(&self) -> Result<Vec<Message>, StorageError> {
+        self.inner.all_messages().await
+    }
+    async fn msgs_by_tag(
+        &self,
+        tag: &str,
+    ) -> Result<Vec<Message>, StorageError> {
+        self.inner.msgs_by_tag(tag).await
+    }
+    async fn count_messages(&self) -> Result<usize, StorageError> {
+        self.inner.count_messages().await
+    }
+    async fn stats(&self) -> Result<StorageStats, StorageError> {
+        self.inner.stats().await
+    }
+    async fn flush(&self) -> Result<(), StorageError> {
+        self.flushed.store(true, Ordering::SeqCst);
+        self.inner.flush().await
+    }
+    fn subscribe(&self) -> tokio::sync::broadcast::Receiver<StorageEvent> {
+        self.inner.subscribe()
+    }
+}